@@ -0,0 +1,203 @@
+use std::time::Instant;
+
+use rust_risc_v::*;
+use rust_risc_v::block::Engine;
+use rust_risc_v::csr::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::branch::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+
+// instructions per second of the interpreter with and without the decode cache and of the block engine,
+// run with cargo bench, cargo bench --features jit adds native code
+
+const INSTRUCTIONS: u64 = 20_000_000;
+
+const NODES: u32 = 256;
+const LIST: u32 = 0x10000;
+const INPUT: u32 = 0x20000;
+const RESULT: u32 = 0x21800;
+const MATRIX: u32 = 0x22000;
+const PRODUCT: u32 = 0x23000;
+
+// the kernels of coremark: a shuffled linked list walk, a state machine parsing numbers from input bytes,
+// a crc16 over the same bytes and a 4x4 matrix multiplication
+fn workload() -> Vec<u32> {
+    let back = |code: &Vec<u32>, target: usize| -(((code.len() - target) * 4) as i16) as u16 & 0x1FFF;
+
+    // list, sums the values and counts those above a threshold
+    let mut code = vec![
+        csr(CSRType::CSRRS, 11, 0, MSCRATCH),
+        mathi(0b000, 13, 0, 0),
+        mathi(0b000, 17, 0, 0),
+    ];
+    let list = code.len();
+    code.extend([
+        load(LoadType::LW, 12, 11, 4),
+        math(0, 0b000, 13, 13, 12),
+        math(0, 0b011, 16, 21, 12),
+        math(0, 0b000, 17, 17, 16),
+        load(LoadType::LW, 11, 11, 0),
+    ]);
+    code.push(branch(BranchType::BNE, 11, 0, back(&code, list)));
+    code.push(store(StoreType::SW, 22, 13, 0));
+    code.push(store(StoreType::SW, 22, 17, 4));
+
+    // state machine, accumulates the decimal numbers of the input and sums them at each separator
+    code.extend([
+        mathi(0b000, 18, 23, 0),
+        mathi(0b000, 19, 23, 64),
+        mathi(0b000, 20, 0, 0),
+        mathi(0b000, 29, 0, 0),
+    ]);
+    let state = code.len();
+    code.extend([
+        load(LoadType::LBU, 14, 18, 0),
+        mathi(0b000, 14, 14, -48i16 as u16),
+        mathi(0b011, 16, 14, 10),
+        branch(BranchType::BEQ, 16, 0, 24),
+        mathi(0b001, 15, 20, 3),
+        math(0, 0b000, 20, 20, 20),
+        math(0, 0b000, 20, 20, 15),
+        math(0, 0b000, 20, 20, 14),
+        jal(0, 12),
+        math(0, 0b000, 29, 29, 20),
+        mathi(0b000, 20, 0, 0),
+        mathi(0b000, 18, 18, 1),
+    ]);
+    code.push(branch(BranchType::BNE, 18, 19, back(&code, state)));
+    code.push(store(StoreType::SW, 22, 29, 8));
+
+    // crc16 with the reflected polynomial 0xA001, one bit at a time
+    code.extend([
+        mathi(0b000, 18, 23, 0),
+        mathi(0b000, 9, 0, 0),
+        lui(7, 0xA000),
+        mathi(0b000, 7, 7, 1),
+    ]);
+    let byte = code.len();
+    code.extend([
+        load(LoadType::LBU, 14, 18, 0),
+        math(0, 0b100, 9, 9, 14),
+        mathi(0b000, 8, 0, 8),
+    ]);
+    let bit = code.len();
+    code.extend([
+        mathi(0b111, 16, 9, 1),
+        mathi(0b101, 9, 9, 1),
+        branch(BranchType::BEQ, 16, 0, 8),
+        math(0, 0b100, 9, 9, 7),
+        mathi(0b000, 8, 8, -1i16 as u16),
+    ]);
+    code.push(branch(BranchType::BNE, 8, 0, back(&code, bit)));
+    code.push(mathi(0b000, 18, 18, 1));
+    code.push(branch(BranchType::BNE, 18, 19, back(&code, byte)));
+    code.push(store(StoreType::SW, 22, 9, 12));
+
+    // 4x4 matrix multiplication, x5 and x6 hold the row and column offsets and x8 walks the inner product
+    code.push(mathi(0b000, 5, 0, 0));
+    let row = code.len();
+    code.push(mathi(0b000, 6, 0, 0));
+    let column = code.len();
+    code.extend([
+        mathi(0b000, 8, 0, 0),
+        mathi(0b000, 10, 0, 0),
+    ]);
+    let product = code.len();
+    code.extend([
+        math(0, 0b000, 15, 5, 8),
+        math(0, 0b000, 15, 15, 27),
+        load(LoadType::LW, 12, 15, 0),
+        mathi(0b001, 16, 8, 2),
+        math(0, 0b000, 16, 16, 6),
+        math(0, 0b000, 16, 16, 27),
+        load(LoadType::LW, 14, 16, 0),
+        math(1, 0b000, 12, 12, 14),
+        math(0, 0b000, 10, 10, 12),
+        mathi(0b000, 8, 8, 4),
+        mathi(0b011, 16, 8, 16),
+    ]);
+    code.push(branch(BranchType::BNE, 16, 0, back(&code, product)));
+    code.extend([
+        math(0, 0b000, 15, 5, 6),
+        math(0, 0b000, 15, 15, 28),
+        store(StoreType::SW, 15, 10, 0),
+        mathi(0b000, 6, 6, 4),
+        mathi(0b011, 16, 6, 16),
+    ]);
+    code.push(branch(BranchType::BNE, 16, 0, back(&code, column)));
+    code.push(mathi(0b000, 5, 5, 16));
+    code.push(mathi(0b011, 16, 5, 64));
+    code.push(branch(BranchType::BNE, 16, 0, back(&code, row)));
+
+    let back = -(code.len() as i32 * 4);
+    code.push(jal(0, back as u32 & 0x1FFFFF));
+    code
+}
+
+fn machine() -> CPU {
+    let mut cpu = CPU::new(0xC000);
+    for (index, instruction) in workload().iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+
+    // nodes linked in a fixed pseudo random order
+    let mut order: Vec<u32> = (0..NODES).collect();
+    let mut seed: u32 = 0x2545F491;
+    for index in (1..order.len()).rev() {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        order.swap(index, (seed >> 8) as usize % (index + 1));
+    }
+    for (position, &node) in order.iter().enumerate() {
+        let next = order.get(position + 1).map_or(0, |&next| LIST + next * 8);
+        cpu.ram().write_word(LIST + node * 8, next);
+        cpu.ram().write_word(LIST + node * 8 + 4, node.wrapping_mul(2654435761) >> 16);
+    }
+
+    for (offset, &byte) in b"id 4711, len 0x2c: 31 41 59 26 53 58 97 93 23 84 62 64 33 83 2795;".iter().take(64).enumerate() {
+        cpu.ram().write_byte(INPUT + offset as u32, byte);
+    }
+    for index in 0..16 {
+        cpu.ram().write_word(MATRIX + index * 4, index * 3 + 1);
+    }
+
+    cpu.csrs().write(MSCRATCH, LIST + order[0] * 8);
+    let registers = cpu.registers();
+    registers.write(21, 0x8000);
+    registers.write(22, RESULT);
+    registers.write(23, INPUT);
+    registers.write(27, MATRIX);
+    registers.write(28, PRODUCT);
+    cpu
+}
+
+fn measure(engine: Engine, cached: bool) -> f64 {
+    let mut cpu = machine();
+    cpu.set_engine(engine);
+    cpu.decode_cache().set_enabled(cached);
+    cpu.run_for(INSTRUCTIONS / 10);
+
+    let start = Instant::now();
+    cpu.run_for(INSTRUCTIONS);
+    let seconds = start.elapsed().as_secs_f64();
+
+    assert_eq!(cpu.csrs().read(MCAUSE), 0, "the workload trapped");
+    INSTRUCTIONS as f64 / seconds
+}
+
+fn main() {
+    let uncached = measure(Engine::Interpreter, false);
+    let cached = measure(Engine::Interpreter, true);
+    let blocks = measure(Engine::Blocks, true);
+
+    println!("uncached     {:8.2} MIPS", uncached / 1e6);
+    println!("decode cache {:8.2} MIPS  {:5.2}x", cached / 1e6, cached / uncached);
+    println!("blocks       {:8.2} MIPS  {:5.2}x", blocks / 1e6, blocks / uncached);
+
+    #[cfg(feature = "jit")]
+    {
+        let jit = measure(Engine::Jit, true);
+        println!("jit          {:8.2} MIPS  {:5.2}x", jit / 1e6, jit / uncached);
+    }
+}
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_risc_v::fuzz::*;
+
+// any bytes are instruction words, crashes can be replayed and minimized with the fuzz binary
+fuzz_target!(|data: &[u8]| {
+    if let Err(violation) = execute(&words(data), STEP_LIMIT) {
+        panic!("{}", violation);
+    }
+});
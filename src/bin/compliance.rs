@@ -0,0 +1,79 @@
+use std::env;
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use rust_risc_v::compliance::*;
+
+const USAGE: &str = "usage: compliance [--steps N] [--signatures DIRECTORY] TEST|DIRECTORY...";
+
+fn main() -> ExitCode {
+    let mut steps = STEP_LIMIT;
+    let mut signatures: Option<PathBuf> = None;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--steps" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => steps = value,
+                None => return usage(),
+            },
+            "--signatures" => match args.next() {
+                Some(value) => signatures = Some(PathBuf::from(value)),
+                None => return usage(),
+            },
+            "-h" | "--help" => return usage(),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        return usage();
+    }
+
+    let mut tests = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            match find_tests(&path) {
+                Ok(found) => tests.extend(found),
+                Err(error) => {
+                    eprintln!("{}: {}", path.display(), error);
+                    return ExitCode::FAILURE;
+                }
+            }
+        } else {
+            tests.push(path);
+        }
+    }
+
+    // failures are reported per test instead of printing panic messages
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut failed = 0;
+    for test in tests.iter() {
+        let report = run_file(test, steps);
+        match &report.outcome {
+            Outcome::Pass => println!("PASS {}", report.name),
+            outcome => {
+                println!("FAIL {}: {:?}", report.name, outcome);
+                failed += 1;
+            }
+        }
+
+        if let (Some(directory), Some(signature)) = (&signatures, &report.signature) {
+            let path = directory.join(format!("{}.signature", report.name.trim_end_matches(".elf")));
+            if let Err(error) = fs::write(&path, format_signature(signature)) {
+                eprintln!("{}: {}", path.display(), error);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", tests.len() - failed, failed);
+    if failed == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::FAILURE
+}
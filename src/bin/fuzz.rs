@@ -0,0 +1,90 @@
+use std::env;
+use std::fs;
+use std::panic;
+use std::process::ExitCode;
+
+use rust_risc_v::fuzz::*;
+
+const USAGE: &str = "usage: fuzz [--seed N] [--iterations N] [--length N] [--steps N] [--isa CLASS,...] [CRASH...]";
+
+fn main() -> ExitCode {
+    let mut seed = 0;
+    let mut iterations = 10_000;
+    let mut length = 64;
+    let mut steps = STEP_LIMIT;
+    let mut isa = RV32I.to_vec();
+    let mut crashes = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => seed = value,
+                None => return usage(),
+            },
+            "--iterations" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => iterations = value,
+                None => return usage(),
+            },
+            "--length" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => length = value,
+                None => return usage(),
+            },
+            "--steps" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => steps = value,
+                None => return usage(),
+            },
+            "--isa" => match args.next().map(|value| value.split(',').map(Class::from_name).collect::<Option<Vec<_>>>()) {
+                Some(Some(value)) => isa = value,
+                _ => return usage(),
+            },
+            "-h" | "--help" => return usage(),
+            _ => crashes.push(arg),
+        }
+    }
+
+    // violations are reported with a reproducer instead of the panic message
+    panic::set_hook(Box::new(|_| {}));
+
+    // crash inputs from cargo fuzz are replayed instead of generating programs
+    if !crashes.is_empty() {
+        let mut failed = false;
+        for path in crashes.iter() {
+            let data = match fs::read(path) {
+                Ok(data) => data,
+                Err(error) => {
+                    eprintln!("{}: {}", path, error);
+                    return ExitCode::FAILURE;
+                }
+            };
+            failed |= check("crash", &words(&data), steps);
+        }
+        return if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS };
+    }
+
+    for iteration in 0..iterations {
+        let seed = seed + iteration;
+        let program = Generator::new(seed, &isa).program(length);
+        if check(&format!("fuzz_seed_{}", seed), &program, steps) {
+            return ExitCode::FAILURE;
+        }
+    }
+
+    println!("{} programs passed", iterations);
+    ExitCode::SUCCESS
+}
+
+fn check(name: &str, program: &[u32], steps: u32) -> bool {
+    let Err(violation) = execute(program, steps) else {
+        return false;
+    };
+
+    let (program, violation) = minimize_violation(program, steps, &violation);
+    println!("{}", reproducer(name, &program, &violation));
+    true
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::FAILURE
+}
@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+use crate::CPU;
+use crate::bus::{AccessSize, Bus, SystemBus};
+use crate::cache::PAGE_SIZE;
+use crate::csr::SATP;
+use crate::instructions::decode::{decode, Condition, MathOperation, Operation};
+use crate::trap::Exception;
+
+// longest block, blocks also end at control transfers and page boundaries
+pub const MAX_LENGTH: usize = 64;
+// translated blocks kept before all of them are dropped
+pub const BLOCK_LIMIT: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    // fetches every instruction in tick
+    Interpreter,
+    // runs translated basic blocks linked to their successors
+    Blocks,
+    // compiles hot blocks to native code in run_for, runs blocks everywhere else
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+// what the run loop has to look at again after a step
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    // registers and the pc only
+    None,
+    // may reach a device or write code
+    Memory,
+    // may change interrupt enables, the privilege or translation
+    System,
+}
+
+// an instruction with its operands bound to the function that runs it
+pub(crate) struct Step<B: Bus> {
+    run: fn(&mut CPU<B>, &Step<B>),
+    rd: u8,
+    rs1: u8,
+    rs2: u8,
+    immediate: u32,
+    // alu operation or branch condition
+    function: fn(u32, u32) -> u32,
+    effect: Effect,
+    pub(crate) instruction: u32,
+    pub(crate) operation: Operation,
+}
+
+struct Block<B: Bus> {
+    // write generation of the page the block was translated from
+    generation: u64,
+    // shared with the run loop, which needs the cpu mutably while it runs them
+    steps: Rc<[Step<B>]>,
+    // blocks that followed this one by their pc, most recent first
+    successors: [Option<(u32, usize)>; 2],
+}
+
+// basic blocks by start pc, a block is translated again once its page was written
+pub struct BlockCache<B: Bus = SystemBus> {
+    blocks: Vec<Block<B>>,
+    lookup: HashMap<u32, usize>,
+    // bumped by every flush so running code notices its block is gone
+    flushes: u64,
+}
+
+impl<B: Bus> BlockCache<B> {
+    pub fn new() -> Self {
+        BlockCache { blocks: Vec::new(), lookup: HashMap::new(), flushes: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.lookup.clear();
+        self.flushes += 1;
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn flushes(&self) -> u64 {
+        self.flushes
+    }
+
+    fn successor(&self, block: usize, pc: u32) -> Option<usize> {
+        self.blocks[block].successors.iter().flatten().find(|&&(start, _)| start == pc).map(|&(_, index)| index)
+    }
+
+    fn link(&mut self, block: usize, pc: u32, index: usize) {
+        let successors = &mut self.blocks[block].successors;
+        successors[1] = successors[0];
+        successors[0] = Some((pc, index));
+    }
+}
+
+impl<B: Bus> Default for BlockCache<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn ends_block(operation: Operation) -> bool {
+    match operation {
+        // code after a write to satp may be fetched through page tables
+        Operation::CSR { csr, .. } => csr == SATP,
+        operation => !matches!(
+            operation,
+            Operation::LUI { .. }
+                | Operation::AUIPC { .. }
+                | Operation::MathImmediate { .. }
+                | Operation::Math { .. }
+                | Operation::Load { .. }
+                | Operation::Store { .. }
+                | Operation::LoadReserved { .. }
+                | Operation::StoreConditional { .. }
+                | Operation::Atomic { .. }
+                | Operation::Fence
+        ),
+    }
+}
+
+// when run_blocks asks its caller whether to stop
+pub(crate) enum Stop<'a, F> {
+    // only the instruction count ends the run
+    Never,
+    // after every instruction
+    Always(&'a mut F),
+    // after traps, returns from traps, csr instructions and jumps, so the predicate may only look at
+    // the privilege and csrs, native code runs in between
+    Privileged(&'a mut F),
+}
+
+// bookkeeping of one run_blocks call
+struct Run<'a, F> {
+    instructions: u64,
+    retired: u64,
+    // ticks before the bus has work to do and ticks not passed to it yet
+    quiet: u64,
+    skipped: u64,
+    stop: Stop<'a, F>,
+}
+
+impl<B: Bus> CPU<B> {
+    // runs up to the given number of instructions and returns how many ran,
+    // devices are only ticked and interrupts only checked when the bus has work to do,
+    // after memory accesses, which may reach a device, and after instructions that change interrupt enables
+    pub(crate) fn run_blocks<F: FnMut(&mut Self) -> bool>(&mut self, instructions: u64, stop: Stop<F>) -> u64 {
+        let mut run = Run { instructions, retired: 0, quiet: 0, skipped: 0, stop };
+        // the block that ran last and the flush count it belongs to
+        let mut previous: Option<(usize, u64)> = None;
+
+        while run.retired < run.instructions {
+            let pc = self.pc;
+            let flushes = self.blocks.flushes;
+            let previous_block = previous.filter(|&(_, epoch)| epoch == flushes).map(|(block, _)| block);
+            let linked = previous_block.and_then(|block| self.blocks.successor(block, pc));
+
+            let Some(index) = self.block(pc, linked) else {
+                // outside of ram, misaligned or behind page tables, the interpreter handles it
+                self.bus.advance(mem::take(&mut run.skipped));
+                run.quiet = 0;
+                self.tick();
+                if self.count(&mut run, true) {
+                    break;
+                }
+                previous = None;
+                continue;
+            };
+            if let Some(block) = previous_block.filter(|_| linked.is_none() && self.blocks.flushes == flushes) {
+                self.blocks.link(block, pc, index);
+            }
+
+            let flushes = self.blocks.flushes;
+            let generation = self.blocks.blocks[index].generation;
+            let steps = Rc::clone(&self.blocks.blocks[index].steps);
+            let logging = self.commit_log.is_some();
+            // a device ran or memory was written, the rest of the block may be gone
+            let mut stale = false;
+            let mut position = 0;
+
+            while position < steps.len() {
+                if run.quiet == 0 {
+                    self.bus.advance(mem::take(&mut run.skipped));
+                    self.bus.tick();
+                    run.quiet = self.bus.idle_ticks();
+                    stale = true;
+
+                    if let Some(interrupt) = self.pending_interrupt() {
+                        self.trap(interrupt.cause(), 0);
+                        if self.count(&mut run, true) {
+                            return run.retired;
+                        }
+                        break;
+                    }
+                } else {
+                    run.quiet -= 1;
+                    run.skipped += 1;
+                }
+
+                // written by a store of the block or by a device, fetched again like tick does
+                if stale {
+                    if self.blocks.flushes != flushes || self.bus.page_generation(self.pc) != Some(generation) {
+                        match self.fetch() {
+                            Ok((instruction, operation)) => self.retire(instruction, operation),
+                            Err(exception) => self.raise(exception),
+                        }
+                        run.quiet = 0;
+                        if self.count(&mut run, true) {
+                            return run.retired;
+                        }
+                        break;
+                    }
+                    stale = false;
+                }
+
+                // native code needs no device or interrupt checks until the end of the block
+                #[cfg(feature = "jit")]
+                if position == 0
+                    && self.engine == Engine::Jit
+                    && !logging
+                    && !matches!(run.stop, Stop::Always(_))
+                    && steps.len() as u64 <= run.instructions - run.retired
+                    && steps.len() as u64 - 1 <= run.quiet
+                {
+                    if let Some(retired) = self.run_native(pc, generation, &steps).filter(|&retired| retired > 0) {
+                        // the time of the first instruction passed above
+                        run.retired += retired;
+                        run.quiet -= retired - 1;
+                        run.skipped += retired - 1;
+                        stale = true;
+
+                        // left the block or stopped in front of an instruction native code does not handle
+                        position = retired as usize;
+                        if run.retired == run.instructions || self.pc != pc.wrapping_add(4 * position as u32) {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+
+                let step = &steps[position];
+                // devices see the time of the access and the time csr is read up to date
+                if step.effect != Effect::None {
+                    self.bus.advance(mem::take(&mut run.skipped));
+                }
+                if logging {
+                    self.retire(step.instruction, step.operation);
+                } else {
+                    (step.run)(self, step);
+                }
+                match step.effect {
+                    Effect::None => {}
+                    Effect::Memory => {
+                        run.quiet = self.bus.idle_ticks();
+                        stale = true;
+                    }
+                    Effect::System => run.quiet = 0,
+                }
+
+                // traps and taken branches leave the block
+                position += 1;
+                let left = self.pc != pc.wrapping_add(4 * position as u32);
+                if self.count(&mut run, left || step.effect == Effect::System) {
+                    return run.retired;
+                }
+                if left || run.retired == run.instructions {
+                    break;
+                }
+            }
+
+            previous = Some((index, flushes));
+        }
+
+        self.bus.advance(run.skipped);
+        run.retired
+    }
+
+    // counts an instruction, true when the run ends here
+    // privileged tells whether the privilege or csrs may have changed, stop sees the bus up to date
+    fn count<F: FnMut(&mut Self) -> bool>(&mut self, run: &mut Run<F>, privileged: bool) -> bool {
+        run.retired += 1;
+        let stop = match &mut run.stop {
+            Stop::Never => return false,
+            Stop::Always(stop) => stop,
+            Stop::Privileged(stop) if privileged => stop,
+            Stop::Privileged(_) => return false,
+        };
+        self.bus.advance(mem::take(&mut run.skipped));
+        stop(self)
+    }
+
+    // block starting at pc, translated again when its page was written since
+    // blocks are kept by physical address and only run while fetches are not translated
+    pub(crate) fn block(&mut self, pc: u32, linked: Option<usize>) -> Option<usize> {
+        if pc & 0b11 != 0 || self.translating() {
+            return None;
+        }
+        let generation = self.bus.page_generation(pc)?;
+
+        match linked.or_else(|| self.blocks.lookup.get(&pc).copied()) {
+            Some(index) if self.blocks.blocks[index].generation == generation => Some(index),
+            Some(index) => {
+                self.blocks.blocks[index] = self.translate_block(pc, generation);
+                Some(index)
+            }
+            None => {
+                if self.blocks.len() >= BLOCK_LIMIT {
+                    self.blocks.flush();
+                }
+                let block = self.translate_block(pc, generation);
+                let index = self.blocks.blocks.len();
+                self.blocks.blocks.push(block);
+                self.blocks.lookup.insert(pc, index);
+                Some(index)
+            }
+        }
+    }
+
+    fn translate_block(&mut self, pc: u32, generation: u64) -> Block<B> {
+        let mut steps = Vec::new();
+        let mut address = pc;
+
+        // ram may end inside the page, reads past it could reach a device
+        while self.bus.page_generation(address).is_some() {
+            let Ok(instruction) = self.bus.read(address, AccessSize::Word) else {
+                break;
+            };
+            let operation = decode(instruction);
+            steps.push(bind(instruction, operation));
+            address = address.wrapping_add(4);
+
+            if ends_block(operation) || address.is_multiple_of(PAGE_SIZE) || steps.len() == MAX_LENGTH {
+                break;
+            }
+        }
+
+        Block { generation, steps: steps.into(), successors: [None; 2] }
+    }
+}
+
+fn bind<B: Bus>(instruction: u32, operation: Operation) -> Step<B> {
+    let step = |run, rd, rs1, rs2, immediate, effect| Step {
+        run,
+        rd,
+        rs1,
+        rs2,
+        immediate,
+        function: |_, _| 0,
+        effect,
+        instruction,
+        operation,
+    };
+
+    match operation {
+        Operation::LUI { rd, immediate } => step(lui, rd, 0, 0, immediate, Effect::None),
+        Operation::AUIPC { rd, immediate } => step(auipc, rd, 0, 0, immediate, Effect::None),
+        Operation::JAL { rd, offset } => step(jal, rd, 0, 0, offset, Effect::None),
+        Operation::JALR { rd, rs1, offset } => step(jalr, rd, rs1, 0, offset, Effect::None),
+        Operation::Branch { condition, rs1, rs2, offset } => Step { function: compare(condition), ..step(branch, 0, rs1, rs2, offset, Effect::None) },
+        Operation::Load { size, signed, rd, rs1, offset } => {
+            let run = match (size, signed) {
+                (AccessSize::Byte, true) => load::<B, 1, true>,
+                (AccessSize::Byte, false) => load::<B, 1, false>,
+                (AccessSize::Half, true) => load::<B, 2, true>,
+                (AccessSize::Half, false) => load::<B, 2, false>,
+                (AccessSize::Word, _) => load::<B, 4, false>,
+            };
+            step(run, rd, rs1, 0, offset, Effect::Memory)
+        }
+        Operation::Store { size, rs1, rs2, offset } => {
+            let run = match size {
+                AccessSize::Byte => store::<B, 1>,
+                AccessSize::Half => store::<B, 2>,
+                AccessSize::Word => store::<B, 4>,
+            };
+            step(run, 0, rs1, rs2, offset, Effect::Memory)
+        }
+        Operation::MathImmediate { operation, rd, rs1, immediate } => {
+            Step { function: alu(operation), ..step(math_immediate, rd, rs1, 0, immediate, Effect::None) }
+        }
+        Operation::Math { operation, rd, rs1, rs2 } => Step { function: alu(operation), ..step(math, rd, rs1, rs2, 0, Effect::None) },
+        Operation::LoadReserved { .. } | Operation::StoreConditional { .. } | Operation::Atomic { .. } => {
+            step(execute, 0, 0, 0, 0, Effect::Memory)
+        }
+        Operation::Fence => step(execute, 0, 0, 0, 0, Effect::None),
+        _ => step(execute, 0, 0, 0, 0, Effect::System),
+    }
+}
+
+fn size(bytes: u32) -> AccessSize {
+    match bytes {
+        1 => AccessSize::Byte,
+        2 => AccessSize::Half,
+        _ => AccessSize::Word,
+    }
+}
+
+fn lui<B: Bus>(cpu: &mut CPU<B>, step: &Step<B>) {
+    cpu.lui(step.rd, step.immediate);
+}
+
+fn auipc<B: Bus>(cpu: &mut CPU<B>, step: &Step<B>) {
+    cpu.auipc(step.rd, step.immediate);
+}
+
+fn jal<B: Bus>(cpu: &mut CPU<B>, step: &Step<B>) {
+    cpu.jal(step.rd, step.immediate);
+}
+
+fn jalr<B: Bus>(cpu: &mut CPU<B>, step: &Step<B>) {
+    cpu.jalr(step.rd, step.rs1, step.immediate);
+}
+
+fn branch<B: Bus>(cpu: &mut CPU<B>, step: &Step<B>) {
+    let taken = (step.function)(cpu.registers.read(step.rs1), cpu.registers.read(step.rs2)) != 0;
+    let target = cpu.pc.wrapping_add(step.immediate);
+    if !taken {
+        cpu.pc = cpu.pc.wrapping_add(4);
+    } else if target & 0b11 != 0 {
+        cpu.raise(Exception::InstructionAddressMisaligned(target));
+    } else {
+        cpu.pc = target;
+    }
+}
+
+fn load<B: Bus, const BYTES: u32, const SIGNED: bool>(cpu: &mut CPU<B>, step: &Step<B>) {
+    cpu.load(size(BYTES), SIGNED, step.rd, step.rs1, step.immediate);
+}
+
+fn store<B: Bus, const BYTES: u32>(cpu: &mut CPU<B>, step: &Step<B>) {
+    cpu.store(size(BYTES), step.rs1, step.rs2, step.immediate);
+}
+
+fn math_immediate<B: Bus>(cpu: &mut CPU<B>, step: &Step<B>) {
+    let value = (step.function)(cpu.registers.read(step.rs1), step.immediate);
+    cpu.registers.write(step.rd, value);
+    cpu.pc = cpu.pc.wrapping_add(4);
+}
+
+fn math<B: Bus>(cpu: &mut CPU<B>, step: &Step<B>) {
+    let value = (step.function)(cpu.registers.read(step.rs1), cpu.registers.read(step.rs2));
+    cpu.registers.write(step.rd, value);
+    cpu.pc = cpu.pc.wrapping_add(4);
+}
+
+// everything else goes through the interpreter
+fn execute<B: Bus>(cpu: &mut CPU<B>, step: &Step<B>) {
+    cpu.execute(step.instruction, step.operation);
+}
+
+fn alu(operation: MathOperation) -> fn(u32, u32) -> u32 {
+    match operation {
+        MathOperation::Add => |a, b| MathOperation::Add.apply(a, b),
+        MathOperation::Subtract => |a, b| MathOperation::Subtract.apply(a, b),
+        MathOperation::ShiftLeft => |a, b| MathOperation::ShiftLeft.apply(a, b),
+        MathOperation::SetLessThan => |a, b| MathOperation::SetLessThan.apply(a, b),
+        MathOperation::SetLessThanUnsigned => |a, b| MathOperation::SetLessThanUnsigned.apply(a, b),
+        MathOperation::Xor => |a, b| MathOperation::Xor.apply(a, b),
+        MathOperation::ShiftRightLogical => |a, b| MathOperation::ShiftRightLogical.apply(a, b),
+        MathOperation::ShiftRightArithmetic => |a, b| MathOperation::ShiftRightArithmetic.apply(a, b),
+        MathOperation::Or => |a, b| MathOperation::Or.apply(a, b),
+        MathOperation::And => |a, b| MathOperation::And.apply(a, b),
+        MathOperation::Multiply => |a, b| MathOperation::Multiply.apply(a, b),
+        MathOperation::MultiplyHigh => |a, b| MathOperation::MultiplyHigh.apply(a, b),
+        MathOperation::MultiplyHighSignedUnsigned => |a, b| MathOperation::MultiplyHighSignedUnsigned.apply(a, b),
+        MathOperation::MultiplyHighUnsigned => |a, b| MathOperation::MultiplyHighUnsigned.apply(a, b),
+        MathOperation::Divide => |a, b| MathOperation::Divide.apply(a, b),
+        MathOperation::DivideUnsigned => |a, b| MathOperation::DivideUnsigned.apply(a, b),
+        MathOperation::Remainder => |a, b| MathOperation::Remainder.apply(a, b),
+        MathOperation::RemainderUnsigned => |a, b| MathOperation::RemainderUnsigned.apply(a, b),
+    }
+}
+
+// branch conditions as 1 for taken and 0 for not taken
+fn compare(condition: Condition) -> fn(u32, u32) -> u32 {
+    match condition {
+        Condition::Equal => |a, b| (a == b) as u32,
+        Condition::NotEqual => |a, b| (a != b) as u32,
+        Condition::LessThan => |a, b| ((a as i32) < (b as i32)) as u32,
+        Condition::GreaterEqual => |a, b| ((a as i32) >= (b as i32)) as u32,
+        Condition::LessThanUnsigned => |a, b| (a < b) as u32,
+        Condition::GreaterEqualUnsigned => |a, b| (a >= b) as u32,
+    }
+}
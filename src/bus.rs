@@ -0,0 +1,500 @@
+use std::any::Any;
+
+use crate::RAM;
+use crate::fdt::{Context, Node};
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessSize {
+    Byte = 1,
+    Half = 2,
+    Word = 4,
+}
+
+impl AccessSize {
+    pub fn bytes(self) -> u32 {
+        self as u32
+    }
+}
+
+// set of access sizes a region accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessSizes(u8);
+
+impl AccessSizes {
+    pub const BYTE: AccessSizes = AccessSizes(1);
+    pub const HALF: AccessSizes = AccessSizes(2);
+    pub const WORD: AccessSizes = AccessSizes(4);
+    pub const ALL: AccessSizes  = AccessSizes(7);
+
+    pub fn union(self, other: AccessSizes) -> AccessSizes {
+        AccessSizes(self.0 | other.0)
+    }
+
+    pub fn contains(self, size: AccessSize) -> bool {
+        self.0 & size as u8 != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    // nothing mapped or the region rejected the access
+    AccessFault(u32),
+    // a new region overlaps an existing one
+    Overlap(u32),
+}
+
+// physical memory access for devices
+pub trait DMA {
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), BusError>;
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), BusError>;
+
+    fn read_u16(&mut self, address: u32) -> Result<u16, BusError> {
+        let mut bytes = [0; 2];
+        self.read(address, &mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_u32(&mut self, address: u32) -> Result<u32, BusError> {
+        let mut bytes = [0; 4];
+        self.read(address, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_u64(&mut self, address: u32) -> Result<u64, BusError> {
+        let mut bytes = [0; 8];
+        self.read(address, &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write_u16(&mut self, address: u32, value: u16) -> Result<(), BusError> {
+        self.write(address, &value.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, address: u32, value: u32) -> Result<(), BusError> {
+        self.write(address, &value.to_le_bytes())
+    }
+}
+
+pub trait Bus {
+    fn read(&mut self, address: u32, size: AccessSize) -> Result<u32, BusError>;
+
+    fn write(&mut self, address: u32, size: AccessSize, value: u32) -> Result<(), BusError>;
+
+    // called once per cpu tick
+    fn tick(&mut self) {}
+
+    // ticks that can pass before tick has work to do, nothing raises an interrupt until then
+    fn idle_ticks(&self) -> u64 {
+        0
+    }
+
+    // the same as calling tick the given number of times
+    fn advance(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    // mip bits driven by interrupt controllers for the given hart
+    fn interrupts(&self, _hart: u32) -> u32 {
+        0
+    }
+
+    // mtime of the timer backing the time csr, None without one
+    fn time(&mut self) -> Option<u64> {
+        None
+    }
+
+    // write generation of the ram page holding address, only ram instructions are cached
+    fn page_generation(&self, _address: u32) -> Option<u64> {
+        None
+    }
+
+    // ram native code may load from and store to directly
+    #[cfg(feature = "jit")]
+    fn memory(&mut self) -> Option<crate::jit::Memory> {
+        None
+    }
+
+    // lr reservation of a hart on the word holding address, a hart holds at most one
+    fn reserve(&mut self, _hart: u32, _address: u32) {}
+
+    // drops the reservation of the hart, true when it was still held on the word holding address
+    fn release(&mut self, _hart: u32, _address: u32) -> bool {
+        false
+    }
+}
+
+pub trait Device: Any {
+    // offsets are relative to the base of the region
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError>;
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError>;
+
+    // ticks that passed since the previous call, called once the deadline is reached,
+    // before the device is accessed and on the tick after an access
+    fn tick(&mut self, _ticks: u64) {}
+
+    // called after tick with access to physical memory
+    fn dma(&mut self, _memory: &mut dyn DMA) {}
+
+    // ticks until the device has to be ticked again, devices only reacting to accesses have none
+    fn deadline(&self) -> u64 {
+        u64::MAX
+    }
+
+    // level of the interrupt line of the device
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    // interrupt controllers receive the line levels of the other devices
+    fn set_interrupt(&mut self, _source: u32, _level: bool) {}
+
+    // mip bits driven by the device for the given hart
+    fn interrupts(&self, _hart: u32) -> u32 {
+        0
+    }
+
+    // current time of timer devices
+    fn time(&self) -> Option<u64> {
+        None
+    }
+
+    // device tree node without reg and interrupts, which are added from the memory map
+    fn device_tree(&self, _context: &Context) -> Option<Node> {
+        None
+    }
+
+    // guest visible state for snapshots, host side backends are not part of it
+    fn save(&self, _state: &mut Writer) {}
+
+    fn restore(&mut self, _state: &mut Reader) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+pub struct Region {
+    base: u32,
+    size: u32,
+    sizes: AccessSizes,
+    source: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+impl Region {
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn source(&self) -> Option<u32> {
+        self.source
+    }
+
+    pub fn device(&self) -> &dyn Device {
+        self.device.as_ref()
+    }
+
+    fn contains(&self, address: u32) -> bool {
+        address >= self.base && address - self.base < self.size
+    }
+}
+
+struct MemoryWindow<'a> {
+    base: u32,
+    ram: &'a mut RAM,
+}
+
+impl MemoryWindow<'_> {
+    fn range(&self, address: u32, length: usize) -> Result<std::ops::Range<usize>, BusError> {
+        let offset = address.checked_sub(self.base).ok_or(BusError::AccessFault(address))? as usize;
+        if offset + length > self.ram.size() as usize {
+            return Err(BusError::AccessFault(address));
+        }
+
+        Ok(offset..offset + length)
+    }
+}
+
+impl DMA for MemoryWindow<'_> {
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), BusError> {
+        let range = self.range(address, buffer.len())?;
+        buffer.copy_from_slice(self.ram.inspect(range.start as u32, buffer.len() as u32));
+        Ok(())
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), BusError> {
+        let range = self.range(address, data.len())?;
+        for (offset, &byte) in range.zip(data.iter()) {
+            self.ram.write_byte(offset as u32, byte);
+        }
+        Ok(())
+    }
+}
+
+// ticks between polls of host input, the deadline of devices waiting for it
+pub const POLL_TICKS: u64 = 1024;
+
+pub struct SystemBus {
+    ram_base: u32,
+    ram: RAM,
+    regions: Vec<Region>,
+    // hart and reserved word, broken by any write to the word
+    reservations: Vec<(u32, u32)>,
+    // ticks the devices did not see yet and the earliest deadline among them
+    elapsed: u64,
+    deadline: u64,
+}
+
+impl SystemBus {
+    pub fn new(ram_base: u32, ram: RAM) -> Self {
+        SystemBus { ram_base, ram, regions: Vec::new(), reservations: Vec::new(), elapsed: 0, deadline: 1 }
+    }
+
+    pub fn ram(&mut self) -> &mut RAM {
+        &mut self.ram
+    }
+
+    pub fn ram_base(&self) -> u32 {
+        self.ram_base
+    }
+
+    pub fn ram_size(&self) -> u32 {
+        self.ram.size()
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+
+    pub fn map(&mut self, base: u32, size: u32, sizes: AccessSizes, device: Box<dyn Device>) -> Result<(), BusError> {
+        let end = base as u64 + size as u64;
+        let overlaps = |start: u32, length: u32| (base as u64) < start as u64 + length as u64 && (start as u64) < end;
+
+        if size == 0 || overlaps(self.ram_base, self.ram.size()) {
+            return Err(BusError::Overlap(base));
+        }
+        if self.regions.iter().any(|region| overlaps(region.base, region.size)) {
+            return Err(BusError::Overlap(base));
+        }
+
+        self.regions.push(Region { base, size, sizes, source: None, device });
+        self.deadline = 1;
+        Ok(())
+    }
+
+    // route the interrupt line of the device mapped at base to an interrupt controller source
+    pub fn connect_interrupt(&mut self, base: u32, source: u32) -> Result<(), BusError> {
+        let region = self.regions.iter_mut().find(|region| region.base == base).ok_or(BusError::AccessFault(base))?;
+        region.source = Some(source);
+        self.deadline = 1;
+        Ok(())
+    }
+
+    // the devices are brought up to date first, changes to the device are seen on the next tick
+    pub fn device<T: Device>(&mut self, base: u32) -> Option<&mut T> {
+        self.update();
+        // the caller may change when the device needs its next tick
+        self.deadline = 1;
+        let region = self.regions.iter_mut().find(|region| region.base == base)?;
+        let device: &mut dyn Any = region.device.as_mut();
+        device.downcast_mut::<T>()
+    }
+
+    fn ram_offset(&self, address: u32, size: AccessSize) -> Option<u32> {
+        let offset = address.checked_sub(self.ram_base)?;
+        if offset as u64 + size.bytes() as u64 <= self.ram.size() as u64 {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    // ram followed by the state of every device in map order and the held reservations
+    pub fn save(&self, state: &mut Writer) {
+        state.u32(self.ram_base);
+        self.ram.save(state);
+
+        state.u32(self.regions.len() as u32);
+        for region in self.regions.iter() {
+            state.u32(region.base);
+            state.u32(region.size);
+
+            let mut device = Writer::new();
+            region.device.save(&mut device);
+            state.bytes(&device.into_inner());
+        }
+        state.u64(self.elapsed);
+        state.u64(self.deadline);
+
+        state.u32(self.reservations.len() as u32);
+        for &(hart, word) in self.reservations.iter() {
+            state.u32(hart);
+            state.u32(word);
+        }
+    }
+
+    pub fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        state.expect(self.ram_base, "ram base")?;
+        self.ram.restore(state)?;
+
+        state.expect(self.regions.len() as u32, "device count")?;
+        for region in self.regions.iter_mut() {
+            state.expect(region.base, "device base")?;
+            state.expect(region.size, "device size")?;
+
+            let mut device = Reader::new(state.bytes()?);
+            region.device.restore(&mut device)?;
+            if !device.is_empty() {
+                return Err(SnapshotError::Mismatch(format!("device at {:#x}", region.base)));
+            }
+        }
+        self.elapsed = state.u64()?;
+        self.deadline = state.u64()?;
+
+        let reservations = state.u32()?;
+        self.reservations.clear();
+        for _ in 0..reservations {
+            self.reservations.push((state.u32()?, state.u32()?));
+        }
+        Ok(())
+    }
+
+    // devices see the ticks before an access and are ticked again on the next one
+    fn region(&mut self, address: u32, size: AccessSize) -> Result<&mut Region, BusError> {
+        let index = self.regions.iter().position(|region| region.contains(address)).ok_or(BusError::AccessFault(address))?;
+
+        let region = &self.regions[index];
+        let last = address.checked_add(size.bytes() - 1);
+        if !region.sizes.contains(size) || !last.is_some_and(|last| region.contains(last)) {
+            return Err(BusError::AccessFault(address));
+        }
+
+        self.update();
+        self.deadline = 1;
+        Ok(&mut self.regions[index])
+    }
+
+    // ticks the devices with the ticks they did not see yet
+    fn update(&mut self) {
+        if self.elapsed > 0 {
+            self.service();
+        }
+    }
+
+    fn service(&mut self) {
+        let ticks = self.elapsed;
+        self.elapsed = 0;
+
+        let mut memory = MemoryWindow { base: self.ram_base, ram: &mut self.ram };
+        for region in self.regions.iter_mut() {
+            region.device.tick(ticks);
+            region.device.dma(&mut memory);
+        }
+
+        // forward interrupt lines to the interrupt controllers
+        for index in 0..self.regions.len() {
+            let Some(source) = self.regions[index].source else {
+                continue;
+            };
+            let level = self.regions[index].device.interrupt();
+            for region in self.regions.iter_mut() {
+                region.device.set_interrupt(source, level);
+            }
+        }
+
+        self.deadline = self.regions.iter().map(|region| region.device.deadline()).min().unwrap_or(u64::MAX).max(1);
+    }
+}
+
+impl Bus for SystemBus {
+    fn read(&mut self, address: u32, size: AccessSize) -> Result<u32, BusError> {
+        if let Some(offset) = self.ram_offset(address, size) {
+            return Ok(match size {
+                AccessSize::Byte => self.ram.read_byte(offset) as u32,
+                AccessSize::Half => self.ram.read_half(offset) as u32,
+                AccessSize::Word => self.ram.read_word(offset),
+            });
+        }
+
+        let region = self.region(address, size)?;
+        let offset = address - region.base;
+        region.device.read(offset, size).map_err(|_| BusError::AccessFault(address))
+    }
+
+    fn write(&mut self, address: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        if !self.reservations.is_empty() {
+            self.reservations.retain(|&(_, word)| word != address & !0b11);
+        }
+
+        if let Some(offset) = self.ram_offset(address, size) {
+            match size {
+                AccessSize::Byte => self.ram.write_byte(offset, value as u8),
+                AccessSize::Half => self.ram.write_half(offset, value as u16),
+                AccessSize::Word => self.ram.write_word(offset, value),
+            }
+            return Ok(());
+        }
+
+        let region = self.region(address, size)?;
+        let offset = address - region.base;
+        region.device.write(offset, size, value).map_err(|_| BusError::AccessFault(address))
+    }
+
+    // devices are only ticked when one of them needs it
+    fn tick(&mut self) {
+        self.elapsed += 1;
+        if self.elapsed >= self.deadline {
+            self.service();
+        }
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        self.deadline.saturating_sub(self.elapsed + 1)
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        self.elapsed += ticks;
+        if self.elapsed >= self.deadline {
+            self.service();
+        }
+    }
+
+    fn interrupts(&self, hart: u32) -> u32 {
+        self.regions.iter().fold(0, |pending, region| pending | region.device.interrupts(hart))
+    }
+
+    // the timer sees the ticks before the read like on an access
+    fn time(&mut self) -> Option<u64> {
+        self.update();
+        self.regions.iter().find_map(|region| region.device.time())
+    }
+
+    fn page_generation(&self, address: u32) -> Option<u64> {
+        self.ram_offset(address, AccessSize::Word).map(|offset| self.ram.page_generation(offset))
+    }
+
+    #[cfg(feature = "jit")]
+    fn memory(&mut self) -> Option<crate::jit::Memory> {
+        let (data, generations) = self.ram.raw();
+        let reserved = !self.reservations.is_empty();
+        Some(crate::jit::Memory { base: self.ram_base, size: self.ram.size(), data, generations, reserved })
+    }
+
+    fn reserve(&mut self, hart: u32, address: u32) {
+        self.reservations.retain(|&(holder, _)| holder != hart);
+        self.reservations.push((hart, address & !0b11));
+    }
+
+    fn release(&mut self, hart: u32, address: u32) -> bool {
+        let held = self.reservations.contains(&(hart, address & !0b11));
+        self.reservations.retain(|&(holder, _)| holder != hart);
+        held
+    }
+}
@@ -0,0 +1,84 @@
+use crate::instructions::decode::Operation;
+
+pub const PAGE_SIZE: u32 = 4096;
+// pages held at once, a page replaces the one in its slot
+pub const SLOTS: usize = 256;
+
+const ENTRIES: usize = (PAGE_SIZE / 4) as usize;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    // write generation of the page when the instruction was decoded
+    generation: u64,
+    instruction: u32,
+    operation: Operation,
+}
+
+struct Page {
+    number: u32,
+    entries: Vec<Option<Entry>>,
+}
+
+// decoded instructions by physical page, an entry is only used while its page was not written since
+pub struct DecodeCache {
+    enabled: bool,
+    pages: Vec<Option<Page>>,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        DecodeCache { enabled: true, pages: (0..SLOTS).map(|_| None).collect() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.flush();
+    }
+
+    // pages currently holding decoded instructions
+    pub fn pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+
+    pub fn get(&self, address: u32, generation: u64) -> Option<(u32, Operation)> {
+        let number = address / PAGE_SIZE;
+        let page = self.pages[number as usize % SLOTS].as_ref().filter(|page| page.number == number)?;
+        let entry = page.entries[Self::index(address)].filter(|entry| entry.generation == generation)?;
+        Some((entry.instruction, entry.operation))
+    }
+
+    pub fn insert(&mut self, address: u32, generation: u64, instruction: u32, operation: Operation) {
+        let number = address / PAGE_SIZE;
+        let slot = &mut self.pages[number as usize % SLOTS];
+        match slot {
+            Some(page) if page.number == number => {}
+            Some(page) => {
+                page.number = number;
+                page.entries.fill(None);
+            }
+            None => *slot = Some(Page { number, entries: vec![None; ENTRIES] }),
+        }
+
+        if let Some(page) = slot {
+            page.entries[Self::index(address)] = Some(Entry { generation, instruction, operation });
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.pages.iter_mut().for_each(|page| *page = None);
+    }
+
+    fn index(address: u32) -> usize {
+        (address % PAGE_SIZE / 4) as usize
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
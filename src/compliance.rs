@@ -0,0 +1,153 @@
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use crate::{CPU, RAM};
+use crate::bus::{AccessSize, Bus, BusError, SystemBus};
+use crate::elf::{ELF, ELFError};
+use crate::htif::HTIF;
+
+// riscv-tests and riscv-arch-test link their programs here
+pub const RAM_BASE: u32   = 0x8000_0000;
+pub const RAM_SIZE: u32   = 0x0040_0000;
+pub const STEP_LIMIT: u64 = 10_000_000;
+
+pub const SUITES: [&str; 3] = ["rv32ui", "rv32um", "rv32ua"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    // failing test number written to tohost
+    Fail(u32),
+    // first signature word that differs from the reference
+    Mismatch { index: usize, expected: Option<u32>, actual: Option<u32> },
+    Timeout,
+    Fault(BusError),
+    Panic(String),
+    Invalid(String),
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        *self == Outcome::Pass
+    }
+}
+
+pub struct Report {
+    pub name: String,
+    pub outcome: Outcome,
+    // words between begin_signature and end_signature
+    pub signature: Option<Vec<u32>>,
+}
+
+// runs a test until it exits through htif, then compares the signature when a reference is given
+pub fn run(name: &str, data: &[u8], reference: Option<&[u32]>, steps: u64) -> Report {
+    let mut report = Report { name: name.to_string(), outcome: Outcome::Pass, signature: None };
+
+    let elf = match ELF::parse(data) {
+        Ok(elf) => elf,
+        Err(error) => {
+            report.outcome = Outcome::Invalid(format!("{:?}", error));
+            return report;
+        }
+    };
+    let Some(mut htif) = HTIF::from_elf(&elf) else {
+        report.outcome = Outcome::Invalid("no tohost symbol".to_string());
+        return report;
+    };
+    htif.capture_output();
+
+    let mut cpu = CPU::with_bus(SystemBus::new(RAM_BASE, RAM::new(RAM_SIZE / 4)));
+    if let Err(error) = elf.load(cpu.bus()) {
+        report.outcome = Outcome::Fault(error);
+        return report;
+    }
+    *cpu.pc() = elf.entry;
+
+    // a panicking instruction only fails this test
+    let result = panic::catch_unwind(AssertUnwindSafe(|| htif.run(&mut cpu, steps)));
+    report.outcome = match result {
+        Ok(Ok(Some(0))) => Outcome::Pass,
+        Ok(Ok(Some(code))) => Outcome::Fail(code),
+        Ok(Ok(None)) => Outcome::Timeout,
+        Ok(Err(error)) => Outcome::Fault(error),
+        Err(panic) => Outcome::Panic(
+            panic.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default()
+        ),
+    };
+
+    report.signature = signature(&mut cpu, &elf);
+    if let (Outcome::Pass, Some(reference), Some(signature)) = (&report.outcome, reference, &report.signature) {
+        report.outcome = compare(signature, reference);
+    }
+
+    report
+}
+
+pub fn signature<B: Bus>(cpu: &mut CPU<B>, elf: &ELF) -> Option<Vec<u32>> {
+    let begin = elf.symbol("begin_signature")?;
+    let end = elf.symbol("end_signature")?;
+
+    (begin..end).step_by(4).map(|address| cpu.bus().read(address, AccessSize::Word).ok()).collect()
+}
+
+pub fn compare(signature: &[u32], reference: &[u32]) -> Outcome {
+    let length = signature.len().max(reference.len());
+    match (0..length).find(|&index| signature.get(index) != reference.get(index)) {
+        Some(index) => Outcome::Mismatch { index, expected: reference.get(index).copied(), actual: signature.get(index).copied() },
+        None => Outcome::Pass,
+    }
+}
+
+// one hexadecimal word per line, as written by the reference models
+pub fn parse_signature(text: &str) -> Option<Vec<u32>> {
+    text.lines().map(str::trim).filter(|line| !line.is_empty()).map(|line| u32::from_str_radix(line, 16).ok()).collect()
+}
+
+pub fn format_signature(signature: &[u32]) -> String {
+    signature.iter().map(|word| format!("{:08x}\n", word)).collect()
+}
+
+// test elfs in a directory, sorted by name
+pub fn find_tests(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut tests = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        // riscv-tests builds have no extension and sit next to their .dump files, arch-test builds end in .elf
+        let candidate = match path.extension() {
+            Some(extension) => extension == "elf",
+            None => SUITES.iter().any(|suite| name.starts_with(suite)),
+        };
+        if path.is_file() && candidate && fs::read(&path).is_ok_and(|data| !matches!(ELF::parse(&data), Err(ELFError::NotELF))) {
+            tests.push(path);
+        }
+    }
+
+    tests.sort();
+    Ok(tests)
+}
+
+// test.reference_output next to the test or in a references directory beside it
+pub fn find_reference(test: &Path) -> Option<Vec<u32>> {
+    let stem = test.file_stem()?.to_str()?;
+    let name = format!("{}.reference_output", stem);
+    let directory = test.parent()?;
+
+    [directory.join(&name), directory.join("references").join(&name)].iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .and_then(|text| parse_signature(&text))
+}
+
+pub fn run_file(path: &Path, steps: u64) -> Report {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    match fs::read(path) {
+        Ok(data) => run(&name, &data, find_reference(path).as_deref(), steps),
+        Err(error) => Report { name, outcome: Outcome::Invalid(error.to_string()), signature: None },
+    }
+}
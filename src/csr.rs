@@ -0,0 +1,211 @@
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+pub const SSTATUS: u16    = 0x100;
+pub const SIE: u16        = 0x104;
+pub const STVEC: u16      = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SSCRATCH: u16   = 0x140;
+pub const SEPC: u16       = 0x141;
+pub const SCAUSE: u16     = 0x142;
+pub const STVAL: u16      = 0x143;
+pub const SIP: u16        = 0x144;
+pub const SATP: u16       = 0x180;
+pub const MSTATUS: u16    = 0x300;
+pub const MISA: u16       = 0x301;
+pub const MEDELEG: u16    = 0x302;
+pub const MIDELEG: u16    = 0x303;
+pub const MIE: u16        = 0x304;
+pub const MTVEC: u16      = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MSTATUSH: u16   = 0x310;
+pub const MSCRATCH: u16   = 0x340;
+pub const MEPC: u16       = 0x341;
+pub const MCAUSE: u16     = 0x342;
+pub const MTVAL: u16      = 0x343;
+pub const MIP: u16        = 0x344;
+pub const MVENDORID: u16  = 0xF11;
+pub const MARCHID: u16    = 0xF12;
+pub const MIMPID: u16     = 0xF13;
+pub const MHARTID: u16    = 0xF14;
+pub const TIME: u16       = 0xC01;
+pub const TIMEH: u16      = 0xC81;
+
+// every implemented csr with the bits instructions can write, the others keep their value
+pub const IMPLEMENTED: [(u16, &str, u32); 29] = [
+    (SSTATUS, "sstatus", SSTATUS_MASK),
+    (SIE, "sie", MIDELEG_MASK),
+    (STVEC, "stvec", !0b10),
+    // time is the only counter
+    (SCOUNTEREN, "scounteren", COUNTEREN_TM),
+    (SSCRATCH, "sscratch", u32::MAX),
+    (SEPC, "sepc", !0b11),
+    (SCAUSE, "scause", u32::MAX),
+    (STVAL, "stval", u32::MAX),
+    // the external interrupt is driven by the plic
+    (SIP, "sip", MIP_SSIP),
+    // address space ids are not implemented
+    (SATP, "satp", SATP_MODE | SATP_PPN),
+    (MSTATUS, "mstatus", MSTATUS_MASK),
+    // the extensions can not be turned off
+    (MISA, "misa", 0),
+    // ecalls from machine mode stay in machine mode
+    (MEDELEG, "medeleg", 0xB3FF),
+    (MIDELEG, "mideleg", MIDELEG_MASK),
+    (MIE, "mie", MIP_LINES | MIDELEG_MASK),
+    // direct and vectored mode
+    (MTVEC, "mtvec", !0b10),
+    (MCOUNTEREN, "mcounteren", COUNTEREN_TM),
+    // little endian only
+    (MSTATUSH, "mstatush", 0),
+    (MSCRATCH, "mscratch", u32::MAX),
+    (MEPC, "mepc", !0b11),
+    (MCAUSE, "mcause", u32::MAX),
+    (MTVAL, "mtval", u32::MAX),
+    // the line driven bits follow the interrupt controllers
+    (MIP, "mip", MIP_SSIP | MIP_STIP),
+    (MVENDORID, "mvendorid", 0),
+    (MARCHID, "marchid", 0),
+    (MIMPID, "mimpid", 0),
+    (MHARTID, "mhartid", 0),
+    // read from the clint
+    (TIME, "time", 0),
+    (TIMEH, "timeh", 0),
+];
+
+pub fn name(csr: u16) -> Option<&'static str> {
+    IMPLEMENTED.iter().find(|(number, _, _)| *number == csr).map(|(_, name, _)| *name)
+}
+
+// bits an instruction may write, None for csrs that do not exist
+pub fn write_mask(csr: u16) -> Option<u32> {
+    IMPLEMENTED.iter().find(|(number, _, _)| *number == csr).map(|(_, _, mask)| *mask)
+}
+
+// value an instruction leaves in the csr, the reserved privilege in mpp keeps the old one
+pub fn legalize(csr: u16, old: u32, value: u32) -> Option<u32> {
+    let mask = write_mask(csr)?;
+    let mut value = (old & !mask) | (value & mask);
+    if csr == MSTATUS && value & MSTATUS_MPP == 0b10 << 11 {
+        value = (value & !MSTATUS_MPP) | (old & MSTATUS_MPP);
+    }
+    Some(value)
+}
+
+// lowest privilege that may access the csr, encoded in its address
+pub fn privilege(csr: u16) -> u32 {
+    (csr as u32 >> 8) & 0b11
+}
+
+pub const MSTATUS_SIE: u32  = 1 << 1;
+pub const MSTATUS_MIE: u32  = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32  = 1 << 8;
+pub const MSTATUS_MPP: u32  = 0b11 << 11;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32  = 1 << 18;
+pub const MSTATUS_MXR: u32  = 1 << 19;
+pub const MSTATUS_TVM: u32  = 1 << 20;
+pub const MSTATUS_TW: u32   = 1 << 21;
+pub const MSTATUS_TSR: u32  = 1 << 22;
+
+pub const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+pub const MSTATUS_MASK: u32 = SSTATUS_MASK | MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP
+    | MSTATUS_MPRV | MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+
+pub const MIP_SSIP: u32 = 1 << 1;
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_STIP: u32 = 1 << 5;
+pub const MIP_MTIP: u32 = 1 << 7;
+pub const MIP_SEIP: u32 = 1 << 9;
+pub const MIP_MEIP: u32 = 1 << 11;
+
+// mip bits that are driven by interrupt controllers
+pub const MIP_LINES: u32 = MIP_MSIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+// interrupts that can be delegated to supervisor mode
+pub const MIDELEG_MASK: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+// lets the next lower privilege read the time csr
+pub const COUNTEREN_TM: u32 = 1 << 1;
+
+pub const SATP_MODE: u32 = 1 << 31;
+pub const SATP_PPN: u32  = 0x003F_FFFF;
+
+// rv32 with the i extension
+pub const MISA_RV32I: u32 = (1 << 30) | (1 << 8);
+// the a and m extensions
+pub const MISA_A: u32 = 1 << 0;
+pub const MISA_M: u32 = 1 << 12;
+// supervisor and user mode
+pub const MISA_S: u32 = 1 << 18;
+pub const MISA_U: u32 = 1 << 20;
+
+pub struct CSRs {
+    registers: Vec<u32>,
+}
+
+impl CSRs {
+    pub fn new() -> Self {
+        let mut csrs = CSRs { registers: vec![0; 4096] };
+        csrs.write(MISA, MISA_RV32I | MISA_A | MISA_M | MISA_S | MISA_U);
+        csrs.write(MSTATUS, MSTATUS_MPP);
+        csrs
+    }
+
+    // the supervisor status and interrupt csrs are views of the machine ones
+    pub fn write(&mut self, csr: u16, value: u32) {
+        let (csr, mask) = match csr & 0xFFF {
+            SSTATUS => (MSTATUS, SSTATUS_MASK),
+            SIE => (MIE, self.registers[MIDELEG as usize]),
+            SIP => (MIP, self.registers[MIDELEG as usize]),
+            csr => (csr, u32::MAX),
+        };
+        let register = &mut self.registers[csr as usize];
+        *register = (*register & !mask) | (value & mask);
+    }
+
+    #[inline(always)]
+    pub fn read(&self, csr: u16) -> u32 {
+        match csr & 0xFFF {
+            SSTATUS => self.registers[MSTATUS as usize] & SSTATUS_MASK,
+            SIE => self.registers[MIE as usize] & self.registers[MIDELEG as usize],
+            SIP => self.registers[MIP as usize] & self.registers[MIDELEG as usize],
+            csr => self.registers[csr as usize],
+        }
+    }
+
+    // csrs with both top address bits set can not be written by instructions
+    pub fn is_read_only(csr: u16) -> bool {
+        (csr >> 10) & 0b11 == 0b11
+    }
+}
+
+impl CSRs {
+    // only csrs that are not zero
+    pub fn save(&self, state: &mut Writer) {
+        let used: Vec<(usize, u32)> = self.registers.iter().copied().enumerate().filter(|&(_, value)| value != 0).collect();
+        state.u32(used.len() as u32);
+        for (csr, value) in used {
+            state.u16(csr as u16);
+            state.u32(value);
+        }
+    }
+
+    pub fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        let mut registers = vec![0; self.registers.len()];
+        for _ in 0..state.u32()? {
+            let csr = state.u16()? as usize;
+            let value = state.u32()?;
+            *registers.get_mut(csr).ok_or(SnapshotError::Mismatch(format!("csr {:#x}", csr)))? = value;
+        }
+
+        self.registers = registers;
+        Ok(())
+    }
+}
+
+impl Default for CSRs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // instruction fetch from the range, stops before the instruction like a breakpoint
+    Execute,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<u32>,
+    pub kind: WatchKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // pc of the instruction that was not executed yet
+    Breakpoint(u32),
+    // first address of the access, data watchpoints stop after the instruction
+    Watchpoint { address: u32, kind: WatchKind },
+    Predicate,
+    InstructionLimit,
+    // reverse execution reached the oldest recorded state
+    HistoryStart,
+}
+
+// breakpoints and watchpoints of one hart, checked by the run loop
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: HashSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    hit: Option<StopReason>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u32) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u32) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn add_watchpoint(&mut self, range: Range<u32>, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { range, kind });
+    }
+
+    pub fn remove_watchpoint(&mut self, range: Range<u32>, kind: WatchKind) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| *watchpoint != Watchpoint { range: range.clone(), kind });
+        self.watchpoints.len() != count
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.hit = None;
+    }
+
+    // no breakpoints or watchpoints are set
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty() && self.watchpoints.is_empty()
+    }
+
+    // stop reason for executing the instruction at pc
+    pub fn check_execute(&self, pc: u32) -> Option<StopReason> {
+        if self.breakpoints.contains(&pc) {
+            return Some(StopReason::Breakpoint(pc));
+        }
+
+        self.find(pc, 4, WatchKind::Execute).map(|_| StopReason::Watchpoint { address: pc, kind: WatchKind::Execute })
+    }
+
+    // records a data access, the first hit is kept until taken
+    pub fn access(&mut self, address: u32, size: u32, kind: WatchKind) {
+        if self.hit.is_none() && self.find(address, size, kind).is_some() {
+            self.hit = Some(StopReason::Watchpoint { address, kind });
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<StopReason> {
+        self.hit.take()
+    }
+
+    fn find(&self, address: u32, size: u32, kind: WatchKind) -> Option<&Watchpoint> {
+        let end = address.saturating_add(size);
+        self.watchpoints.iter().find(|watchpoint| watchpoint.kind == kind && address < watchpoint.range.end && watchpoint.range.start < end)
+    }
+}
@@ -0,0 +1,235 @@
+use std::time::Instant;
+
+use crate::bus::{AccessSize, BusError, Device};
+use crate::csr::{MIP_MSIP, MIP_MTIP};
+use crate::fdt::{Context, Node};
+use crate::reverse::InputLog;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+pub enum TimeBase {
+    // mtime advances by one every n retired instructions
+    Instructions(u32),
+    // mtime follows the host clock at the given frequency in hz
+    WallClock(u64),
+    // like the wall clock, samples are logged so reverse execution can replay them
+    Recorded(u64, InputLog),
+}
+
+pub struct CLINT {
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    time_base: TimeBase,
+    instructions: u64,
+    start: Instant,
+    offset: u64,
+}
+
+impl CLINT {
+    pub const BASE: u32 = 0x0200_0000;
+    pub const SIZE: u32 = 0x0001_0000;
+
+    pub const MSIP: u32     = 0x0000;
+    pub const MTIMECMP: u32 = 0x4000;
+    pub const MTIME: u32    = 0xBFF8;
+
+    pub fn new(harts: u32, time_base: TimeBase) -> Self {
+        CLINT {
+            msip: vec![false; harts as usize],
+            mtimecmp: vec![u64::MAX; harts as usize],
+            time_base,
+            instructions: 0,
+            start: Instant::now(),
+            offset: 0,
+        }
+    }
+
+    pub fn harts(&self) -> u32 {
+        self.msip.len() as u32
+    }
+
+    pub fn mtime(&self) -> u64 {
+        let wall_clock = |frequency: u64| {
+            let nanos = self.start.elapsed().as_nanos();
+            (nanos * frequency as u128 / 1_000_000_000) as u64
+        };
+        let ticks = match &self.time_base {
+            TimeBase::Instructions(n) => self.instructions / (*n).max(1) as u64,
+            TimeBase::WallClock(frequency) => wall_clock(*frequency),
+            TimeBase::Recorded(frequency, inputs) => inputs.time(|| wall_clock(*frequency)),
+        };
+
+        ticks.wrapping_add(self.offset)
+    }
+
+    pub fn set_mtime(&mut self, value: u64) {
+        self.offset = 0;
+        self.offset = value.wrapping_sub(self.mtime());
+    }
+
+    pub fn mtimecmp(&self, hart: u32) -> u64 {
+        self.mtimecmp[hart as usize]
+    }
+
+    pub fn set_mtimecmp(&mut self, hart: u32, value: u64) {
+        self.mtimecmp[hart as usize] = value;
+    }
+
+    pub fn msip(&self, hart: u32) -> bool {
+        self.msip[hart as usize]
+    }
+
+    pub fn set_msip(&mut self, hart: u32, value: bool) {
+        self.msip[hart as usize] = value;
+    }
+
+    // mip bits driven by the clint for the given hart
+    pub fn interrupts(&self, hart: u32) -> u32 {
+        let mut pending = 0;
+
+        if self.msip(hart) {
+            pending |= MIP_MSIP;
+        }
+        if self.mtime() >= self.mtimecmp(hart) {
+            pending |= MIP_MTIP;
+        }
+
+        pending
+    }
+
+    pub fn read_word(&self, offset: u32) -> u32 {
+        let harts = self.harts();
+
+        if offset < Self::MSIP + harts * 4 {
+            // msip
+            self.msip((offset - Self::MSIP) / 4) as u32
+        } else if (Self::MTIMECMP..Self::MTIMECMP + harts * 8).contains(&offset) {
+            // mtimecmp
+            let value = self.mtimecmp((offset - Self::MTIMECMP) / 8);
+            Self::select_half(value, offset)
+        } else if (Self::MTIME..Self::MTIME + 8).contains(&offset) {
+            // mtime
+            Self::select_half(self.mtime(), offset)
+        } else {
+            0
+        }
+    }
+
+    pub fn write_word(&mut self, offset: u32, value: u32) {
+        let harts = self.harts();
+
+        if offset < Self::MSIP + harts * 4 {
+            // msip
+            self.set_msip((offset - Self::MSIP) / 4, value & 1 == 1);
+        } else if (Self::MTIMECMP..Self::MTIMECMP + harts * 8).contains(&offset) {
+            // mtimecmp
+            let hart = (offset - Self::MTIMECMP) / 8;
+            let merged = Self::merge_half(self.mtimecmp(hart), offset, value);
+            self.set_mtimecmp(hart, merged);
+        } else if (Self::MTIME..Self::MTIME + 8).contains(&offset) {
+            // mtime
+            let merged = Self::merge_half(self.mtime(), offset, value);
+            self.set_mtime(merged);
+        }
+    }
+
+    fn select_half(value: u64, offset: u32) -> u32 {
+        if offset & 0b100 == 0 {
+            value as u32
+        } else {
+            (value >> 32) as u32
+        }
+    }
+
+    fn merge_half(value: u64, offset: u32, half: u32) -> u64 {
+        if offset & 0b100 == 0 {
+            (value & 0xFFFFFFFF00000000) | half as u64
+        } else {
+            (value & 0x00000000FFFFFFFF) | ((half as u64) << 32)
+        }
+    }
+}
+
+impl Device for CLINT {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        if size != AccessSize::Word || offset & 0b11 != 0 {
+            return Err(BusError::AccessFault(offset));
+        }
+
+        Ok(self.read_word(offset))
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        if size != AccessSize::Word || offset & 0b11 != 0 {
+            return Err(BusError::AccessFault(offset));
+        }
+
+        self.write_word(offset, value);
+        Ok(())
+    }
+
+    fn tick(&mut self, ticks: u64) {
+        self.instructions += ticks;
+    }
+
+    // counted time reaches the next mtimecmp on a tick, wall clock time is read when it is needed
+    fn deadline(&self) -> u64 {
+        let TimeBase::Instructions(n) = self.time_base else {
+            return u64::MAX;
+        };
+        let n = n.max(1) as u64;
+        let mtime = self.mtime();
+        self.mtimecmp.iter()
+            .filter(|&&compare| compare > mtime)
+            .map(|&compare| (compare - mtime).saturating_mul(n) - self.instructions % n)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    fn interrupts(&self, hart: u32) -> u32 {
+        if hart < self.harts() {
+            CLINT::interrupts(self, hart)
+        } else {
+            0
+        }
+    }
+
+    fn time(&self) -> Option<u64> {
+        Some(self.mtime())
+    }
+
+    // software and timer interrupt of every hart
+    fn device_tree(&self, context: &Context) -> Option<Node> {
+        let interrupts: Vec<u32> = (0..self.harts())
+            .flat_map(|hart| {
+                let controller = context.cpu_interrupt_controller(hart);
+                [controller, 3, controller, 7]
+            })
+            .collect();
+
+        let mut node = Node::new("clint");
+        node.property_strings("compatible", &["sifive,clint0", "riscv,clint0"])
+            .property_cells("interrupts-extended", &interrupts);
+        Some(node)
+    }
+
+    // mtime continues from the saved value regardless of the time base
+    fn save(&self, state: &mut Writer) {
+        state.bools(&self.msip);
+        state.u32(self.mtimecmp.len() as u32);
+        self.mtimecmp.iter().for_each(|&value| state.u64(value));
+        state.u64(self.instructions);
+        state.u64(self.mtime());
+    }
+
+    fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        state.bools_into(&mut self.msip, "clint harts")?;
+        state.expect(self.mtimecmp.len() as u32, "clint harts")?;
+        for value in self.mtimecmp.iter_mut() {
+            *value = state.u64()?;
+        }
+        self.instructions = state.u64()?;
+        let mtime = state.u64()?;
+        self.set_mtime(mtime);
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+pub mod clint;
+pub mod plic;
+pub mod rom;
+pub mod uart;
+pub mod virtio;
@@ -0,0 +1,294 @@
+use crate::bus::{AccessSize, BusError, Device};
+use crate::csr::{MIP_MEIP, MIP_SEIP};
+use crate::fdt::{Context, Node};
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+pub struct PLIC {
+    priority: Vec<u32>,
+    pending: Vec<bool>,
+    level: Vec<bool>,
+    claimed: Vec<bool>,
+    enable: Vec<Vec<bool>>,
+    threshold: Vec<u32>,
+}
+
+impl PLIC {
+    pub const BASE: u32 = 0x0C00_0000;
+    pub const SIZE: u32 = 0x0400_0000;
+
+    pub const PRIORITY: u32  = 0x000000;
+    pub const PENDING: u32   = 0x001000;
+    pub const ENABLE: u32    = 0x002000;
+    pub const THRESHOLD: u32 = 0x200000;
+    pub const CLAIM: u32     = 0x200004;
+
+    pub const ENABLE_STRIDE: u32  = 0x80;
+    pub const CONTEXT_STRIDE: u32 = 0x1000;
+
+    pub const MAX_PRIORITY: u32 = 7;
+
+    // source 0 is reserved, contexts 2n and 2n + 1 are machine and supervisor mode of hart n
+    pub fn new(sources: u32, contexts: u32) -> Self {
+        let sources = sources as usize + 1;
+        let contexts = contexts as usize;
+
+        PLIC {
+            priority: vec![0; sources],
+            pending: vec![false; sources],
+            level: vec![false; sources],
+            claimed: vec![false; sources],
+            enable: vec![vec![false; sources]; contexts],
+            threshold: vec![0; contexts],
+        }
+    }
+
+    pub fn sources(&self) -> u32 {
+        self.priority.len() as u32 - 1
+    }
+
+    pub fn contexts(&self) -> u32 {
+        self.threshold.len() as u32
+    }
+
+    // drive the interrupt line of a source, lines are level triggered
+    pub fn set_level(&mut self, source: u32, level: bool) {
+        let source = source as usize;
+        if source == 0 || source >= self.level.len() {
+            return;
+        }
+
+        self.level[source] = level;
+        if level && !self.claimed[source] {
+            self.pending[source] = true;
+        }
+    }
+
+    pub fn is_pending(&self, source: u32) -> bool {
+        self.pending.get(source as usize).copied().unwrap_or(false)
+    }
+
+    pub fn set_priority(&mut self, source: u32, priority: u32) {
+        if source != 0 && (source as usize) < self.priority.len() {
+            self.priority[source as usize] = priority.min(Self::MAX_PRIORITY);
+        }
+    }
+
+    pub fn set_enabled(&mut self, context: u32, source: u32, enabled: bool) {
+        if source != 0 && (source as usize) < self.priority.len() {
+            self.enable[context as usize][source as usize] = enabled;
+        }
+    }
+
+    pub fn set_threshold(&mut self, context: u32, threshold: u32) {
+        self.threshold[context as usize] = threshold.min(Self::MAX_PRIORITY);
+    }
+
+    // highest priority pending source above the threshold, lowest id wins ties
+    pub fn best_source(&self, context: u32) -> Option<u32> {
+        let enable = &self.enable[context as usize];
+        let threshold = self.threshold[context as usize];
+        let mut best: Option<u32> = None;
+
+        for (source, &priority) in self.priority.iter().enumerate().skip(1) {
+            if !self.pending[source] || !enable[source] || priority <= threshold {
+                continue;
+            }
+            if best.is_none_or(|best| priority > self.priority[best as usize]) {
+                best = Some(source as u32);
+            }
+        }
+
+        best
+    }
+
+    pub fn claim(&mut self, context: u32) -> u32 {
+        match self.best_source(context) {
+            Some(source) => {
+                self.pending[source as usize] = false;
+                self.claimed[source as usize] = true;
+                source
+            }
+            None => 0,
+        }
+    }
+
+    pub fn complete(&mut self, context: u32, source: u32) {
+        let index = source as usize;
+        if index == 0 || index >= self.claimed.len() || !self.enable[context as usize][index] {
+            return;
+        }
+
+        self.claimed[index] = false;
+        if self.level[index] {
+            self.pending[index] = true;
+        }
+    }
+
+    // mip bits driven by the plic for the given hart
+    pub fn interrupts(&self, hart: u32) -> u32 {
+        let mut pending = 0;
+
+        if hart * 2 < self.contexts() && self.best_source(hart * 2).is_some() {
+            pending |= MIP_MEIP;
+        }
+        if hart * 2 + 1 < self.contexts() && self.best_source(hart * 2 + 1).is_some() {
+            pending |= MIP_SEIP;
+        }
+
+        pending
+    }
+
+    pub fn read_word(&mut self, offset: u32) -> u32 {
+        let words = self.priority.len().div_ceil(32) as u32;
+
+        if offset < Self::PENDING {
+            // priority
+            self.priority.get((offset / 4) as usize).copied().unwrap_or(0)
+        } else if offset < Self::PENDING + words * 4 {
+            // pending
+            Self::pack(&self.pending, (offset - Self::PENDING) / 4)
+        } else if offset < Self::THRESHOLD {
+            // enable
+            if offset < Self::ENABLE {
+                return 0;
+            }
+            let context = (offset - Self::ENABLE) / Self::ENABLE_STRIDE;
+            let word = (offset - Self::ENABLE) % Self::ENABLE_STRIDE / 4;
+            match self.enable.get(context as usize) {
+                Some(enable) if word < words => Self::pack(enable, word),
+                _ => 0,
+            }
+        } else {
+            // threshold and claim
+            let context = (offset - Self::THRESHOLD) / Self::CONTEXT_STRIDE;
+            if context >= self.contexts() {
+                return 0;
+            }
+            match (offset - Self::THRESHOLD) % Self::CONTEXT_STRIDE {
+                0 => self.threshold[context as usize],
+                4 => self.claim(context),
+                _ => 0,
+            }
+        }
+    }
+
+    pub fn write_word(&mut self, offset: u32, value: u32) {
+        let words = self.priority.len().div_ceil(32) as u32;
+
+        if offset < Self::PENDING {
+            // priority
+            self.set_priority(offset / 4, value);
+        } else if offset < Self::ENABLE {
+            // pending is read only
+        } else if offset < Self::THRESHOLD {
+            // enable
+            let context = (offset - Self::ENABLE) / Self::ENABLE_STRIDE;
+            let word = (offset - Self::ENABLE) % Self::ENABLE_STRIDE / 4;
+            if context >= self.contexts() || word >= words {
+                return;
+            }
+            for bit in 0..32 {
+                self.set_enabled(context, word * 32 + bit, value & (1 << bit) != 0);
+            }
+        } else {
+            // threshold and complete
+            let context = (offset - Self::THRESHOLD) / Self::CONTEXT_STRIDE;
+            if context >= self.contexts() {
+                return;
+            }
+            match (offset - Self::THRESHOLD) % Self::CONTEXT_STRIDE {
+                0 => self.set_threshold(context, value),
+                4 => self.complete(context, value),
+                _ => {}
+            }
+        }
+    }
+
+    fn pack(bits: &[bool], word: u32) -> u32 {
+        let mut value = 0;
+        for bit in 0..32 {
+            if bits.get((word * 32 + bit) as usize).copied().unwrap_or(false) {
+                value |= 1 << bit;
+            }
+        }
+        value
+    }
+}
+
+impl Device for PLIC {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        if size != AccessSize::Word || offset & 0b11 != 0 {
+            return Err(BusError::AccessFault(offset));
+        }
+
+        Ok(self.read_word(offset))
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        if size != AccessSize::Word || offset & 0b11 != 0 {
+            return Err(BusError::AccessFault(offset));
+        }
+
+        self.write_word(offset, value);
+        Ok(())
+    }
+
+    fn set_interrupt(&mut self, source: u32, level: bool) {
+        self.set_level(source, level);
+    }
+
+    fn interrupts(&self, hart: u32) -> u32 {
+        PLIC::interrupts(self, hart)
+    }
+
+    // machine and supervisor external interrupt of every hart
+    fn device_tree(&self, context: &Context) -> Option<Node> {
+        let interrupts: Vec<u32> = (0..self.contexts())
+            .flat_map(|index| {
+                let cause = if index % 2 == 0 { 11 } else { 9 };
+                [context.cpu_interrupt_controller(index / 2), cause]
+            })
+            .collect();
+
+        let mut node = Node::new("plic");
+        node.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"])
+            .property_u32("#interrupt-cells", 1)
+            .property_u32("#address-cells", 0)
+            .property_empty("interrupt-controller")
+            .property_u32("riscv,ndev", self.sources())
+            .property_cells("interrupts-extended", &interrupts)
+            .property_u32("phandle", context.interrupt_parent());
+        Some(node)
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.u32(self.priority.len() as u32);
+        self.priority.iter().for_each(|&priority| state.u32(priority));
+        state.bools(&self.pending);
+        state.bools(&self.level);
+        state.bools(&self.claimed);
+
+        state.u32(self.threshold.len() as u32);
+        for (enable, &threshold) in self.enable.iter().zip(self.threshold.iter()) {
+            state.bools(enable);
+            state.u32(threshold);
+        }
+    }
+
+    fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        state.expect(self.priority.len() as u32, "plic sources")?;
+        for priority in self.priority.iter_mut() {
+            *priority = state.u32()?;
+        }
+        state.bools_into(&mut self.pending, "plic sources")?;
+        state.bools_into(&mut self.level, "plic sources")?;
+        state.bools_into(&mut self.claimed, "plic sources")?;
+
+        state.expect(self.threshold.len() as u32, "plic contexts")?;
+        for (enable, threshold) in self.enable.iter_mut().zip(self.threshold.iter_mut()) {
+            state.bools_into(enable, "plic sources")?;
+            *threshold = state.u32()?;
+        }
+        Ok(())
+    }
+}
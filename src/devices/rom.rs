@@ -0,0 +1,28 @@
+use crate::bus::{AccessSize, BusError, Device};
+
+pub struct ROM {
+    data: Vec<u8>,
+}
+
+impl ROM {
+    pub fn new(data: Vec<u8>) -> Self {
+        ROM { data }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+}
+
+impl Device for ROM {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        let start = offset as usize;
+        let bytes = self.data.get(start..start + size.bytes() as usize).ok_or(BusError::AccessFault(offset))?;
+
+        Ok(bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u32))
+    }
+
+    fn write(&mut self, offset: u32, _size: AccessSize, _value: u32) -> Result<(), BusError> {
+        Err(BusError::AccessFault(offset))
+    }
+}
@@ -0,0 +1,446 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::bus::{AccessSize, BusError, Device, POLL_TICKS};
+use crate::fdt::{Context, Node};
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+pub trait UARTBackend {
+    // next received byte if one is available, must not block
+    fn read(&mut self) -> Option<u8>;
+
+    fn write(&mut self, byte: u8);
+}
+
+// host stdin and stdout, stdin is read on a background thread
+pub struct StdioBackend {
+    input: Receiver<u8>,
+}
+
+impl StdioBackend {
+    pub fn new() -> Self {
+        StdioBackend { input: spawn_reader(std::io::stdin()) }
+    }
+}
+
+impl Default for StdioBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UARTBackend for StdioBackend {
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        let _ = stdout.flush();
+    }
+}
+
+// in memory buffers, clones share the same buffers
+#[derive(Clone, Default)]
+pub struct BufferBackend {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl BufferBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_input(&self, data: &[u8]) {
+        self.input.borrow_mut().extend(data);
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.output.borrow().clone()
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.output.borrow_mut())
+    }
+}
+
+impl UARTBackend for BufferBackend {
+    fn read(&mut self) -> Option<u8> {
+        self.input.borrow_mut().pop_front()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.output.borrow_mut().push(byte);
+    }
+}
+
+// host pseudo terminal, connect to it with a terminal program using the path
+#[cfg(unix)]
+pub struct PTYBackend {
+    master: std::fs::File,
+    input: Receiver<u8>,
+    path: String,
+}
+
+#[cfg(unix)]
+impl PTYBackend {
+    pub fn new() -> std::io::Result<Self> {
+        use std::ffi::CStr;
+        use std::os::fd::AsRawFd;
+        use std::os::raw::{c_char, c_int};
+
+        extern "C" {
+            fn grantpt(fd: c_int) -> c_int;
+            fn unlockpt(fd: c_int) -> c_int;
+            fn ptsname(fd: c_int) -> *const c_char;
+        }
+
+        let master = std::fs::OpenOptions::new().read(true).write(true).open("/dev/ptmx")?;
+        let fd = master.as_raw_fd();
+
+        // SAFETY: fd is an open pseudo terminal master and ptsname returns a nul terminated string
+        let path = unsafe {
+            if grantpt(fd) != 0 || unlockpt(fd) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let name = ptsname(fd);
+            if name.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            CStr::from_ptr(name).to_string_lossy().into_owned()
+        };
+
+        let input = spawn_reader(master.try_clone()?);
+        Ok(PTYBackend { master, input, path })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl UARTBackend for PTYBackend {
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let _ = self.master.write_all(&[byte]);
+    }
+}
+
+fn spawn_reader<R: Read + Send + 'static>(mut reader: R) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut byte = [0];
+        while let Ok(1) = reader.read(&mut byte) {
+            if sender.send(byte[0]).is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+pub struct UART {
+    backend: Box<dyn UARTBackend>,
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    fcr: u8,
+    scr: u8,
+    divisor: u16,
+    overrun: bool,
+    thr_empty_pending: bool,
+    idle_ticks: u32,
+    // the last poll returned a byte, so more input may be waiting
+    receiving: bool,
+}
+
+impl UART {
+    pub const BASE: u32 = 0x1000_0000;
+    pub const SIZE: u32 = 0x0000_0100;
+    pub const IRQ: u32  = 10;
+
+    // reported to the guest, the divisor latch does not affect the emulated baud rate
+    pub const CLOCK_FREQUENCY: u32 = 3_686_400;
+
+    pub const FIFO_SIZE: usize = 16;
+
+    // ticks without receive activity before a character timeout
+    pub const TIMEOUT_TICKS: u32 = 64;
+
+    pub const RBR: u32 = 0;
+    pub const THR: u32 = 0;
+    pub const IER: u32 = 1;
+    pub const IIR: u32 = 2;
+    pub const FCR: u32 = 2;
+    pub const LCR: u32 = 3;
+    pub const MCR: u32 = 4;
+    pub const LSR: u32 = 5;
+    pub const MSR: u32 = 6;
+    pub const SCR: u32 = 7;
+
+    pub const IER_RDA: u8  = 1 << 0;
+    pub const IER_THRE: u8 = 1 << 1;
+    pub const IER_RLS: u8  = 1 << 2;
+
+    pub const IIR_NONE: u8    = 0b0001;
+    pub const IIR_RLS: u8     = 0b0110;
+    pub const IIR_RDA: u8     = 0b0100;
+    pub const IIR_TIMEOUT: u8 = 0b1100;
+    pub const IIR_THRE: u8    = 0b0010;
+
+    pub const FCR_ENABLE: u8   = 1 << 0;
+    pub const FCR_CLEAR_RX: u8 = 1 << 1;
+
+    pub const LCR_DLAB: u8 = 1 << 7;
+
+    pub const MCR_LOOP: u8 = 1 << 4;
+
+    pub const LSR_DR: u8   = 1 << 0;
+    pub const LSR_OE: u8   = 1 << 1;
+    pub const LSR_THRE: u8 = 1 << 5;
+    pub const LSR_TEMT: u8 = 1 << 6;
+
+    pub fn new(backend: Box<dyn UARTBackend>) -> Self {
+        UART {
+            backend,
+            rx_fifo: VecDeque::new(),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            fcr: 0,
+            scr: 0,
+            divisor: 0,
+            overrun: false,
+            thr_empty_pending: false,
+            idle_ticks: 0,
+            receiving: false,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & Self::FCR_ENABLE != 0
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() { Self::FIFO_SIZE } else { 1 }
+    }
+
+    fn rx_trigger(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx_fifo.len() < self.rx_capacity() {
+            self.rx_fifo.push_back(byte);
+        } else {
+            self.overrun = true;
+        }
+        self.idle_ticks = 0;
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & Self::MCR_LOOP != 0 {
+            self.receive(byte);
+        } else {
+            self.backend.write(byte);
+        }
+
+        // transmission is instant so the holding register is empty again
+        self.thr_empty_pending = true;
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut lsr = Self::LSR_THRE | Self::LSR_TEMT;
+
+        if !self.rx_fifo.is_empty() {
+            lsr |= Self::LSR_DR;
+        }
+        if self.overrun {
+            lsr |= Self::LSR_OE;
+        }
+
+        lsr
+    }
+
+    // identification of the highest priority pending interrupt
+    pub fn interrupt_identification(&self) -> u8 {
+        if self.ier & Self::IER_RLS != 0 && self.overrun {
+            Self::IIR_RLS
+        } else if self.ier & Self::IER_RDA != 0 && self.rx_fifo.len() >= self.rx_trigger() {
+            Self::IIR_RDA
+        } else if self.ier & Self::IER_RDA != 0 && !self.rx_fifo.is_empty() && self.idle_ticks >= Self::TIMEOUT_TICKS {
+            Self::IIR_TIMEOUT
+        } else if self.ier & Self::IER_THRE != 0 && self.thr_empty_pending {
+            Self::IIR_THRE
+        } else {
+            Self::IIR_NONE
+        }
+    }
+
+    pub fn read_register(&mut self, register: u32) -> u8 {
+        let dlab = self.lcr & Self::LCR_DLAB != 0;
+
+        match register {
+            Self::RBR if dlab => self.divisor as u8,
+            Self::RBR => {
+                self.idle_ticks = 0;
+                self.rx_fifo.pop_front().unwrap_or(0)
+            }
+            Self::IER if dlab => (self.divisor >> 8) as u8,
+            Self::IER => self.ier,
+            Self::IIR => {
+                let iir = self.interrupt_identification();
+                if iir == Self::IIR_THRE {
+                    self.thr_empty_pending = false;
+                }
+                let fifo = if self.fifo_enabled() { 0b11000000 } else { 0 };
+                iir | fifo
+            }
+            Self::LCR => self.lcr,
+            Self::MCR => self.mcr,
+            Self::LSR => {
+                let lsr = self.line_status();
+                self.overrun = false;
+                lsr
+            }
+            // clear to send, data set ready and carrier detect
+            Self::MSR => 0b10110000,
+            Self::SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u32, value: u8) {
+        let dlab = self.lcr & Self::LCR_DLAB != 0;
+
+        match register {
+            Self::THR if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            Self::THR => self.transmit(value),
+            Self::IER if dlab => self.divisor = (self.divisor & 0x00FF) | ((value as u16) << 8),
+            Self::IER => {
+                // enabling the interrupt reports an already empty holding register
+                if value & Self::IER_THRE != 0 && self.ier & Self::IER_THRE == 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & 0x0F;
+            }
+            Self::FCR => {
+                if value & Self::FCR_CLEAR_RX != 0 {
+                    self.rx_fifo.clear();
+                }
+                self.fcr = value & 0b11001001;
+                self.rx_fifo.truncate(self.rx_capacity());
+            }
+            Self::LCR => self.lcr = value,
+            Self::MCR => self.mcr = value & 0x1F,
+            Self::SCR => self.scr = value,
+            _ => {}
+        }
+    }
+}
+
+impl Device for UART {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        if size != AccessSize::Byte || offset > Self::SCR {
+            return Err(BusError::AccessFault(offset));
+        }
+
+        Ok(self.read_register(offset) as u32)
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        if size != AccessSize::Byte || offset > Self::SCR {
+            return Err(BusError::AccessFault(offset));
+        }
+
+        self.write_register(offset, value as u8);
+        Ok(())
+    }
+
+    // the backend is polled once for all ticks
+    fn tick(&mut self, ticks: u64) {
+        let skipped = ticks.saturating_sub(1).min(u32::MAX as u64) as u32;
+        self.idle_ticks = self.idle_ticks.saturating_add(skipped);
+
+        if self.rx_fifo.len() < self.rx_capacity() && self.mcr & Self::MCR_LOOP == 0 {
+            let byte = self.backend.read();
+            self.receiving = byte.is_some();
+            if let Some(byte) = byte {
+                self.receive(byte);
+                return;
+            }
+        }
+
+        self.idle_ticks = self.idle_ticks.saturating_add(1);
+    }
+
+    // the character timeout is raised on time, input is polled in between and every tick during a burst
+    fn deadline(&self) -> u64 {
+        if self.receiving && self.rx_fifo.len() < self.rx_capacity() && self.mcr & Self::MCR_LOOP == 0 {
+            1
+        } else if !self.rx_fifo.is_empty() && self.idle_ticks < Self::TIMEOUT_TICKS {
+            ((Self::TIMEOUT_TICKS - self.idle_ticks) as u64).min(POLL_TICKS)
+        } else {
+            POLL_TICKS
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_identification() != Self::IIR_NONE
+    }
+
+    fn device_tree(&self, _context: &Context) -> Option<Node> {
+        let mut node = Node::new("serial");
+        node.property_string("compatible", "ns16550a")
+            .property_u32("clock-frequency", Self::CLOCK_FREQUENCY);
+        Some(node)
+    }
+
+    // received bytes that the guest has not read yet are part of the state
+    fn save(&self, state: &mut Writer) {
+        state.bytes(&self.rx_fifo.iter().copied().collect::<Vec<u8>>());
+        for register in [self.ier, self.lcr, self.mcr, self.fcr, self.scr] {
+            state.u8(register);
+        }
+        state.u16(self.divisor);
+        state.bool(self.overrun);
+        state.bool(self.thr_empty_pending);
+        state.u32(self.idle_ticks);
+        state.bool(self.receiving);
+    }
+
+    fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        self.rx_fifo = state.bytes()?.iter().copied().collect();
+        for register in [&mut self.ier, &mut self.lcr, &mut self.mcr, &mut self.fcr, &mut self.scr] {
+            *register = state.u8()?;
+        }
+        self.divisor = state.u16()?;
+        self.overrun = state.bool()?;
+        self.thr_empty_pending = state.bool()?;
+        self.idle_ticks = state.u32()?;
+        self.receiving = state.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::bus::{BusError, DMA};
+use crate::snapshot::{Reader, SnapshotError, Writer};
+use super::{VirtioDevice, DEVICE_ID_BLOCK};
+use super::queue::{DescriptorChain, Virtqueue};
+
+pub const SECTOR_SIZE: usize = 512;
+
+pub const VIRTIO_BLK_F_RO: u64    = 1 << 5;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+pub const VIRTIO_BLK_T_IN: u32     = 0;
+pub const VIRTIO_BLK_T_OUT: u32    = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32  = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+pub const VIRTIO_BLK_S_OK: u8     = 0;
+pub const VIRTIO_BLK_S_IOERR: u8  = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskMode {
+    ReadWrite,
+    ReadOnly,
+    // writes are kept in memory and never reach the image
+    CopyOnWrite,
+}
+
+enum Storage {
+    File(File),
+    Memory(Vec<u8>),
+}
+
+pub struct Disk {
+    storage: Storage,
+    mode: DiskMode,
+    sectors: u64,
+    overlay: HashMap<u64, Vec<u8>>,
+}
+
+impl Disk {
+    pub fn open<P: AsRef<Path>>(path: P, mode: DiskMode) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(mode == DiskMode::ReadWrite).open(path)?;
+        let sectors = file.metadata()?.len() / SECTOR_SIZE as u64;
+
+        Ok(Disk { storage: Storage::File(file), mode, sectors, overlay: HashMap::new() })
+    }
+
+    pub fn memory(mut data: Vec<u8>, mode: DiskMode) -> Self {
+        let sectors = data.len().div_ceil(SECTOR_SIZE) as u64;
+        data.resize(sectors as usize * SECTOR_SIZE, 0);
+
+        Disk { storage: Storage::Memory(data), mode, sectors, overlay: HashMap::new() }
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn mode(&self) -> DiskMode {
+        self.mode
+    }
+
+    // sectors written since the disk was opened in copy on write mode
+    pub fn dirty_sectors(&self) -> usize {
+        self.overlay.len()
+    }
+
+    pub fn read_sector(&mut self, sector: u64, buffer: &mut [u8]) -> io::Result<()> {
+        if sector >= self.sectors {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(data) = self.overlay.get(&sector) {
+            buffer.copy_from_slice(data);
+            return Ok(());
+        }
+
+        let offset = sector as usize * SECTOR_SIZE;
+        match &mut self.storage {
+            Storage::File(file) => {
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.read_exact(buffer)
+            }
+            Storage::Memory(data) => {
+                buffer.copy_from_slice(&data[offset..offset + SECTOR_SIZE]);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_sector(&mut self, sector: u64, buffer: &[u8]) -> io::Result<()> {
+        if sector >= self.sectors {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let offset = sector as usize * SECTOR_SIZE;
+        match (self.mode, &mut self.storage) {
+            (DiskMode::ReadOnly, _) => Err(io::ErrorKind::PermissionDenied.into()),
+            (DiskMode::CopyOnWrite, _) => {
+                self.overlay.insert(sector, buffer.to_vec());
+                Ok(())
+            }
+            (DiskMode::ReadWrite, Storage::File(file)) => {
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.write_all(buffer)
+            }
+            (DiskMode::ReadWrite, Storage::Memory(data)) => {
+                data[offset..offset + SECTOR_SIZE].copy_from_slice(buffer);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match (self.mode, &mut self.storage) {
+            (DiskMode::ReadWrite, Storage::File(file)) => file.sync_data(),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct VirtioBlock {
+    disk: Disk,
+    id: [u8; 20],
+}
+
+impl VirtioBlock {
+    pub fn new(disk: Disk) -> Self {
+        let mut id = [0; 20];
+        id[..15].copy_from_slice(b"rust-risc-v-blk");
+
+        VirtioBlock { disk, id }
+    }
+
+    pub fn disk(&mut self) -> &mut Disk {
+        &mut self.disk
+    }
+
+    // returns the status and the number of bytes written into the request
+    fn handle(&mut self, chain: &DescriptorChain, memory: &mut dyn DMA) -> Result<(u8, u32), BusError> {
+        let request = chain.read_all(memory)?;
+        if request.len() < 16 || chain.writable_length() == 0 {
+            return Err(BusError::AccessFault(chain.head as u32));
+        }
+
+        let request_type = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+        let data_length = chain.writable_length() - 1;
+
+        let result = match request_type {
+            VIRTIO_BLK_T_IN => {
+                // copied one sector at a time, the chain length comes from the guest
+                let mut buffer = [0; SECTOR_SIZE];
+                let mut written = 0;
+                for index in 0..data_length / SECTOR_SIZE as u32 {
+                    let read = sector.checked_add(index as u64)
+                        .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))
+                        .and_then(|sector| self.disk.read_sector(sector, &mut buffer));
+                    if read.is_err() {
+                        return Self::status(chain, memory, data_length, VIRTIO_BLK_S_IOERR, written);
+                    }
+                    written += chain.write_at(memory, index * SECTOR_SIZE as u32, &buffer)?;
+                }
+                Ok((VIRTIO_BLK_S_OK, written))
+            }
+            VIRTIO_BLK_T_OUT => {
+                let data = &request[16..];
+                let written = data.chunks_exact(SECTOR_SIZE).enumerate().try_for_each(|(index, buffer)| {
+                    let sector = sector.checked_add(index as u64).ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                    self.disk.write_sector(sector, buffer)
+                });
+                match written {
+                    Ok(()) if data.len() % SECTOR_SIZE == 0 => Ok((VIRTIO_BLK_S_OK, 0)),
+                    _ => Ok((VIRTIO_BLK_S_IOERR, 0)),
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => match self.disk.flush() {
+                Ok(()) => Ok((VIRTIO_BLK_S_OK, 0)),
+                Err(_) => Ok((VIRTIO_BLK_S_IOERR, 0)),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let length = (self.id.len() as u32).min(data_length) as usize;
+                Ok((VIRTIO_BLK_S_OK, chain.write_at(memory, 0, &self.id[..length])?))
+            }
+            _ => Ok((VIRTIO_BLK_S_UNSUPP, 0)),
+        };
+
+        let (status, written) = result?;
+        Self::status(chain, memory, data_length, status, written)
+    }
+
+    // the status byte is the last writable byte
+    fn status(chain: &DescriptorChain, memory: &mut dyn DMA, data_length: u32, status: u8, written: u32) -> Result<(u8, u32), BusError> {
+        chain.write_at(memory, data_length, &[status])?;
+        Ok((status, written + 1))
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        match self.disk.mode() {
+            DiskMode::ReadOnly => VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO,
+            _ => VIRTIO_BLK_F_FLUSH,
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    // capacity in sectors is the first field of the configuration
+    fn read_config(&self, offset: u32) -> u8 {
+        match offset {
+            0..=7 => (self.disk.sectors() >> (offset * 8)) as u8,
+            _ => 0,
+        }
+    }
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Virtqueue], memory: &mut dyn DMA) -> Result<bool, BusError> {
+        let mut used = false;
+
+        while let Some(chain) = queues[0].pop(memory)? {
+            let (_, written) = self.handle(&chain, memory)?;
+            queues[0].push_used(memory, chain.head, written)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+
+    // copy on write sectors, images written in place are not part of the snapshot
+    fn save(&self, state: &mut Writer) {
+        let mut sectors: Vec<(&u64, &Vec<u8>)> = self.disk.overlay.iter().collect();
+        sectors.sort();
+
+        state.u64(self.disk.sectors);
+        state.u32(sectors.len() as u32);
+        for (&sector, data) in sectors {
+            state.u64(sector);
+            state.bytes(data);
+        }
+    }
+
+    fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        if state.u64()? != self.disk.sectors {
+            return Err(SnapshotError::Mismatch("disk size".to_string()));
+        }
+
+        let mut overlay = HashMap::new();
+        for _ in 0..state.u32()? {
+            let sector = state.u64()?;
+            let data = state.bytes()?;
+            if sector >= self.disk.sectors || data.len() != SECTOR_SIZE {
+                return Err(SnapshotError::Mismatch(format!("disk sector {}", sector)));
+            }
+            overlay.insert(sector, data.to_vec());
+        }
+
+        self.disk.overlay = overlay;
+        Ok(())
+    }
+}
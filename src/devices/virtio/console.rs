@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+
+use crate::bus::{BusError, DMA};
+use crate::devices::uart::UARTBackend;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+use super::{VirtioDevice, DEVICE_ID_CONSOLE};
+use super::queue::Virtqueue;
+
+pub const VIRTIO_CONSOLE_F_SIZE: u64        = 1 << 0;
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64   = 1 << 1;
+pub const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+pub const VIRTIO_CONSOLE_DEVICE_READY: u16  = 0;
+pub const VIRTIO_CONSOLE_DEVICE_ADD: u16    = 1;
+pub const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+pub const VIRTIO_CONSOLE_PORT_READY: u16    = 3;
+pub const VIRTIO_CONSOLE_CONSOLE_PORT: u16  = 4;
+pub const VIRTIO_CONSOLE_RESIZE: u16        = 5;
+pub const VIRTIO_CONSOLE_PORT_OPEN: u16     = 6;
+pub const VIRTIO_CONSOLE_PORT_NAME: u16     = 7;
+
+pub const CONTROL_RECEIVE: usize  = 2;
+pub const CONTROL_TRANSMIT: usize = 3;
+
+struct Port {
+    name: String,
+    backend: Box<dyn UARTBackend>,
+    input: VecDeque<u8>,
+}
+
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    columns: u16,
+    rows: u16,
+    multiport: bool,
+    control: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsole {
+    // port 0 is the console, hvc0 on linux
+    pub fn new(console: Box<dyn UARTBackend>) -> Self {
+        let port = Port { name: String::new(), backend: console, input: VecDeque::new() };
+
+        VirtioConsole { ports: vec![port], columns: 80, rows: 25, multiport: false, control: VecDeque::new() }
+    }
+
+    // additional named ports, only visible to drivers supporting multiport
+    pub fn add_port(&mut self, name: &str, backend: Box<dyn UARTBackend>) -> u32 {
+        self.ports.push(Port { name: name.to_string(), backend, input: VecDeque::new() });
+        self.ports.len() as u32 - 1
+    }
+
+    pub fn set_size(&mut self, columns: u16, rows: u16) {
+        self.columns = columns;
+        self.rows = rows;
+    }
+
+    // queue indices of the receive and transmit queue of a port
+    pub fn port_queues(port: u32) -> (usize, usize) {
+        if port == 0 {
+            (0, 1)
+        } else {
+            (2 + 2 * port as usize, 3 + 2 * port as usize)
+        }
+    }
+
+    fn port_of_queue(&self, queue: usize) -> Option<(u32, bool)> {
+        match queue {
+            0 | 1 => Some((0, queue == 1)),
+            CONTROL_RECEIVE | CONTROL_TRANSMIT => None,
+            _ => Some(((queue as u32 - 2) / 2, queue % 2 == 1)),
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut message = Vec::with_capacity(8 + extra.len());
+        message.extend_from_slice(&id.to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(extra);
+        self.control.push_back(message);
+    }
+
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < 8 {
+            return;
+        }
+
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..self.ports.len() as u32 {
+                    self.send_control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && (id as usize) < self.ports.len() => {
+                if id == 0 {
+                    self.send_control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                let name = self.ports[id as usize].name.clone();
+                if !name.is_empty() {
+                    self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                }
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            _ => {}
+        }
+    }
+
+    fn transmit(&mut self, queue: usize, queues: &mut [Virtqueue], memory: &mut dyn DMA) -> Result<bool, BusError> {
+        let mut used = false;
+
+        while let Some(chain) = queues[queue].pop(memory)? {
+            let data = chain.read_all(memory)?;
+            match self.port_of_queue(queue) {
+                Some((port, _)) => data.iter().for_each(|&byte| self.ports[port as usize].backend.write(byte)),
+                None => self.handle_control(&data),
+            }
+            queues[queue].push_used(memory, chain.head, 0)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        let features = VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_EMERG_WRITE;
+        if self.ports.len() > 1 {
+            features | VIRTIO_CONSOLE_F_MULTIPORT
+        } else {
+            features
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        if self.ports.len() > 1 {
+            2 + 2 * self.ports.len()
+        } else {
+            2
+        }
+    }
+
+    // columns, rows, max_nr_ports and emerg_wr
+    fn read_config(&self, offset: u32) -> u8 {
+        let ports = self.ports.len() as u32;
+        match offset {
+            0..=1 => (self.columns >> (offset * 8)) as u8,
+            2..=3 => (self.rows >> ((offset - 2) * 8)) as u8,
+            4..=7 => (ports >> ((offset - 4) * 8)) as u8,
+            _ => 0,
+        }
+    }
+
+    fn write_config(&mut self, offset: u32, value: u8) {
+        // emergency write to the console port
+        if offset == 8 {
+            self.ports[0].backend.write(value);
+        }
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], memory: &mut dyn DMA) -> Result<bool, BusError> {
+        match self.port_of_queue(queue) {
+            // receive buffers are filled when polled
+            Some((_, false)) => Ok(false),
+            _ if queue == CONTROL_RECEIVE => Ok(false),
+            _ => self.transmit(queue, queues, memory),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &mut dyn DMA) -> Result<bool, BusError> {
+        let mut used = false;
+        let ports = if self.multiport { self.ports.len() } else { 1 };
+
+        for port in 0..ports {
+            let (receive, _) = Self::port_queues(port as u32);
+            let port = &mut self.ports[port];
+            while let Some(byte) = port.backend.read() {
+                port.input.push_back(byte);
+            }
+
+            while !port.input.is_empty() {
+                let Some(chain) = queues[receive].pop(memory)? else {
+                    break;
+                };
+                let length = (chain.writable_length() as usize).min(port.input.len());
+                let data: Vec<u8> = port.input.drain(..length).collect();
+                let written = chain.write_at(memory, 0, &data)?;
+                queues[receive].push_used(memory, chain.head, written)?;
+                used = true;
+            }
+        }
+
+        while self.multiport && !self.control.is_empty() {
+            let Some(chain) = queues[CONTROL_RECEIVE].pop(memory)? else {
+                break;
+            };
+            let message = self.control.pop_front().unwrap();
+            let written = chain.write_at(memory, 0, &message)?;
+            queues[CONTROL_RECEIVE].push_used(memory, chain.head, written)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.u32(self.ports.len() as u32);
+        for port in self.ports.iter() {
+            state.bytes(&port.input.iter().copied().collect::<Vec<u8>>());
+        }
+        state.u16(self.columns);
+        state.u16(self.rows);
+        state.bool(self.multiport);
+        state.u32(self.control.len() as u32);
+        self.control.iter().for_each(|message| state.bytes(message));
+    }
+
+    fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        state.expect(self.ports.len() as u32, "console ports")?;
+        for port in self.ports.iter_mut() {
+            port.input = state.bytes()?.iter().copied().collect();
+        }
+        self.columns = state.u16()?;
+        self.rows = state.u16()?;
+        self.multiport = state.bool()?;
+
+        let mut control = VecDeque::new();
+        for _ in 0..state.u32()? {
+            control.push_back(state.bytes()?.to_vec());
+        }
+        self.control = control;
+        Ok(())
+    }
+}
@@ -0,0 +1,370 @@
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod queue;
+pub mod rng;
+
+use crate::bus::{AccessSize, BusError, Device, DMA, POLL_TICKS};
+use crate::fdt::{Context, Node};
+use crate::snapshot::{Reader, SnapshotError, Writer};
+use queue::Virtqueue;
+
+pub const DEVICE_ID_NET: u32     = 1;
+pub const DEVICE_ID_BLOCK: u32   = 2;
+pub const DEVICE_ID_CONSOLE: u32 = 3;
+pub const DEVICE_ID_RNG: u32     = 4;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+pub const STATUS_ACKNOWLEDGE: u32  = 1;
+pub const STATUS_DRIVER: u32       = 2;
+pub const STATUS_DRIVER_OK: u32    = 4;
+pub const STATUS_FEATURES_OK: u32  = 8;
+pub const STATUS_NEEDS_RESET: u32  = 64;
+pub const STATUS_FAILED: u32       = 128;
+
+// first of the consecutive transports of the virt machine
+pub const BASE: u32 = 0x1000_1000;
+pub const SIZE: u32 = 0x0000_1000;
+pub const IRQ: u32  = 1;
+
+pub const MAGIC: u32  = 0x74726976;
+pub const VENDOR: u32 = 0x554D4551;
+
+pub const MAGIC_VALUE: u32         = 0x000;
+pub const VERSION: u32             = 0x004;
+pub const DEVICE_ID: u32           = 0x008;
+pub const VENDOR_ID: u32           = 0x00C;
+pub const DEVICE_FEATURES: u32     = 0x010;
+pub const DEVICE_FEATURES_SEL: u32 = 0x014;
+pub const DRIVER_FEATURES: u32     = 0x020;
+pub const DRIVER_FEATURES_SEL: u32 = 0x024;
+pub const QUEUE_SEL: u32           = 0x030;
+pub const QUEUE_NUM_MAX: u32       = 0x034;
+pub const QUEUE_NUM: u32           = 0x038;
+pub const QUEUE_READY: u32         = 0x044;
+pub const QUEUE_NOTIFY: u32        = 0x050;
+pub const INTERRUPT_STATUS: u32    = 0x060;
+pub const INTERRUPT_ACK: u32       = 0x064;
+pub const STATUS: u32              = 0x070;
+pub const QUEUE_DESC_LOW: u32      = 0x080;
+pub const QUEUE_DESC_HIGH: u32     = 0x084;
+pub const QUEUE_DRIVER_LOW: u32    = 0x090;
+pub const QUEUE_DRIVER_HIGH: u32   = 0x094;
+pub const QUEUE_DEVICE_LOW: u32    = 0x0A0;
+pub const QUEUE_DEVICE_HIGH: u32   = 0x0A4;
+pub const CONFIG_GENERATION: u32   = 0x0FC;
+pub const CONFIG: u32              = 0x100;
+
+pub const INTERRUPT_USED: u32   = 1;
+pub const INTERRUPT_CONFIG: u32 = 2;
+
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+
+    // device specific feature bits, the transport adds VIRTIO_F_VERSION_1
+    fn features(&self) -> u64;
+
+    fn queue_count(&self) -> usize;
+
+    fn queue_size(&self) -> u16 {
+        256
+    }
+
+    fn read_config(&self, _offset: u32) -> u8 {
+        0
+    }
+
+    fn write_config(&mut self, _offset: u32, _value: u8) {}
+
+    // called when the driver is ready with the negotiated features
+    fn activate(&mut self, _features: u64) {}
+
+    // process the requests of a notified queue, returns whether buffers were used
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], memory: &mut dyn DMA) -> Result<bool, BusError>;
+
+    // called every tick so devices can deliver host side input
+    fn poll(&mut self, _queues: &mut [Virtqueue], _memory: &mut dyn DMA) -> Result<bool, BusError> {
+        Ok(false)
+    }
+
+    fn reset(&mut self) {}
+
+    // device specific state for snapshots, the transport saves the queues
+    fn save(&self, _state: &mut Writer) {}
+
+    fn restore(&mut self, _state: &mut Reader) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+// virtio mmio version 2 transport
+pub struct VirtioMMIO<D: VirtioDevice> {
+    device: D,
+    queues: Vec<Virtqueue>,
+    notified: Vec<bool>,
+    queue_select: u32,
+    device_features_select: u32,
+    driver_features_select: u32,
+    driver_features: u64,
+    status: u32,
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+impl<D: VirtioDevice> VirtioMMIO<D> {
+    pub fn new(device: D) -> Self {
+        let queues = vec![Virtqueue::new(device.queue_size()); device.queue_count()];
+        let notified = vec![false; queues.len()];
+
+        VirtioMMIO {
+            device,
+            queues,
+            notified,
+            queue_select: 0,
+            device_features_select: 0,
+            driver_features_select: 0,
+            driver_features: 0,
+            status: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    // signal a change of the device configuration to the driver
+    pub fn config_changed(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.interrupt_status |= INTERRUPT_CONFIG;
+    }
+
+    fn reset(&mut self) {
+        self.queues.iter_mut().for_each(Virtqueue::reset);
+        self.notified.iter_mut().for_each(|notified| *notified = false);
+        self.queue_select = 0;
+        self.device_features_select = 0;
+        self.driver_features_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.device.reset();
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    fn set_half(value: u64, high: bool, half: u32) -> u64 {
+        if high {
+            (value & 0x00000000FFFFFFFF) | ((half as u64) << 32)
+        } else {
+            (value & 0xFFFFFFFF00000000) | half as u64
+        }
+    }
+
+    fn process(&mut self, memory: &mut dyn DMA) -> Result<bool, BusError> {
+        let mut used = false;
+
+        for queue in 0..self.queues.len() {
+            if std::mem::take(&mut self.notified[queue]) {
+                used |= self.device.notify(queue, &mut self.queues, memory)?;
+            }
+        }
+        used |= self.device.poll(&mut self.queues, memory)?;
+
+        Ok(used)
+    }
+
+    fn read_register(&mut self, offset: u32) -> u32 {
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_select {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => self.selected_queue().map_or(0, |queue| queue.max_size as u32),
+            QUEUE_READY => self.selected_queue().map_or(0, |queue| queue.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, value: u32) {
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_select = value,
+            DRIVER_FEATURES_SEL => self.driver_features_select = value,
+            DRIVER_FEATURES if self.driver_features_select < 2 => {
+                let features = Self::set_half(self.driver_features, self.driver_features_select == 1, value);
+                self.driver_features = features & self.features();
+            }
+            QUEUE_SEL => self.queue_select = value,
+            QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue() {
+                    if value <= queue.max_size as u32 && value.is_power_of_two() {
+                        queue.size = value as u16;
+                    }
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value & 1 == 1;
+                }
+            }
+            QUEUE_NOTIFY => {
+                if let Some(notified) = self.notified.get_mut(value as usize) {
+                    *notified = true;
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else if value & STATUS_FEATURES_OK != 0 && self.driver_features & VIRTIO_F_VERSION_1 == 0 {
+                    // legacy drivers are not supported
+                    self.status = value & !STATUS_FEATURES_OK;
+                } else {
+                    if value & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0 {
+                        self.device.activate(self.driver_features);
+                    }
+                    self.status = value;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.descriptor_table = Self::set_half(queue.descriptor_table, offset == QUEUE_DESC_HIGH, value);
+                }
+            }
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.driver_area = Self::set_half(queue.driver_area, offset == QUEUE_DRIVER_HIGH, value);
+                }
+            }
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.device_area = Self::set_half(queue.device_area, offset == QUEUE_DEVICE_HIGH, value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<D: VirtioDevice + 'static> Device for VirtioMMIO<D> {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        if offset >= CONFIG {
+            let config = offset - CONFIG;
+            let value = (0..size.bytes()).rev()
+                .fold(0, |value, byte| (value << 8) | self.device.read_config(config + byte) as u32);
+            return Ok(value);
+        }
+        if size != AccessSize::Word || offset & 0b11 != 0 {
+            return Err(BusError::AccessFault(offset));
+        }
+
+        Ok(self.read_register(offset))
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        if offset >= CONFIG {
+            let config = offset - CONFIG;
+            for byte in 0..size.bytes() {
+                self.device.write_config(config + byte, (value >> (byte * 8)) as u8);
+            }
+            return Ok(());
+        }
+        if size != AccessSize::Word || offset & 0b11 != 0 {
+            return Err(BusError::AccessFault(offset));
+        }
+
+        self.write_register(offset, value);
+        Ok(())
+    }
+
+    fn dma(&mut self, memory: &mut dyn DMA) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_NEEDS_RESET != 0 {
+            return;
+        }
+
+        match self.process(memory) {
+            Ok(true) => self.interrupt_status |= INTERRUPT_USED,
+            Ok(false) => {}
+            Err(_) => {
+                // malformed queue, the driver has to reset the device
+                self.status |= STATUS_NEEDS_RESET;
+                self.config_changed();
+            }
+        }
+    }
+
+    // notified queues are processed on the tick after the access, host input is polled in between
+    fn deadline(&self) -> u64 {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_NEEDS_RESET != 0 {
+            u64::MAX
+        } else {
+            POLL_TICKS
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn device_tree(&self, _context: &Context) -> Option<Node> {
+        let mut node = Node::new("virtio_mmio");
+        node.property_string("compatible", "virtio,mmio");
+        Some(node)
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.u32(self.device.device_id());
+        state.u32(self.queues.len() as u32);
+        self.queues.iter().for_each(|queue| queue.save(state));
+        state.bools(&self.notified);
+        state.u32(self.queue_select);
+        state.u32(self.device_features_select);
+        state.u32(self.driver_features_select);
+        state.u64(self.driver_features);
+        state.u32(self.status);
+        state.u32(self.interrupt_status);
+        state.u32(self.config_generation);
+        self.device.save(state);
+    }
+
+    fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        state.expect(self.device.device_id(), "virtio device id")?;
+        state.expect(self.queues.len() as u32, "virtio queues")?;
+        for queue in self.queues.iter_mut() {
+            queue.restore(state)?;
+        }
+        state.bools_into(&mut self.notified, "virtio queues")?;
+        self.queue_select = state.u32()?;
+        self.device_features_select = state.u32()?;
+        self.driver_features_select = state.u32()?;
+        self.driver_features = state.u64()?;
+        self.status = state.u32()?;
+        self.interrupt_status = state.u32()?;
+        self.config_generation = state.u32()?;
+        self.device.restore(state)
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bus::{BusError, DMA};
+use crate::snapshot::{Reader, SnapshotError, Writer};
+use super::{VirtioDevice, DEVICE_ID_NET};
+use super::queue::Virtqueue;
+
+pub const VIRTIO_NET_F_MAC: u64    = 1 << 5;
+pub const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// virtio_net_hdr including num_buffers
+pub const HEADER_SIZE: usize = 12;
+
+pub const RECEIVE: usize  = 0;
+pub const TRANSMIT: usize = 1;
+
+// frames that are held back while the driver has no receive buffers
+pub const MAX_PENDING: usize = 256;
+
+// largest frame taken from a backend
+pub const MAX_FRAME: usize = 65536;
+
+pub trait PacketBackend {
+    fn send(&mut self, frame: &[u8]);
+
+    // next received frame if one is available, must not block
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+// writes sent frames to a pcap file and replays received frames from another one
+pub struct PcapBackend {
+    output: Option<BufWriter<File>>,
+    input: Option<BufReader<File>>,
+    // longest record the input file may hold
+    snaplen: u32,
+}
+
+impl PcapBackend {
+    pub const MAGIC: u32 = 0xA1B2C3D4;
+    pub const LINKTYPE_ETHERNET: u32 = 1;
+
+    pub fn new<P: AsRef<Path>>(output: Option<P>, input: Option<P>) -> io::Result<Self> {
+        let output = match output {
+            Some(path) => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                Self::write_header(&mut writer)?;
+                Some(writer)
+            }
+            None => None,
+        };
+        let mut snaplen = 0;
+        let input = match input {
+            Some(path) => {
+                let mut reader = BufReader::new(File::open(path)?);
+                let mut header = [0; 24];
+                reader.read_exact(&mut header)?;
+                if u32::from_le_bytes(header[0..4].try_into().unwrap()) != Self::MAGIC {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "not a little endian pcap file"));
+                }
+                if u32::from_le_bytes(header[20..24].try_into().unwrap()) != Self::LINKTYPE_ETHERNET {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "not an ethernet capture"));
+                }
+                snaplen = u32::from_le_bytes(header[16..20].try_into().unwrap()).min(MAX_FRAME as u32);
+                Some(reader)
+            }
+            None => None,
+        };
+
+        Ok(PcapBackend { output, input, snaplen })
+    }
+
+    fn write_header(writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&Self::MAGIC.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&65535u32.to_le_bytes())?;
+        writer.write_all(&Self::LINKTYPE_ETHERNET.to_le_bytes())
+    }
+
+    fn write_record(writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        writer.write_all(&(now.as_secs() as u32).to_le_bytes())?;
+        writer.write_all(&now.subsec_micros().to_le_bytes())?;
+        writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        writer.write_all(frame)?;
+        writer.flush()
+    }
+
+    fn read_record(reader: &mut impl Read, snaplen: u32) -> io::Result<Vec<u8>> {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if length > snaplen {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "record longer than the snapshot length"));
+        }
+
+        let mut frame = vec![0; length as usize];
+        reader.read_exact(&mut frame)?;
+        Ok(frame)
+    }
+}
+
+impl PacketBackend for PcapBackend {
+    fn send(&mut self, frame: &[u8]) {
+        if let Some(writer) = &mut self.output {
+            let _ = Self::write_record(writer, frame);
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let reader = self.input.as_mut()?;
+        match Self::read_record(reader, self.snaplen) {
+            Ok(frame) => Some(frame),
+            Err(_) => {
+                self.input = None;
+                None
+            }
+        }
+    }
+}
+
+// one end of a virtual cable between two emulator instances
+pub struct LoopbackBackend {
+    incoming: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    outgoing: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl LoopbackBackend {
+    pub fn pair() -> (LoopbackBackend, LoopbackBackend) {
+        let first = Arc::new(Mutex::new(VecDeque::new()));
+        let second = Arc::new(Mutex::new(VecDeque::new()));
+
+        (
+            LoopbackBackend { incoming: first.clone(), outgoing: second.clone() },
+            LoopbackBackend { incoming: second, outgoing: first },
+        )
+    }
+}
+
+impl PacketBackend for LoopbackBackend {
+    fn send(&mut self, frame: &[u8]) {
+        self.outgoing.lock().unwrap().push_back(frame.to_vec());
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.incoming.lock().unwrap().pop_front()
+    }
+}
+
+// one frame per datagram on a unix socket
+#[cfg(unix)]
+pub struct UnixSocketBackend {
+    socket: std::os::unix::net::UnixDatagram,
+    // polled every tick, only received frames are copied out
+    buffer: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl UnixSocketBackend {
+    pub fn new<P: AsRef<Path>>(local: P, remote: P) -> io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::bind(local)?;
+        socket.connect(remote)?;
+        Self::from_socket(socket)
+    }
+
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (first, second) = std::os::unix::net::UnixDatagram::pair()?;
+        Ok((Self::from_socket(first)?, Self::from_socket(second)?))
+    }
+
+    fn from_socket(socket: std::os::unix::net::UnixDatagram) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(UnixSocketBackend { socket, buffer: vec![0; MAX_FRAME] })
+    }
+}
+
+#[cfg(unix)]
+impl PacketBackend for UnixSocketBackend {
+    fn send(&mut self, frame: &[u8]) {
+        // like a real link, frames are dropped when the peer is gone or busy
+        let _ = self.socket.send(frame);
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let length = self.socket.recv(&mut self.buffer).ok()?;
+        Some(self.buffer[..length].to_vec())
+    }
+}
+
+pub struct VirtioNet {
+    backend: Box<dyn PacketBackend>,
+    mac: [u8; 6],
+    pending: VecDeque<Vec<u8>>,
+    link_up: bool,
+}
+
+impl VirtioNet {
+    pub fn new(backend: Box<dyn PacketBackend>, mac: [u8; 6]) -> Self {
+        VirtioNet { backend, mac, pending: VecDeque::new(), link_up: true }
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn set_link_up(&mut self, link_up: bool) {
+        self.link_up = link_up;
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    // mac followed by the link status
+    fn read_config(&self, offset: u32) -> u8 {
+        let status = if self.link_up { VIRTIO_NET_S_LINK_UP } else { 0 };
+        match offset {
+            0..=5 => self.mac[offset as usize],
+            6..=7 => (status >> ((offset - 6) * 8)) as u8,
+            _ => 0,
+        }
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], memory: &mut dyn DMA) -> Result<bool, BusError> {
+        if queue != TRANSMIT {
+            return Ok(false);
+        }
+
+        let mut used = false;
+        while let Some(chain) = queues[TRANSMIT].pop(memory)? {
+            let data = chain.read_all(memory)?;
+            if data.len() > HEADER_SIZE && self.link_up {
+                self.backend.send(&data[HEADER_SIZE..]);
+            }
+            queues[TRANSMIT].push_used(memory, chain.head, 0)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &mut dyn DMA) -> Result<bool, BusError> {
+        while self.pending.len() < MAX_PENDING {
+            match self.backend.receive() {
+                Some(frame) if self.link_up => self.pending.push_back(frame),
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let Some(chain) = queues[RECEIVE].pop(memory)? else {
+                break;
+            };
+            let frame = self.pending.pop_front().unwrap();
+
+            // frames that do not fit the buffer are truncated
+            let mut packet = vec![0; HEADER_SIZE];
+            packet[10] = 1;
+            packet.extend_from_slice(&frame);
+            let written = chain.write_at(memory, 0, &packet)?;
+            queues[RECEIVE].push_used(memory, chain.head, written)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    // frames received from the backend but not delivered to the guest yet
+    fn save(&self, state: &mut Writer) {
+        state.bool(self.link_up);
+        state.u32(self.pending.len() as u32);
+        self.pending.iter().for_each(|frame| state.bytes(frame));
+    }
+
+    fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        self.link_up = state.bool()?;
+        let mut pending = VecDeque::new();
+        for _ in 0..state.u32()? {
+            pending.push_back(state.bytes()?.to_vec());
+        }
+
+        self.pending = pending;
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use crate::bus::{BusError, DMA};
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+pub const DESC_F_NEXT: u16  = 1;
+pub const DESC_F_WRITE: u16 = 2;
+
+// bytes a chain may describe in each direction, larger requests are refused before anything is allocated
+pub const MAX_CHAIN_LENGTH: u32 = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor {
+    pub address: u32,
+    pub length: u32,
+}
+
+// buffers of one request, readable ones come before writable ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorChain {
+    pub head: u16,
+    pub readable: Vec<Descriptor>,
+    pub writable: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    // lengths come from the guest, their sums saturate
+    pub fn readable_length(&self) -> u32 {
+        self.readable.iter().fold(0, |total: u32, descriptor| total.saturating_add(descriptor.length))
+    }
+
+    pub fn writable_length(&self) -> u32 {
+        self.writable.iter().fold(0, |total: u32, descriptor| total.saturating_add(descriptor.length))
+    }
+
+    // gather all readable buffers
+    pub fn read_all(&self, memory: &mut dyn DMA) -> Result<Vec<u8>, BusError> {
+        let mut data = vec![0; self.readable_length() as usize];
+        let mut offset = 0;
+
+        for descriptor in self.readable.iter() {
+            let length = descriptor.length as usize;
+            memory.read(descriptor.address, &mut data[offset..offset + length])?;
+            offset += length;
+        }
+
+        Ok(data)
+    }
+
+    // scatter data over the writable buffers starting at the given byte offset
+    pub fn write_at(&self, memory: &mut dyn DMA, mut offset: u32, mut data: &[u8]) -> Result<u32, BusError> {
+        let mut written = 0;
+
+        for descriptor in self.writable.iter() {
+            if data.is_empty() {
+                break;
+            }
+            if offset >= descriptor.length {
+                offset -= descriptor.length;
+                continue;
+            }
+
+            let length = (descriptor.length - offset).min(data.len() as u32);
+            let address = descriptor.address.checked_add(offset).ok_or(BusError::AccessFault(descriptor.address))?;
+            memory.write(address, &data[..length as usize])?;
+            data = &data[length as usize..];
+            written += length;
+            offset = 0;
+        }
+
+        Ok(written)
+    }
+}
+
+// split virtqueue as seen by the device
+#[derive(Debug, Clone, Default)]
+pub struct Virtqueue {
+    pub size: u16,
+    pub max_size: u16,
+    pub ready: bool,
+    pub descriptor_table: u64,
+    pub driver_area: u64,
+    pub device_area: u64,
+    last_available: u16,
+    used_index: u16,
+}
+
+impl Virtqueue {
+    pub fn new(max_size: u16) -> Self {
+        Virtqueue { max_size, ..Default::default() }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Virtqueue::new(self.max_size);
+    }
+
+    pub fn save(&self, state: &mut Writer) {
+        state.u16(self.size);
+        state.bool(self.ready);
+        state.u64(self.descriptor_table);
+        state.u64(self.driver_area);
+        state.u64(self.device_area);
+        state.u16(self.last_available);
+        state.u16(self.used_index);
+    }
+
+    pub fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        self.size = state.u16()?;
+        self.ready = state.bool()?;
+        self.descriptor_table = state.u64()?;
+        self.driver_area = state.u64()?;
+        self.device_area = state.u64()?;
+        self.last_available = state.u16()?;
+        self.used_index = state.u16()?;
+        Ok(())
+    }
+
+    fn address(area: u64, offset: u32) -> Result<u32, BusError> {
+        let address = area + offset as u64;
+        u32::try_from(address).map_err(|_| BusError::AccessFault(u32::MAX))
+    }
+
+    pub fn has_available(&self, memory: &mut dyn DMA) -> Result<bool, BusError> {
+        if !self.ready || self.size == 0 {
+            return Ok(false);
+        }
+
+        let index = memory.read_u16(Self::address(self.driver_area, 2)?)?;
+        Ok(index != self.last_available)
+    }
+
+    // next request made available by the driver
+    pub fn pop(&mut self, memory: &mut dyn DMA) -> Result<Option<DescriptorChain>, BusError> {
+        if !self.has_available(memory)? {
+            return Ok(None);
+        }
+
+        let slot = (self.last_available % self.size) as u32;
+        let head = memory.read_u16(Self::address(self.driver_area, 4 + slot * 2)?)?;
+        self.last_available = self.last_available.wrapping_add(1);
+
+        let mut chain = DescriptorChain { head, readable: Vec::new(), writable: Vec::new() };
+        let mut index = head;
+
+        // a chain can not be longer than the queue
+        for _ in 0..self.size {
+            if index >= self.size {
+                return Err(BusError::AccessFault(index as u32));
+            }
+
+            let entry = index as u32 * 16;
+            let address = memory.read_u64(Self::address(self.descriptor_table, entry)?)?;
+            let length = memory.read_u32(Self::address(self.descriptor_table, entry + 8)?)?;
+            let flags = memory.read_u16(Self::address(self.descriptor_table, entry + 12)?)?;
+            let next = memory.read_u16(Self::address(self.descriptor_table, entry + 14)?)?;
+
+            let address = u32::try_from(address).map_err(|_| BusError::AccessFault(u32::MAX))?;
+            let descriptor = Descriptor { address, length };
+            if flags & DESC_F_WRITE != 0 {
+                chain.writable.push(descriptor);
+            } else {
+                chain.readable.push(descriptor);
+            }
+
+            if chain.readable_length() > MAX_CHAIN_LENGTH || chain.writable_length() > MAX_CHAIN_LENGTH {
+                return Err(BusError::AccessFault(head as u32));
+            }
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+            index = next;
+        }
+
+        Err(BusError::AccessFault(head as u32))
+    }
+
+    // hand a processed request back to the driver
+    pub fn push_used(&mut self, memory: &mut dyn DMA, head: u16, length: u32) -> Result<(), BusError> {
+        let slot = (self.used_index % self.size) as u32;
+
+        memory.write_u32(Self::address(self.device_area, 4 + slot * 8)?, head as u32)?;
+        memory.write_u32(Self::address(self.device_area, 8 + slot * 8)?, length)?;
+
+        self.used_index = self.used_index.wrapping_add(1);
+        memory.write_u16(Self::address(self.device_area, 2)?, self.used_index)
+    }
+}
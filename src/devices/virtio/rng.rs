@@ -0,0 +1,140 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::Read;
+
+use crate::bus::{BusError, DMA};
+use crate::reverse::InputLog;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+use super::{VirtioDevice, DEVICE_ID_RNG};
+use super::queue::Virtqueue;
+
+// bytes generated at a time, a multiple of the seeded stream's eight byte steps
+const CHUNK_SIZE: usize = 4096;
+
+pub enum EntropySource {
+    // host entropy from /dev/urandom
+    Host(Option<File>),
+    // reproducible stream from a seed
+    Seeded(u64),
+    // host entropy logged for replay, by the channel of the log
+    Recorded(Option<File>, InputLog, usize),
+}
+
+pub struct VirtioRNG {
+    source: EntropySource,
+}
+
+impl VirtioRNG {
+    pub fn host() -> Self {
+        VirtioRNG { source: EntropySource::Host(File::open("/dev/urandom").ok()) }
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        VirtioRNG { source: EntropySource::Seeded(seed) }
+    }
+
+    pub fn recorded(inputs: InputLog) -> Self {
+        let channel = inputs.channel();
+        VirtioRNG { source: EntropySource::Recorded(File::open("/dev/urandom").ok(), inputs, channel) }
+    }
+
+    pub fn fill(&mut self, buffer: &mut [u8]) {
+        if let EntropySource::Recorded(file, inputs, channel) = &mut self.source {
+            let data = inputs.input(*channel, || {
+                let mut data = vec![0; buffer.len()];
+                if file.as_mut().is_none_or(|file| file.read_exact(&mut data).is_err()) {
+                    fill_seeded(&mut RandomState::new().hash_one(0u64), &mut data);
+                }
+                Some(data)
+            });
+            // a replayed request always has the recorded length
+            if let Some(data) = data {
+                let length = data.len().min(buffer.len());
+                buffer[..length].copy_from_slice(&data[..length]);
+            }
+            return;
+        }
+
+        if let EntropySource::Host(Some(file)) = &mut self.source {
+            if file.read_exact(buffer).is_ok() {
+                return;
+            }
+        }
+
+        // hosts without /dev/urandom fall back to a randomly seeded stream
+        if let EntropySource::Host(_) = self.source {
+            self.source = EntropySource::Seeded(RandomState::new().hash_one(0u64));
+        }
+
+        if let EntropySource::Seeded(state) = &mut self.source {
+            fill_seeded(state, buffer);
+        }
+    }
+}
+
+fn fill_seeded(state: &mut u64, buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let value = splitmix64(state).to_le_bytes();
+        chunk.copy_from_slice(&value[..chunk.len()]);
+    }
+}
+
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+impl VirtioDevice for VirtioRNG {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Virtqueue], memory: &mut dyn DMA) -> Result<bool, BusError> {
+        let mut used = false;
+
+        while let Some(chain) = queues[0].pop(memory)? {
+            // the buffers are filled a chunk at a time, their length comes from the guest
+            let mut chunk = [0; CHUNK_SIZE];
+            let mut written = 0;
+            while written < chain.writable_length() {
+                let length = (chain.writable_length() - written).min(CHUNK_SIZE as u32) as usize;
+                self.fill(&mut chunk[..length]);
+                written += chain.write_at(memory, written, &chunk[..length])?;
+            }
+            queues[0].push_used(memory, chain.head, written)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+
+    // seeded streams continue where they were, host entropy has no state
+    fn save(&self, state: &mut Writer) {
+        match self.source {
+            EntropySource::Seeded(seed) => {
+                state.bool(true);
+                state.u64(seed);
+            }
+            EntropySource::Host(_) | EntropySource::Recorded(..) => state.bool(false),
+        }
+    }
+
+    fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        if state.bool()? {
+            self.source = EntropySource::Seeded(state.u64()?);
+        }
+        Ok(())
+    }
+}
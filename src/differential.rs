@@ -0,0 +1,203 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::CPU;
+use crate::bus::Bus;
+use crate::trace::{CommitLog, LogBuffer};
+
+// ticks without a retired instruction before the comparison gives up, covers traps and interrupts
+pub const RETIRE_LIMIT: u32 = 1000;
+
+// one retired instruction of a trace
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Commit {
+    pub hart: u32,
+    pub pc: u32,
+    pub instruction: u32,
+    pub registers: Vec<(u8, u32)>,
+    pub stores: Vec<(u32, u32)>,
+    // trace text the commit was parsed from
+    pub line: String,
+}
+
+// first point where the cpu and the reference trace disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // index of the reference commit
+    pub step: usize,
+    pub reason: String,
+    pub expected: Commit,
+    pub actual: Option<Commit>,
+    // reference commits before the divergence that matched
+    pub context: Vec<Commit>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "divergence at step {}: {}", self.step, self.reason)?;
+        for commit in self.context.iter() {
+            writeln!(f, "    {}", commit.line)?;
+        }
+        writeln!(f, "  expected: {}", self.expected.line)?;
+        match &self.actual {
+            Some(actual) => write!(f, "  actual:   {}", actual.line),
+            None => write!(f, "  actual:   no instruction retired"),
+        }
+    }
+}
+
+// a spike --log-commits line, other lines are skipped
+pub fn parse_spike(line: &str) -> Option<Commit> {
+    let rest = line.trim().strip_prefix("core")?;
+    let (hart, rest) = rest.split_once(':')?;
+    let mut tokens = rest.split_whitespace().peekable();
+
+    // the privilege level distinguishes commit lines from instruction traces
+    let privilege = tokens.next()?;
+    if privilege.len() != 1 || !privilege.chars().all(|character| character.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut commit = Commit {
+        hart: hart.trim().parse().ok()?,
+        pc: parse_value(tokens.next()?)?,
+        instruction: parse_value(tokens.next()?.strip_prefix('(')?.strip_suffix(')')?)?,
+        line: line.trim().to_string(),
+        ..Commit::default()
+    };
+
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let address = parse_value(tokens.next()?)?;
+            // stores carry a value, loads only the address
+            if let Some(value) = tokens.peek().filter(|next| next.starts_with("0x")).and_then(|value| parse_value(value)) {
+                tokens.next();
+                commit.stores.push((address, value));
+            }
+        } else if let Some(register) = token.strip_prefix('x').and_then(|number| number.parse::<u8>().ok()) {
+            commit.registers.push((register, parse_value(tokens.next()?)?));
+        } else {
+            // csr and floating point writebacks are not compared
+            tokens.next();
+        }
+    }
+
+    Some(commit)
+}
+
+// sail prints the instruction followed by its effects on separate lines
+pub fn parse_sail(text: &str) -> Vec<Commit> {
+    let mut commits: Vec<Commit> = Vec::new();
+    let mut trapped = false;
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            if trapped {
+                commits.pop();
+            }
+            trapped = false;
+
+            let mut tokens = line.split_whitespace();
+            let hart = tokens.next().and_then(|hart| hart.strip_prefix('[')?.strip_suffix(']')?.parse().ok());
+            tokens.next();
+            let pc = tokens.next().and_then(parse_value);
+            let instruction = tokens.next().and_then(|value| parse_value(value.strip_prefix('(')?.strip_suffix(')')?));
+            if let (Some(hart), Some(pc), Some(instruction)) = (hart, pc, instruction) {
+                commits.push(Commit { hart, pc, instruction, line: line.to_string(), ..Commit::default() });
+            }
+        } else if line.starts_with("trapping") {
+            trapped = true;
+        } else if let Some(commit) = commits.last_mut() {
+            let Some((target, value)) = line.split_once("<-") else {
+                continue;
+            };
+            let Some(value) = parse_value(value.trim()) else {
+                continue;
+            };
+            let target = target.trim();
+            if let Some(register) = target.strip_prefix('x').and_then(|number| number.parse::<u8>().ok()) {
+                commit.registers.push((register, value));
+            } else if let Some(address) = target.strip_prefix("mem[").and_then(|address| parse_value(address.trim_end_matches(']'))) {
+                commit.stores.push((address, value));
+            }
+        }
+    }
+    if trapped {
+        commits.pop();
+    }
+
+    commits
+}
+
+// spike or sail, detected from the first line that parses as spike
+pub fn parse_trace(text: &str) -> Vec<Commit> {
+    let spike: Vec<Commit> = text.lines().filter_map(parse_spike).collect();
+    if spike.is_empty() { parse_sail(text) } else { spike }
+}
+
+pub fn read_trace(path: &Path) -> io::Result<Vec<Commit>> {
+    Ok(parse_trace(&fs::read_to_string(path)?))
+}
+
+// steps the cpu in lockstep with the reference and returns the number of matching commits
+pub fn compare<B: Bus>(cpu: &mut CPU<B>, reference: &[Commit], context: usize) -> Result<usize, Box<Divergence>> {
+    let buffer = LogBuffer::new();
+    let previous = cpu.set_commit_log(Some(CommitLog::new(Box::new(buffer.clone()))));
+
+    let mut result = Ok(reference.len());
+    for (step, expected) in reference.iter().enumerate() {
+        let actual = retire(cpu, &buffer);
+        let reason = match &actual {
+            None => Some("no instruction retired".to_string()),
+            Some(actual) => difference(expected, actual),
+        };
+
+        if let Some(reason) = reason {
+            let context = reference[step.saturating_sub(context)..step].to_vec();
+            result = Err(Box::new(Divergence { step, reason, expected: expected.clone(), actual, context }));
+            break;
+        }
+    }
+
+    cpu.set_commit_log(previous);
+    result
+}
+
+fn retire<B: Bus>(cpu: &mut CPU<B>, buffer: &LogBuffer) -> Option<Commit> {
+    for _ in 0..RETIRE_LIMIT {
+        cpu.tick();
+        let line = buffer.take();
+        if !line.is_empty() {
+            return parse_spike(&line);
+        }
+    }
+
+    None
+}
+
+fn difference(expected: &Commit, actual: &Commit) -> Option<String> {
+    if expected.pc != actual.pc {
+        return Some(format!("pc 0x{:08x} instead of 0x{:08x}", actual.pc, expected.pc));
+    }
+    if expected.instruction != actual.instruction {
+        return Some(format!("instruction 0x{:08x} instead of 0x{:08x}", actual.instruction, expected.instruction));
+    }
+
+    // references may log writes to x0, the cpu does not
+    let registers = |commit: &Commit| -> Vec<(u8, u32)> { commit.registers.iter().copied().filter(|&(register, _)| register != 0).collect() };
+    if registers(expected) != registers(actual) {
+        return Some(format!("register writes {:x?} instead of {:x?}", registers(actual), registers(expected)));
+    }
+    if expected.stores != actual.stores {
+        return Some(format!("memory writes {:x?} instead of {:x?}", actual.stores, expected.stores));
+    }
+
+    None
+}
+
+// hexadecimal with 0x, 64 bit values from rv64 builds are truncated
+fn parse_value(text: &str) -> Option<u32> {
+    u64::from_str_radix(text.strip_prefix("0x")?, 16).ok().map(|value| value as u32)
+}
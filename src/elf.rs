@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use crate::bus::{AccessSize, Bus, BusError};
+
+pub const EM_RISCV: u16 = 243;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const SHT_SYMTAB: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ELFError {
+    Truncated,
+    NotELF,
+    // 64 bit, big endian or not risc-v
+    Unsupported,
+}
+
+pub struct Segment {
+    pub address: u32,
+    // file contents, the rest up to memory_size is zero filled
+    pub data: Vec<u8>,
+    pub memory_size: u32,
+    pub flags: u32,
+}
+
+pub struct ELF {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    // address of the program headers once loaded, used for the auxiliary vector
+    pub program_headers: Option<u32>,
+    pub program_header_size: u32,
+    pub program_header_count: u32,
+    symbols: HashMap<String, u32>,
+}
+
+impl ELF {
+    pub fn parse(data: &[u8]) -> Result<Self, ELFError> {
+        if data.len() < 52 {
+            return Err(ELFError::Truncated);
+        }
+        if data[0..4] != [0x7F, b'E', b'L', b'F'] {
+            return Err(ELFError::NotELF);
+        }
+        // 32 bit little endian risc-v only
+        if data[4] != 1 || data[5] != 1 || half(data, 18)? != EM_RISCV {
+            return Err(ELFError::Unsupported);
+        }
+
+        let entry = word(data, 24)?;
+        let program_header_offset = word(data, 28)?;
+        let section_header_offset = word(data, 32)?;
+        let program_header_size = half(data, 42)? as u32;
+        let program_header_count = half(data, 44)? as u32;
+        let section_header_size = half(data, 46)? as u32;
+        let section_header_count = half(data, 48)? as u32;
+
+        let mut segments = Vec::new();
+        let mut program_headers = None;
+        for index in 0..program_header_count {
+            let header = program_header_offset as usize + (index * program_header_size) as usize;
+            if word(data, header)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = word(data, header + 4)?;
+            let address = word(data, header + 8)?;
+            let file_size = word(data, header + 16)?;
+            let memory_size = word(data, header + 20)?;
+            let flags = word(data, header + 24)?;
+
+            let contents = data.get(offset as usize..offset as usize + file_size as usize).ok_or(ELFError::Truncated)?;
+            if (offset as u64..offset as u64 + file_size as u64).contains(&(program_header_offset as u64)) {
+                program_headers = Some(address.wrapping_add(program_header_offset - offset));
+            }
+            segments.push(Segment { address, data: contents.to_vec(), memory_size: memory_size.max(file_size), flags });
+        }
+
+        let mut symbols = HashMap::new();
+        for index in 0..section_header_count {
+            let header = section_header_offset as usize + (index * section_header_size) as usize;
+            if word(data, header + 4)? != SHT_SYMTAB {
+                continue;
+            }
+
+            let offset = word(data, header + 16)? as usize;
+            let size = word(data, header + 20)? as usize;
+            let link = word(data, header + 24)?;
+            let strings = word(data, section_header_offset as usize + link as usize * section_header_size as usize + 16)? as usize;
+
+            // entries are 16 bytes: name, value, size, info, other, section
+            for symbol in (offset..offset + size).step_by(16) {
+                let name = strings + word(data, symbol)? as usize;
+                let end = data.get(name..).and_then(|rest| rest.iter().position(|&byte| byte == 0)).ok_or(ELFError::Truncated)?;
+                if end > 0 {
+                    let name = String::from_utf8_lossy(&data[name..name + end]).into_owned();
+                    symbols.insert(name, word(data, symbol + 4)?);
+                }
+            }
+        }
+
+        Ok(ELF { entry, segments, program_headers, program_header_size, program_header_count, symbols })
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    // first address after the highest segment, where the heap starts
+    pub fn end(&self) -> u32 {
+        self.segments.iter().map(|segment| segment.address.saturating_add(segment.memory_size)).max().unwrap_or(0)
+    }
+
+    // copies the segments to memory and zero fills bss
+    pub fn load<B: Bus>(&self, bus: &mut B) -> Result<(), BusError> {
+        for segment in self.segments.iter() {
+            for offset in 0..segment.memory_size {
+                let value = segment.data.get(offset as usize).copied().unwrap_or(0);
+                let address = segment.address.checked_add(offset).ok_or(BusError::AccessFault(segment.address))?;
+                bus.write(address, AccessSize::Byte, value as u32)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn half(data: &[u8], offset: usize) -> Result<u16, ELFError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ELFError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn word(data: &[u8], offset: usize) -> Result<u32, ELFError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ELFError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
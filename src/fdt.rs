@@ -0,0 +1,297 @@
+use crate::CPU;
+use crate::bus::{AccessSize, Bus, BusError, SystemBus};
+use crate::csr::{MHARTID, MISA, MISA_S};
+
+pub const MAGIC: u32 = 0xD00DFEED;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32   = 2;
+const FDT_PROP: u32       = 3;
+const FDT_END: u32        = 9;
+
+pub struct Node {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node { name: name.to_string(), properties: Vec::new(), children: Vec::new() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) -> &mut Self {
+        self.properties.push((name.to_string(), value.to_vec()));
+        self
+    }
+
+    pub fn property_empty(&mut self, name: &str) -> &mut Self {
+        self.property(name, &[])
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) -> &mut Self {
+        self.property_cells(name, &[value])
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value)
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) -> &mut Self {
+        self.property_strings(name, &[value])
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) -> &mut Self {
+        let value: Vec<u8> = values.iter().flat_map(|value| value.bytes().chain([0])).collect();
+        self.property(name, &value)
+    }
+
+    pub fn child(&mut self, node: Node) -> &mut Self {
+        self.children.push(node);
+        self
+    }
+
+    // flattened device tree blob with this node as the root
+    pub fn flatten(&self, boot_hart: u32) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        self.flatten_node(&mut structure, &mut strings);
+        structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+        let header_size = 40;
+        let reservations = header_size;
+        let structure_offset = reservations + 16;
+        let strings_offset = structure_offset + structure.len();
+        let total_size = strings_offset + strings.len();
+
+        let header = [
+            MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            reservations as u32,
+            17,
+            16,
+            boot_hart,
+            strings.len() as u32,
+            structure.len() as u32,
+        ];
+
+        let mut blob: Vec<u8> = header.iter().flat_map(|field| field.to_be_bytes()).collect();
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&structure);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+
+    fn flatten_node(&self, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+        structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+        structure.extend_from_slice(self.name.as_bytes());
+        structure.push(0);
+        Self::pad(structure);
+
+        for (name, value) in self.properties.iter() {
+            structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+            structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            structure.extend_from_slice(&Self::string_offset(strings, name).to_be_bytes());
+            structure.extend_from_slice(value);
+            Self::pad(structure);
+        }
+
+        for child in self.children.iter() {
+            child.flatten_node(structure, strings);
+        }
+
+        structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    }
+
+    fn pad(structure: &mut Vec<u8>) {
+        while structure.len() & 0b11 != 0 {
+            structure.push(0);
+        }
+    }
+
+    // property names are stored once in the strings block
+    fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+        let mut offset = 0;
+        for string in strings.split(|&byte| byte == 0) {
+            if string == name.as_bytes() && offset < strings.len() {
+                return offset as u32;
+            }
+            offset += string.len() + 1;
+        }
+
+        let offset = strings.len();
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+        offset as u32
+    }
+}
+
+// phandles shared between the cpu nodes and the device nodes
+pub struct Context {
+    pub harts: u32,
+}
+
+impl Context {
+    pub fn cpu_interrupt_controller(&self, hart: u32) -> u32 {
+        hart + 1
+    }
+
+    // the external interrupt controller devices are connected to
+    pub fn interrupt_parent(&self) -> u32 {
+        self.harts + 1
+    }
+}
+
+pub struct Description {
+    pub harts: u32,
+    pub isa: String,
+    // paging scheme of the harts, linux only enables the mmu of cpus that name one
+    pub mmu_type: Option<String>,
+    pub timebase_frequency: u32,
+    pub bootargs: Option<String>,
+    // start and end address of the initial ramdisk
+    pub initrd: Option<(u32, u32)>,
+}
+
+impl Description {
+    pub fn new(harts: u32, isa: &str, timebase_frequency: u32) -> Self {
+        Description { harts, isa: isa.to_string(), mmu_type: None, timebase_frequency, bootargs: None, initrd: None }
+    }
+}
+
+// isa string such as rv32ima from the extension bits of misa
+pub fn isa_string(misa: u32) -> String {
+    let base = match misa >> 30 {
+        2 => "rv64",
+        _ => "rv32",
+    };
+    // supervisor and user mode are privilege levels, not extensions in the isa string
+    let extensions: String = ('a'..='z')
+        .filter(|letter| !matches!(letter, 's' | 'u'))
+        .filter(|letter| misa & (1 << (*letter as u32 - 'a' as u32)) != 0)
+        .collect();
+
+    format!("{}{}", base, extensions)
+}
+
+// device tree describing the harts and every device mapped on the bus
+pub fn generate(bus: &SystemBus, description: &Description) -> Vec<u8> {
+    let context = Context { harts: description.harts };
+    let mut root = Node::new("");
+    root.property_u32("#address-cells", 2)
+        .property_u32("#size-cells", 2)
+        .property_string("compatible", "riscv-virtio")
+        .property_string("model", "rust-risc-v,virt");
+
+    let mut soc = Node::new("soc");
+    soc.property_u32("#address-cells", 2)
+        .property_u32("#size-cells", 2)
+        .property_string("compatible", "simple-bus")
+        .property_empty("ranges");
+
+    let mut stdout = None;
+    for region in bus.regions() {
+        let Some(mut node) = region.device().device_tree(&context) else {
+            continue;
+        };
+
+        let name = format!("{}@{:x}", node.name(), region.base());
+        if stdout.is_none() && node.name() == "serial" {
+            stdout = Some(format!("/soc/{}", name));
+        }
+        node.set_name(&name);
+        node.property_cells("reg", &[0, region.base(), 0, region.size()]);
+        if let Some(source) = region.source() {
+            node.property_u32("interrupts", source)
+                .property_u32("interrupt-parent", context.interrupt_parent());
+        }
+        soc.child(node);
+    }
+
+    let mut chosen = Node::new("chosen");
+    if let Some(bootargs) = &description.bootargs {
+        chosen.property_string("bootargs", bootargs);
+    }
+    if let Some((start, end)) = description.initrd {
+        chosen.property_u32("linux,initrd-start", start)
+            .property_u32("linux,initrd-end", end);
+    }
+    if let Some(stdout) = &stdout {
+        chosen.property_string("stdout-path", stdout);
+    }
+
+    let mut memory = Node::new(&format!("memory@{:x}", bus.ram_base()));
+    memory.property_string("device_type", "memory")
+        .property_cells("reg", &[0, bus.ram_base(), 0, bus.ram_size()]);
+
+    let mut cpus = Node::new("cpus");
+    cpus.property_u32("#address-cells", 1)
+        .property_u32("#size-cells", 0)
+        .property_u32("timebase-frequency", description.timebase_frequency);
+
+    for hart in 0..description.harts {
+        let mut interrupt_controller = Node::new("interrupt-controller");
+        interrupt_controller.property_u32("#interrupt-cells", 1)
+            .property_empty("interrupt-controller")
+            .property_string("compatible", "riscv,cpu-intc")
+            .property_u32("phandle", context.cpu_interrupt_controller(hart));
+
+        let mut cpu = Node::new(&format!("cpu@{}", hart));
+        cpu.property_string("device_type", "cpu")
+            .property_u32("reg", hart)
+            .property_string("status", "okay")
+            .property_string("compatible", "riscv")
+            .property_string("riscv,isa", &description.isa);
+        if let Some(mmu_type) = &description.mmu_type {
+            cpu.property_string("mmu-type", mmu_type);
+        }
+        cpu.child(interrupt_controller);
+        cpus.child(cpu);
+    }
+
+    root.child(chosen).child(memory).child(cpus).child(soc);
+    root.flatten(0)
+}
+
+// copies the blob to the top of ram and sets up a0 and a1 as expected by opensbi and linux
+pub fn place(cpu: &mut CPU, dtb: &[u8]) -> Result<u32, BusError> {
+    let ram_base = cpu.bus().ram_base();
+    let ram_size = cpu.bus().ram_size();
+    if dtb.len() as u64 > ram_size as u64 {
+        return Err(BusError::AccessFault(ram_base));
+    }
+
+    // ram may end at the top of the address space, which does not fit in 32 bits
+    let end = ram_base as u64 + ram_size as u64;
+    let address = ((end - dtb.len() as u64) & !0xFFF) as u32;
+    for (offset, &byte) in dtb.iter().enumerate() {
+        cpu.bus().write(address.wrapping_add(offset as u32), AccessSize::Byte, byte as u32)?;
+    }
+
+    let hart = cpu.csrs().read(MHARTID);
+    cpu.registers().write(10, hart);
+    cpu.registers().write(11, address);
+    Ok(address)
+}
+
+// description of harts configured like the given cpu
+pub fn describe(cpu: &mut CPU, harts: u32, timebase_frequency: u32) -> Description {
+    let misa = cpu.csrs().read(MISA);
+    let mut description = Description::new(harts, &isa_string(misa), timebase_frequency);
+    if misa & MISA_S != 0 {
+        description.mmu_type = Some("riscv,sv32".to_string());
+    }
+    description
+}
+
@@ -0,0 +1,286 @@
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::CPU;
+use crate::csr;
+use crate::instructions::*;
+use crate::instructions::branch::*;
+use crate::instructions::csr::*;
+use crate::instructions::load::*;
+use crate::instructions::store::*;
+
+// ram of the fuzzed cpu in words, programs start at 0 and traps return there
+pub const MEMORY: u32 = 0x4000;
+pub const STEP_LIMIT: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    // lui and auipc
+    Upper,
+    // jal and jalr
+    Jump,
+    Branch,
+    Load,
+    Store,
+    // register and immediate alu operations
+    Arithmetic,
+    // fence and fence.i
+    Fence,
+    CSR,
+    // ecall, ebreak, mret and wfi
+    System,
+}
+
+impl Class {
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::Upper => "upper",
+            Class::Jump => "jump",
+            Class::Branch => "branch",
+            Class::Load => "load",
+            Class::Store => "store",
+            Class::Arithmetic => "arithmetic",
+            Class::Fence => "fence",
+            Class::CSR => "csr",
+            Class::System => "system",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Class> {
+        RV32I.into_iter().find(|class| class.name() == name)
+    }
+}
+
+pub const RV32I: [Class; 9] = [
+    Class::Upper,
+    Class::Jump,
+    Class::Branch,
+    Class::Load,
+    Class::Store,
+    Class::Arithmetic,
+    Class::Fence,
+    Class::CSR,
+    Class::System,
+];
+
+// random valid instructions of the configured classes, the same seed gives the same stream
+pub struct Generator {
+    state: u64,
+    isa: Vec<Class>,
+}
+
+impl Generator {
+    pub fn new(seed: u64, isa: &[Class]) -> Self {
+        // xorshift needs a nonzero state
+        Generator { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1, isa: isa.to_vec() }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 32) as u32
+    }
+
+    pub fn program(&mut self, length: usize) -> Vec<u32> {
+        (0..length).map(|_| self.instruction()).collect()
+    }
+
+    pub fn instruction(&mut self) -> u32 {
+        if self.isa.is_empty() {
+            return 0;
+        }
+
+        let index = self.below(self.isa.len() as u32) as usize;
+        let class = self.isa[index];
+        let rd = self.register();
+        let rs1 = self.register();
+        let rs2 = self.register();
+        let value = self.next_u32();
+
+        match class {
+            Class::Upper => {
+                if value & 1 == 0 { lui(rd, value) } else { auipc(rd, value) }
+            }
+            Class::Jump => {
+                // short jumps mostly stay inside the program, odd multiples of 2 are misaligned
+                if value & 1 == 0 { jal(rd, self.offset() as u32 & 0x1FFFFF) } else { jalr(rd, rs1, (value >> 20) as u16) }
+            }
+            Class::Branch => {
+                let types = [BranchType::BEQ, BranchType::BNE, BranchType::BLT, BranchType::BGE, BranchType::BLTU, BranchType::BGEU];
+                let branch_type = types.into_iter().nth(self.below(6) as usize).unwrap();
+                branch(branch_type, rs1, rs2, self.offset() as u16 & 0x1FFF)
+            }
+            Class::Load => {
+                let types = [LoadType::LB, LoadType::LH, LoadType::LW, LoadType::LBU, LoadType::LHU];
+                let load_type = types.into_iter().nth(self.below(5) as usize).unwrap();
+                load(load_type, rd, rs1, value as u16)
+            }
+            Class::Store => {
+                let types = [StoreType::SB, StoreType::SH, StoreType::SW];
+                let store_type = types.into_iter().nth(self.below(3) as usize).unwrap();
+                store(store_type, rs1, rs2, value as u16)
+            }
+            Class::Arithmetic => self.arithmetic(rd, rs1, rs2, value),
+            Class::Fence => {
+                if value & 1 == 0 { (value & 0x0FF0_0000) | InstructionGroup::FENCE as u32 } else { 0x0000_100F }
+            }
+            Class::CSR => {
+                let types = [CSRType::CSRRW, CSRType::CSRRS, CSRType::CSRRC, CSRType::CSRRWI, CSRType::CSRRSI, CSRType::CSRRCI];
+                let csr_type = types.into_iter().nth(self.below(6) as usize).unwrap();
+                let (number, _, _) = csr::IMPLEMENTED[self.below(csr::IMPLEMENTED.len() as u32) as usize];
+                csr(csr_type, rd, rs1, number)
+            }
+            Class::System => [ECALL, EBREAK, MRET, WFI][self.below(4) as usize],
+        }
+    }
+
+    fn arithmetic(&mut self, rd: u8, rs1: u8, rs2: u8, value: u32) -> u32 {
+        let funct3 = self.below(8) as u8;
+
+        if value & 1 == 0 {
+            // shifts take a 5 bit amount, only right shifts have an arithmetic variant
+            let immediate = match funct3 {
+                0b001 => value >> 20 & 0x1F,
+                0b101 => (value >> 20 & 0x1F) | (value & 0x4000_0000) >> 20,
+                _ => value >> 20,
+            };
+            mathi(funct3, rd, rs1, immediate as u16)
+        } else {
+            // sub and sra are the only variants with funct7 0x20
+            let alternate = matches!(funct3, 0b000 | 0b101) && value & 2 != 0;
+            math((alternate as u8) << 5, funct3, rd, rs1, rs2)
+        }
+    }
+
+    fn register(&mut self) -> u8 {
+        self.below(32) as u8
+    }
+
+    // multiple of 2 between -64 and 62
+    fn offset(&mut self) -> i32 {
+        (self.below(64) as i32 - 32) * 2
+    }
+
+    fn below(&mut self, bound: u32) -> u32 {
+        self.next_u32() % bound
+    }
+}
+
+// broken invariant and the step it was detected after
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    Panic { step: u32, message: String },
+    Register0 { step: u32, value: u32 },
+    Misaligned { step: u32, pc: u32 },
+}
+
+impl Violation {
+    pub fn step(&self) -> u32 {
+        match *self {
+            Violation::Panic { step, .. } | Violation::Register0 { step, .. } | Violation::Misaligned { step, .. } => step,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Panic { step, message } => write!(f, "panic at step {}: {}", step, message),
+            Violation::Register0 { step, value } => write!(f, "x0 is 0x{:08x} after step {}", value, step),
+            Violation::Misaligned { step, pc } => write!(f, "pc 0x{:08x} is misaligned after step {}", pc, step),
+        }
+    }
+}
+
+// fuzzer input as little endian instruction words
+pub fn words(data: &[u8]) -> Vec<u32> {
+    data.chunks_exact(4).take(MEMORY as usize).map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect()
+}
+
+// runs the program from 0 and checks the invariants after every tick
+pub fn execute(program: &[u32], steps: u32) -> Result<(), Violation> {
+    let mut cpu = CPU::new(MEMORY);
+    for (index, instruction) in program.iter().take(MEMORY as usize).enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+
+    let mut step = 0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        while step < steps {
+            cpu.tick();
+
+            let value = cpu.registers().read(0);
+            if value != 0 {
+                return Err(Violation::Register0 { step, value });
+            }
+            let pc = *cpu.pc();
+            if pc & 0b11 != 0 {
+                return Err(Violation::Misaligned { step, pc });
+            }
+            step += 1;
+        }
+        Ok(())
+    }));
+
+    result.unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(Violation::Panic { step, message })
+    })
+}
+
+// removes chunks of instructions while the program keeps failing
+pub fn minimize<F: FnMut(&[u32]) -> bool>(program: &[u32], mut fails: F) -> Vec<u32> {
+    let mut program = program.to_vec();
+    let mut size = program.len().div_ceil(2);
+
+    while size > 0 {
+        let mut start = 0;
+        while start < program.len() {
+            let end = (start + size).min(program.len());
+            let candidate: Vec<u32> = program[..start].iter().chain(program[end..].iter()).copied().collect();
+            if fails(&candidate) {
+                program = candidate;
+            } else {
+                start = end;
+            }
+        }
+        size /= 2;
+    }
+
+    program
+}
+
+// minimizes a program that breaks an invariant, keeping the kind of violation
+pub fn minimize_violation(program: &[u32], steps: u32, violation: &Violation) -> (Vec<u32>, Violation) {
+    let kind = std::mem::discriminant(violation);
+    let program = minimize(program, |candidate| execute(candidate, steps).is_err_and(|found| std::mem::discriminant(&found) == kind));
+
+    // the original violation if minimization could not reproduce it
+    match execute(&program, steps) {
+        Err(found) => (program, found),
+        Ok(()) => (program, violation.clone()),
+    }
+}
+
+// test case for tests/fuzz_test.rs that replays the program
+pub fn reproducer(name: &str, program: &[u32], violation: &Violation) -> String {
+    let mut test = format!("// {}\n#[test]\nfn {}() {{\n", violation, name);
+    test.push_str(&format!("    let mut cpu = CPU::new(0x{:x});\n", MEMORY));
+    test.push_str("    let program = [\n");
+    for instruction in program.iter() {
+        test.push_str(&format!("        0x{:08x},\n", instruction));
+    }
+    test.push_str("    ];\n");
+    test.push_str("    for (index, instruction) in program.iter().enumerate() {\n");
+    test.push_str("        cpu.ram().write_word(index as u32 * 4, *instruction);\n");
+    test.push_str("    }\n\n");
+    test.push_str(&format!("    for _ in 0..{} {{\n", violation.step() + 1));
+    test.push_str("        cpu.tick();\n");
+    test.push_str("        assert_eq!(cpu.registers().read(0), 0);\n");
+    test.push_str("        assert_eq!(*cpu.pc() % 4, 0);\n");
+    test.push_str("    }\n}\n");
+    test
+}
@@ -0,0 +1,796 @@
+use std::collections::HashSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+use crate::CPU;
+use crate::bus::{AccessSize, Bus};
+use crate::csr::IMPLEMENTED;
+use crate::debug::{self, Debugger, StopReason};
+use crate::instructions::decode::{decode, Operation};
+use crate::reverse::{Recordable, Recorder};
+use crate::smp::System;
+
+pub const SIGINT: u8  = 2;
+pub const SIGTRAP: u8 = 5;
+
+// gdb numbers x0 to x31, then pc, then the csrs from 65
+pub const PC_REGISTER: u32 = 32;
+pub const CSR_REGISTER: u32 = 65;
+
+// instructions between checks for an interrupt from gdb while running
+const POLL_INTERVAL: u32 = 1024;
+
+// a machine the stub can inspect, every hart is a gdb thread
+pub trait Target {
+    fn harts(&self) -> u32;
+    fn read_register(&mut self, hart: u32, register: u8) -> u32;
+    fn write_register(&mut self, hart: u32, register: u8, value: u32);
+    fn pc(&mut self, hart: u32) -> u32;
+    fn set_pc(&mut self, hart: u32, value: u32);
+    fn read_csr(&mut self, hart: u32, csr: u16) -> u32;
+    fn write_csr(&mut self, hart: u32, csr: u16, value: u32);
+    fn read_memory(&mut self, address: u32) -> Option<u8>;
+    fn write_memory(&mut self, address: u32, value: u8) -> bool;
+    // executes one instruction and returns the hart that ran it, None when no hart can run
+    fn step(&mut self) -> Option<u32>;
+
+    // steps since recording started, None for targets without a history
+    fn position(&self) -> Option<u64> {
+        None
+    }
+
+    // moves to the state before the step at position, false for targets without a history
+    fn seek(&mut self, _position: u64) -> bool {
+        false
+    }
+
+    // moves back to the previous step that hit one of the breakpoints or watchpoints, None for targets without a history
+    fn reverse_continue(&mut self, _breakpoints: &[u32], _watchpoints: &[Watchpoint]) -> Option<StopReason> {
+        None
+    }
+}
+
+impl<B: Bus> Target for CPU<B> {
+    fn harts(&self) -> u32 {
+        1
+    }
+
+    fn read_register(&mut self, _hart: u32, register: u8) -> u32 {
+        self.registers().read(register)
+    }
+
+    fn write_register(&mut self, _hart: u32, register: u8, value: u32) {
+        self.registers().write(register, value);
+    }
+
+    fn pc(&mut self, _hart: u32) -> u32 {
+        *CPU::pc(self)
+    }
+
+    fn set_pc(&mut self, _hart: u32, value: u32) {
+        *CPU::pc(self) = value;
+    }
+
+    fn read_csr(&mut self, _hart: u32, csr: u16) -> u32 {
+        self.csrs().read(csr)
+    }
+
+    fn write_csr(&mut self, _hart: u32, csr: u16, value: u32) {
+        self.csrs().write(csr, value);
+    }
+
+    fn read_memory(&mut self, address: u32) -> Option<u8> {
+        self.bus().read(address, AccessSize::Byte).ok().map(|value| value as u8)
+    }
+
+    fn write_memory(&mut self, address: u32, value: u8) -> bool {
+        self.bus().write(address, AccessSize::Byte, value as u32).is_ok()
+    }
+
+    fn step(&mut self) -> Option<u32> {
+        self.tick();
+        Some(0)
+    }
+}
+
+impl Target for System {
+    fn harts(&self) -> u32 {
+        System::harts(self)
+    }
+
+    fn read_register(&mut self, hart: u32, register: u8) -> u32 {
+        self.hart(hart).registers().read(register)
+    }
+
+    fn write_register(&mut self, hart: u32, register: u8, value: u32) {
+        self.hart(hart).registers().write(register, value);
+    }
+
+    fn pc(&mut self, hart: u32) -> u32 {
+        *self.hart(hart).pc()
+    }
+
+    fn set_pc(&mut self, hart: u32, value: u32) {
+        *self.hart(hart).pc() = value;
+    }
+
+    fn read_csr(&mut self, hart: u32, csr: u16) -> u32 {
+        self.hart(hart).csrs().read(csr)
+    }
+
+    fn write_csr(&mut self, hart: u32, csr: u16, value: u32) {
+        self.hart(hart).csrs().write(csr, value);
+    }
+
+    fn read_memory(&mut self, address: u32) -> Option<u8> {
+        self.bus().read(address, AccessSize::Byte).ok().map(|value| value as u8)
+    }
+
+    fn write_memory(&mut self, address: u32, value: u8) -> bool {
+        self.bus().write(address, AccessSize::Byte, value as u32).is_ok()
+    }
+
+    fn step(&mut self) -> Option<u32> {
+        System::step(self)
+    }
+}
+
+// changing state discards the history after the current position
+impl<M: Recordable> Target for Recorder<M> {
+    fn harts(&self) -> u32 {
+        Recorder::harts(self)
+    }
+
+    fn read_register(&mut self, hart: u32, register: u8) -> u32 {
+        self.machine().hart(hart).registers().read(register)
+    }
+
+    fn write_register(&mut self, hart: u32, register: u8, value: u32) {
+        self.edit().hart(hart).registers().write(register, value);
+    }
+
+    fn pc(&mut self, hart: u32) -> u32 {
+        *self.machine().hart(hart).pc()
+    }
+
+    fn set_pc(&mut self, hart: u32, value: u32) {
+        *self.edit().hart(hart).pc() = value;
+    }
+
+    fn read_csr(&mut self, hart: u32, csr: u16) -> u32 {
+        self.machine().hart(hart).csrs().read(csr)
+    }
+
+    fn write_csr(&mut self, hart: u32, csr: u16, value: u32) {
+        self.edit().hart(hart).csrs().write(csr, value);
+    }
+
+    fn read_memory(&mut self, address: u32) -> Option<u8> {
+        self.machine().hart(0).bus().read(address, AccessSize::Byte).ok().map(|value| value as u8)
+    }
+
+    fn write_memory(&mut self, address: u32, value: u8) -> bool {
+        self.edit().hart(0).bus().write(address, AccessSize::Byte, value as u32).is_ok()
+    }
+
+    fn step(&mut self) -> Option<u32> {
+        Recorder::step(self)
+    }
+
+    fn position(&self) -> Option<u64> {
+        Some(Recorder::position(self))
+    }
+
+    fn seek(&mut self, position: u64) -> bool {
+        Recorder::seek(self, position);
+        true
+    }
+
+    // the stub's breakpoints and watchpoints replace the debuggers of the harts while the recorder searches
+    fn reverse_continue(&mut self, breakpoints: &[u32], watchpoints: &[Watchpoint]) -> Option<StopReason> {
+        let debugger = || {
+            let mut debugger = Debugger::new();
+            breakpoints.iter().for_each(|&pc| debugger.add_breakpoint(pc));
+            for watchpoint in watchpoints {
+                let range = watchpoint.address..watchpoint.address.saturating_add(watchpoint.length);
+                if watchpoint.kind != WatchKind::Write {
+                    debugger.add_watchpoint(range.clone(), debug::WatchKind::Read);
+                }
+                if watchpoint.kind != WatchKind::Read {
+                    debugger.add_watchpoint(range, debug::WatchKind::Write);
+                }
+            }
+            debugger
+        };
+
+        let harts = Recorder::harts(self);
+        let saved: Vec<Debugger> = (0..harts).map(|hart| mem::replace(self.machine().hart(hart).debugger(), debugger())).collect();
+        let reason = Recorder::reverse_continue(self);
+        for (hart, saved) in saved.into_iter().enumerate() {
+            *self.machine().hart(hart as u32).debugger() = saved;
+        }
+        Some(reason)
+    }
+}
+
+// byte stream to gdb that can be polled for an interrupt while the target runs
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub length: u32,
+    pub kind: WatchKind,
+}
+
+enum Reply {
+    Packet(String),
+    Resume { step: bool, reverse: bool },
+    // kill or detach, the session ends after the reply
+    Close(Option<String>),
+}
+
+// remote serial protocol server for one debugger session at a time
+pub struct GDBStub<T: Target> {
+    target: T,
+    breakpoints: HashSet<u32>,
+    hardware_breakpoints: HashSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    // thread for register access and thread to step, gdb thread ids are hart + 1
+    general_hart: u32,
+    step_hart: Option<u32>,
+    no_ack: bool,
+}
+
+impl<T: Target> GDBStub<T> {
+    pub fn new(target: T) -> Self {
+        GDBStub {
+            target,
+            breakpoints: HashSet::new(),
+            hardware_breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+            general_hart: 0,
+            step_hart: None,
+            no_ack: false,
+        }
+    }
+
+    pub fn target(&mut self) -> &mut T {
+        &mut self.target
+    }
+
+    pub fn into_target(self) -> T {
+        self.target
+    }
+
+    // accepts a single connection on a tcp address such as localhost:1234
+    pub fn serve_tcp<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<()> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(stream)
+    }
+
+    #[cfg(unix)]
+    pub fn serve_unix<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        self.serve(stream)
+    }
+
+    // handles packets until gdb kills or detaches or the connection closes
+    pub fn serve<C: Connection>(&mut self, mut connection: C) -> io::Result<()> {
+        self.no_ack = false;
+
+        loop {
+            let Some(packet) = self.receive(&mut connection)? else {
+                return Ok(());
+            };
+
+            match self.handle(&packet) {
+                Reply::Packet(reply) => self.send(&mut connection, &reply)?,
+                Reply::Resume { step, reverse } => {
+                    connection.set_nonblocking(true)?;
+                    let mut interrupted = || {
+                        let mut byte = [0];
+                        // a closed connection also stops the target
+                        match connection.read(&mut byte) {
+                            Ok(0) => true,
+                            Ok(_) => byte[0] == 0x03,
+                            Err(_) => false,
+                        }
+                    };
+                    let reply = if reverse { self.reverse(step) } else { self.resume(step, &mut interrupted) };
+                    connection.set_nonblocking(false)?;
+                    self.send(&mut connection, &reply)?;
+                }
+                Reply::Close(reply) => {
+                    if let Some(reply) = reply {
+                        self.send(&mut connection, &reply)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // answers a packet without its framing, resuming commands run until the target stops
+    pub fn packet(&mut self, packet: &str) -> Option<String> {
+        match self.handle(packet) {
+            Reply::Packet(reply) => Some(reply),
+            Reply::Resume { step, reverse: false } => Some(self.resume(step, || false)),
+            Reply::Resume { step, reverse: true } => Some(self.reverse(step)),
+            Reply::Close(reply) => reply,
+        }
+    }
+
+    // payload of the next packet, interrupts outside of packets are ignored while stopped
+    fn receive<C: Connection>(&mut self, connection: &mut C) -> io::Result<Option<String>> {
+        loop {
+            let Some(byte) = Self::read_byte(connection)? else {
+                return Ok(None);
+            };
+            if byte != b'$' {
+                continue;
+            }
+
+            let mut payload = Vec::new();
+            loop {
+                match Self::read_byte(connection)? {
+                    Some(b'#') => break,
+                    Some(byte) => payload.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            connection.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+            let valid = expected == Some(Self::checksum(&payload));
+            if !self.no_ack {
+                connection.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&payload).into_owned()));
+            }
+        }
+    }
+
+    fn send<C: Connection>(&mut self, connection: &mut C, payload: &str) -> io::Result<()> {
+        loop {
+            let packet = format!("${}#{:02x}", payload, Self::checksum(payload.as_bytes()));
+            connection.write_all(packet.as_bytes())?;
+            connection.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            // retransmit until gdb acknowledges
+            match Self::read_byte(connection)? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn read_byte<C: Connection>(connection: &mut C) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match connection.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(error) if error.kind() == ErrorKind::ConnectionReset => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => self.stop_reply(SIGTRAP, self.general_hart, ""),
+            "g" => (0..=PC_REGISTER).map(|register| hex_word(self.read_register(register).unwrap_or(0))).collect(),
+            "G" => self.write_registers(arguments),
+            "p" => match u32::from_str_radix(arguments, 16).ok().and_then(|register| self.read_register(register)) {
+                Some(value) => hex_word(value),
+                None => "E01".to_string(),
+            },
+            "P" => self.write_register(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "c" | "s" => {
+                if let Some(address) = parse_hex(arguments) {
+                    let hart = self.step_hart.unwrap_or(self.general_hart);
+                    self.target.set_pc(hart, address);
+                }
+                return Reply::Resume { step: command == "s", reverse: false };
+            }
+            "b" if arguments == "s" || arguments == "c" => return Reply::Resume { step: arguments == "s", reverse: true },
+            "H" => self.set_thread(arguments),
+            "T" => match parse_thread(arguments) {
+                Some(hart) if hart < self.target.harts() => "OK".to_string(),
+                _ => "E01".to_string(),
+            },
+            "Z" | "z" => self.breakpoint(command == "Z", arguments),
+            "q" => self.query(arguments),
+            "Q" if arguments == "StartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "k" => return Reply::Close(None),
+            "D" => return Reply::Close(Some("OK".to_string())),
+            _ => String::new(),
+        };
+
+        Reply::Packet(reply)
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        let harts = self.target.harts();
+
+        if query.starts_with("Supported") {
+            let reverse = if self.target.position().is_some() { ";ReverseStep+;ReverseContinue+" } else { "" };
+            format!("PacketSize=4000;QStartNoAckMode+;qXfer:features:read+;swbreak+;hwbreak+{}", reverse)
+        } else if query == "Attached" {
+            "1".to_string()
+        } else if query == "C" {
+            format!("QC{:x}", self.general_hart + 1)
+        } else if query == "fThreadInfo" {
+            let threads: Vec<String> = (1..=harts).map(|thread| format!("{:x}", thread)).collect();
+            format!("m{}", threads.join(","))
+        } else if query == "sThreadInfo" {
+            "l".to_string()
+        } else if let Some(thread) = query.strip_prefix("ThreadExtraInfo,") {
+            match parse_thread(thread) {
+                Some(hart) if hart < harts => hex_bytes(format!("hart {}", hart).as_bytes()),
+                _ => "E01".to_string(),
+            }
+        } else if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let description = self.target_description();
+            let Some((offset, length)) = annex.split_once(',').and_then(|(offset, length)| Some((parse_hex(offset)?, parse_hex(length)?))) else {
+                return "E01".to_string();
+            };
+            let start = (offset as usize).min(description.len());
+            let end = (start + length as usize).min(description.len());
+            let prefix = if end == description.len() { "l" } else { "m" };
+            format!("{}{}", prefix, &description[start..end])
+        } else {
+            String::new()
+        }
+    }
+
+    fn target_description(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">");
+        xml.push_str("<architecture>riscv:rv32</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">");
+        for register in 0..32 {
+            let kind = match register {
+                1 => " type=\"code_ptr\"",
+                2 | 8 => " type=\"data_ptr\"",
+                _ => " type=\"int\"",
+            };
+            xml.push_str(&format!("<reg name=\"x{}\" bitsize=\"32\"{}/>", register, kind));
+        }
+        xml.push_str("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/></feature><feature name=\"org.gnu.gdb.riscv.csr\">");
+        for (csr, name, _) in IMPLEMENTED {
+            xml.push_str(&format!("<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\"/>", name, CSR_REGISTER + csr as u32));
+        }
+        xml.push_str("</feature></target>");
+        xml
+    }
+
+    fn read_register(&mut self, register: u32) -> Option<u32> {
+        let hart = self.general_hart;
+        match register {
+            0..=31 => Some(self.target.read_register(hart, register as u8)),
+            PC_REGISTER => Some(self.target.pc(hart)),
+            _ => {
+                let csr = u16::try_from(register.checked_sub(CSR_REGISTER)?).ok().filter(|&csr| csr < 0x1000)?;
+                Some(self.target.read_csr(hart, csr))
+            }
+        }
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        // the packet may hold bytes that are not ascii, so words are taken by checked slices
+        let values: Option<Vec<u32>> = (0..data.len() / 8).map(|index| data.get(index * 8..index * 8 + 8).and_then(parse_word)).collect();
+        let Some(values) = values.filter(|values| values.len() > PC_REGISTER as usize) else {
+            return "E01".to_string();
+        };
+
+        let hart = self.general_hart;
+        for (register, &value) in values.iter().take(32).enumerate() {
+            self.target.write_register(hart, register as u8, value);
+        }
+        self.target.set_pc(hart, values[PC_REGISTER as usize]);
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, arguments: &str) -> String {
+        let Some((register, value)) = arguments.split_once('=').and_then(|(register, value)| Some((parse_hex(register)?, parse_word(value)?))) else {
+            return "E01".to_string();
+        };
+
+        let hart = self.general_hart;
+        match register {
+            0..=31 => self.target.write_register(hart, register as u8, value),
+            PC_REGISTER => self.target.set_pc(hart, value),
+            _ => match register.checked_sub(CSR_REGISTER).filter(|&csr| csr < 0x1000) {
+                Some(csr) => self.target.write_csr(hart, csr as u16, value),
+                None => return "E01".to_string(),
+            },
+        }
+        "OK".to_string()
+    }
+
+    fn read_memory(&mut self, arguments: &str) -> String {
+        let Some((address, length)) = parse_range(arguments) else {
+            return "E01".to_string();
+        };
+
+        let mut data = Vec::new();
+        for offset in 0..length {
+            match self.target.read_memory(address.wrapping_add(offset)) {
+                Some(byte) => data.push(byte),
+                // partial reads are allowed as long as something was read
+                None if data.is_empty() => return "E14".to_string(),
+                None => break,
+            }
+        }
+        hex_bytes(&data)
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let Some((range, data)) = arguments.split_once(':') else {
+            return "E01".to_string();
+        };
+        let Some((address, length)) = parse_range(range) else {
+            return "E01".to_string();
+        };
+        let bytes: Option<Vec<u8>> = (0..data.len() / 2).map(|index| u8::from_str_radix(data.get(index * 2..index * 2 + 2)?, 16).ok()).collect();
+        let Some(bytes) = bytes.filter(|bytes| bytes.len() == length as usize) else {
+            return "E01".to_string();
+        };
+
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            if !self.target.write_memory(address.wrapping_add(offset as u32), byte) {
+                return "E14".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Hg selects the thread for register access and Hc the thread to step, 0 and -1 mean any thread
+    fn set_thread(&mut self, arguments: &str) -> String {
+        let Some((operation, thread)) = arguments.split_at_checked(1) else {
+            return "E01".to_string();
+        };
+        let hart = match thread {
+            "0" | "-1" => None,
+            thread => match parse_thread(thread) {
+                Some(hart) if hart < self.target.harts() => Some(hart),
+                _ => return "E01".to_string(),
+            },
+        };
+
+        match operation {
+            "g" => self.general_hart = hart.unwrap_or(0),
+            "c" => self.step_hart = hart,
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    // type 0 and 1 are breakpoints, 2 to 4 are write, read and access watchpoints
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let (Some(kind), Some(address), Some(length)) = (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) else {
+            return "E01".to_string();
+        };
+
+        let watch = match kind {
+            "0" | "1" => {
+                let breakpoints = if kind == "0" { &mut self.breakpoints } else { &mut self.hardware_breakpoints };
+                if insert {
+                    breakpoints.insert(address);
+                } else {
+                    breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        let watchpoint = Watchpoint { address, length: length.max(1), kind: watch };
+        if insert {
+            self.watchpoints.push(watchpoint);
+        } else {
+            self.watchpoints.retain(|existing| *existing != watchpoint);
+        }
+        "OK".to_string()
+    }
+
+    // runs until a breakpoint, watchpoint or interrupt, a step finishes after one instruction of the stepped hart
+    fn resume<F: FnMut() -> bool>(&mut self, step: bool, mut interrupted: F) -> String {
+        let harts = self.target.harts();
+        let mut first = true;
+        let mut count = 0u32;
+
+        loop {
+            // breakpoints are checked before the instruction, except where execution resumes
+            if !first {
+                if let Some((hart, reason)) = self.breakpoint_hit() {
+                    return self.stop_reply(SIGTRAP, hart, reason);
+                }
+            }
+            first = false;
+
+            count = count.wrapping_add(1);
+            if count.is_multiple_of(POLL_INTERVAL) && interrupted() {
+                return self.stop_reply(SIGINT, self.general_hart, "");
+            }
+
+            let accesses: Vec<Option<(u32, u32, bool)>> = (0..harts).map(|hart| self.memory_access(hart)).collect();
+            let Some(hart) = self.target.step() else {
+                return self.stop_reply(SIGTRAP, self.general_hart, "");
+            };
+
+            if let Some(reason) = self.watchpoint_hit(accesses[hart as usize]) {
+                return self.stop_reply(SIGTRAP, hart, &reason);
+            }
+
+            if step && self.step_hart.is_none_or(|stepped| stepped == hart) {
+                return self.stop_reply(SIGTRAP, hart, "");
+            }
+        }
+    }
+
+    // runs backwards to the previous breakpoint or watchpoint through the target's history,
+    // watchpoints stop before the accessing instruction
+    fn reverse(&mut self, step: bool) -> String {
+        let Some(current) = self.target.position() else {
+            return "E01".to_string();
+        };
+        let hart = self.step_hart.unwrap_or(self.general_hart);
+        if current == 0 {
+            return self.stop_reply(SIGTRAP, hart, "replaylog:begin;");
+        }
+        if step {
+            self.target.seek(current - 1);
+            return self.stop_reply(SIGTRAP, hart, "");
+        }
+
+        let breakpoints: Vec<u32> = self.breakpoints.union(&self.hardware_breakpoints).copied().collect();
+        let reason = self.target.reverse_continue(&breakpoints, &self.watchpoints);
+
+        // the hart and reply come from the state the target stopped in
+        let stopped = match reason {
+            Some(StopReason::Breakpoint(_)) => self.breakpoint_hit().map(|(hart, reason)| (hart, reason.to_string())),
+            Some(StopReason::Watchpoint { .. }) => (0..self.target.harts()).find_map(|hart| {
+                let access = self.memory_access(hart);
+                self.watchpoint_hit(access).map(|reason| (hart, reason))
+            }),
+            _ => None,
+        };
+        match stopped {
+            Some((hart, reason)) => self.stop_reply(SIGTRAP, hart, &reason),
+            None => self.stop_reply(SIGTRAP, hart, "replaylog:begin;"),
+        }
+    }
+
+    // hart stopped at a breakpoint and the stop reason for it
+    fn breakpoint_hit(&mut self) -> Option<(u32, &'static str)> {
+        for hart in 0..self.target.harts() {
+            let pc = self.target.pc(hart);
+            if self.breakpoints.contains(&pc) {
+                return Some((hart, "swbreak:;"));
+            }
+            if self.hardware_breakpoints.contains(&pc) {
+                return Some((hart, "hwbreak:;"));
+            }
+        }
+        None
+    }
+
+    // stop reason for a watchpoint covering the access
+    fn watchpoint_hit(&self, access: Option<(u32, u32, bool)>) -> Option<String> {
+        let (address, size, write) = access?;
+        let watchpoint = self.watchpoints.iter().find(|watchpoint| {
+            let kind = match watchpoint.kind {
+                WatchKind::Write => write,
+                WatchKind::Read => !write,
+                WatchKind::Access => true,
+            };
+            kind && address < watchpoint.address.saturating_add(watchpoint.length) && watchpoint.address < address.saturating_add(size)
+        })?;
+
+        let name = match watchpoint.kind {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        };
+        Some(format!("{}:{:x};", name, watchpoint.address))
+    }
+
+    // address, size and direction of the load or store the hart is about to execute
+    fn memory_access(&mut self, hart: u32) -> Option<(u32, u32, bool)> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+
+        let pc = self.target.pc(hart);
+        let bytes: Option<Vec<u8>> = (0..4).map(|offset| self.target.read_memory(pc.wrapping_add(offset))).collect();
+        let instruction = u32::from_le_bytes(bytes?.try_into().ok()?);
+
+        let (rs1, offset, size, store) = match decode(instruction) {
+            Operation::Load { size, rs1, offset, .. } => (rs1, offset, size, false),
+            Operation::Store { size, rs1, offset, .. } => (rs1, offset, size, true),
+            Operation::LoadReserved { rs1, .. } => (rs1, 0, AccessSize::Word, false),
+            Operation::StoreConditional { rs1, .. } | Operation::Atomic { rs1, .. } => (rs1, 0, AccessSize::Word, true),
+            _ => return None,
+        };
+        let base = self.target.read_register(hart, rs1);
+        Some((base.wrapping_add(offset), size.bytes(), store))
+    }
+
+    fn stop_reply(&self, signal: u8, hart: u32, reason: &str) -> String {
+        format!("T{:02x}thread:{:x};{}", signal, hart + 1, reason)
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_thread(text: &str) -> Option<u32> {
+    parse_hex(text)?.checked_sub(1)
+}
+
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+// registers are sent as little endian bytes
+fn hex_word(value: u32) -> String {
+    hex_bytes(&value.to_le_bytes())
+}
+
+fn parse_word(text: &str) -> Option<u32> {
+    let value = u32::from_str_radix(text.get(0..8)?, 16).ok()?;
+    Some(value.swap_bytes())
+}
+
+fn hex_bytes(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use crate::CPU;
+use crate::bus::{AccessSize, Bus, BusError};
+use crate::elf::ELF;
+use crate::linux::{EBADF, ENOSYS, IO_CHUNK, SYS_EXIT, SYS_READ, SYS_WRITE};
+
+pub const DEVICE_SYSCALL: u8 = 0;
+pub const DEVICE_CONSOLE: u8 = 1;
+
+pub const CONSOLE_GETCHAR: u8 = 0;
+pub const CONSOLE_PUTCHAR: u8 = 1;
+
+const PAYLOAD_MASK: u64 = (1 << 48) - 1;
+
+// device in the top byte, command in the next and a 48 bit payload
+pub fn encode(device: u8, command: u8, payload: u64) -> u64 {
+    (device as u64) << 56 | (command as u64) << 48 | payload & PAYLOAD_MASK
+}
+
+pub fn decode(value: u64) -> (u8, u8, u64) {
+    ((value >> 56) as u8, (value >> 48) as u8, value & PAYLOAD_MASK)
+}
+
+// host target interface of spike, programs write commands to tohost and receive responses in fromhost
+pub struct HTIF {
+    tohost: u32,
+    fromhost: Option<u32>,
+    stdin: Option<VecDeque<u8>>,
+    stdout: Option<Vec<u8>>,
+    // rv32 writes tohost with two stores, low word first
+    pending: bool,
+    exit: Option<u32>,
+}
+
+impl HTIF {
+    pub fn new(tohost: u32, fromhost: Option<u32>) -> Self {
+        HTIF { tohost, fromhost, stdin: None, stdout: None, pending: false, exit: None }
+    }
+
+    // addresses of the tohost and fromhost symbols, None without tohost
+    pub fn from_elf(elf: &ELF) -> Option<Self> {
+        Some(Self::new(elf.symbol("tohost")?, elf.symbol("fromhost")))
+    }
+
+    pub fn tohost(&self) -> u32 {
+        self.tohost
+    }
+
+    pub fn fromhost(&self) -> Option<u32> {
+        self.fromhost
+    }
+
+    pub fn set_stdin(&mut self, data: &[u8]) {
+        self.stdin = Some(data.iter().copied().collect());
+    }
+
+    pub fn capture_output(&mut self) {
+        self.stdout = Some(Vec::new());
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.stdout.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // exit code, riscv-tests report 0 on success and the failing test number otherwise
+    pub fn exit(&self) -> Option<u32> {
+        self.exit
+    }
+
+    pub fn passed(&self) -> bool {
+        self.exit == Some(0)
+    }
+
+    // executes one instruction and services a command written to tohost
+    // fails when tohost or a syscall buffer is not mapped
+    pub fn step<B: Bus>(&mut self, cpu: &mut CPU<B>) -> Result<Option<u32>, BusError> {
+        if self.exit.is_none() {
+            cpu.tick();
+            self.poll(cpu)?;
+        }
+
+        Ok(self.exit)
+    }
+
+    pub fn run<B: Bus>(&mut self, cpu: &mut CPU<B>, steps: u64) -> Result<Option<u32>, BusError> {
+        for _ in 0..steps {
+            if let Some(code) = self.step(cpu)? {
+                return Ok(Some(code));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn poll<B: Bus>(&mut self, cpu: &mut CPU<B>) -> Result<(), BusError> {
+        let value = Self::read_double(cpu, self.tohost)?;
+        if value == 0 {
+            self.pending = false;
+            return Ok(());
+        }
+        if value >> 32 == 0 && !self.pending {
+            self.pending = true;
+            return Ok(());
+        }
+        self.pending = false;
+
+        Self::write_double(cpu, self.tohost, 0)?;
+        let response = match decode(value) {
+            (DEVICE_SYSCALL, 0, payload) if payload & 1 == 1 => {
+                self.exit = Some((payload >> 1) as u32);
+                None
+            }
+            (DEVICE_SYSCALL, 0, payload) => {
+                self.syscall(cpu, payload as u32)?;
+                Some(encode(DEVICE_SYSCALL, 0, 1))
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR, payload) => {
+                self.output(&[payload as u8]);
+                Some(encode(DEVICE_CONSOLE, CONSOLE_PUTCHAR, 0))
+            }
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR, _) => {
+                let byte = self.input().map_or(u64::MAX, |byte| byte as u64);
+                Some(encode(DEVICE_CONSOLE, CONSOLE_GETCHAR, byte))
+            }
+            _ => None,
+        };
+
+        match (response, self.fromhost) {
+            (Some(response), Some(fromhost)) => Self::write_double(cpu, fromhost, response),
+            _ => Ok(()),
+        }
+    }
+
+    // the payload points to eight double words, the number and arguments, the result replaces the number
+    fn syscall<B: Bus>(&mut self, cpu: &mut CPU<B>, address: u32) -> Result<(), BusError> {
+        let mut arguments = [0; 4];
+        for (index, argument) in arguments.iter_mut().enumerate() {
+            *argument = Self::read_double(cpu, address.wrapping_add(index as u32 * 8))? as u32;
+        }
+
+        let result = match arguments[0] {
+            // staged IO_CHUNK bytes at a time
+            SYS_WRITE if arguments[1] == 1 || arguments[1] == 2 => {
+                for start in (0..arguments[3]).step_by(IO_CHUNK as usize) {
+                    let mut data = Vec::new();
+                    for offset in start..arguments[3].min(start.saturating_add(IO_CHUNK)) {
+                        data.push(cpu.bus().read(arguments[2].wrapping_add(offset), AccessSize::Byte)? as u8);
+                    }
+                    self.output(&data);
+                }
+                arguments[3] as i64
+            }
+            SYS_READ if arguments[1] == 0 => {
+                let mut read = 0;
+                while read < arguments[3] {
+                    let Some(byte) = self.input() else {
+                        break;
+                    };
+                    cpu.bus().write(arguments[2].wrapping_add(read), AccessSize::Byte, byte as u32)?;
+                    read += 1;
+                }
+                read as i64
+            }
+            SYS_WRITE | SYS_READ => -EBADF as i64,
+            SYS_EXIT => {
+                self.exit = Some(arguments[1]);
+                0
+            }
+            _ => -ENOSYS as i64,
+        };
+
+        Self::write_double(cpu, address, result as u64)
+    }
+
+    fn output(&mut self, data: &[u8]) {
+        match &mut self.stdout {
+            Some(output) => output.extend_from_slice(data),
+            None => {
+                let _ = io::stdout().write_all(data).and_then(|_| io::stdout().flush());
+            }
+        }
+    }
+
+    fn input(&mut self) -> Option<u8> {
+        self.stdin.as_mut()?.pop_front()
+    }
+
+    fn read_double<B: Bus>(cpu: &mut CPU<B>, address: u32) -> Result<u64, BusError> {
+        let low = cpu.bus().read(address, AccessSize::Word)?;
+        let high = cpu.bus().read(address.wrapping_add(4), AccessSize::Word)?;
+        Ok((high as u64) << 32 | low as u64)
+    }
+
+    fn write_double<B: Bus>(cpu: &mut CPU<B>, address: u32, value: u64) -> Result<(), BusError> {
+        cpu.bus().write(address, AccessSize::Word, value as u32)?;
+        cpu.bus().write(address.wrapping_add(4), AccessSize::Word, (value >> 32) as u32)
+    }
+}
@@ -0,0 +1,35 @@
+pub enum CSRType {
+    CSRRW  = 0b001000001110011,
+    CSRRS  = 0b010000001110011,
+    CSRRC  = 0b011000001110011,
+    CSRRWI = 0b101000001110011,
+    CSRRSI = 0b110000001110011,
+    CSRRCI = 0b111000001110011,
+    MASK   = 0b111000001111111,
+}
+
+impl CSRType {
+    pub fn check(instruction: u32, csr_type: CSRType) -> bool {
+        ((instruction & CSRType::MASK as u32) ^ csr_type as u32) == 0
+    }
+}
+
+pub const ECALL: u32  = 0x00000073;
+pub const EBREAK: u32 = 0x00100073;
+pub const SRET: u32   = 0x10200073;
+pub const MRET: u32   = 0x30200073;
+pub const WFI: u32    = 0x10500073;
+
+// flushes the translations of the address in rs1 and the address space in rs2, x0 selects all
+pub fn sfence_vma(rs1: u8, rs2: u8) -> u32 {
+    0b0001001 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | 0b1110011
+}
+
+// rs1 holds the 5 bit immediate for the immediate variants
+pub fn csr(csr_type: CSRType, rd: u8, rs1: u8, csr: u16) -> u32 {
+    let shifted_rd  = (rd  as u32) <<  7;
+    let shifted_rs1 = (rs1 as u32) << 15;
+    let shifted_csr = ((csr as u32) & 0b111111111111) << 20;
+
+    csr_type as u32 | shifted_rd | shifted_rs1 | shifted_csr
+}
@@ -0,0 +1,330 @@
+use crate::bus::AccessSize;
+use super::InstructionGroup;
+use super::csr::{EBREAK, ECALL, MRET, SRET, WFI};
+
+// field decoders shared by the cpu and the encoder tests, immediates keep their bit positions
+
+pub fn extract_rd_register(instruction: u32) -> u8 {
+    ((instruction >> 7) & 0b11111) as u8
+}
+
+pub fn extract_rs1_register(instruction: u32) -> u8 {
+    ((instruction >> 15) & 0b11111) as u8
+}
+
+pub fn extract_rs2_register(instruction: u32) -> u8 {
+    ((instruction >> 20) & 0b11111) as u8
+}
+
+pub fn extract_immediate_31_12(instruction: u32) -> u32 {
+    instruction & 0xFFFFF000
+}
+
+#[allow(clippy::identity_op)]
+pub fn extract_immediate_20_1(instruction: u32) -> u32 {
+    let imm_10_1  = (instruction >> 20) & 0b000000000011111111110;
+    let imm_11    = (instruction >>  9) & 0b000000000100000000000;
+    let imm_19_12 = (instruction >>  0) & 0b011111111000000000000;
+    let imm_20    = (instruction >> 11) & 0b100000000000000000000;
+
+    imm_10_1 | imm_11 | imm_19_12 | imm_20
+}
+
+pub fn extract_immediate_12_1(instruction: u32) -> u16 {
+    let imm_4_1 =  ((instruction >>  7) as u16) & 0b0000000011110;
+    let imm_10_5 = ((instruction >> 20) as u16) & 0b0011111100000;
+    let imm_11 =   ((instruction <<  4) as u16) & 0b0100000000000;
+    let imm_12 =   ((instruction >> 19) as u16) & 0b1000000000000;
+
+    imm_4_1 | imm_10_5 | imm_11 | imm_12
+}
+
+pub fn extract_immediate_11_0(instruction: u32) -> u16 {
+    ((instruction >> 20) as u16) & 0b111111111111
+}
+
+pub fn extract_store_immediate_11_0(instruction: u32) -> u16 {
+    let imm_4_0  = ((instruction >>  7) as u16) & 0b000000011111;
+    let imm_11_5 = ((instruction >> 20) as u16) & 0b111111100000;
+
+    imm_4_0 | imm_11_5
+}
+
+pub fn extract_funct3(instruction: u32) -> u8 {
+    ((instruction >> 12) & 0b111) as u8
+}
+
+pub fn extract_funct7(instruction: u32) -> u8 {
+    ((instruction >> 25) & 0b1111111) as u8
+}
+
+pub fn extract_csr(instruction: u32) -> u16 {
+    ((instruction >> 20) as u16) & 0b111111111111
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    LessThan,
+    GreaterEqual,
+    LessThanUnsigned,
+    GreaterEqualUnsigned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CSROperation {
+    ReadWrite,
+    ReadSet,
+    ReadClear,
+}
+
+// register and immediate alu operations, the m extension only exists with registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathOperation {
+    Add,
+    Subtract,
+    ShiftLeft,
+    SetLessThan,
+    SetLessThanUnsigned,
+    Xor,
+    ShiftRightLogical,
+    ShiftRightArithmetic,
+    Or,
+    And,
+    Multiply,
+    MultiplyHigh,
+    MultiplyHighSignedUnsigned,
+    MultiplyHighUnsigned,
+    Divide,
+    DivideUnsigned,
+    Remainder,
+    RemainderUnsigned,
+}
+
+impl MathOperation {
+    // shifts use the low five bits, division by zero and overflow give the results the spec defines instead of trapping
+    pub fn apply(self, a: u32, b: u32) -> u32 {
+        match self {
+            MathOperation::Add => a.wrapping_add(b),
+            MathOperation::Subtract => a.wrapping_sub(b),
+            MathOperation::ShiftLeft => a << (b & 0b11111),
+            MathOperation::SetLessThan => ((a as i32) < (b as i32)) as u32,
+            MathOperation::SetLessThanUnsigned => (a < b) as u32,
+            MathOperation::Xor => a ^ b,
+            MathOperation::ShiftRightLogical => a >> (b & 0b11111),
+            MathOperation::ShiftRightArithmetic => ((a as i32) >> (b & 0b11111)) as u32,
+            MathOperation::Or => a | b,
+            MathOperation::And => a & b,
+            MathOperation::Multiply => a.wrapping_mul(b),
+            MathOperation::MultiplyHigh => ((a as i32 as i64 * b as i32 as i64) >> 32) as u32,
+            MathOperation::MultiplyHighSignedUnsigned => ((a as i32 as i64 * b as i64) >> 32) as u32,
+            MathOperation::MultiplyHighUnsigned => ((a as u64 * b as u64) >> 32) as u32,
+            MathOperation::Divide => match b {
+                0 => u32::MAX,
+                _ => (a as i32).wrapping_div(b as i32) as u32,
+            },
+            MathOperation::DivideUnsigned => a.checked_div(b).unwrap_or(u32::MAX),
+            MathOperation::Remainder => match b {
+                0 => a,
+                _ => (a as i32).wrapping_rem(b as i32) as u32,
+            },
+            MathOperation::RemainderUnsigned => a.checked_rem(b).unwrap_or(a),
+        }
+    }
+}
+
+// read modify write operations of the a extension, the old value goes to rd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicOperation {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    MinUnsigned,
+    MaxUnsigned,
+}
+
+impl AtomicOperation {
+    // value written back to memory
+    pub fn apply(self, memory: u32, register: u32) -> u32 {
+        match self {
+            AtomicOperation::Swap => register,
+            AtomicOperation::Add => memory.wrapping_add(register),
+            AtomicOperation::Xor => memory ^ register,
+            AtomicOperation::And => memory & register,
+            AtomicOperation::Or => memory | register,
+            AtomicOperation::Min => (memory as i32).min(register as i32) as u32,
+            AtomicOperation::Max => (memory as i32).max(register as i32) as u32,
+            AtomicOperation::MinUnsigned => memory.min(register),
+            AtomicOperation::MaxUnsigned => memory.max(register),
+        }
+    }
+}
+
+// an instruction with its fields extracted, offsets are sign extended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    LUI { rd: u8, immediate: u32 },
+    AUIPC { rd: u8, immediate: u32 },
+    JAL { rd: u8, offset: u32 },
+    JALR { rd: u8, rs1: u8, offset: u32 },
+    Branch { condition: Condition, rs1: u8, rs2: u8, offset: u32 },
+    Load { size: AccessSize, signed: bool, rd: u8, rs1: u8, offset: u32 },
+    Store { size: AccessSize, rs1: u8, rs2: u8, offset: u32 },
+    // shift immediates hold only the shift amount
+    MathImmediate { operation: MathOperation, rd: u8, rs1: u8, immediate: u32 },
+    Math { operation: MathOperation, rd: u8, rs1: u8, rs2: u8 },
+    LoadReserved { rd: u8, rs1: u8 },
+    StoreConditional { rd: u8, rs1: u8, rs2: u8 },
+    Atomic { operation: AtomicOperation, rd: u8, rs1: u8, rs2: u8 },
+    Fence,
+    FenceI,
+    // immediate csr instructions use rs1 as the value
+    CSR { operation: CSROperation, immediate: bool, rd: u8, rs1: u8, csr: u16 },
+    ECALL,
+    EBREAK,
+    SRET,
+    MRET,
+    WFI,
+    SFenceVMA { rs1: u8, rs2: u8 },
+    Illegal,
+}
+
+pub fn decode(instruction: u32) -> Operation {
+    let rd = extract_rd_register(instruction);
+    let rs1 = extract_rs1_register(instruction);
+    let rs2 = extract_rs2_register(instruction);
+    let funct3 = extract_funct3(instruction);
+    let offset = sign_extend(extract_immediate_11_0(instruction) as u32, 12);
+
+    match instruction & InstructionGroup::MASK as u32 {
+        0b0110111 => Operation::LUI { rd, immediate: extract_immediate_31_12(instruction) },
+        0b0010111 => Operation::AUIPC { rd, immediate: extract_immediate_31_12(instruction) },
+        0b1101111 => Operation::JAL { rd, offset: sign_extend(extract_immediate_20_1(instruction), 21) },
+        0b1100111 if funct3 == 0 => Operation::JALR { rd, rs1, offset },
+        0b1100011 => {
+            let condition = match funct3 {
+                0b000 => Condition::Equal,
+                0b001 => Condition::NotEqual,
+                0b100 => Condition::LessThan,
+                0b101 => Condition::GreaterEqual,
+                0b110 => Condition::LessThanUnsigned,
+                0b111 => Condition::GreaterEqualUnsigned,
+                _ => return Operation::Illegal,
+            };
+            Operation::Branch { condition, rs1, rs2, offset: sign_extend(extract_immediate_12_1(instruction) as u32, 13) }
+        }
+        0b0000011 => {
+            let (size, signed) = match funct3 {
+                0b000 => (AccessSize::Byte, true),
+                0b001 => (AccessSize::Half, true),
+                0b010 => (AccessSize::Word, false),
+                0b100 => (AccessSize::Byte, false),
+                0b101 => (AccessSize::Half, false),
+                _ => return Operation::Illegal,
+            };
+            Operation::Load { size, signed, rd, rs1, offset }
+        }
+        0b0100011 => {
+            let size = match funct3 {
+                0b000 => AccessSize::Byte,
+                0b001 => AccessSize::Half,
+                0b010 => AccessSize::Word,
+                _ => return Operation::Illegal,
+            };
+            Operation::Store { size, rs1, rs2, offset: sign_extend(extract_store_immediate_11_0(instruction) as u32, 12) }
+        }
+        0b0001111 => match funct3 {
+            0b000 => Operation::Fence,
+            0b001 => Operation::FenceI,
+            _ => Operation::Illegal,
+        },
+        0b1110011 => {
+            let csr = extract_csr(instruction);
+            let operation = match funct3 & 0b011 {
+                0b01 => CSROperation::ReadWrite,
+                0b10 => CSROperation::ReadSet,
+                0b11 => CSROperation::ReadClear,
+                _ => {
+                    return match instruction {
+                        ECALL => Operation::ECALL,
+                        EBREAK => Operation::EBREAK,
+                        SRET => Operation::SRET,
+                        MRET => Operation::MRET,
+                        WFI => Operation::WFI,
+                        _ if instruction & 0xFE007FFF == 0x12000073 => Operation::SFenceVMA { rs1, rs2 },
+                        _ => Operation::Illegal,
+                    }
+                }
+            };
+            Operation::CSR { operation, immediate: funct3 & 0b100 != 0, rd, rs1, csr }
+        }
+        0b0010011 => {
+            let funct7 = extract_funct7(instruction);
+            let (operation, immediate) = match (funct3, funct7) {
+                (0b000, _) => (MathOperation::Add, offset),
+                (0b010, _) => (MathOperation::SetLessThan, offset),
+                (0b011, _) => (MathOperation::SetLessThanUnsigned, offset),
+                (0b100, _) => (MathOperation::Xor, offset),
+                (0b110, _) => (MathOperation::Or, offset),
+                (0b111, _) => (MathOperation::And, offset),
+                (0b001, 0b0000000) => (MathOperation::ShiftLeft, rs2 as u32),
+                (0b101, 0b0000000) => (MathOperation::ShiftRightLogical, rs2 as u32),
+                (0b101, 0b0100000) => (MathOperation::ShiftRightArithmetic, rs2 as u32),
+                _ => return Operation::Illegal,
+            };
+            Operation::MathImmediate { operation, rd, rs1, immediate }
+        }
+        0b0110011 => {
+            let operation = match (extract_funct7(instruction), funct3) {
+                (0b0000000, 0b000) => MathOperation::Add,
+                (0b0100000, 0b000) => MathOperation::Subtract,
+                (0b0000000, 0b001) => MathOperation::ShiftLeft,
+                (0b0000000, 0b010) => MathOperation::SetLessThan,
+                (0b0000000, 0b011) => MathOperation::SetLessThanUnsigned,
+                (0b0000000, 0b100) => MathOperation::Xor,
+                (0b0000000, 0b101) => MathOperation::ShiftRightLogical,
+                (0b0100000, 0b101) => MathOperation::ShiftRightArithmetic,
+                (0b0000000, 0b110) => MathOperation::Or,
+                (0b0000000, 0b111) => MathOperation::And,
+                (0b0000001, 0b000) => MathOperation::Multiply,
+                (0b0000001, 0b001) => MathOperation::MultiplyHigh,
+                (0b0000001, 0b010) => MathOperation::MultiplyHighSignedUnsigned,
+                (0b0000001, 0b011) => MathOperation::MultiplyHighUnsigned,
+                (0b0000001, 0b100) => MathOperation::Divide,
+                (0b0000001, 0b101) => MathOperation::DivideUnsigned,
+                (0b0000001, 0b110) => MathOperation::Remainder,
+                (0b0000001, 0b111) => MathOperation::RemainderUnsigned,
+                _ => return Operation::Illegal,
+            };
+            Operation::Math { operation, rd, rs1, rs2 }
+        }
+        // acquire and release ordering bits are ignored, a single hart runs at a time
+        0b0101111 if funct3 == 0b010 => {
+            let operation = match instruction >> 27 {
+                0b00010 if rs2 == 0 => return Operation::LoadReserved { rd, rs1 },
+                0b00011 => return Operation::StoreConditional { rd, rs1, rs2 },
+                0b00001 => AtomicOperation::Swap,
+                0b00000 => AtomicOperation::Add,
+                0b00100 => AtomicOperation::Xor,
+                0b01100 => AtomicOperation::And,
+                0b01000 => AtomicOperation::Or,
+                0b10000 => AtomicOperation::Min,
+                0b10100 => AtomicOperation::Max,
+                0b11000 => AtomicOperation::MinUnsigned,
+                0b11100 => AtomicOperation::MaxUnsigned,
+                _ => return Operation::Illegal,
+            };
+            Operation::Atomic { operation, rd, rs1, rs2 }
+        }
+        _ => Operation::Illegal,
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    (((value << (32 - bits)) as i32) >> (32 - bits)) as u32
+}
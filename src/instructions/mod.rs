@@ -1,5 +1,8 @@
 pub mod branch;
+pub mod csr;
+pub mod decode;
 pub mod load;
+pub mod store;
 
 pub enum InstructionGroup {
     LUI    = 0b0110111,
@@ -12,6 +15,7 @@ pub enum InstructionGroup {
     MATHI  = 0b0010011,
     MATH   = 0b0110011,
     FENCE  = 0b0001111,
+    AMO    = 0b0101111,
     CSR    = 0b1110011,
     MASK   = 0b1111111,
 }
@@ -36,6 +40,7 @@ pub fn auipc(rd: u8, value: u32) -> u32 {
     truncated_value | shifted_rd | InstructionGroup::AUIPC as u32
 }
 
+#[allow(clippy::identity_op)]
 pub fn jal(rd: u8, value: u32) -> u32 {
     let shifted_rd = (rd as u32) << 7;
     let imm_10_1  = (value & 0b000000000011111111110) << 20;
@@ -53,3 +58,33 @@ pub fn jalr(rd: u8, rs1: u8, value: u16) -> u32 {
 
     imm_11_0 | shifted_rs1 | shifted_rd | InstructionGroup::JALR as u32
 }
+
+// register and immediate alu operations, funct7 0b0000001 selects the m extension
+pub fn math(funct7: u8, funct3: u8, rd: u8, rs1: u8, rs2: u8) -> u32 {
+    let shifted_funct7 = ((funct7 as u32) & 0b1111111) << 25;
+    let shifted_rs2    = (rs2 as u32) << 20;
+    let shifted_rs1    = (rs1 as u32) << 15;
+    let shifted_funct3 = ((funct3 as u32) & 0b111) << 12;
+    let shifted_rd     = (rd as u32) << 7;
+
+    shifted_funct7 | shifted_rs2 | shifted_rs1 | shifted_funct3 | shifted_rd | InstructionGroup::MATH as u32
+}
+
+pub fn mathi(funct3: u8, rd: u8, rs1: u8, value: u16) -> u32 {
+    let imm_11_0       = ((value as u32) & 0b111111111111) << 20;
+    let shifted_rs1    = (rs1 as u32) << 15;
+    let shifted_funct3 = ((funct3 as u32) & 0b111) << 12;
+    let shifted_rd     = (rd as u32) << 7;
+
+    imm_11_0 | shifted_rs1 | shifted_funct3 | shifted_rd | InstructionGroup::MATHI as u32
+}
+
+// word atomics of the a extension without acquire and release ordering, funct5 selects the operation
+pub fn amo(funct5: u8, rd: u8, rs1: u8, rs2: u8) -> u32 {
+    let shifted_funct5 = ((funct5 as u32) & 0b11111) << 27;
+    let shifted_rs2    = (rs2 as u32) << 20;
+    let shifted_rs1    = (rs1 as u32) << 15;
+    let shifted_rd     = (rd as u32) << 7;
+
+    shifted_funct5 | shifted_rs2 | shifted_rs1 | 0b010 << 12 | shifted_rd | InstructionGroup::AMO as u32
+}
@@ -0,0 +1,21 @@
+pub enum StoreType {
+    SB   = 0b000000000100011,
+    SH   = 0b001000000100011,
+    SW   = 0b010000000100011,
+    MASK = 0b111000001111111,
+}
+
+impl StoreType {
+    pub fn check(instruction: u32, store_type: StoreType) -> bool {
+        ((instruction & StoreType::MASK as u32) ^ store_type as u32) == 0
+    }
+}
+
+pub fn store(store_type: StoreType, rs1: u8, rs2: u8, offset: u16) -> u32 {
+    let shifted_rs1 = (rs1 as u32) << 15;
+    let shifted_rs2 = (rs2 as u32) << 20;
+    let imm_4_0     = ((offset as u32) & 0b000000011111) <<  7;
+    let imm_11_5    = ((offset as u32) & 0b111111100000) << 20;
+
+    store_type as u32 | shifted_rs1 | shifted_rs2 | imm_4_0 | imm_11_5
+}
@@ -0,0 +1,614 @@
+use std::ffi::c_void;
+use std::mem::offset_of;
+
+use crate::CPU;
+use crate::block::Step;
+use crate::bus::{AccessSize, Bus};
+use crate::cache::PAGE_SIZE;
+use crate::instructions::decode::{Condition, MathOperation, Operation};
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature needs an x86-64 linux host");
+
+// runs of a block before it is compiled
+pub const HOT: u32 = 16;
+// compiled blocks by pc, a block replaces the one in its slot
+pub const SLOTS: usize = 1 << 14;
+// bytes of native code kept before all of it is dropped
+pub const CODE_LIMIT: usize = 64 << 20;
+
+const CHUNK: usize = 1 << 20;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(address: *mut c_void, length: usize, protection: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(address: *mut c_void, length: usize, protection: i32) -> i32;
+    fn munmap(address: *mut c_void, length: usize) -> i32;
+}
+
+// the context, returns the number of retired instructions
+type Function = unsafe extern "sysv64" fn(*mut Context) -> u32;
+
+// guest state and the ram window native code works on, all of it reached through one pointer
+#[repr(C)]
+pub(crate) struct Context {
+    registers: [u32; 32],
+    pc: u32,
+    ram_base: u32,
+    // accesses ending past the limit leave native code, zero sends all of them to the cpu
+    load_limit: u64,
+    store_limit: u64,
+    ram: *mut u8,
+    generations: *mut u64,
+}
+
+const PC: u32 = offset_of!(Context, pc) as u32;
+const RAM_BASE: u32 = offset_of!(Context, ram_base) as u32;
+const LOAD_LIMIT: u32 = offset_of!(Context, load_limit) as u32;
+const STORE_LIMIT: u32 = offset_of!(Context, store_limit) as u32;
+const RAM: u32 = offset_of!(Context, ram) as u32;
+const GENERATIONS: u32 = offset_of!(Context, generations) as u32;
+
+// ram as native code sees it, stores bump the write generation of their page themselves
+pub struct Memory {
+    pub base: u32,
+    pub size: u32,
+    pub data: *mut u8,
+    pub generations: *mut u64,
+    // stores have to break lr reservations and go through the bus while any are held
+    pub reserved: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    pc: u32,
+    // write generation of the page the block was compiled from
+    generation: u64,
+    function: Function,
+}
+
+// mapped memory holding native code, writable only while a block is copied in
+struct Region {
+    memory: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+impl Region {
+    fn new(size: usize) -> Option<Self> {
+        let memory = unsafe { mmap(std::ptr::null_mut(), size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+        if memory == MAP_FAILED {
+            return None;
+        }
+        Some(Region { memory: memory as *mut u8, size, used: 0 })
+    }
+
+    fn append(&mut self, code: &[u8]) -> Option<*const u8> {
+        if self.size - self.used < code.len() {
+            return None;
+        }
+
+        unsafe {
+            if mprotect(self.memory as *mut c_void, self.size, PROT_READ | PROT_WRITE) != 0 {
+                return None;
+            }
+            let start = self.memory.add(self.used);
+            std::ptr::copy_nonoverlapping(code.as_ptr(), start, code.len());
+            if mprotect(self.memory as *mut c_void, self.size, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+            // keep functions 16 byte aligned
+            self.used += code.len().next_multiple_of(16);
+            Some(start)
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.memory as *mut c_void, self.size);
+        }
+    }
+}
+
+// native code of hot basic blocks, dropped whenever the block cache is flushed
+pub struct Jit {
+    regions: Vec<Region>,
+    table: Vec<Option<Entry>>,
+    // runs of the block last seen in each slot
+    hits: Vec<(u32, u32)>,
+    compiled: usize,
+    // flush count of the block cache the code belongs to
+    epoch: u64,
+    context: Context,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        let context = Context {
+            registers: [0; 32],
+            pc: 0,
+            ram_base: 0,
+            load_limit: 0,
+            store_limit: 0,
+            ram: std::ptr::null_mut(),
+            generations: std::ptr::null_mut(),
+        };
+        Jit { regions: Vec::new(), table: vec![None; SLOTS], hits: vec![(0, 0); SLOTS], compiled: 0, epoch: 0, context }
+    }
+
+    // blocks compiled since the last reset
+    pub fn compiled(&self) -> usize {
+        self.compiled
+    }
+
+    // bytes of native code currently mapped
+    pub fn code_size(&self) -> usize {
+        self.regions.iter().map(|region| region.used).sum()
+    }
+
+    // must not be called while native code runs, the regions are unmapped
+    fn reset(&mut self, epoch: u64) {
+        self.regions.clear();
+        self.table.fill(None);
+        self.hits.fill((0, 0));
+        self.compiled = 0;
+        self.epoch = epoch;
+    }
+
+    fn get(&self, pc: u32, generation: u64) -> Option<Entry> {
+        self.table[Self::slot(pc)].filter(|entry| entry.pc == pc && entry.generation == generation)
+    }
+
+    // counts a run of the block at pc, true once it is hot, a block replaces the count of the one in its slot
+    fn hot(&mut self, pc: u32) -> bool {
+        let hits = &mut self.hits[Self::slot(pc)];
+        if hits.0 != pc {
+            *hits = (pc, 0);
+        }
+        hits.1 += 1;
+        hits.1 >= HOT
+    }
+
+    fn install(&mut self, pc: u32, generation: u64, code: &[u8]) -> Option<Entry> {
+        if self.code_size() + code.len() > CODE_LIMIT {
+            self.reset(self.epoch);
+        }
+
+        let start = match self.regions.last_mut().and_then(|region| region.append(code)) {
+            Some(start) => start,
+            None => {
+                let mut region = Region::new(CHUNK.max(code.len()))?;
+                let start = region.append(code)?;
+                self.regions.push(region);
+                start
+            }
+        };
+
+        let function = unsafe { std::mem::transmute::<*const u8, Function>(start) };
+        let entry = Entry { pc, generation, function };
+        self.table[Self::slot(pc)] = Some(entry);
+        // a block whose page keeps being written has to get hot again before it is compiled again
+        self.hits[Self::slot(pc)] = (pc, 0);
+        self.compiled += 1;
+        Some(entry)
+    }
+
+    fn slot(pc: u32) -> usize {
+        (pc >> 2) as usize % SLOTS
+    }
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // runs the block at pc as native code once it is hot, None while it is not compiled
+    // native code leaves in front of the first instruction it does not handle, which the caller runs
+    pub(crate) fn run_native(&mut self, pc: u32, generation: u64, steps: &[Step<B>]) -> Option<u64> {
+        // the flush may have come from a fence.i, no native code is running anymore
+        if self.jit.epoch != self.blocks.flushes() {
+            self.jit.reset(self.blocks.flushes());
+        }
+        let memory = self.bus.memory()?;
+
+        let entry = match self.jit.get(pc, generation) {
+            Some(entry) => entry,
+            None if self.jit.hot(pc) => {
+                let code = compile(steps, pc, (pc - memory.base) / PAGE_SIZE);
+                self.jit.install(pc, generation, &code)?
+            }
+            None => return None,
+        };
+
+        // translated loads and stores go through the cpu, stores also while reservations are held
+        let direct = !self.translating_data();
+        let context = &mut self.jit.context;
+        context.registers = self.registers.registers;
+        context.pc = pc;
+        context.ram_base = memory.base;
+        context.load_limit = if direct { memory.size as u64 } else { 0 };
+        context.store_limit = if direct && !memory.reserved { memory.size as u64 } else { 0 };
+        context.ram = memory.data;
+        context.generations = memory.generations;
+
+        let retired = unsafe { (entry.function)(context) };
+        self.registers.registers = context.registers;
+        self.pc = context.pc;
+        Some(retired as u64)
+    }
+
+    pub fn jit(&mut self) -> &mut Jit {
+        &mut self.jit
+    }
+}
+
+// rbx holds the context, eax, ecx and edx are scratch, page is the ram page of the block
+fn compile<B: Bus>(steps: &[Step<B>], pc: u32, page: u32) -> Vec<u8> {
+    let mut assembler = Assembler::new();
+    assembler.prologue();
+
+    for (position, step) in steps.iter().enumerate() {
+        let address = pc.wrapping_add(4 * position as u32);
+        let retired = position as u32 + 1;
+
+        match step.operation {
+            Operation::LUI { rd, immediate } => assembler.set_register(rd, immediate),
+            Operation::AUIPC { rd, immediate } => assembler.set_register(rd, address.wrapping_add(immediate)),
+            Operation::JAL { rd, offset } if address.wrapping_add(offset) & 0b11 == 0 => {
+                assembler.set_register(rd, address.wrapping_add(4));
+                assembler.set_pc(address.wrapping_add(offset));
+                assembler.exit(retired);
+            }
+            Operation::JALR { rd, rs1, offset } => {
+                assembler.load(EAX, rs1);
+                assembler.emit(&[0x05]);
+                assembler.emit_u32(offset);
+                assembler.emit(&[0x25]);
+                assembler.emit_u32(0xFFFFFFFE);
+                let misaligned = assembler.jump_if_bit1();
+                assembler.set_register(rd, address.wrapping_add(4));
+                assembler.context(&[0x89], EAX, PC);
+                assembler.exit(retired);
+
+                assembler.bind(misaligned);
+                assembler.leave(address, position as u32);
+            }
+            Operation::Branch { condition, rs1, rs2, offset } if address.wrapping_add(offset) & 0b11 == 0 => {
+                assembler.load(EAX, rs1);
+                assembler.load(ECX, rs2);
+                let taken = assembler.compare_and_jump(condition);
+                assembler.set_pc(address.wrapping_add(4));
+                assembler.exit(retired);
+
+                assembler.bind(taken);
+                assembler.set_pc(address.wrapping_add(offset));
+                assembler.exit(retired);
+            }
+            Operation::MathImmediate { operation, rd, rs1, immediate } if Assembler::has_immediate(operation) => {
+                assembler.load(EAX, rs1);
+                assembler.math_immediate(operation, immediate);
+                assembler.store(EAX, rd);
+            }
+            Operation::Math { operation, rd, rs1, rs2 } if Assembler::has_register(operation) => {
+                assembler.load(EAX, rs1);
+                assembler.load(ECX, rs2);
+                assembler.math(operation);
+                assembler.store(EAX, rd);
+            }
+            Operation::Load { size, signed, rd, rs1, offset } => {
+                let outside = assembler.ram_offset(rs1, offset, size, LOAD_LIMIT);
+                assembler.load_ram(size, signed);
+                assembler.store(ECX, rd);
+                let done = assembler.jump();
+
+                assembler.bind(outside);
+                assembler.leave(address, position as u32);
+                assembler.bind(done);
+            }
+            Operation::Store { size, rs1, rs2, offset } => {
+                let outside = assembler.ram_offset(rs1, offset, size, STORE_LIMIT);
+                let crossing = assembler.jump_if_page_crossing(size);
+                assembler.load(ECX, rs2);
+                assembler.store_ram(size);
+                assembler.touch_page();
+
+                // a store into the page of the block ends it, the rest is translated again
+                let own = assembler.jump_if_page(page);
+                let done = assembler.jump();
+                assembler.bind(own);
+                assembler.set_pc(address.wrapping_add(4));
+                assembler.exit(retired);
+
+                assembler.bind(outside);
+                if let Some(crossing) = crossing {
+                    assembler.bind(crossing);
+                }
+                assembler.leave(address, position as u32);
+                assembler.bind(done);
+            }
+            Operation::Fence => {}
+            // everything else, including traps, runs outside of native code
+            _ => {
+                assembler.leave(address, position as u32);
+                return assembler.code;
+            }
+        }
+    }
+
+    assembler.set_pc(pc.wrapping_add(4 * steps.len() as u32));
+    assembler.exit(steps.len() as u32);
+    assembler.code
+}
+
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+
+// the few x86-64 instructions blocks are made of
+struct Assembler {
+    code: Vec<u8>,
+}
+
+// position of a rel32 to patch once its target is known
+struct Label(usize);
+
+impl Assembler {
+    fn new() -> Self {
+        Assembler { code: Vec::new() }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn prologue(&mut self) {
+        // push rbx; mov rbx, rdi
+        self.emit(&[0x53, 0x48, 0x89, 0xFB]);
+    }
+
+    fn exit(&mut self, retired: u32) {
+        // mov eax, retired; pop rbx; ret
+        self.emit(&[0xB8]);
+        self.emit_u32(retired);
+        self.emit(&[0x5B, 0xC3]);
+    }
+
+    // returns in front of the instruction at address, which the cpu runs
+    fn leave(&mut self, address: u32, retired: u32) {
+        self.set_pc(address);
+        self.exit(retired);
+    }
+
+    // opcode with a register and the context field at offset as operands
+    fn context(&mut self, opcode: &[u8], register: u8, offset: u32) {
+        // modrm for [rbx + disp32]
+        self.emit(opcode);
+        self.emit(&[0x80 | register << 3 | 0b011]);
+        self.emit_u32(offset);
+    }
+
+    fn load(&mut self, register: u8, guest: u8) {
+        // mov register, [rbx + 4 * guest]
+        self.context(&[0x8B], register, 4 * guest as u32);
+    }
+
+    fn store(&mut self, register: u8, guest: u8) {
+        if guest == 0 {
+            return;
+        }
+        // mov [rbx + 4 * guest], register
+        self.context(&[0x89], register, 4 * guest as u32);
+    }
+
+    fn set_register(&mut self, guest: u8, value: u32) {
+        if guest == 0 {
+            return;
+        }
+        // mov dword [rbx + 4 * guest], value
+        self.context(&[0xC7], 0, 4 * guest as u32);
+        self.emit_u32(value);
+    }
+
+    fn set_pc(&mut self, pc: u32) {
+        // mov dword [rbx + pc], pc
+        self.context(&[0xC7], 0, PC);
+        self.emit_u32(pc);
+    }
+
+    fn has_immediate(operation: MathOperation) -> bool {
+        matches!(
+            operation,
+            MathOperation::Add
+                | MathOperation::Xor
+                | MathOperation::Or
+                | MathOperation::And
+                | MathOperation::SetLessThan
+                | MathOperation::SetLessThanUnsigned
+                | MathOperation::ShiftLeft
+                | MathOperation::ShiftRightLogical
+                | MathOperation::ShiftRightArithmetic
+        )
+    }
+
+    // division and mulhsu leave native code
+    fn has_register(operation: MathOperation) -> bool {
+        Self::has_immediate(operation)
+            || matches!(operation, MathOperation::Subtract | MathOperation::Multiply | MathOperation::MultiplyHigh | MathOperation::MultiplyHighUnsigned)
+    }
+
+    // eax = eax operation immediate
+    fn math_immediate(&mut self, operation: MathOperation, immediate: u32) {
+        let shift = (immediate & 0b11111) as u8;
+        match operation {
+            MathOperation::Add => self.emit(&[0x05]),
+            MathOperation::Xor => self.emit(&[0x35]),
+            MathOperation::Or => self.emit(&[0x0D]),
+            MathOperation::And => self.emit(&[0x25]),
+            // cmp eax, immediate
+            MathOperation::SetLessThan | MathOperation::SetLessThanUnsigned => self.emit(&[0x3D]),
+            // shl, shr and sar eax, shift
+            MathOperation::ShiftLeft => return self.emit(&[0xC1, 0xE0, shift]),
+            MathOperation::ShiftRightLogical => return self.emit(&[0xC1, 0xE8, shift]),
+            MathOperation::ShiftRightArithmetic => return self.emit(&[0xC1, 0xF8, shift]),
+            _ => unreachable!("no native code for {:?}", operation),
+        }
+        self.emit_u32(immediate);
+        self.set_flag(operation);
+    }
+
+    // eax = eax operation ecx
+    fn math(&mut self, operation: MathOperation) {
+        match operation {
+            MathOperation::Add => self.emit(&[0x01, 0xC8]),
+            MathOperation::Subtract => self.emit(&[0x29, 0xC8]),
+            MathOperation::Xor => self.emit(&[0x31, 0xC8]),
+            MathOperation::Or => self.emit(&[0x09, 0xC8]),
+            MathOperation::And => self.emit(&[0x21, 0xC8]),
+            // cmp eax, ecx
+            MathOperation::SetLessThan | MathOperation::SetLessThanUnsigned => {
+                self.emit(&[0x39, 0xC8]);
+                self.set_flag(operation);
+            }
+            // x86 masks the shift amount in cl to five bits like risc-v
+            MathOperation::ShiftLeft => self.emit(&[0xD3, 0xE0]),
+            MathOperation::ShiftRightLogical => self.emit(&[0xD3, 0xE8]),
+            MathOperation::ShiftRightArithmetic => self.emit(&[0xD3, 0xF8]),
+            // imul eax, ecx
+            MathOperation::Multiply => self.emit(&[0x0F, 0xAF, 0xC1]),
+            // imul ecx or mul ecx, then mov eax, edx
+            MathOperation::MultiplyHigh => self.emit(&[0xF7, 0xE9, 0x89, 0xD0]),
+            MathOperation::MultiplyHighUnsigned => self.emit(&[0xF7, 0xE1, 0x89, 0xD0]),
+            _ => unreachable!("no native code for {:?}", operation),
+        }
+    }
+
+    // eax = 1 when the preceding compare was less than, signed or unsigned
+    fn set_flag(&mut self, operation: MathOperation) {
+        match operation {
+            // setl al or setb al, then movzx eax, al
+            MathOperation::SetLessThan => self.emit(&[0x0F, 0x9C, 0xC0, 0x0F, 0xB6, 0xC0]),
+            MathOperation::SetLessThanUnsigned => self.emit(&[0x0F, 0x92, 0xC0, 0x0F, 0xB6, 0xC0]),
+            _ => {}
+        }
+    }
+
+    // rax = guest register + offset - ram base, jumps to the label when the access does not end below the limit
+    fn ram_offset(&mut self, guest: u8, offset: u32, size: AccessSize, limit: u32) -> Label {
+        self.load(EAX, guest);
+        // add eax, offset; sub eax, [rbx + ram base]
+        self.emit(&[0x05]);
+        self.emit_u32(offset);
+        self.context(&[0x2B], EAX, RAM_BASE);
+        // lea rcx, [rax + bytes]; cmp rcx, [rbx + limit]; ja
+        self.emit(&[0x48, 0x8D, 0x48, size.bytes() as u8]);
+        self.context(&[0x48, 0x3B], ECX, limit);
+        self.emit(&[0x0F, 0x87]);
+        self.label()
+    }
+
+    // writes ending in the next page go through the cpu, which bumps both generations
+    fn jump_if_page_crossing(&mut self, size: AccessSize) -> Option<Label> {
+        if size == AccessSize::Byte {
+            return None;
+        }
+        // lea edx, [rax + bytes - 1]; xor edx, eax; test edx, !(page size - 1); jnz
+        self.emit(&[0x8D, 0x50, size.bytes() as u8 - 1, 0x31, 0xC2, 0xF7, 0xC2]);
+        self.emit_u32(!(PAGE_SIZE - 1));
+        self.emit(&[0x0F, 0x85]);
+        Some(self.label())
+    }
+
+    // ecx = ram[rax], extended like the load
+    fn load_ram(&mut self, size: AccessSize, signed: bool) {
+        // mov rdx, [rbx + ram]
+        self.context(&[0x48, 0x8B], EDX, RAM);
+        // movsx, movzx or mov ecx, [rdx + rax]
+        match (size, signed) {
+            (AccessSize::Byte, true) => self.emit(&[0x0F, 0xBE, 0x0C, 0x02]),
+            (AccessSize::Byte, false) => self.emit(&[0x0F, 0xB6, 0x0C, 0x02]),
+            (AccessSize::Half, true) => self.emit(&[0x0F, 0xBF, 0x0C, 0x02]),
+            (AccessSize::Half, false) => self.emit(&[0x0F, 0xB7, 0x0C, 0x02]),
+            (AccessSize::Word, _) => self.emit(&[0x8B, 0x0C, 0x02]),
+        }
+    }
+
+    // ram[rax] = ecx
+    fn store_ram(&mut self, size: AccessSize) {
+        // mov rdx, [rbx + ram]
+        self.context(&[0x48, 0x8B], EDX, RAM);
+        // mov [rdx + rax], cl, cx or ecx
+        match size {
+            AccessSize::Byte => self.emit(&[0x88, 0x0C, 0x02]),
+            AccessSize::Half => self.emit(&[0x66, 0x89, 0x0C, 0x02]),
+            AccessSize::Word => self.emit(&[0x89, 0x0C, 0x02]),
+        }
+    }
+
+    // bumps the write generation of the page at rax and leaves its number in ecx
+    fn touch_page(&mut self) {
+        // mov ecx, eax; shr ecx, page bits
+        self.emit(&[0x89, 0xC1, 0xC1, 0xE9, PAGE_SIZE.trailing_zeros() as u8]);
+        // mov rdx, [rbx + generations]; inc qword [rdx + rcx * 8]
+        self.context(&[0x48, 0x8B], EDX, GENERATIONS);
+        self.emit(&[0x48, 0xFF, 0x04, 0xCA]);
+    }
+
+    fn jump_if_page(&mut self, page: u32) -> Label {
+        // cmp ecx, page; je
+        self.emit(&[0x81, 0xF9]);
+        self.emit_u32(page);
+        self.emit(&[0x0F, 0x84]);
+        self.label()
+    }
+
+    fn jump(&mut self) -> Label {
+        self.emit(&[0xE9]);
+        self.label()
+    }
+
+    fn jump_if_bit1(&mut self) -> Label {
+        // test al, 2; jnz
+        self.emit(&[0xA8, 0x02, 0x0F, 0x85]);
+        self.label()
+    }
+
+    fn compare_and_jump(&mut self, condition: Condition) -> Label {
+        let jump = match condition {
+            Condition::Equal => 0x84,
+            Condition::NotEqual => 0x85,
+            Condition::LessThan => 0x8C,
+            Condition::GreaterEqual => 0x8D,
+            Condition::LessThanUnsigned => 0x82,
+            Condition::GreaterEqualUnsigned => 0x83,
+        };
+        // cmp eax, ecx; jcc
+        self.emit(&[0x39, 0xC8, 0x0F, jump]);
+        self.label()
+    }
+
+    fn label(&mut self) -> Label {
+        let label = Label(self.code.len());
+        self.emit_u32(0);
+        label
+    }
+
+    fn bind(&mut self, label: Label) {
+        let offset = (self.code.len() - (label.0 + 4)) as u32;
+        self.code[label.0..label.0 + 4].copy_from_slice(&offset.to_le_bytes());
+    }
+}
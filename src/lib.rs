@@ -1,58 +1,186 @@
 pub mod instructions;
-
-use instructions::*;
-use instructions::branch::*;
-use instructions::load::LoadType;
-
-pub struct CPU {
+pub mod block;
+pub mod bus;
+pub mod cache;
+pub mod compliance;
+pub mod csr;
+pub mod debug;
+pub mod devices;
+pub mod differential;
+pub mod elf;
+pub mod fdt;
+pub mod fuzz;
+pub mod gdb;
+pub mod htif;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod linux;
+pub mod machine;
+pub mod mmu;
+pub mod reverse;
+pub mod sbi;
+pub mod semihosting;
+pub mod smp;
+pub mod snapshot;
+pub mod trace;
+pub mod trap;
+
+use instructions::decode::*;
+use block::{BlockCache, Engine, Stop};
+use bus::{AccessSize, Bus, SystemBus};
+use cache::DecodeCache;
+use csr::*;
+use debug::{Debugger, StopReason, WatchKind};
+use mmu::Access;
+use snapshot::{Reader, SnapshotError, Writer};
+use trace::CommitLog;
+use trap::{Exception, Interrupt, Privilege};
+
+pub struct CPU<B: Bus = SystemBus> {
     registers: Registers,
     pc: u32,
-    ram: RAM,
+    privilege: Privilege,
+    csrs: CSRs,
+    bus: B,
+    debugger: Debugger,
+    commit_log: Option<CommitLog>,
+    cache: DecodeCache,
+    engine: Engine,
+    blocks: BlockCache<B>,
+    #[cfg(feature = "jit")]
+    jit: jit::Jit,
 }
 
 impl CPU {
     pub fn new(ram_size: u32) -> Self {
-        CPU { registers: Registers::new(), pc: 0, ram: RAM::new(ram_size) }
+        CPU::with_bus(SystemBus::new(0, RAM::new(ram_size)))
+    }
+
+    pub fn ram(&mut self) -> &mut RAM {
+        self.bus.ram()
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn with_bus(bus: B) -> Self {
+        CPU {
+            registers: Registers::new(),
+            pc: 0,
+            privilege: Privilege::Machine,
+            csrs: CSRs::new(),
+            bus,
+            debugger: Debugger::new(),
+            commit_log: None,
+            cache: DecodeCache::new(),
+            engine: Engine::Interpreter,
+            blocks: BlockCache::new(),
+            #[cfg(feature = "jit")]
+            jit: jit::Jit::new(),
+        }
     }
 
     pub fn tick(&mut self) {
+        // advance devices
+        self.bus.tick();
+
+        // take pending interrupt instead of executing an instruction
+        if let Some(interrupt) = self.pending_interrupt() {
+            self.trap(interrupt.cause(), 0);
+            return;
+        }
+
         // fetch instruchtion
-        let instruction = self.ram.read_word(self.pc);
-
-        // check instruction group
-        if InstructionGroup::check(instruction, InstructionGroup::LUI) {
-            // lui
-            self.lui(instruction);
-        } else if InstructionGroup::check(instruction, InstructionGroup::AUIPC) {
-            // auipc
-            self.auipc(instruction);
-        } else if InstructionGroup::check(instruction, InstructionGroup::JAL) {
-            // jal
-            self.jal(instruction);
-        } else if InstructionGroup::check(instruction, InstructionGroup::JALR) {
-            // jalr
-            self.jalr(instruction);
-        } else if InstructionGroup::check(instruction, InstructionGroup::BRANCH) {
-            // branch
-            self.branch(instruction);
-        } else if InstructionGroup::check(instruction, InstructionGroup::LOAD) {
-            // load
-            self.load(instruction);
-        } else if InstructionGroup::check(instruction, InstructionGroup::STORE) {
-            // store
-            todo!("store group not implemented");
-        } else if InstructionGroup::check(instruction, InstructionGroup::MATHI) {
-            // math intermediate
-            todo!("math intermediate group not implemented");
-        } else if InstructionGroup::check(instruction, InstructionGroup::MATH) {
-            // math
-            todo!("math group not implemented");
-        } else if InstructionGroup::check(instruction, InstructionGroup::FENCE) {
-            // fence
-            todo!("fence group not implemented");
-        } else if InstructionGroup::check(instruction, InstructionGroup::CSR) {
-            // csr
-            todo!("csr group not implemented");
+        let (instruction, operation) = match self.fetch() {
+            Ok(fetched) => fetched,
+            Err(exception) => {
+                self.raise(exception);
+                return;
+            }
+        };
+
+        self.retire(instruction, operation);
+    }
+
+    fn retire(&mut self, instruction: u32, operation: Operation) {
+        if self.commit_log.is_none() {
+            self.execute(instruction, operation);
+            return;
+        }
+
+        let pc = self.pc;
+        let privilege = self.privilege;
+        if let Some(log) = &mut self.commit_log {
+            log.begin();
+        }
+        self.execute(instruction, operation);
+        self.log_commit(privilege, pc, instruction);
+    }
+
+    // instruction at pc with its decoded form, from the cache while its ram page is unchanged
+    #[inline(always)]
+    fn fetch(&mut self) -> Result<(u32, Operation), Exception> {
+        let pc = self.translate(self.pc, Access::Fetch)?;
+        let generation = if self.cache.enabled() && pc & 0b11 == 0 { self.bus.page_generation(pc) } else { None };
+        if let Some(fetched) = generation.and_then(|generation| self.cache.get(pc, generation)) {
+            return Ok(fetched);
+        }
+
+        let instruction = self.bus.read(pc, AccessSize::Word).map_err(|_| Exception::InstructionAccessFault(self.pc))?;
+        let operation = decode(instruction);
+        if let Some(generation) = generation {
+            self.cache.insert(pc, generation, instruction, operation);
+        }
+        Ok((instruction, operation))
+    }
+
+    fn execute(&mut self, instruction: u32, operation: Operation) {
+        match operation {
+            Operation::LUI { rd, immediate } => self.lui(rd, immediate),
+            Operation::AUIPC { rd, immediate } => self.auipc(rd, immediate),
+            Operation::JAL { rd, offset } => self.jal(rd, offset),
+            Operation::JALR { rd, rs1, offset } => self.jalr(rd, rs1, offset),
+            Operation::Branch { condition, rs1, rs2, offset } => self.branch(condition, rs1, rs2, offset),
+            Operation::Load { size, signed, rd, rs1, offset } => self.load(size, signed, rd, rs1, offset),
+            Operation::Store { size, rs1, rs2, offset } => self.store(size, rs1, rs2, offset),
+            Operation::MathImmediate { operation, rd, rs1, immediate } => self.math(operation, rd, self.registers.read(rs1), immediate),
+            Operation::Math { operation, rd, rs1, rs2 } => self.math(operation, rd, self.registers.read(rs1), self.registers.read(rs2)),
+            Operation::LoadReserved { rd, rs1 } => self.load_reserved(rd, rs1),
+            Operation::StoreConditional { rd, rs1, rs2 } => self.store_conditional(rd, rs1, rs2),
+            Operation::Atomic { operation, rd, rs1, rs2 } => self.atomic(operation, rd, rs1, rs2),
+            Operation::Fence => {
+                // a single in order hart has nothing to order
+                self.pc = self.pc.wrapping_add(4);
+            }
+            Operation::FenceI => {
+                // stores already invalidate decoded instructions, fence.i drops all of them anyway
+                self.cache.flush();
+                self.blocks.flush();
+                self.pc = self.pc.wrapping_add(4);
+            }
+            Operation::CSR { operation, immediate, rd, rs1, csr } => self.csr(instruction, operation, immediate, rd, rs1, csr),
+            Operation::ECALL => self.raise(Exception::EnvironmentCall(self.privilege)),
+            Operation::EBREAK => self.raise(Exception::Breakpoint(self.pc)),
+            Operation::SRET => self.sret(instruction),
+            Operation::MRET => self.mret(instruction),
+            Operation::WFI => {
+                // supervisor mode may be kept from waiting, interrupts are checked every tick
+                if self.privilege < Privilege::Machine && self.csrs.read(MSTATUS) & MSTATUS_TW != 0 {
+                    self.raise(Exception::IllegalInstruction(instruction));
+                    return;
+                }
+                self.pc = self.pc.wrapping_add(4);
+            }
+            Operation::SFenceVMA { .. } => {
+                // page table walks are not cached, decoded instructions are kept by physical address
+                if self.privilege < Privilege::Supervisor
+                    || self.privilege == Privilege::Supervisor && self.csrs.read(MSTATUS) & MSTATUS_TVM != 0
+                {
+                    self.raise(Exception::IllegalInstruction(instruction));
+                    return;
+                }
+                self.pc = self.pc.wrapping_add(4);
+            }
+            Operation::Illegal => self.raise(Exception::IllegalInstruction(instruction)),
         }
     }
 
@@ -64,208 +192,515 @@ impl CPU {
         &mut self.pc
     }
 
-    pub fn ram(&mut self) -> &mut RAM {
-        &mut self.ram
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
     }
 
-    fn extract_rd_register(instruction: u32) -> u8 {
-        ((instruction >> 7) & 0b11111) as u8
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.privilege = privilege;
     }
 
-    fn extract_rs1_register(instruction: u32) -> u8 {
-        ((instruction >> 15) & 0b11111) as u8
+    pub fn csrs(&mut self) -> &mut CSRs {
+        &mut self.csrs
     }
 
-    fn extract_rs2_register(instruction: u32) -> u8 {
-        ((instruction >> 20) & 0b11111) as u8
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
     }
 
-    fn extract_immediate_31_12(instruction: u32) -> u32 {
-        instruction & 0xFFFFF000
+    pub fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
     }
 
-    fn extract_immediate_20_1(instruction: u32) -> u32 {
-        let imm_10_1  = (instruction >> 20) & 0b000000000011111111110;
-        let imm_11    = (instruction >>  9) & 0b000000000100000000000;
-        let imm_19_12 = (instruction >>  0) & 0b011111111000000000000;
-        let imm_20    = (instruction >> 11) & 0b100000000000000000000;
+    pub fn decode_cache(&mut self) -> &mut DecodeCache {
+        &mut self.cache
+    }
 
-        imm_10_1 | imm_11 | imm_19_12 | imm_20
+    pub fn block_cache(&mut self) -> &mut BlockCache<B> {
+        &mut self.blocks
     }
 
-    fn extract_immediate_12_1(instruction: u32) -> u16 {
-        let imm_4_1 =  ((instruction >>  7) as u16) & 0b0000000011110;
-        let imm_10_5 = ((instruction >> 20) as u16) & 0b0011111100000;
-        let imm_11 =   ((instruction <<  4) as u16) & 0b0100000000000;
-        let imm_12 =   ((instruction >> 19) as u16) & 0b1000000000000;
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
 
-        imm_4_1 | imm_10_5 | imm_11 | imm_12
+    // how run and run_until execute, tick always interprets a single instruction
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
     }
 
-    fn extract_immediate_11_0(instruction: u32) -> u16 {
-        ((instruction >> 20) as u16) & 0b111111111111
+    // logs every retired instruction, None turns logging off and returns the previous log
+    pub fn set_commit_log(&mut self, commit_log: Option<CommitLog>) -> Option<CommitLog> {
+        std::mem::replace(&mut self.commit_log, commit_log)
     }
 
-    fn lui(&mut self, instruction: u32) {
-        // extract destination register
-        let rd = Self::extract_rd_register(instruction);
-        // extract immediate value
-        let immediate_value = Self::extract_immediate_31_12(instruction);
-        // store immediate value in destination register
-        self.registers.write(rd, immediate_value);
-        // increment program counter
-        self.pc += 4;
+    fn log_commit(&mut self, privilege: Privilege, pc: u32, instruction: u32) {
+        let hart = self.csrs.read(MHARTID);
+        let register = trace::destination(instruction).map(|register| (register, self.registers.read(register)));
+        let csr = trace::csr_destination(instruction).map(|csr| (csr, self.csrs.read(csr)));
+
+        if let Some(log) = &mut self.commit_log {
+            if !log.trapped() {
+                log.commit(hart, privilege, pc, instruction, register, csr);
+            }
+        }
     }
 
-    fn auipc(&mut self, instruction: u32) {
-        // extract destination register
-        let rd = Self::extract_rd_register(instruction);
-        // extract immediate value
-        let immediate_value = Self::extract_immediate_31_12(instruction);
-        // add immediate value to program counter
-        if immediate_value == 0 {
-            self.pc += 4;
-        } else {
-            self.pc += immediate_value;
+    // runs until a breakpoint or watchpoint is hit
+    pub fn run(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    pub fn run_for(&mut self, instructions: u64) -> StopReason {
+        if instructions == 0 {
+            return StopReason::InstructionLimit;
+        }
+
+        // without breakpoints or watchpoints blocks and native code only stop for the count
+        if self.engine != Engine::Interpreter && self.debugger.is_empty() {
+            self.debugger.take_hit();
+            self.run_blocks(instructions, Stop::<fn(&mut Self) -> bool>::Never);
+            return StopReason::InstructionLimit;
+        }
+
+        let mut remaining = instructions;
+        let reason = self.run_until(|_| {
+            remaining -= 1;
+            remaining == 0
+        });
+        match reason {
+            StopReason::Predicate => StopReason::InstructionLimit,
+            reason => reason,
+        }
+    }
+
+    // the predicate is checked after every instruction, breakpoints at the starting pc are ignored
+    pub fn run_until<F: FnMut(&mut Self) -> bool>(&mut self, mut predicate: F) -> StopReason {
+        // drop accesses recorded by earlier ticks outside of a run
+        self.debugger.take_hit();
+
+        let mut reason = StopReason::Predicate;
+        let mut stop = |cpu: &mut Self| {
+            let found = match cpu.debugger.take_hit() {
+                Some(hit) => Some(hit),
+                None if predicate(cpu) => Some(StopReason::Predicate),
+                None => cpu.debugger.check_execute(cpu.pc),
+            };
+            if let Some(found) = found {
+                reason = found;
+            }
+            found.is_some()
+        };
+
+        match self.engine {
+            Engine::Interpreter => loop {
+                self.tick();
+                if stop(self) {
+                    break;
+                }
+            },
+            // the predicate is checked after every instruction, so native code is not used
+            _ => {
+                self.run_blocks(u64::MAX, Stop::Always(&mut stop));
+            }
+        }
+        reason
+    }
+
+    // runs up to the given number of instructions and returns how many ran, stopping early once the predicate holds,
+    // which is only checked after traps, returns from traps, csr instructions and jumps so it may only look at the
+    // privilege and csrs, with breakpoints or watchpoints it is checked after every instruction like in run_until
+    pub fn run_until_privileged<F: FnMut(&mut Self) -> bool>(&mut self, instructions: u64, mut predicate: F) -> u64 {
+        if instructions == 0 {
+            return 0;
+        }
+        if predicate(self) {
+            self.run_for(1);
+            return 1;
         }
-        // write program counter to target register
-        self.registers.write(rd, self.pc);
+
+        if self.engine == Engine::Interpreter || !self.debugger.is_empty() {
+            let mut retired = 0;
+            self.run_until(|cpu| {
+                retired += 1;
+                retired == instructions || predicate(cpu)
+            });
+            return retired;
+        }
+
+        self.debugger.take_hit();
+        self.run_blocks(instructions, Stop::Privileged(&mut predicate))
     }
 
-    fn jal(&mut self, instruction: u32) {
-        let rd_index = Self::extract_rd_register(instruction);
-        let immediate = Self::extract_immediate_20_1(instruction);
+    fn pending_interrupt(&mut self) -> Option<Interrupt> {
+        // refresh the interrupt lines driven by devices
+        let hart = self.csrs.read(MHARTID);
+        let lines = self.bus.interrupts(hart) & MIP_LINES;
+        let mip = (self.csrs.read(MIP) & !MIP_LINES) | lines;
+        self.csrs.write(MIP, mip);
 
-        self.registers.write(rd_index, self.pc + 4);
+        let pending = mip & self.csrs.read(MIE);
+        if pending == 0 {
+            return None;
+        }
 
-        let sign   = immediate & 0b100000000000000000000;
-        let amount = immediate & 0b011111111111111111111;
+        // interrupts for a higher privilege are always taken, those for the current one need its enable bit
+        let mstatus = self.csrs.read(MSTATUS);
+        let delegated = self.csrs.read(MIDELEG);
+        let machine = match self.privilege {
+            Privilege::Machine => mstatus & MSTATUS_MIE != 0,
+            _ => true,
+        };
+        let supervisor = match self.privilege {
+            Privilege::Machine => false,
+            Privilege::Supervisor => mstatus & MSTATUS_SIE != 0,
+            Privilege::User => true,
+        };
+
+        let enabled = if machine { pending & !delegated } else { 0 } | if supervisor { pending & delegated } else { 0 };
+        Interrupt::from_pending(enabled)
+    }
 
-        if sign == 0 {
-            self.pc += amount;
+    fn raise(&mut self, exception: Exception) {
+        self.trap(exception.cause(), exception.value());
+    }
+
+    fn trap(&mut self, cause: u32, value: u32) {
+        if let Some(log) = &mut self.commit_log {
+            log.trap();
+        }
+
+        // traps below machine mode go to supervisor mode when their cause is delegated
+        let interrupt = cause & 0x80000000 != 0;
+        let delegation = if interrupt { self.csrs.read(MIDELEG) } else { self.csrs.read(MEDELEG) };
+        let delegated = self.privilege < Privilege::Machine && (delegation >> (cause & 0x1F)) & 1 != 0;
+        let (epc, cause_csr, tval, tvec) = if delegated { (SEPC, SCAUSE, STVAL, STVEC) } else { (MEPC, MCAUSE, MTVAL, MTVEC) };
+
+        self.csrs.write(epc, self.pc);
+        self.csrs.write(cause_csr, cause);
+        self.csrs.write(tval, value);
+
+        // stack interrupt enable and the previous privilege
+        let mstatus = self.csrs.read(MSTATUS);
+        let mstatus = if delegated {
+            let spie = if mstatus & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+            let spp = if self.privilege == Privilege::Supervisor { MSTATUS_SPP } else { 0 };
+            (mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) | spie | spp
+        } else {
+            let mpie = if mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+            let mpp = (self.privilege as u32) << 11;
+            (mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp
+        };
+        self.csrs.write(MSTATUS, mstatus);
+        self.privilege = if delegated { Privilege::Supervisor } else { Privilege::Machine };
+
+        // vectored mode only applies to interrupts
+        let tvec = self.csrs.read(tvec);
+        let base = tvec & 0xFFFFFFFC;
+        if tvec & 0b11 == 1 && interrupt {
+            self.pc = base.wrapping_add(4 * (cause & 0x7FFFFFFF));
         } else {
-            let neg = (amount ^ 0b11111111111111111111) + 1;
-            self.pc -= neg;
+            self.pc = base;
         }
     }
 
-    fn jalr(&mut self, instruction: u32) {
-        let rd_index = Self::extract_rd_register(instruction);
-        let rs1_index = Self::extract_rs1_register(instruction);
-        let immediate = Self::extract_immediate_11_0(instruction);
-        let mut total_address = self.registers.read(rs1_index);
+    fn read(&mut self, address: u32, size: AccessSize) -> Result<u32, Exception> {
+        self.debugger.access(address, size.bytes(), WatchKind::Read);
+        if let Some(log) = &mut self.commit_log {
+            log.load(address, size);
+        }
+        let physical = self.translate(address, Access::Load)?;
+        self.bus.read(physical, size).map_err(|_| Exception::LoadAccessFault(address))
+    }
 
-        self.registers.write(rd_index, self.pc + 4);
+    fn write(&mut self, address: u32, size: AccessSize, value: u32) -> Result<(), Exception> {
+        self.debugger.access(address, size.bytes(), WatchKind::Write);
+        if let Some(log) = &mut self.commit_log {
+            log.store(address, size, value);
+        }
+        let physical = self.translate(address, Access::Store)?;
+        self.bus.write(physical, size, value).map_err(|_| Exception::StoreAccessFault(address))
+    }
 
-        let sign   = immediate & 0b100000000000;
-        let amount = immediate & 0b011111111111;
+    fn lui(&mut self, rd: u8, immediate: u32) {
+        // store immediate value in destination register
+        self.registers.write(rd, immediate);
+        // increment program counter
+        self.pc = self.pc.wrapping_add(4);
+    }
 
-        if sign == 0 {
-            total_address += amount as u32;
-        } else {
-            let neg = (amount ^ 0b11111111111) + 1;
-            total_address -= neg as u32;
-        }
-
-        total_address &= 0xFFFFFFFE;
-
-        self.pc = total_address;
-    }
-
-    fn branch(&mut self, instruction: u32) {
-        let rs1_index = Self::extract_rs1_register(instruction);
-        let rs2_index = Self::extract_rs2_register(instruction);
-        let rs1 = self.registers.read(rs1_index);
-        let rs2 = self.registers.read(rs2_index);
-        let immediate = Self::extract_immediate_12_1(instruction);
-        let mut branch = false;
-
-        if BranchType::check(instruction, BranchType::BEQ) {
-            // beq
-            branch = rs1 == rs2;
-        } else if BranchType::check(instruction, BranchType::BNE) {
-            // bne
-            branch = rs1 != rs2;
-        } else if BranchType::check(instruction, BranchType::BLT) {
-            // blt
-            branch = (rs1 as i32) < (rs2 as i32);
-        } else if BranchType::check(instruction, BranchType::BGE) {
-            // bge
-            branch = (rs1 as i32) >= (rs2 as i32);
-        } else if BranchType::check(instruction, BranchType::BLTU) {
-            // bltu
-            branch = rs1 < rs2;
-        } else if BranchType::check(instruction, BranchType::BGEU) {
-            // bgeu
-            branch = rs1 >= rs2;
+    fn auipc(&mut self, rd: u8, immediate: u32) {
+        // add immediate value to the address of this instruction
+        self.registers.write(rd, self.pc.wrapping_add(immediate));
+        // increment program counter
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    fn jal(&mut self, rd: u8, offset: u32) {
+        let target = self.pc.wrapping_add(offset);
+
+        if target & 0b11 != 0 {
+            self.raise(Exception::InstructionAddressMisaligned(target));
+            return;
         }
 
+        self.registers.write(rd, self.pc.wrapping_add(4));
+        self.pc = target;
+    }
+
+    fn jalr(&mut self, rd: u8, rs1: u8, offset: u32) {
+        let target = self.registers.read(rs1).wrapping_add(offset) & 0xFFFFFFFE;
+
+        if target & 0b11 != 0 {
+            self.raise(Exception::InstructionAddressMisaligned(target));
+            return;
+        }
+
+        self.registers.write(rd, self.pc.wrapping_add(4));
+        self.pc = target;
+    }
+
+    fn math(&mut self, operation: MathOperation, rd: u8, a: u32, b: u32) {
+        self.registers.write(rd, operation.apply(a, b));
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    fn branch(&mut self, condition: Condition, rs1: u8, rs2: u8, offset: u32) {
+        let rs1 = self.registers.read(rs1);
+        let rs2 = self.registers.read(rs2);
+
+        let branch = match condition {
+            Condition::Equal => rs1 == rs2,
+            Condition::NotEqual => rs1 != rs2,
+            Condition::LessThan => (rs1 as i32) < (rs2 as i32),
+            Condition::GreaterEqual => (rs1 as i32) >= (rs2 as i32),
+            Condition::LessThanUnsigned => rs1 < rs2,
+            Condition::GreaterEqualUnsigned => rs1 >= rs2,
+        };
+
         if branch {
-            let sign = immediate & 0b1000000000000;
-            let amount = immediate & 0b111111111111;
-
-            if sign == 0 {
-                self.pc += amount as u32;
-            } else {
-                let neg = (amount ^ 0b111111111111) as u32 + 1;
-                self.pc -= neg;
+            let target = self.pc.wrapping_add(offset);
+
+            // only taken branches check the target
+            if target & 0b11 != 0 {
+                self.raise(Exception::InstructionAddressMisaligned(target));
+                return;
             }
+
+            self.pc = target;
         } else {
-            self.pc += 4;
+            self.pc = self.pc.wrapping_add(4);
         }
     }
 
-    fn load(&mut self, instruction: u32) {
-        let rd_index = Self::extract_rd_register(instruction);
-        let rs1_index = Self::extract_rs1_register(instruction);
-        let immediate = Self::extract_immediate_11_0(instruction);
-        let mut total_address = self.registers.read(rs1_index);
+    fn load(&mut self, size: AccessSize, signed: bool, rd: u8, rs1: u8, offset: u32) {
+        let address = self.registers.read(rs1).wrapping_add(offset);
+
+        let value = match self.read(address, size) {
+            Ok(value) => value,
+            Err(exception) => {
+                self.raise(exception);
+                return;
+            }
+        };
+
+        // lb and lh sign extend, lbu and lhu zero extend
+        let value = match (size, signed) {
+            (AccessSize::Byte, true) => value as u8 as i8 as i32 as u32,
+            (AccessSize::Half, true) => value as u16 as i16 as i32 as u32,
+            _ => value,
+        };
+        self.registers.write(rd, value);
+
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    fn store(&mut self, size: AccessSize, rs1: u8, rs2: u8, offset: u32) {
+        let value = self.registers.read(rs2);
+        let address = self.registers.read(rs1).wrapping_add(offset);
 
-        let sign   = immediate & 0b100000000000;
-        let amount = immediate & 0b011111111111;
-        let neg = (amount ^ 0b11111111111) + 1;
+        if let Err(exception) = self.write(address, size, value) {
+            self.raise(exception);
+            return;
+        }
+
+        self.pc = self.pc.wrapping_add(4);
+    }
 
-        if sign == 0 {
-            total_address += amount as u32;
+    // the reservation is kept by the bus, where writes of the other harts break it
+    fn load_reserved(&mut self, rd: u8, rs1: u8) {
+        let address = self.registers.read(rs1);
+        if address & 0b11 != 0 {
+            self.raise(Exception::LoadAddressMisaligned(address));
+            return;
+        }
+
+        let reserved = self.translate(address, Access::Load).and_then(|physical| Ok((physical, self.read(address, AccessSize::Word)?)));
+        let (physical, value) = match reserved {
+            Ok(reserved) => reserved,
+            Err(exception) => {
+                self.raise(exception);
+                return;
+            }
+        };
+        let hart = self.csrs.read(MHARTID);
+        self.bus.reserve(hart, physical);
+        self.registers.write(rd, value);
+
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    // rd is 0 when the store happened and 1 when the reservation was lost
+    fn store_conditional(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        let address = self.registers.read(rs1);
+        if address & 0b11 != 0 {
+            self.raise(Exception::StoreAddressMisaligned(address));
+            return;
+        }
+
+        let physical = match self.translate(address, Access::Store) {
+            Ok(physical) => physical,
+            Err(exception) => {
+                self.raise(exception);
+                return;
+            }
+        };
+        let hart = self.csrs.read(MHARTID);
+        if self.bus.release(hart, physical) {
+            if let Err(exception) = self.write(address, AccessSize::Word, self.registers.read(rs2)) {
+                self.raise(exception);
+                return;
+            }
+            self.registers.write(rd, 0);
         } else {
-            total_address -= neg as u32;
+            self.registers.write(rd, 1);
         }
 
-        if LoadType::check(instruction, LoadType::LB) {
-            // lb
-            let load_u8 = self.ram.read_byte(total_address);
-            let load_i8 = load_u8 as i8;
-            let load_i32 = load_i8 as i32;
-            self.registers.write(rd_index, load_i32 as u32);
+        self.pc = self.pc.wrapping_add(4);
+    }
 
-        } else if LoadType::check(instruction, LoadType::LH) {
-            // lh
-            let load_u16 = self.ram.read_half(total_address);
-            let load_i16 = load_u16 as i16;
-            let load_i32 = load_i16 as i32;
-            self.registers.write(rd_index, load_i32 as u32);
+    // harts interleave by instruction so the read and the write can not be separated
+    fn atomic(&mut self, operation: AtomicOperation, rd: u8, rs1: u8, rs2: u8) {
+        let address = self.registers.read(rs1);
+        if address & 0b11 != 0 {
+            self.raise(Exception::StoreAddressMisaligned(address));
+            return;
+        }
 
-        } else if LoadType::check(instruction, LoadType::LW) {
-            // lw
-            let load_u32 = self.ram.read_word(total_address);
-            self.registers.write(rd_index, load_u32);
+        // faults are reported as store faults, the read only happens when the write is allowed
+        let value = match self.translate(address, Access::Store) {
+            Ok(_) => self.read(address, AccessSize::Word).map_err(|_| Exception::StoreAccessFault(address)),
+            Err(exception) => Err(exception),
+        };
+        let value = match value {
+            Ok(value) => value,
+            Err(exception) => {
+                self.raise(exception);
+                return;
+            }
+        };
+        if let Err(exception) = self.write(address, AccessSize::Word, operation.apply(value, self.registers.read(rs2))) {
+            self.raise(exception);
+            return;
+        }
+        self.registers.write(rd, value);
 
-        } else if LoadType::check(instruction, LoadType::LBU) {
-            // lbu
-            let load_u8 = self.ram.read_byte(total_address);
-            self.registers.write(rd_index, load_u8 as u32);
+        self.pc = self.pc.wrapping_add(4);
+    }
 
-        } else if LoadType::check(instruction, LoadType::LHU) {
-            // lhu
-            let load_u16 = self.ram.read_half(total_address);
-            self.registers.write(rd_index, load_u16 as u32);
+    fn csr(&mut self, instruction: u32, operation: CSROperation, immediate: bool, rd: u8, rs1: u8, csr: u16) {
+        // missing csrs, csrs of a higher privilege and satp when supervisor mode may not touch it
+        let trapped_satp = csr == SATP && self.privilege == Privilege::Supervisor && self.csrs.read(MSTATUS) & MSTATUS_TVM != 0;
+        if write_mask(csr).is_none() || privilege(csr) > self.privilege as u32 || trapped_satp {
+            self.raise(Exception::IllegalInstruction(instruction));
+            return;
+        }
+        let source = if immediate { rs1 as u32 } else { self.registers.read(rs1) };
+
+        // new value and whether the csr gets written, set and clear only write with a nonzero rs1
+        let (new, write) = match operation {
+            CSROperation::ReadWrite => (source, true),
+            CSROperation::ReadSet => (self.csrs.read(csr) | source, rs1 != 0),
+            CSROperation::ReadClear => (self.csrs.read(csr) & !source, rs1 != 0),
+        };
+
+        if write && CSRs::is_read_only(csr) {
+            self.raise(Exception::IllegalInstruction(instruction));
+            return;
+        }
 
+        // warl fields keep their legal value
+        let old = match csr {
+            TIME | TIMEH => match self.time(csr) {
+                Some(time) => time,
+                None => {
+                    self.raise(Exception::IllegalInstruction(instruction));
+                    return;
+                }
+            },
+            csr => self.csrs.read(csr),
+        };
+        if let Some(new) = legalize(csr, old, new).filter(|_| write) {
+            self.csrs.write(csr, new);
         }
+        self.registers.write(rd, old);
 
-        self.pc += 4;
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    // half of mtime, lower privileges need the time bit of every counter enable above them
+    fn time(&mut self, csr: u16) -> Option<u32> {
+        let enabled = match self.privilege {
+            Privilege::Machine => COUNTEREN_TM,
+            Privilege::Supervisor => self.csrs.read(MCOUNTEREN),
+            Privilege::User => self.csrs.read(MCOUNTEREN) & self.csrs.read(SCOUNTEREN),
+        };
+        if enabled & COUNTEREN_TM == 0 {
+            return None;
+        }
+
+        let time = self.bus.time()?;
+        Some(if csr == TIMEH { (time >> 32) as u32 } else { time as u32 })
+    }
+
+    fn mret(&mut self, instruction: u32) {
+        if self.privilege < Privilege::Machine {
+            self.raise(Exception::IllegalInstruction(instruction));
+            return;
+        }
+
+        // return to the privilege in mpp, which falls back to user mode
+        let mstatus = self.csrs.read(MSTATUS);
+        let privilege = Privilege::from_bits(mstatus >> 11);
+        let mie = if mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        let mprv = if privilege == Privilege::Machine { mstatus & MSTATUS_MPRV } else { 0 };
+        let mstatus = (mstatus & !(MSTATUS_MIE | MSTATUS_MPP | MSTATUS_MPRV)) | mie | MSTATUS_MPIE | mprv;
+        self.csrs.write(MSTATUS, mstatus);
+        self.privilege = privilege;
+
+        // mepc is always 4 byte aligned without compressed instructions
+        self.pc = self.csrs.read(MEPC) & 0xFFFFFFFC;
+    }
+
+    fn sret(&mut self, instruction: u32) {
+        let mstatus = self.csrs.read(MSTATUS);
+        if self.privilege < Privilege::Supervisor || self.privilege == Privilege::Supervisor && mstatus & MSTATUS_TSR != 0 {
+            self.raise(Exception::IllegalInstruction(instruction));
+            return;
+        }
+
+        let privilege = if mstatus & MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User };
+        let sie = if mstatus & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+        let mstatus = (mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | sie | MSTATUS_SPIE;
+        self.csrs.write(MSTATUS, mstatus);
+        self.privilege = privilege;
+
+        self.pc = self.csrs.read(SEPC) & 0xFFFFFFFC;
     }
 }
 
+// laid out as the plain array native code addresses
+#[repr(C)]
 pub struct Registers {
     registers: [u32; 32],
 }
@@ -290,62 +725,115 @@ impl Registers {
     }
 }
 
+impl Registers {
+    pub fn save(&self, state: &mut Writer) {
+        self.registers.iter().for_each(|&value| state.u32(value));
+    }
+
+    pub fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        for register in self.registers.iter_mut() {
+            *register = state.u32()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct RAM {
     data: Vec<u8>,
+    // bumped by every write to a page so decoded instructions of it are dropped
+    generations: Vec<u64>,
 }
 
 impl RAM {
     pub fn new(size: u32) -> Self {
-        RAM { data: vec![0; (size * 4) as usize] }
+        let bytes = size * 4;
+        RAM { data: vec![0; bytes as usize], generations: vec![0; bytes.div_ceil(cache::PAGE_SIZE) as usize] }
     }
 
     pub fn read_byte(&self, address: u32) -> u8 {
         self.data[address as usize]
     }
 
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    #[allow(clippy::identity_op)]
     pub fn read_half(&self, address:u32) -> u16 {
-        let b0 = (self.data[(address + 0) as usize] as u16) << 8;
-        let b1 = (self.data[(address + 1) as usize] as u16) << 0;
+        let b0 = (self.data[(address + 0) as usize] as u16) << 0;
+        let b1 = (self.data[(address + 1) as usize] as u16) << 8;
 
         b0 | b1
     }
 
+    #[allow(clippy::identity_op)]
     pub fn read_word(&self, address: u32) -> u32 {
-        let b0 = (self.data[(address + 0) as usize] as u32) << 24;
-        let b1 = (self.data[(address + 1) as usize] as u32) << 16;
-        let b2 = (self.data[(address + 2) as usize] as u32) << 8;
-        let b3 = (self.data[(address + 3) as usize] as u32) << 0;
+        let b0 = (self.data[(address + 0) as usize] as u32) << 0;
+        let b1 = (self.data[(address + 1) as usize] as u32) << 8;
+        let b2 = (self.data[(address + 2) as usize] as u32) << 16;
+        let b3 = (self.data[(address + 3) as usize] as u32) << 24;
 
         b0 | b1 | b2 | b3
     }
 
     pub fn write_byte(&mut self, address: u32, value: u8) {
         self.data[address as usize] = value;
+        self.touch(address, 1);
     }
 
+    #[allow(clippy::identity_op)]
     pub fn write_half(&mut self, address: u32, value: u16) {
-        self.data[(address + 0) as usize] = (value >> 8) as u8;
-        self.data[(address + 1) as usize] = (value >> 0) as u8;
+        self.data[(address + 0) as usize] = (value >> 0) as u8;
+        self.data[(address + 1) as usize] = (value >> 8) as u8;
+        self.touch(address, 2);
     }
 
+    #[allow(clippy::identity_op)]
     pub fn write_word(&mut self, address: u32, value: u32) {
-        self.data[(address + 0) as usize] = (value >> 24) as u8;
-        self.data[(address + 1) as usize] = (value >> 16) as u8;
-        self.data[(address + 2) as usize] = (value >>  8) as u8;
-        self.data[(address + 3) as usize] = (value >>  0) as u8;
+        self.data[(address + 0) as usize] = (value >>  0) as u8;
+        self.data[(address + 1) as usize] = (value >>  8) as u8;
+        self.data[(address + 2) as usize] = (value >> 16) as u8;
+        self.data[(address + 3) as usize] = (value >> 24) as u8;
+        self.touch(address, 4);
+    }
+
+    pub fn page_generation(&self, address: u32) -> u64 {
+        self.generations[(address / cache::PAGE_SIZE) as usize]
+    }
+
+    // bytes and page generations for native code, which bumps the generations itself
+    #[cfg(feature = "jit")]
+    pub(crate) fn raw(&mut self) -> (*mut u8, *mut u64) {
+        (self.data.as_mut_ptr(), self.generations.as_mut_ptr())
+    }
+
+    // unaligned writes may end in the next page
+    fn touch(&mut self, address: u32, length: u32) {
+        let first = address / cache::PAGE_SIZE;
+        let last = (address + length - 1) / cache::PAGE_SIZE;
+        for page in first..=last {
+            self.generations[page as usize] += 1;
+        }
     }
 
     pub fn inspect(&self, start: u32, length: u32) -> &[u8] {
         &self.data[start as usize..(start+length) as usize]
     }
 
+    #[allow(clippy::identity_op)]
     pub fn inspect_word(&self, start: u32, length: u32) -> Vec<u32> {
         let mut words = Vec::new();
         for i in (start..start+(length*4)).step_by(4) {
-            let b0 = (self.data[(i + 0) as usize] as u32) << 24;
-            let b1 = (self.data[(i + 1) as usize] as u32) << 16;
-            let b2 = (self.data[(i + 2) as usize] as u32) << 8;
-            let b3 = (self.data[(i + 3) as usize] as u32) << 0;
+            let b0 = (self.data[(i + 0) as usize] as u32) << 0;
+            let b1 = (self.data[(i + 1) as usize] as u32) << 8;
+            let b2 = (self.data[(i + 2) as usize] as u32) << 16;
+            let b3 = (self.data[(i + 3) as usize] as u32) << 24;
 
             words.push(b0 | b1 | b2 | b3);
         }
@@ -353,3 +841,20 @@ impl RAM {
         words
     }
 }
+
+impl RAM {
+    pub fn save(&self, state: &mut Writer) {
+        state.bytes(&self.data);
+    }
+
+    pub fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        let data = state.bytes()?;
+        if data.len() != self.data.len() {
+            return Err(SnapshotError::Mismatch(format!("snapshot has {} bytes of ram instead of {}", data.len(), self.data.len())));
+        }
+
+        self.data.copy_from_slice(data);
+        self.generations.iter_mut().for_each(|generation| *generation += 1);
+        Ok(())
+    }
+}
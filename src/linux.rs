@@ -0,0 +1,604 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{CPU, RAM};
+use crate::bus::{AccessSize, Bus, BusError};
+use crate::csr::{MCAUSE, MEPC, MTVAL, MTVEC};
+use crate::devices::virtio::rng::VirtioRNG;
+use crate::elf::ELF;
+use crate::instructions::csr::ECALL;
+
+// asm-generic syscall numbers as used by rv32
+pub const SYS_IOCTL: u32           = 29;
+pub const SYS_OPENAT: u32          = 56;
+pub const SYS_CLOSE: u32           = 57;
+pub const SYS_LLSEEK: u32          = 62;
+pub const SYS_READ: u32            = 63;
+pub const SYS_WRITE: u32           = 64;
+pub const SYS_WRITEV: u32          = 66;
+pub const SYS_FSTAT: u32           = 80;
+pub const SYS_EXIT: u32            = 93;
+pub const SYS_EXIT_GROUP: u32      = 94;
+pub const SYS_SET_TID_ADDRESS: u32 = 96;
+pub const SYS_RT_SIGACTION: u32    = 134;
+pub const SYS_RT_SIGPROCMASK: u32  = 135;
+pub const SYS_UNAME: u32           = 160;
+pub const SYS_GETPID: u32          = 172;
+pub const SYS_GETUID: u32          = 174;
+pub const SYS_GETEUID: u32         = 175;
+pub const SYS_GETGID: u32          = 176;
+pub const SYS_GETEGID: u32         = 177;
+pub const SYS_GETTID: u32          = 178;
+pub const SYS_BRK: u32             = 214;
+pub const SYS_MUNMAP: u32          = 215;
+pub const SYS_MMAP2: u32           = 222;
+pub const SYS_MPROTECT: u32        = 226;
+pub const SYS_GETRANDOM: u32       = 278;
+pub const SYS_CLOCK_GETTIME64: u32 = 403;
+
+pub const ENOENT: i32 = 2;
+pub const EIO: i32    = 5;
+pub const EBADF: i32  = 9;
+pub const ENOMEM: i32 = 12;
+pub const EACCES: i32 = 13;
+pub const EFAULT: i32 = 14;
+pub const EINVAL: i32 = 22;
+pub const ENOTTY: i32 = 25;
+pub const ENOSYS: i32 = 38;
+
+pub const AT_FDCWD: u32 = -100i32 as u32;
+
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32   = 0o2;
+pub const O_CREAT: u32  = 0o100;
+pub const O_TRUNC: u32  = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+pub const MAP_FIXED: u32     = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x20;
+
+pub const CLOCK_REALTIME: u32  = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+pub const AT_NULL: u32   = 0;
+pub const AT_PHDR: u32   = 3;
+pub const AT_PHENT: u32  = 4;
+pub const AT_PHNUM: u32  = 5;
+pub const AT_PAGESZ: u32 = 6;
+pub const AT_ENTRY: u32  = 9;
+pub const AT_UID: u32    = 11;
+pub const AT_EUID: u32   = 12;
+pub const AT_GID: u32    = 13;
+pub const AT_EGID: u32   = 14;
+pub const AT_RANDOM: u32 = 25;
+
+pub const PAGE_SIZE: u32  = 4096;
+pub const STACK_SIZE: u32 = 0x0010_0000;
+
+// most iovecs a single writev takes, like the kernel
+pub const IOV_MAX: u32 = 1024;
+
+// guest buffers are staged through host memory at most this many bytes at a time
+pub(crate) const IO_CHUNK: u32 = 0x0001_0000;
+
+// exceptions jump outside of memory so faults end the process
+const TRAP_VECTOR: u32 = 0xFFFF_FFF0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Exited(i32),
+    // unhandled exception with mcause, mepc and mtval
+    Fault { cause: u32, pc: u32, value: u32 },
+}
+
+enum Descriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+// statically linked linux program running without a kernel
+pub struct Process {
+    cpu: CPU,
+    files: Vec<Option<Descriptor>>,
+    root: Option<PathBuf>,
+    stdin: Option<VecDeque<u8>>,
+    stdout: Option<Vec<u8>>,
+    stderr: Option<Vec<u8>>,
+    brk: u32,
+    brk_start: u32,
+    // mappings are allocated downwards from the bottom of the stack
+    mmap_bottom: u32,
+    start: Instant,
+    random: VirtioRNG,
+    exit: Option<Exit>,
+}
+
+impl Process {
+    // memory size in bytes, memory starts at address 0
+    pub fn new(elf: &ELF, args: &[&str], env: &[&str], memory_size: u32) -> Result<Self, BusError> {
+        let mut cpu = CPU::new(memory_size / 4);
+        elf.load(cpu.bus())?;
+
+        let brk_start = Self::page_align(elf.end());
+        let top = cpu.ram().size() & !0xF;
+        let mmap_bottom = top.checked_sub(STACK_SIZE).filter(|&bottom| bottom >= brk_start).ok_or(BusError::AccessFault(top))?;
+
+        let mut process = Process {
+            cpu,
+            files: vec![Some(Descriptor::Stdin), Some(Descriptor::Stdout), Some(Descriptor::Stderr)],
+            root: None,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            brk: brk_start,
+            brk_start,
+            mmap_bottom,
+            start: Instant::now(),
+            random: VirtioRNG::host(),
+            exit: None,
+        };
+
+        let sp = process.setup_stack(elf, args, env, top).map_err(|_| BusError::AccessFault(top))?;
+        process.cpu.registers().write(2, sp);
+        process.cpu.csrs().write(MTVEC, TRAP_VECTOR);
+        *process.cpu.pc() = elf.entry;
+
+        Ok(process)
+    }
+
+    pub fn cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    // host directory that openat is confined to, files cannot be opened without one
+    pub fn set_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.root = Some(root.as_ref().to_path_buf());
+    }
+
+    // read stdin from a buffer instead of the host
+    pub fn set_stdin(&mut self, data: &[u8]) {
+        self.stdin = Some(data.iter().copied().collect());
+    }
+
+    // collect stdout and stderr instead of writing to the host
+    pub fn capture_output(&mut self) {
+        self.stdout = Some(Vec::new());
+        self.stderr = Some(Vec::new());
+    }
+
+    pub fn take_stdout(&mut self) -> Vec<u8> {
+        self.stdout.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn take_stderr(&mut self) -> Vec<u8> {
+        self.stderr.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn brk(&self) -> u32 {
+        self.brk
+    }
+
+    pub fn exit(&self) -> Option<Exit> {
+        self.exit
+    }
+
+    pub fn step(&mut self) -> Option<Exit> {
+        if self.exit.is_some() {
+            return self.exit;
+        }
+
+        let pc = *self.cpu.pc();
+        if self.cpu.bus().read(pc, AccessSize::Word) == Ok(ECALL) {
+            self.syscall();
+            *self.cpu.pc() += 4;
+            return self.exit;
+        }
+
+        self.cpu.tick();
+        if *self.cpu.pc() == TRAP_VECTOR {
+            let csrs = self.cpu.csrs();
+            self.exit = Some(Exit::Fault { cause: csrs.read(MCAUSE), pc: csrs.read(MEPC), value: csrs.read(MTVAL) });
+        }
+
+        self.exit
+    }
+
+    // runs until the process exits or the step limit is reached
+    pub fn run(&mut self, steps: u64) -> Option<Exit> {
+        for _ in 0..steps {
+            if let Some(exit) = self.step() {
+                return Some(exit);
+            }
+        }
+
+        None
+    }
+
+    // argc, argv, envp and auxv followed by the strings, sp is 16 byte aligned
+    fn setup_stack(&mut self, elf: &ELF, args: &[&str], env: &[&str], top: u32) -> Result<u32, i32> {
+        let mut sp = top;
+        let mut push = |process: &mut Self, data: &[u8]| -> Result<u32, i32> {
+            sp = sp.checked_sub(data.len() as u32).ok_or(ENOMEM)?;
+            process.write_memory(sp, data)?;
+            Ok(sp)
+        };
+
+        let mut arg_pointers = Vec::new();
+        for arg in args {
+            arg_pointers.push(push(self, &[arg.as_bytes(), &[0]].concat())?);
+        }
+        let mut env_pointers = Vec::new();
+        for variable in env {
+            env_pointers.push(push(self, &[variable.as_bytes(), &[0]].concat())?);
+        }
+        let mut random = [0; 16];
+        self.random.fill(&mut random);
+        let random = push(self, &random)?;
+
+        let mut table = vec![args.len() as u32];
+        table.extend(arg_pointers);
+        table.push(0);
+        table.extend(env_pointers);
+        table.push(0);
+        let auxv = [
+            (AT_PHDR, elf.program_headers.unwrap_or(0)),
+            (AT_PHENT, elf.program_header_size),
+            (AT_PHNUM, elf.program_header_count),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_RANDOM, random),
+            (AT_NULL, 0),
+        ];
+        table.extend(auxv.iter().flat_map(|&(key, value)| [key, value]));
+
+        let sp = (sp.checked_sub(table.len() as u32 * 4).ok_or(ENOMEM)?) & !0xF;
+        let table: Vec<u8> = table.iter().flat_map(|value| value.to_le_bytes()).collect();
+        self.write_memory(sp, &table)?;
+
+        Ok(sp)
+    }
+
+    fn syscall(&mut self) {
+        let registers = self.cpu.registers();
+        let number = registers.read(17);
+        let a = [10, 11, 12, 13, 14, 15].map(|register| registers.read(register));
+
+        let result = match number {
+            SYS_READ => self.read(a[0], a[1], a[2]),
+            SYS_WRITE => self.write(a[0], a[1], a[2]),
+            SYS_WRITEV => self.writev(a[0], a[1], a[2]),
+            SYS_OPENAT => self.openat(a[0], a[1], a[2]),
+            SYS_CLOSE => self.close(a[0]),
+            SYS_LLSEEK => self.llseek(a[0], a[1], a[2], a[3], a[4]),
+            SYS_FSTAT => self.fstat(a[0], a[1]),
+            SYS_BRK => Ok(self.set_brk(a[0])),
+            SYS_MMAP2 => self.mmap(a[0], a[1], a[3], a[4], a[5]),
+            SYS_MUNMAP | SYS_MPROTECT | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit = Some(Exit::Exited(a[0] as i32));
+                Ok(0)
+            }
+            SYS_CLOCK_GETTIME64 => self.clock_gettime(a[0], a[1]),
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            SYS_IOCTL => Err(ENOTTY),
+            SYS_UNAME => self.uname(a[0]),
+            SYS_GETRANDOM => self.getrandom(a[0], a[1]),
+            _ => Err(ENOSYS),
+        };
+
+        let value = result.unwrap_or_else(|errno| (-errno) as u32);
+        self.cpu.registers().write(10, value);
+    }
+
+    // larger reads return early like reads of a pipe
+    fn read(&mut self, fd: u32, buffer: u32, count: u32) -> Result<u32, i32> {
+        self.check_memory(buffer, count)?;
+        let mut data = vec![0; count.min(IO_CHUNK) as usize];
+
+        let read = match self.descriptor(fd)? {
+            Descriptor::Stdin => match &mut self.stdin {
+                Some(input) => {
+                    let length = input.len().min(data.len());
+                    input.drain(..length).zip(data.iter_mut()).for_each(|(byte, target)| *target = byte);
+                    length
+                }
+                None => io::stdin().read(&mut data).map_err(Self::errno)?,
+            },
+            Descriptor::File(file) => file.read(&mut data).map_err(Self::errno)?,
+            _ => return Err(EBADF),
+        };
+
+        self.write_memory(buffer, &data[..read])?;
+        Ok(read as u32)
+    }
+
+    fn write(&mut self, fd: u32, buffer: u32, count: u32) -> Result<u32, i32> {
+        let data = self.read_memory(buffer, count)?;
+
+        match self.descriptor(fd)? {
+            Descriptor::Stdout => match &mut self.stdout {
+                Some(output) => output.extend_from_slice(&data),
+                None => io::stdout().write_all(&data).and_then(|_| io::stdout().flush()).map_err(Self::errno)?,
+            },
+            Descriptor::Stderr => match &mut self.stderr {
+                Some(output) => output.extend_from_slice(&data),
+                None => io::stderr().write_all(&data).map_err(Self::errno)?,
+            },
+            Descriptor::File(file) => file.write_all(&data).map_err(Self::errno)?,
+            Descriptor::Stdin => return Err(EBADF),
+        }
+
+        Ok(count)
+    }
+
+    fn writev(&mut self, fd: u32, vectors: u32, count: u32) -> Result<u32, i32> {
+        if count > IOV_MAX {
+            return Err(EINVAL);
+        }
+
+        let mut written = 0u32;
+        for index in 0..count {
+            let address = index.checked_mul(8).and_then(|offset| vectors.checked_add(offset)).ok_or(EFAULT)?;
+            let vector = self.read_memory(address, 8)?;
+            let base = u32::from_le_bytes(vector[0..4].try_into().unwrap());
+            let length = u32::from_le_bytes(vector[4..8].try_into().unwrap());
+            written = written.wrapping_add(self.write(fd, base, length)?);
+        }
+
+        Ok(written)
+    }
+
+    fn getrandom(&mut self, buffer: u32, count: u32) -> Result<u32, i32> {
+        self.check_memory(buffer, count)?;
+
+        let mut data = vec![0; count.min(IO_CHUNK) as usize];
+        for offset in (0..count).step_by(IO_CHUNK as usize) {
+            let length = (count - offset).min(IO_CHUNK) as usize;
+            self.random.fill(&mut data[..length]);
+            self.write_memory(buffer + offset, &data[..length])?;
+        }
+
+        Ok(count)
+    }
+
+    fn openat(&mut self, directory: u32, path: u32, flags: u32) -> Result<u32, i32> {
+        let path = self.read_string(path)?;
+        let root = self.root.as_ref().ok_or(EACCES)?;
+        if directory != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+        let host = confine(root, &path)?;
+
+        let access = flags & 0b11;
+        let file = OpenOptions::new()
+            .read(access != O_WRONLY)
+            .write(access == O_WRONLY || access == O_RDWR)
+            .append(flags & O_APPEND != 0)
+            .create(flags & O_CREAT != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .open(host)
+            .map_err(Self::errno)?;
+
+        let descriptor = Some(Descriptor::File(file));
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = descriptor;
+                Ok(fd as u32)
+            }
+            None => {
+                self.files.push(descriptor);
+                Ok(self.files.len() as u32 - 1)
+            }
+        }
+    }
+
+    fn close(&mut self, fd: u32) -> Result<u32, i32> {
+        self.descriptor(fd)?;
+        self.files[fd as usize] = None;
+        Ok(0)
+    }
+
+    fn llseek(&mut self, fd: u32, high: u32, low: u32, result: u32, whence: u32) -> Result<u32, i32> {
+        let offset = ((high as u64) << 32 | low as u64) as i64;
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+
+        let Descriptor::File(file) = self.descriptor(fd)? else {
+            return Err(EINVAL);
+        };
+        let position = file.seek(position).map_err(Self::errno)?;
+        self.write_memory(result, &position.to_le_bytes())?;
+        Ok(0)
+    }
+
+    // struct stat64 of asm-generic, 104 bytes
+    fn fstat(&mut self, fd: u32, buffer: u32) -> Result<u32, i32> {
+        let (mode, size) = match self.descriptor(fd)? {
+            Descriptor::File(file) => {
+                let metadata = file.metadata().map_err(Self::errno)?;
+                let kind: u32 = if metadata.is_dir() { 0o040000 } else { 0o100000 };
+                (kind | 0o644, metadata.len())
+            }
+            // character devices
+            _ => (0o020620u32, 0),
+        };
+
+        let mut stat = [0u8; 104];
+        stat[16..20].copy_from_slice(&mode.to_le_bytes());
+        stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+        stat[48..56].copy_from_slice(&size.to_le_bytes());
+        stat[56..60].copy_from_slice(&PAGE_SIZE.to_le_bytes());
+        stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes());
+        self.write_memory(buffer, &stat)?;
+        Ok(0)
+    }
+
+    // returns the current break when the request cannot be satisfied
+    fn set_brk(&mut self, brk: u32) -> u32 {
+        if brk >= self.brk_start && brk <= self.mmap_bottom {
+            self.brk = brk;
+        }
+
+        self.brk
+    }
+
+    fn mmap(&mut self, address: u32, length: u32, flags: u32, fd: u32, page_offset: u32) -> Result<u32, i32> {
+        if length == 0 {
+            return Err(EINVAL);
+        }
+        let length = Self::page_align(length);
+
+        let address = if flags & MAP_FIXED != 0 {
+            self.check_memory(address, length)?;
+            address
+        } else {
+            let bottom = self.mmap_bottom.checked_sub(length).filter(|&bottom| bottom >= self.brk).ok_or(ENOMEM)?;
+            self.mmap_bottom = bottom;
+            bottom
+        };
+
+        self.check_memory(address, length)?;
+        let mut data = vec![0; length as usize];
+        if flags & MAP_ANONYMOUS == 0 {
+            let Descriptor::File(file) = self.descriptor(fd)? else {
+                return Err(EBADF);
+            };
+            let mut file = file.try_clone().map_err(Self::errno)?;
+            file.seek(SeekFrom::Start(page_offset as u64 * PAGE_SIZE as u64)).map_err(Self::errno)?;
+            let mut filled = 0;
+            while filled < data.len() {
+                match file.read(&mut data[filled..]).map_err(Self::errno)? {
+                    0 => break,
+                    read => filled += read,
+                }
+            }
+        }
+
+        self.write_memory(address, &data)?;
+        Ok(address)
+    }
+
+    fn clock_gettime(&mut self, clock: u32, buffer: u32) -> Result<u32, i32> {
+        let time = match clock {
+            CLOCK_REALTIME => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            CLOCK_MONOTONIC => self.start.elapsed(),
+            _ => return Err(EINVAL),
+        };
+
+        let mut timespec = Vec::with_capacity(16);
+        timespec.extend_from_slice(&time.as_secs().to_le_bytes());
+        timespec.extend_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+        self.write_memory(buffer, &timespec)?;
+        Ok(0)
+    }
+
+    fn uname(&mut self, buffer: u32) -> Result<u32, i32> {
+        let fields = ["Linux", "rust-risc-v", "6.1.0", "#1", "riscv32", ""];
+        let mut utsname = vec![0; fields.len() * 65];
+        for (index, field) in fields.iter().enumerate() {
+            utsname[index * 65..index * 65 + field.len()].copy_from_slice(field.as_bytes());
+        }
+
+        self.write_memory(buffer, &utsname)?;
+        Ok(0)
+    }
+
+    fn descriptor(&mut self, fd: u32) -> Result<&mut Descriptor, i32> {
+        self.files.get_mut(fd as usize).and_then(Option::as_mut).ok_or(EBADF)
+    }
+
+    fn check_memory(&mut self, address: u32, length: u32) -> Result<(), i32> {
+        if address as u64 + length as u64 > self.cpu.ram().size() as u64 {
+            return Err(EFAULT);
+        }
+
+        Ok(())
+    }
+
+    fn read_memory(&mut self, address: u32, length: u32) -> Result<Vec<u8>, i32> {
+        self.check_memory(address, length)?;
+        Ok(self.cpu.ram().inspect(address, length).to_vec())
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), i32> {
+        self.check_memory(address, data.len() as u32)?;
+        let ram: &mut RAM = self.cpu.ram();
+        for (offset, &byte) in data.iter().enumerate() {
+            ram.write_byte(address + offset as u32, byte);
+        }
+
+        Ok(())
+    }
+
+    fn read_string(&mut self, address: u32) -> Result<String, i32> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.cpu.bus().read(address.wrapping_add(bytes.len() as u32), AccessSize::Byte).map_err(|_| EFAULT)? as u8;
+            if byte == 0 {
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            if bytes.len() >= PAGE_SIZE as usize {
+                return Err(EINVAL);
+            }
+            bytes.push(byte);
+        }
+    }
+
+    fn page_align(value: u32) -> u32 {
+        value.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+    }
+
+    fn errno(error: io::Error) -> i32 {
+        match error.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            io::ErrorKind::PermissionDenied => EACCES,
+            _ => error.raw_os_error().unwrap_or(EIO),
+        }
+    }
+}
+
+// host path of a guest path inside root, symlinks may not lead out of it
+// only the host can create symlinks, so the checks do not race with the guest
+pub(crate) fn confine(root: &Path, path: &str) -> Result<PathBuf, i32> {
+    let mut host = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => host.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => return Err(EACCES),
+        }
+    }
+
+    let errno = |error: io::Error| match error.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        _ => EACCES,
+    };
+    let root = root.canonicalize().map_err(errno)?;
+    let inside = |path: PathBuf| if path.starts_with(&root) { Ok(path) } else { Err(EACCES) };
+
+    // the file itself may not exist yet when it is created
+    let Some(name) = host.file_name().map(|name| name.to_os_string()) else {
+        return Ok(root);
+    };
+    let parent = inside(root.join(&host).parent().unwrap().canonicalize().map_err(errno)?)?;
+    let file = parent.join(name);
+    match file.symlink_metadata() {
+        // a dangling link would create its target wherever it points
+        Ok(metadata) if metadata.file_type().is_symlink() => inside(file.canonicalize().map_err(|_| EACCES)?),
+        _ => Ok(file),
+    }
+}
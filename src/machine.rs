@@ -0,0 +1,221 @@
+use crate::{CPU, RAM};
+use crate::bus::{AccessSize, AccessSizes, Bus, BusError, SystemBus};
+use crate::devices::clint::{TimeBase, CLINT};
+use crate::devices::plic::PLIC;
+use crate::devices::uart::{UARTBackend, UART};
+use crate::devices::virtio::{self, VirtioMMIO};
+use crate::devices::virtio::block::{Disk, VirtioBlock};
+use crate::fdt;
+use crate::reverse::InputLog;
+use crate::sbi::{self, HartState, SystemReset, SBI};
+use crate::snapshot::{self, Reader, SnapshotError, Writer};
+use crate::trap::Privilege;
+
+pub const RAM_BASE: u32 = 0x8000_0000;
+
+// where opensbi fw_jump expects the next stage on rv32
+pub const KERNEL_OFFSET: u32 = 0x0040_0000;
+
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+pub const PLIC_SOURCES: u32 = 95;
+
+// top of ram kept free for the device tree
+pub const DTB_RESERVED: u32 = 0x0001_0000;
+
+pub enum Firmware {
+    // fw_jump image loaded at the start of ram, running in machine mode
+    OpenSBI(Vec<u8>),
+    // the kernel is entered in supervisor mode directly, traps into machine mode are serviced by the emulator
+    Native,
+}
+
+pub struct VirtConfig {
+    pub ram_size: u32,
+    pub firmware: Firmware,
+    pub kernel: Vec<u8>,
+    pub initrd: Option<Vec<u8>>,
+    pub disk: Option<Disk>,
+    pub console: Box<dyn UARTBackend>,
+    pub bootargs: Option<String>,
+    // logs the clock for a recorder, the console should be a replay backend on the same log
+    pub inputs: Option<InputLog>,
+}
+
+impl VirtConfig {
+    // ram size in bytes
+    pub fn new(ram_size: u32, kernel: Vec<u8>, console: Box<dyn UARTBackend>) -> Self {
+        VirtConfig {
+            ram_size,
+            firmware: Firmware::Native,
+            kernel,
+            initrd: None,
+            disk: None,
+            console,
+            bootargs: None,
+            inputs: None,
+        }
+    }
+}
+
+// machine modelled after the qemu virt board
+pub struct Virt {
+    cpu: CPU,
+    sbi: Option<SBI>,
+    dtb: u32,
+}
+
+impl Virt {
+    pub fn new(config: VirtConfig) -> Result<Self, BusError> {
+        let mut bus = SystemBus::new(RAM_BASE, RAM::new(config.ram_size / 4));
+        let time_base = match config.inputs {
+            Some(inputs) => TimeBase::Recorded(TIMEBASE_FREQUENCY as u64, inputs),
+            None => TimeBase::WallClock(TIMEBASE_FREQUENCY as u64),
+        };
+        bus.map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, time_base)))?;
+        bus.map(PLIC::BASE, PLIC::SIZE, AccessSizes::WORD, Box::new(PLIC::new(PLIC_SOURCES, 2)))?;
+        bus.map(UART::BASE, UART::SIZE, AccessSizes::BYTE, Box::new(UART::new(config.console)))?;
+        bus.connect_interrupt(UART::BASE, UART::IRQ)?;
+        if let Some(disk) = config.disk {
+            bus.map(virtio::BASE, virtio::SIZE, AccessSizes::ALL, Box::new(VirtioMMIO::new(VirtioBlock::new(disk))))?;
+            bus.connect_interrupt(virtio::BASE, virtio::IRQ)?;
+        }
+
+        let mut cpu = CPU::with_bus(bus);
+        let kernel = RAM_BASE + KERNEL_OFFSET;
+        let (entry, sbi) = match &config.firmware {
+            Firmware::OpenSBI(image) => {
+                if image.len() as u32 > KERNEL_OFFSET {
+                    return Err(BusError::Overlap(kernel));
+                }
+                Self::load(&mut cpu, RAM_BASE, image)?;
+                (RAM_BASE, None)
+            }
+            Firmware::Native => (kernel, Some(SBI::new(1))),
+        };
+        Self::load(&mut cpu, kernel, &config.kernel)?;
+
+        let mut description = fdt::describe(&mut cpu, 1, TIMEBASE_FREQUENCY);
+        description.bootargs = config.bootargs;
+
+        // the initial ramdisk goes right below the device tree
+        if let Some(initrd) = &config.initrd {
+            let kernel_end = kernel.saturating_add(config.kernel.len() as u32);
+            let start = (RAM_BASE as u64 + config.ram_size as u64)
+                .checked_sub(DTB_RESERVED as u64 + initrd.len() as u64)
+                .map(|start| start as u32 & !0xFFF)
+                .filter(|&start| start >= kernel_end)
+                .ok_or(BusError::Overlap(kernel_end))?;
+            Self::load(&mut cpu, start, initrd)?;
+            description.initrd = Some((start, start + initrd.len() as u32));
+        }
+
+        let dtb = fdt::generate(cpu.bus(), &description);
+        if dtb.len() as u32 > DTB_RESERVED {
+            return Err(BusError::Overlap(RAM_BASE.wrapping_add(config.ram_size).wrapping_sub(DTB_RESERVED)));
+        }
+        let dtb = fdt::place(&mut cpu, &dtb)?;
+        *cpu.pc() = entry;
+        if sbi.is_some() {
+            sbi::enter_supervisor(&mut cpu, entry);
+        }
+
+        Ok(Virt { cpu, sbi, dtb })
+    }
+
+    pub fn cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    // only present when the native sbi is used
+    pub fn sbi(&mut self) -> Option<&mut SBI> {
+        self.sbi.as_mut()
+    }
+
+    pub fn dtb(&self) -> u32 {
+        self.dtb
+    }
+
+    pub fn reset(&self) -> Option<SystemReset> {
+        self.sbi.as_ref().and_then(SBI::reset)
+    }
+
+    // shut down through the sbi or the boot hart stopped itself
+    pub fn halted(&self) -> bool {
+        match &self.sbi {
+            Some(sbi) => sbi.reset().is_some() || sbi.hart_state(0) != Some(HartState::Started),
+            None => false,
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.halted() {
+            return;
+        }
+
+        self.cpu.tick();
+        self.service();
+    }
+
+    // returns the number of steps taken before the machine halted,
+    // the cpu engine runs until the hart traps into machine mode for the native sbi
+    pub fn run(&mut self, steps: u64) -> u64 {
+        let sbi = self.sbi.is_some();
+        let mut step = 0;
+
+        while step < steps && !self.halted() {
+            step += self.cpu.run_until_privileged(steps - step, |cpu| sbi && cpu.privilege() == Privilege::Machine);
+            self.service();
+        }
+
+        step
+    }
+
+    // traps the native sbi does not handle leave the hart in machine mode
+    fn service(&mut self) {
+        if let Some(sbi) = &mut self.sbi {
+            if self.cpu.privilege() == Privilege::Machine {
+                sbi.trap(&mut self.cpu);
+            }
+        }
+    }
+
+    // like snapshot::save with the native sbi state, the device tree is part of ram
+    pub fn save(&mut self) -> Vec<u8> {
+        let mut state = Writer::new();
+        snapshot::write_header(&mut state);
+        snapshot::save_hart(&mut self.cpu, &mut state);
+        self.cpu.bus().save(&mut state);
+        state.bool(self.sbi.is_some());
+        if let Some(sbi) = &self.sbi {
+            sbi.save(&mut state);
+        }
+        state.into_inner()
+    }
+
+    // into a machine built from the same configuration
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let mut state = Reader::new(data);
+        snapshot::read_header(&mut state)?;
+        snapshot::restore_hart(&mut self.cpu, &mut state)?;
+        self.cpu.bus().restore(&mut state)?;
+        match (state.bool()?, &mut self.sbi) {
+            (true, Some(sbi)) => sbi.restore(&mut state)?,
+            (false, None) => {}
+            _ => return Err(SnapshotError::Mismatch("firmware".to_string())),
+        }
+
+        if !state.is_empty() {
+            return Err(SnapshotError::Mismatch("trailing data".to_string()));
+        }
+        Ok(())
+    }
+
+    fn load(cpu: &mut CPU, address: u32, data: &[u8]) -> Result<(), BusError> {
+        for (offset, &byte) in data.iter().enumerate() {
+            let address = address.checked_add(offset as u32).ok_or(BusError::AccessFault(address))?;
+            cpu.bus().write(address, AccessSize::Byte, byte as u32)?;
+        }
+
+        Ok(())
+    }
+}
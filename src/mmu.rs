@@ -0,0 +1,119 @@
+use crate::CPU;
+use crate::bus::{AccessSize, Bus};
+use crate::csr::*;
+use crate::trap::{Exception, Privilege};
+
+pub const PTE_V: u32 = 1 << 0;
+pub const PTE_R: u32 = 1 << 1;
+pub const PTE_W: u32 = 1 << 2;
+pub const PTE_X: u32 = 1 << 3;
+pub const PTE_U: u32 = 1 << 4;
+pub const PTE_G: u32 = 1 << 5;
+pub const PTE_A: u32 = 1 << 6;
+pub const PTE_D: u32 = 1 << 7;
+
+// sv32 has two levels of 1024 entries, the upper level maps 4 MiB megapages
+const LEVELS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self, address: u32) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault(address),
+            Access::Load => Exception::LoadPageFault(address),
+            Access::Store => Exception::StorePageFault(address),
+        }
+    }
+
+    fn access_fault(self, address: u32) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(address),
+            Access::Load => Exception::LoadAccessFault(address),
+            Access::Store => Exception::StoreAccessFault(address),
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // privilege loads and stores are checked with, mprv lends them the privilege in mpp
+    fn effective_privilege(&self, access: Access) -> Privilege {
+        let mstatus = self.csrs.read(MSTATUS);
+        if access != Access::Fetch && self.privilege == Privilege::Machine && mstatus & MSTATUS_MPRV != 0 {
+            return Privilege::from_bits(mstatus >> 11);
+        }
+        self.privilege
+    }
+
+    // whether instruction fetches go through the page tables
+    pub(crate) fn translating(&self) -> bool {
+        self.privilege != Privilege::Machine && self.csrs.read(SATP) & SATP_MODE != 0
+    }
+
+    // whether loads and stores go through the page tables
+    #[cfg(feature = "jit")]
+    pub(crate) fn translating_data(&self) -> bool {
+        self.csrs.read(SATP) & SATP_MODE != 0 && self.effective_privilege(Access::Load) != Privilege::Machine
+    }
+
+    // physical address of a virtual one, faults carry the virtual address
+    // accessed and dirty bits are set by the walk instead of faulting
+    pub(crate) fn translate(&mut self, address: u32, access: Access) -> Result<u32, Exception> {
+        let satp = self.csrs.read(SATP);
+        let privilege = self.effective_privilege(access);
+        if satp & SATP_MODE == 0 || privilege == Privilege::Machine {
+            return Ok(address);
+        }
+
+        let mstatus = self.csrs.read(MSTATUS);
+        let mut table = (satp & SATP_PPN) as u64 * 4096;
+
+        for level in (0..LEVELS).rev() {
+            let index = (address >> (12 + 10 * level)) & 0x3FF;
+            // page tables above 4 GiB are not reachable from the 32 bit bus
+            let entry = u32::try_from(table + index as u64 * 4).map_err(|_| access.access_fault(address))?;
+            let pte = self.bus.read(entry, AccessSize::Word).map_err(|_| access.access_fault(address))?;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault(address));
+            }
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = (pte >> 10) as u64 * 4096;
+                continue;
+            }
+
+            let permitted = match access {
+                Access::Fetch => pte & PTE_X != 0,
+                Access::Load => pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+                Access::Store => pte & PTE_W != 0,
+            };
+            let user = match privilege {
+                Privilege::User => pte & PTE_U != 0,
+                // supervisor mode never executes user pages and only touches them with sum
+                _ => pte & PTE_U == 0 || (access != Access::Fetch && mstatus & MSTATUS_SUM != 0),
+            };
+            // the low half of a megapage number must be zero
+            let aligned = level == 0 || (pte >> 10) & 0x3FF == 0;
+            if !permitted || !user || !aligned {
+                return Err(access.page_fault(address));
+            }
+
+            let updated = pte | PTE_A | if access == Access::Store { PTE_D } else { 0 };
+            if updated != pte {
+                self.bus.write(entry, AccessSize::Word, updated).map_err(|_| access.access_fault(address))?;
+            }
+
+            let offset_bits = 12 + 10 * level;
+            let page = ((pte >> 10) as u64) << 12 >> offset_bits << offset_bits;
+            let physical = page | (address & ((1 << offset_bits) - 1)) as u64;
+            return u32::try_from(physical).map_err(|_| access.access_fault(address));
+        }
+
+        Err(access.page_fault(address))
+    }
+}
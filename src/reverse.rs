@@ -0,0 +1,579 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::CPU;
+use crate::bus::{Bus, SystemBus};
+use crate::debug::StopReason;
+use crate::devices::uart::UARTBackend;
+use crate::devices::virtio::net::PacketBackend;
+use crate::differential::{self, Commit};
+use crate::machine::Virt;
+use crate::smp::{SharedBus, System};
+use crate::snapshot::{self, SnapshotError};
+use crate::trace::{CommitLog, LogBuffer};
+
+// steps between snapshots, doubled whenever the limit is reached
+pub const INTERVAL: u64 = 4096;
+pub const SNAPSHOT_LIMIT: usize = 64;
+
+#[derive(Default)]
+struct Channel {
+    // host data by the step it was read in, in the order it was read
+    data: Vec<(u64, Vec<u8>)>,
+    // next entry to replay
+    replayed: usize,
+}
+
+#[derive(Default)]
+struct Inputs {
+    position: u64,
+    // steps before the end are replayed from the log instead of read from the host
+    end: u64,
+    channels: Vec<Channel>,
+    // clock samples, logged only when the value changes
+    clock: Vec<(u64, u64)>,
+    sampled: Option<u64>,
+}
+
+// nondeterministic device inputs by the step they were read in, clones share the same log
+#[derive(Clone, Default)]
+pub struct InputLog {
+    inputs: Rc<RefCell<Inputs>>,
+}
+
+impl InputLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.inputs.borrow().position
+    }
+
+    pub fn replaying(&self) -> bool {
+        let inputs = self.inputs.borrow();
+        inputs.position < inputs.end
+    }
+
+    // every source of host input logs into its own channel
+    pub fn channel(&self) -> usize {
+        let mut inputs = self.inputs.borrow_mut();
+        inputs.channels.push(Channel::default());
+        inputs.channels.len() - 1
+    }
+
+    // data read from the host in the current step, read once and replayed afterwards
+    pub fn input<F: FnOnce() -> Option<Vec<u8>>>(&self, channel: usize, read: F) -> Option<Vec<u8>> {
+        if self.replaying() {
+            let mut inputs = self.inputs.borrow_mut();
+            let position = inputs.position;
+            let channel = &mut inputs.channels[channel];
+            let (_, data) = channel.data.get(channel.replayed).filter(|&&(logged, _)| logged == position)?;
+            let data = data.clone();
+            channel.replayed += 1;
+            return Some(data);
+        }
+
+        let data = read();
+        if let Some(data) = &data {
+            let mut inputs = self.inputs.borrow_mut();
+            let position = inputs.position;
+            inputs.channels[channel].data.push((position, data.clone()));
+        }
+        data
+    }
+
+    // byte received in the current step
+    pub fn receive<F: FnOnce() -> Option<u8>>(&self, channel: usize, read: F) -> Option<u8> {
+        self.input(channel, || read().map(|byte| vec![byte])).and_then(|data| data.first().copied())
+    }
+
+    // host time of the current step, sampled once per step and replayed afterwards
+    pub fn time<F: FnOnce() -> u64>(&self, sample: F) -> u64 {
+        let mut inputs = self.inputs.borrow_mut();
+        let position = inputs.position;
+        if position < inputs.end || inputs.sampled == Some(position) {
+            let index = inputs.clock.partition_point(|&(logged, _)| logged <= position);
+            return index.checked_sub(1).map_or(0, |index| inputs.clock[index].1);
+        }
+
+        let value = sample();
+        inputs.sampled = Some(position);
+        if inputs.clock.last().is_none_or(|&(_, last)| last != value) {
+            inputs.clock.push((position, value));
+        }
+        value
+    }
+
+    pub(crate) fn set_position(&self, position: u64) {
+        let mut inputs = self.inputs.borrow_mut();
+        inputs.position = position;
+        inputs.end = inputs.end.max(position);
+        for channel in inputs.channels.iter_mut() {
+            channel.replayed = channel.data.partition_point(|&(logged, _)| logged < position);
+        }
+    }
+
+    // forgets the inputs from the current step on
+    pub(crate) fn truncate(&self) {
+        let mut inputs = self.inputs.borrow_mut();
+        let position = inputs.position;
+        for channel in inputs.channels.iter_mut() {
+            channel.data.retain(|&(logged, _)| logged < position);
+            channel.replayed = channel.data.len();
+        }
+        inputs.clock.retain(|&(logged, _)| logged < position);
+        inputs.sampled = None;
+        inputs.end = position;
+    }
+}
+
+// uart or virtio console backend with logged input, output is dropped while replaying because the host already saw it
+pub struct ReplayBackend {
+    backend: Box<dyn UARTBackend>,
+    inputs: InputLog,
+    channel: usize,
+}
+
+impl ReplayBackend {
+    pub fn new(backend: Box<dyn UARTBackend>, inputs: InputLog) -> Self {
+        let channel = inputs.channel();
+        ReplayBackend { backend, inputs, channel }
+    }
+}
+
+impl UARTBackend for ReplayBackend {
+    fn read(&mut self) -> Option<u8> {
+        let backend = &mut self.backend;
+        self.inputs.receive(self.channel, || backend.read())
+    }
+
+    fn write(&mut self, byte: u8) {
+        if !self.inputs.replaying() {
+            self.backend.write(byte);
+        }
+    }
+}
+
+// virtio net backend with logged received frames, sent frames are dropped while replaying
+pub struct ReplayPacketBackend {
+    backend: Box<dyn PacketBackend>,
+    inputs: InputLog,
+    channel: usize,
+}
+
+impl ReplayPacketBackend {
+    pub fn new(backend: Box<dyn PacketBackend>, inputs: InputLog) -> Self {
+        let channel = inputs.channel();
+        ReplayPacketBackend { backend, inputs, channel }
+    }
+}
+
+impl PacketBackend for ReplayPacketBackend {
+    fn send(&mut self, frame: &[u8]) {
+        if !self.inputs.replaying() {
+            self.backend.send(frame);
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let backend = &mut self.backend;
+        self.inputs.input(self.channel, || backend.receive())
+    }
+}
+
+// a machine the recorder can drive, snapshots hold everything except host input
+pub trait Recordable {
+    type Bus: Bus;
+
+    fn harts(&self) -> u32;
+    fn hart(&mut self, hart: u32) -> &mut CPU<Self::Bus>;
+    // executes one instruction and returns the hart that ran it, None when no hart can run
+    fn step(&mut self) -> Option<u32>;
+    fn save(&mut self) -> Vec<u8>;
+    fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError>;
+}
+
+impl Recordable for CPU {
+    type Bus = SystemBus;
+
+    fn harts(&self) -> u32 {
+        1
+    }
+
+    fn hart(&mut self, _hart: u32) -> &mut CPU {
+        self
+    }
+
+    fn step(&mut self) -> Option<u32> {
+        self.tick();
+        Some(0)
+    }
+
+    fn save(&mut self) -> Vec<u8> {
+        snapshot::save(self)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        snapshot::restore(self, data)
+    }
+}
+
+impl Recordable for Virt {
+    type Bus = SystemBus;
+
+    fn harts(&self) -> u32 {
+        1
+    }
+
+    fn hart(&mut self, _hart: u32) -> &mut CPU {
+        self.cpu()
+    }
+
+    fn step(&mut self) -> Option<u32> {
+        (!self.halted()).then(|| {
+            Virt::step(self);
+            0
+        })
+    }
+
+    fn save(&mut self) -> Vec<u8> {
+        Virt::save(self)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        Virt::restore(self, data)
+    }
+}
+
+impl Recordable for System {
+    type Bus = SharedBus;
+
+    fn harts(&self) -> u32 {
+        System::harts(self)
+    }
+
+    fn hart(&mut self, hart: u32) -> &mut CPU<SharedBus> {
+        System::hart(self, hart)
+    }
+
+    fn step(&mut self) -> Option<u32> {
+        System::step(self)
+    }
+
+    fn save(&mut self) -> Vec<u8> {
+        System::save(self)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        System::restore(self, data)
+    }
+}
+
+// instruction of the history that wrote a register or memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub position: u64,
+    pub pc: u32,
+    pub value: u32,
+}
+
+struct Snapshot {
+    position: u64,
+    data: Vec<u8>,
+    // taken after the state was edited, replay restores it instead of executing up to it
+    edited: bool,
+}
+
+// a machine with its execution history, positions count the steps since the recorder was created
+pub struct Recorder<M: Recordable = CPU> {
+    machine: M,
+    inputs: InputLog,
+    snapshots: Vec<Snapshot>,
+    interval: u64,
+    position: u64,
+    end: u64,
+    edited: bool,
+}
+
+impl Recorder<CPU> {
+    pub fn cpu(&mut self) -> &mut CPU {
+        &mut self.machine
+    }
+}
+
+impl<M: Recordable> Recorder<M> {
+    // the log must be the one the devices read host input through, it is cleared
+    pub fn new(machine: M, inputs: InputLog) -> Self {
+        inputs.set_position(0);
+        inputs.truncate();
+
+        let mut recorder = Recorder { machine, inputs, snapshots: Vec::new(), interval: INTERVAL, position: 0, end: 0, edited: false };
+        recorder.snapshot(false);
+        recorder
+    }
+
+    // for inspection, use edit to change the state
+    pub fn machine(&mut self) -> &mut M {
+        &mut self.machine
+    }
+
+    // the history after the current position is discarded
+    pub fn edit(&mut self) -> &mut M {
+        if !self.edited {
+            let position = self.position;
+            self.snapshots.retain(|snapshot| snapshot.position < position);
+            self.end = position;
+            self.inputs.set_position(position);
+            self.inputs.truncate();
+            self.edited = true;
+        }
+        &mut self.machine
+    }
+
+    pub fn inputs(&self) -> &InputLog {
+        &self.inputs
+    }
+
+    pub fn harts(&self) -> u32 {
+        self.machine.harts()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // steps recorded so far, stepping before the end replays them
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    // returns the hart that executed the step
+    pub fn step(&mut self) -> Option<u32> {
+        self.settle();
+        self.inputs.set_position(self.position);
+
+        if self.position < self.end {
+            // edits are not part of the replayed steps
+            if let Ok(index) = self.snapshots.binary_search_by_key(&self.position, |snapshot| snapshot.position) {
+                if self.snapshots[index].edited {
+                    self.machine.restore(&self.snapshots[index].data).expect("snapshot of the recorded machine");
+                }
+            }
+        } else if self.position.is_multiple_of(self.interval) && self.snapshots.last().is_none_or(|snapshot| snapshot.position < self.position) {
+            self.snapshot(false);
+        }
+
+        let hart = self.machine.step();
+        self.position += 1;
+        self.end = self.end.max(self.position);
+        self.inputs.set_position(self.position);
+        hart
+    }
+
+    // runs until a breakpoint or watchpoint is hit
+    pub fn run(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    pub fn run_for(&mut self, instructions: u64) -> StopReason {
+        if instructions == 0 {
+            return StopReason::InstructionLimit;
+        }
+
+        let mut remaining = instructions;
+        let reason = self.run_until(|_| {
+            remaining -= 1;
+            remaining == 0
+        });
+        match reason {
+            StopReason::Predicate => StopReason::InstructionLimit,
+            reason => reason,
+        }
+    }
+
+    // like CPU::run_until while recording the history, the debuggers of all harts are checked
+    pub fn run_until<F: FnMut(&mut Self) -> bool>(&mut self, mut predicate: F) -> StopReason {
+        self.take_hit();
+
+        let mut first = true;
+        loop {
+            if !first {
+                if let Some(reason) = self.check_execute() {
+                    return reason;
+                }
+            }
+            first = false;
+
+            self.step();
+            if let Some(reason) = self.take_hit() {
+                return reason;
+            }
+            if predicate(self) {
+                return StopReason::Predicate;
+            }
+        }
+    }
+
+    // moves to the state before the step at position, positions after the end are recorded
+    pub fn seek(&mut self, position: u64) {
+        self.settle();
+        let logs = self.set_commit_logs(|| None);
+
+        if position < self.position {
+            let index = self.snapshots.partition_point(|snapshot| snapshot.position <= position) - 1;
+            let snapshot = &self.snapshots[index];
+            self.inputs.set_position(snapshot.position);
+            self.machine.restore(&snapshot.data).expect("snapshot of the recorded machine");
+            self.position = snapshot.position;
+        }
+        while self.position < position {
+            self.step();
+        }
+
+        self.take_hit();
+        self.restore_commit_logs(logs);
+    }
+
+    // undoes the last step, false at the start of the history
+    pub fn reverse_step(&mut self) -> bool {
+        if self.position == 0 {
+            return false;
+        }
+
+        self.seek(self.position - 1);
+        true
+    }
+
+    // back to the previous breakpoint or watchpoint hit, watchpoints stop before the accessing instruction
+    pub fn reverse_continue(&mut self) -> StopReason {
+        let logs = self.set_commit_logs(|| None);
+        self.take_hit();
+
+        let found = self.search(|recorder| {
+            let breakpoint = recorder.check_execute();
+            recorder.step();
+            breakpoint.or(recorder.take_hit())
+        });
+        let reason = match found {
+            Some((position, reason)) => {
+                self.seek(position);
+                reason
+            }
+            None => {
+                self.seek(0);
+                StopReason::HistoryStart
+            }
+        };
+
+        self.restore_commit_logs(logs);
+        reason
+    }
+
+    // most recent instruction before the current position that wrote the register of any hart
+    pub fn last_register_write(&mut self, register: u8) -> Option<Change> {
+        self.last_change(|commit| commit.registers.iter().find(|&&(written, _)| written == register).map(|&(_, value)| value))
+    }
+
+    // most recent store to the byte at address, the value is the stored byte
+    pub fn last_memory_write(&mut self, address: u32) -> Option<Change> {
+        self.last_change(|commit| {
+            let size = 1 << ((commit.instruction >> 12) & 0b11);
+            commit.stores.iter().find_map(|&(start, value)| {
+                let offset = address.wrapping_sub(start);
+                (offset < size).then_some((value >> (offset * 8)) & 0xFF)
+            })
+        })
+    }
+
+    fn last_change<F: FnMut(&Commit) -> Option<u32>>(&mut self, mut written: F) -> Option<Change> {
+        let position = self.position;
+        let buffer = LogBuffer::new();
+        let logs = self.set_commit_logs(|| Some(CommitLog::new(Box::new(buffer.clone()))));
+
+        let found = self.search(|recorder| {
+            recorder.step();
+            let commit = differential::parse_spike(&buffer.take())?;
+            written(&commit).map(|value| (commit.pc, value))
+        });
+        self.seek(position);
+
+        self.restore_commit_logs(logs);
+        found.map(|(position, (pc, value))| Change { position, pc, value })
+    }
+
+    // replays the history before the current position between snapshots, most recent first,
+    // visit executes one step and the last match of the first window with one wins
+    fn search<T, F: FnMut(&mut Self) -> Option<T>>(&mut self, mut visit: F) -> Option<(u64, T)> {
+        self.settle();
+
+        let mut end = self.position;
+        while end > 0 {
+            let index = self.snapshots.partition_point(|snapshot| snapshot.position < end) - 1;
+            let start = self.snapshots[index].position;
+            self.seek(start);
+
+            let mut found = None;
+            while self.position < end {
+                let position = self.position;
+                if let Some(value) = visit(self) {
+                    found = Some((position, value));
+                }
+            }
+            if found.is_some() {
+                return found;
+            }
+            end = start;
+        }
+
+        None
+    }
+
+    // breakpoint of the first hart whose next instruction has one
+    fn check_execute(&mut self) -> Option<StopReason> {
+        (0..self.machine.harts()).find_map(|hart| {
+            let cpu = self.machine.hart(hart);
+            let pc = *cpu.pc();
+            cpu.debugger().check_execute(pc)
+        })
+    }
+
+    // watchpoint hits of all harts are taken, the first one is returned
+    fn take_hit(&mut self) -> Option<StopReason> {
+        (0..self.machine.harts()).fold(None, |hit, hart| {
+            let taken = self.machine.hart(hart).debugger().take_hit();
+            hit.or(taken)
+        })
+    }
+
+    // one log for every hart, the previous ones are returned
+    fn set_commit_logs<F: FnMut() -> Option<CommitLog>>(&mut self, mut log: F) -> Vec<Option<CommitLog>> {
+        (0..self.machine.harts()).map(|hart| self.machine.hart(hart).set_commit_log(log())).collect()
+    }
+
+    fn restore_commit_logs(&mut self, logs: Vec<Option<CommitLog>>) {
+        for (hart, log) in logs.into_iter().enumerate() {
+            self.machine.hart(hart as u32).set_commit_log(log);
+        }
+    }
+
+    // an edit is recorded as a snapshot once execution moves on
+    fn settle(&mut self) {
+        if self.edited {
+            self.edited = false;
+            self.snapshot(true);
+        }
+    }
+
+    fn snapshot(&mut self, edited: bool) {
+        let data = self.machine.save();
+        self.snapshots.push(Snapshot { position: self.position, data, edited });
+
+        // keeps every other snapshot when there are too many
+        if self.snapshots.len() > SNAPSHOT_LIMIT {
+            self.interval *= 2;
+            let interval = self.interval;
+            self.snapshots.retain(|snapshot| snapshot.edited || snapshot.position.is_multiple_of(interval));
+        }
+    }
+}
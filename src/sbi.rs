@@ -0,0 +1,389 @@
+use crate::CPU;
+use crate::bus::{AccessSize, Bus, BusError};
+use crate::csr::*;
+use crate::devices::clint::CLINT;
+use crate::devices::uart::UART;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+use crate::trap::{Interrupt, Privilege};
+
+pub const EXTENSION_SET_TIMER: u32       = 0x00;
+pub const EXTENSION_CONSOLE_PUTCHAR: u32 = 0x01;
+pub const EXTENSION_CONSOLE_GETCHAR: u32 = 0x02;
+pub const EXTENSION_BASE: u32            = 0x10;
+pub const EXTENSION_TIME: u32            = 0x54494D45;
+pub const EXTENSION_IPI: u32             = 0x00735049;
+pub const EXTENSION_RFENCE: u32          = 0x52464E43;
+pub const EXTENSION_HSM: u32             = 0x0048534D;
+pub const EXTENSION_SRST: u32            = 0x53525354;
+pub const EXTENSION_DBCN: u32            = 0x4442434E;
+
+pub const SUCCESS: i32               = 0;
+pub const ERR_FAILED: i32            = -1;
+pub const ERR_NOT_SUPPORTED: i32     = -2;
+pub const ERR_INVALID_PARAM: i32     = -3;
+pub const ERR_ALREADY_STARTED: i32   = -7;
+pub const ERR_ALREADY_STOPPED: i32   = -8;
+
+// every exception except ecalls from supervisor and machine mode goes to the kernel
+pub const DELEGATED_EXCEPTIONS: u32 = 0xB1FF;
+// cause of an ecall from supervisor mode
+const SUPERVISOR_ECALL: u32 = 9;
+
+// sbi specification 2.0
+pub const SPEC_VERSION: u32 = 2 << 24;
+// not a registered implementation id
+pub const IMPLEMENTATION_ID: u32 = 0x5256;
+pub const IMPLEMENTATION_VERSION: u32 = 1;
+
+const EXTENSIONS: [u32; 10] = [
+    EXTENSION_SET_TIMER,
+    EXTENSION_CONSOLE_PUTCHAR,
+    EXTENSION_CONSOLE_GETCHAR,
+    EXTENSION_BASE,
+    EXTENSION_TIME,
+    EXTENSION_IPI,
+    EXTENSION_RFENCE,
+    EXTENSION_HSM,
+    EXTENSION_SRST,
+    EXTENSION_DBCN,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    // start address and opaque value passed in a1
+    StartPending(u32, u32),
+}
+
+impl HartState {
+    pub fn status(&self) -> u32 {
+        match self {
+            HartState::Started => 0,
+            HartState::Stopped => 1,
+            HartState::StartPending(..) => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemReset {
+    pub reset_type: u32,
+    pub reason: u32,
+}
+
+// supervisor binary interface implemented by the emulator instead of firmware
+pub struct SBI {
+    harts: Vec<HartState>,
+    reset: Option<SystemReset>,
+}
+
+impl SBI {
+    // hart 0 boots, the others wait for hart_start
+    pub fn new(harts: u32) -> Self {
+        let mut states = vec![HartState::Stopped; harts as usize];
+        states[0] = HartState::Started;
+
+        SBI { harts: states, reset: None }
+    }
+
+    pub fn hart_state(&self, hart: u32) -> Option<HartState> {
+        self.harts.get(hart as usize).copied()
+    }
+
+    pub fn set_hart_state(&mut self, hart: u32, state: HartState) {
+        self.harts[hart as usize] = state;
+    }
+
+    // reset or shutdown requested by the guest
+    pub fn reset(&self) -> Option<SystemReset> {
+        self.reset
+    }
+
+    pub fn save(&self, state: &mut Writer) {
+        state.u32(self.harts.len() as u32);
+        for hart in self.harts.iter() {
+            state.u32(hart.status());
+            if let HartState::StartPending(address, opaque) = *hart {
+                state.u32(address);
+                state.u32(opaque);
+            }
+        }
+
+        state.bool(self.reset.is_some());
+        if let Some(reset) = self.reset {
+            state.u32(reset.reset_type);
+            state.u32(reset.reason);
+        }
+    }
+
+    pub fn restore(&mut self, state: &mut Reader) -> Result<(), SnapshotError> {
+        state.expect(self.harts.len() as u32, "sbi hart count")?;
+        for hart in self.harts.iter_mut() {
+            *hart = match state.u32()? {
+                0 => HartState::Started,
+                1 => HartState::Stopped,
+                2 => HartState::StartPending(state.u32()?, state.u32()?),
+                status => return Err(SnapshotError::Mismatch(format!("hart state {}", status))),
+            };
+        }
+
+        self.reset = match state.bool()? {
+            true => Some(SystemReset { reset_type: state.u32()?, reason: state.u32()? }),
+            false => None,
+        };
+        Ok(())
+    }
+
+    // handles a trap into machine mode like firmware would and returns to supervisor mode,
+    // false for traps the sbi does not handle, which stay in machine mode
+    pub fn trap<B: Bus>(&mut self, cpu: &mut CPU<B>) -> bool {
+        let cause = cpu.csrs().read(MCAUSE);
+        let epc = cpu.csrs().read(MEPC);
+        let hart = cpu.csrs().read(MHARTID);
+
+        if cause == SUPERVISOR_ECALL {
+            self.ecall(cpu);
+            return_to_supervisor(cpu, epc.wrapping_add(4));
+        } else if cause == Interrupt::MachineTimer.cause() {
+            // the kernel sees the timer until it sets the next one
+            let mie = cpu.csrs().read(MIE);
+            cpu.csrs().write(MIE, mie & !MIP_MTIP);
+            let mip = cpu.csrs().read(MIP);
+            cpu.csrs().write(MIP, mip | MIP_STIP);
+            return_to_supervisor(cpu, epc);
+        } else if cause == Interrupt::MachineSoftware.cause() {
+            if cpu.bus().write(CLINT::BASE + CLINT::MSIP + hart * 4, AccessSize::Word, 0).is_err() {
+                return false;
+            }
+            let mip = cpu.csrs().read(MIP);
+            cpu.csrs().write(MIP, mip | MIP_SSIP);
+            return_to_supervisor(cpu, epc);
+        } else {
+            return false;
+        }
+
+        true
+    }
+
+    // moves a hart started through hsm to supervisor mode at its start address
+    pub fn boot<B: Bus>(&mut self, hart: u32, cpu: &mut CPU<B>) -> bool {
+        let Some(HartState::StartPending(address, opaque)) = self.hart_state(hart) else {
+            return false;
+        };
+
+        cpu.registers().write(10, hart);
+        cpu.registers().write(11, opaque);
+        enter_supervisor(cpu, address);
+        self.set_hart_state(hart, HartState::Started);
+        true
+    }
+
+    // services an ecall from supervisor mode, a0 and a1 receive error and value
+    pub fn ecall<B: Bus>(&mut self, cpu: &mut CPU<B>) {
+        let hart = cpu.csrs().read(MHARTID);
+        let registers = cpu.registers();
+        let arguments = [10, 11, 12, 13, 14, 15].map(|register| registers.read(register));
+        let function = registers.read(16);
+        let extension = registers.read(17);
+
+        match extension {
+            // legacy extensions only return a value in a0
+            EXTENSION_SET_TIMER => {
+                let result = Self::set_timer(cpu, hart, arguments[0], arguments[1]);
+                cpu.registers().write(10, result as u32);
+            }
+            EXTENSION_CONSOLE_PUTCHAR => {
+                let result = match Self::putchar(cpu, arguments[0] as u8) {
+                    Ok(()) => SUCCESS,
+                    Err(_) => ERR_FAILED,
+                };
+                cpu.registers().write(10, result as u32);
+            }
+            EXTENSION_CONSOLE_GETCHAR => {
+                let value = Self::getchar(cpu).map_or(-1, |byte| byte as i32);
+                cpu.registers().write(10, value as u32);
+            }
+            _ => {
+                let (error, value) = self.call(cpu, hart, extension, function, arguments);
+                cpu.registers().write(10, error as u32);
+                cpu.registers().write(11, value);
+            }
+        }
+    }
+
+    fn call<B: Bus>(&mut self, cpu: &mut CPU<B>, hart: u32, extension: u32, function: u32, arguments: [u32; 6]) -> (i32, u32) {
+        match (extension, function) {
+            (EXTENSION_BASE, 0) => (SUCCESS, SPEC_VERSION),
+            (EXTENSION_BASE, 1) => (SUCCESS, IMPLEMENTATION_ID),
+            (EXTENSION_BASE, 2) => (SUCCESS, IMPLEMENTATION_VERSION),
+            (EXTENSION_BASE, 3) => (SUCCESS, EXTENSIONS.contains(&arguments[0]) as u32),
+            // mvendorid, marchid and mimpid
+            (EXTENSION_BASE, 4..=6) => (SUCCESS, 0),
+
+            (EXTENSION_TIME, 0) => (Self::set_timer(cpu, hart, arguments[0], arguments[1]), 0),
+
+            (EXTENSION_IPI, 0) => (self.send_ipi(cpu, arguments[0], arguments[1]), 0),
+
+            // page table walks are not cached, so there is nothing to flush
+            (EXTENSION_RFENCE, 0..=6) => (SUCCESS, 0),
+
+            (EXTENSION_HSM, 0) => (self.hart_start(arguments[0], arguments[1], arguments[2]), 0),
+            (EXTENSION_HSM, 1) => (self.hart_stop(hart), 0),
+            (EXTENSION_HSM, 2) => match self.hart_state(arguments[0]) {
+                Some(state) => (SUCCESS, state.status()),
+                None => (ERR_INVALID_PARAM, 0),
+            },
+
+            (EXTENSION_SRST, 0) if arguments[0] <= 2 => {
+                self.reset = Some(SystemReset { reset_type: arguments[0], reason: arguments[1] });
+                (SUCCESS, 0)
+            }
+            (EXTENSION_SRST, 0) => (ERR_INVALID_PARAM, 0),
+
+            (EXTENSION_DBCN, 0) => Self::console_write(cpu, arguments[0], arguments[1], arguments[2]),
+            (EXTENSION_DBCN, 1) => Self::console_read(cpu, arguments[0], arguments[1], arguments[2]),
+            (EXTENSION_DBCN, 2) => match Self::putchar(cpu, arguments[0] as u8) {
+                Ok(()) => (SUCCESS, 0),
+                Err(_) => (ERR_FAILED, 0),
+            },
+
+            _ => (ERR_NOT_SUPPORTED, 0),
+        }
+    }
+
+    fn set_timer<B: Bus>(cpu: &mut CPU<B>, hart: u32, low: u32, high: u32) -> i32 {
+        let address = CLINT::BASE + CLINT::MTIMECMP + hart * 8;
+        let written = cpu.bus().write(address, AccessSize::Word, low)
+            .and_then(|_| cpu.bus().write(address + 4, AccessSize::Word, high));
+
+        if written.is_err() {
+            return ERR_FAILED;
+        }
+
+        // the pending timer is cleared and machine mode waits for the next one
+        let mip = cpu.csrs().read(MIP);
+        cpu.csrs().write(MIP, mip & !MIP_STIP);
+        let mie = cpu.csrs().read(MIE);
+        cpu.csrs().write(MIE, mie | MIP_MTIP);
+        SUCCESS
+    }
+
+    // software interrupts are delivered through the clint
+    fn send_ipi<B: Bus>(&mut self, cpu: &mut CPU<B>, mask: u32, base: u32) -> i32 {
+        let targets: Vec<u32> = if base == u32::MAX {
+            (0..self.harts.len() as u32).collect()
+        } else {
+            (0..32).filter(|bit| mask & (1 << bit) != 0).map(|bit| base.wrapping_add(bit)).collect()
+        };
+
+        if targets.iter().any(|&hart| hart as usize >= self.harts.len()) {
+            return ERR_INVALID_PARAM;
+        }
+        for hart in targets {
+            if cpu.bus().write(CLINT::BASE + CLINT::MSIP + hart * 4, AccessSize::Word, 1).is_err() {
+                return ERR_FAILED;
+            }
+        }
+
+        SUCCESS
+    }
+
+    fn hart_start(&mut self, hart: u32, address: u32, opaque: u32) -> i32 {
+        match self.hart_state(hart) {
+            None => ERR_INVALID_PARAM,
+            Some(HartState::Stopped) => {
+                self.set_hart_state(hart, HartState::StartPending(address, opaque));
+                SUCCESS
+            }
+            Some(_) => ERR_ALREADY_STARTED,
+        }
+    }
+
+    fn hart_stop(&mut self, hart: u32) -> i32 {
+        match self.hart_state(hart) {
+            Some(HartState::Started) => {
+                self.set_hart_state(hart, HartState::Stopped);
+                SUCCESS
+            }
+            Some(_) => ERR_ALREADY_STOPPED,
+            None => ERR_FAILED,
+        }
+    }
+
+    fn putchar<B: Bus>(cpu: &mut CPU<B>, byte: u8) -> Result<(), BusError> {
+        cpu.bus().write(UART::BASE + UART::THR, AccessSize::Byte, byte as u32)
+    }
+
+    fn getchar<B: Bus>(cpu: &mut CPU<B>) -> Option<u8> {
+        let lsr = cpu.bus().read(UART::BASE + UART::LSR, AccessSize::Byte).ok()?;
+        if lsr as u8 & UART::LSR_DR == 0 {
+            return None;
+        }
+
+        cpu.bus().read(UART::BASE + UART::RBR, AccessSize::Byte).ok().map(|byte| byte as u8)
+    }
+
+    fn console_write<B: Bus>(cpu: &mut CPU<B>, length: u32, low: u32, high: u32) -> (i32, u32) {
+        if high != 0 {
+            return (ERR_INVALID_PARAM, 0);
+        }
+
+        for offset in 0..length {
+            let Ok(byte) = cpu.bus().read(low.wrapping_add(offset), AccessSize::Byte) else {
+                return (ERR_INVALID_PARAM, offset);
+            };
+            if Self::putchar(cpu, byte as u8).is_err() {
+                return (ERR_FAILED, offset);
+            }
+        }
+
+        (SUCCESS, length)
+    }
+
+    fn console_read<B: Bus>(cpu: &mut CPU<B>, length: u32, low: u32, high: u32) -> (i32, u32) {
+        if high != 0 {
+            return (ERR_INVALID_PARAM, 0);
+        }
+
+        let mut read = 0;
+        while read < length {
+            let Some(byte) = Self::getchar(cpu) else {
+                break;
+            };
+            if cpu.bus().write(low.wrapping_add(read), AccessSize::Byte, byte as u32).is_err() {
+                return (ERR_INVALID_PARAM, read);
+            }
+            read += 1;
+        }
+
+        (SUCCESS, read)
+    }
+}
+
+// state firmware leaves a hart in when it jumps to a kernel, the registers are left to the caller
+pub fn enter_supervisor<B: Bus>(cpu: &mut CPU<B>, address: u32) {
+    let csrs = cpu.csrs();
+    csrs.write(MEDELEG, DELEGATED_EXCEPTIONS);
+    csrs.write(MIDELEG, MIDELEG_MASK);
+    // the kernel reads the time csr for its clocksource
+    csrs.write(MCOUNTEREN, COUNTEREN_TM);
+    // machine timer and software interrupts are forwarded to supervisor mode by trap
+    csrs.write(MIE, MIP_MTIP | MIP_MSIP);
+    csrs.write(SATP, 0);
+    let mstatus = csrs.read(MSTATUS);
+    csrs.write(MSTATUS, mstatus & !MSTATUS_SIE);
+
+    cpu.set_privilege(Privilege::Supervisor);
+    *cpu.pc() = address;
+}
+
+// returns from machine mode like mret with mepc set to pc
+fn return_to_supervisor<B: Bus>(cpu: &mut CPU<B>, pc: u32) {
+    let mstatus = cpu.csrs().read(MSTATUS);
+    let mie = if mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+    cpu.csrs().write(MSTATUS, (mstatus & !(MSTATUS_MIE | MSTATUS_MPP | MSTATUS_MPRV)) | mie | MSTATUS_MPIE);
+
+    cpu.set_privilege(Privilege::from_bits(mstatus >> 11));
+    *cpu.pc() = pc;
+}
@@ -0,0 +1,443 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::CPU;
+use crate::bus::{AccessSize, Bus};
+use crate::instructions::csr::EBREAK;
+use crate::linux::{self, EACCES, EBADF, EINVAL, EIO, ENOENT, IO_CHUNK};
+
+// slli x0, x0, 0x1f and srai x0, x0, 7 around the ebreak mark a semihosting call
+pub const ENTRY_NOP: u32 = 0x01F01013;
+pub const EXIT_NOP: u32  = 0x40705013;
+
+pub const SYS_OPEN: u32          = 0x01;
+pub const SYS_CLOSE: u32         = 0x02;
+pub const SYS_WRITEC: u32        = 0x03;
+pub const SYS_WRITE0: u32        = 0x04;
+pub const SYS_WRITE: u32         = 0x05;
+pub const SYS_READ: u32          = 0x06;
+pub const SYS_READC: u32         = 0x07;
+pub const SYS_ISERROR: u32       = 0x08;
+pub const SYS_ISTTY: u32         = 0x09;
+pub const SYS_SEEK: u32          = 0x0A;
+pub const SYS_FLEN: u32          = 0x0C;
+pub const SYS_REMOVE: u32        = 0x0E;
+pub const SYS_CLOCK: u32         = 0x10;
+pub const SYS_TIME: u32          = 0x11;
+pub const SYS_ERRNO: u32         = 0x13;
+pub const SYS_GET_CMDLINE: u32   = 0x15;
+pub const SYS_HEAPINFO: u32      = 0x16;
+pub const SYS_EXIT: u32          = 0x18;
+pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+pub const SYS_ELAPSED: u32       = 0x30;
+pub const SYS_TICKFREQ: u32      = 0x31;
+
+pub const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+// special file names, the console and the extension feature bytes
+pub const CONSOLE: &str  = ":tt";
+pub const FEATURES: &str = ":semihosting-features";
+pub const FEATURE_EXIT_EXTENDED: u8 = 1 << 0;
+pub const FEATURE_STDOUT_STDERR: u8 = 1 << 1;
+
+// SYS_ELAPSED counts microseconds
+pub const TICK_FREQUENCY: u32 = 1_000_000;
+
+const FAILED: u32 = u32::MAX;
+
+// longest file name the guest may pass
+const PATH_MAX: u32 = 4096;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+    // read position in the feature bytes
+    Features(usize),
+}
+
+// host services for bare-metal programs, usually provided by a debugger
+pub struct Semihosting {
+    files: Vec<Option<Handle>>,
+    root: Option<PathBuf>,
+    command_line: String,
+    stdin: Option<VecDeque<u8>>,
+    stdout: Option<Vec<u8>>,
+    stderr: Option<Vec<u8>>,
+    errno: i32,
+    start: Instant,
+    exit: Option<i32>,
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Semihosting {
+    pub fn new() -> Self {
+        Semihosting {
+            files: Vec::new(),
+            root: None,
+            command_line: String::new(),
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            errno: 0,
+            start: Instant::now(),
+            exit: None,
+        }
+    }
+
+    // host directory that SYS_OPEN and SYS_REMOVE are confined to
+    pub fn set_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.root = Some(root.as_ref().to_path_buf());
+    }
+
+    // returned by SYS_GET_CMDLINE, including the program name
+    pub fn set_command_line(&mut self, args: &[&str]) {
+        self.command_line = args.join(" ");
+    }
+
+    pub fn set_stdin(&mut self, data: &[u8]) {
+        self.stdin = Some(data.iter().copied().collect());
+    }
+
+    pub fn capture_output(&mut self) {
+        self.stdout = Some(Vec::new());
+        self.stderr = Some(Vec::new());
+    }
+
+    pub fn take_stdout(&mut self) -> Vec<u8> {
+        self.stdout.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn take_stderr(&mut self) -> Vec<u8> {
+        self.stderr.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // exit code once the program called SYS_EXIT or SYS_EXIT_EXTENDED
+    pub fn exit(&self) -> Option<i32> {
+        self.exit
+    }
+
+    // length of the call sequence at pc, either from the slli or from the ebreak
+    pub fn call_at<B: Bus>(cpu: &mut CPU<B>) -> Option<u32> {
+        let pc = *cpu.pc();
+        let mut word = |address: u32| cpu.bus().read(address, AccessSize::Word).ok();
+
+        match word(pc)? {
+            ENTRY_NOP if word(pc.wrapping_add(4)) == Some(EBREAK) && word(pc.wrapping_add(8)) == Some(EXIT_NOP) => Some(12),
+            EBREAK if word(pc.wrapping_sub(4)) == Some(ENTRY_NOP) && word(pc.wrapping_add(4)) == Some(EXIT_NOP) => Some(8),
+            _ => None,
+        }
+    }
+
+    // services a call sequence at pc or executes one instruction
+    pub fn step<B: Bus>(&mut self, cpu: &mut CPU<B>) -> Option<i32> {
+        if self.exit.is_some() {
+            return self.exit;
+        }
+
+        match Self::call_at(cpu) {
+            Some(length) => {
+                self.call(cpu);
+                *cpu.pc() = cpu.pc().wrapping_add(length);
+            }
+            None => cpu.tick(),
+        }
+
+        self.exit
+    }
+
+    pub fn run<B: Bus>(&mut self, cpu: &mut CPU<B>, steps: u64) -> Option<i32> {
+        for _ in 0..steps {
+            if let Some(code) = self.step(cpu) {
+                return Some(code);
+            }
+        }
+
+        None
+    }
+
+    // operation in a0 and parameter block in a1, the result is returned in a0
+    pub fn call<B: Bus>(&mut self, cpu: &mut CPU<B>) {
+        let operation = cpu.registers().read(10);
+        let parameter = cpu.registers().read(11);
+        let mut arguments = [0; 4];
+        for (index, argument) in arguments.iter_mut().enumerate() {
+            *argument = Self::read_word(cpu, parameter.wrapping_add(index as u32 * 4)).unwrap_or(0);
+        }
+
+        let result = match operation {
+            SYS_OPEN => self.open(cpu, arguments[0], arguments[1], arguments[2]),
+            SYS_CLOSE => self.close(arguments[0]),
+            SYS_WRITEC => match Self::read_bytes(cpu, parameter, 1) {
+                Some(data) => self.output(&Handle::Stdout, &data).map(|_| 0),
+                None => Err(EINVAL),
+            },
+            SYS_WRITE0 => self.write_string(cpu, parameter),
+            SYS_WRITE => self.write(cpu, arguments[0], arguments[1], arguments[2]),
+            SYS_READ => self.read(cpu, arguments[0], arguments[1], arguments[2]),
+            SYS_READC => {
+                let mut byte = [0];
+                self.input(&mut byte).map(|_| byte[0] as u32)
+            }
+            SYS_ISERROR => Ok(((arguments[0] as i32) < 0) as u32),
+            SYS_ISTTY => match self.handle(arguments[0]) {
+                Ok(Handle::Stdin | Handle::Stdout | Handle::Stderr) => Ok(1),
+                Ok(_) => Ok(0),
+                Err(errno) => Err(errno),
+            },
+            SYS_SEEK => self.seek(arguments[0], arguments[1]),
+            SYS_FLEN => self.length(arguments[0]),
+            SYS_REMOVE => {
+                let path = Self::read_path(cpu, arguments[0], arguments[1]).ok_or(EINVAL);
+                path.and_then(|path| self.host_path(&String::from_utf8_lossy(&path))).and_then(|path| fs::remove_file(path).map_err(Self::errno)).map(|_| 0)
+            }
+            // centiseconds since the program started
+            SYS_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as u32),
+            SYS_TIME => Ok(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32),
+            SYS_ERRNO => Ok(self.errno as u32),
+            SYS_GET_CMDLINE => self.command_line(cpu, parameter, arguments[0], arguments[1]),
+            // unknown, the runtime falls back to its linker symbols
+            SYS_HEAPINFO => Self::write_bytes(cpu, arguments[0], &[0; 16]).map(|_| 0).ok_or(EINVAL),
+            // 32 bit targets pass the reason directly instead of a parameter block
+            SYS_EXIT => {
+                self.exit = Some(if parameter == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 });
+                Ok(0)
+            }
+            SYS_EXIT_EXTENDED => {
+                self.exit = Some(if arguments[0] == ADP_STOPPED_APPLICATION_EXIT { arguments[1] as i32 } else { 1 });
+                Ok(0)
+            }
+            SYS_ELAPSED => {
+                let ticks = self.start.elapsed().as_micros() as u64;
+                Self::write_bytes(cpu, parameter, &ticks.to_le_bytes()).map(|_| 0).ok_or(EINVAL)
+            }
+            SYS_TICKFREQ => Ok(TICK_FREQUENCY),
+            _ => Err(EINVAL),
+        };
+
+        let value = result.unwrap_or_else(|errno| {
+            self.errno = errno;
+            FAILED
+        });
+        cpu.registers().write(10, value);
+    }
+
+    // modes follow fopen: r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+    fn open<B: Bus>(&mut self, cpu: &mut CPU<B>, name: u32, mode: u32, length: u32) -> Result<u32, i32> {
+        let name = Self::read_path(cpu, name, length).ok_or(EINVAL)?;
+        let name = String::from_utf8_lossy(&name);
+        if mode > 11 {
+            return Err(EINVAL);
+        }
+
+        let handle = if name == CONSOLE {
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else if name == FEATURES {
+            Handle::Features(0)
+        } else {
+            let path = self.host_path(&name)?;
+            let update = mode & 0b10 != 0;
+            let file = match mode >> 2 {
+                0 => OpenOptions::new().read(true).write(update).open(path),
+                1 => OpenOptions::new().write(true).read(update).create(true).truncate(true).open(path),
+                _ => OpenOptions::new().append(true).read(update).create(true).open(path),
+            };
+            Handle::File(file.map_err(Self::errno)?)
+        };
+
+        match self.files.iter().position(Option::is_none) {
+            Some(index) => {
+                self.files[index] = Some(handle);
+                Ok(index as u32)
+            }
+            None => {
+                self.files.push(Some(handle));
+                Ok(self.files.len() as u32 - 1)
+            }
+        }
+    }
+
+    fn close(&mut self, handle: u32) -> Result<u32, i32> {
+        self.handle(handle)?;
+        self.files[handle as usize] = None;
+        Ok(0)
+    }
+
+    // returns the number of bytes that were not written, the buffer is staged IO_CHUNK bytes at a time
+    fn write<B: Bus>(&mut self, cpu: &mut CPU<B>, handle: u32, buffer: u32, length: u32) -> Result<u32, i32> {
+        let mut written = 0;
+        loop {
+            let data = Self::read_bytes(cpu, buffer.wrapping_add(written), (length - written).min(IO_CHUNK)).ok_or(EINVAL)?;
+
+            match self.handle(handle)? {
+                Handle::Stdout => self.output(&Handle::Stdout, &data)?,
+                Handle::Stderr => self.output(&Handle::Stderr, &data)?,
+                Handle::File(file) => file.write_all(&data).map_err(Self::errno)?,
+                Handle::Stdin | Handle::Features(_) => return Err(EBADF),
+            }
+
+            written += data.len() as u32;
+            if written == length {
+                return Ok(0);
+            }
+        }
+    }
+
+    // zero terminated string written IO_CHUNK bytes at a time, it has to end before the address space does
+    fn write_string<B: Bus>(&mut self, cpu: &mut CPU<B>, address: u32) -> Result<u32, i32> {
+        let mut data = Vec::new();
+        for address in address..=u32::MAX {
+            let byte = cpu.bus().read(address, AccessSize::Byte).map_err(|_| EINVAL)? as u8;
+            if byte == 0 {
+                return self.output(&Handle::Stdout, &data).map(|_| 0);
+            }
+            data.push(byte);
+            if data.len() == IO_CHUNK as usize {
+                self.output(&Handle::Stdout, &data)?;
+                data.clear();
+            }
+        }
+
+        Err(EINVAL)
+    }
+
+    // returns the number of bytes that were not read, length at the end of the file
+    // large reads are cut short, the caller reads the rest
+    fn read<B: Bus>(&mut self, cpu: &mut CPU<B>, handle: u32, buffer: u32, length: u32) -> Result<u32, i32> {
+        let mut data = vec![0; length.min(IO_CHUNK) as usize];
+
+        let read = match self.handle(handle)? {
+            Handle::Stdin => self.input(&mut data)?,
+            Handle::File(file) => file.read(&mut data).map_err(Self::errno)?,
+            Handle::Features(position) => {
+                let features = Self::features();
+                let remaining = features.get(*position..).unwrap_or_default();
+                let read = remaining.len().min(data.len());
+                data[..read].copy_from_slice(&remaining[..read]);
+                *position += read;
+                read
+            }
+            Handle::Stdout | Handle::Stderr => return Err(EBADF),
+        };
+
+        Self::write_bytes(cpu, buffer, &data[..read]).ok_or(EINVAL)?;
+        Ok(length - read as u32)
+    }
+
+    fn seek(&mut self, handle: u32, position: u32) -> Result<u32, i32> {
+        match self.handle(handle)? {
+            Handle::File(file) => file.seek(SeekFrom::Start(position as u64)).map(|_| 0).map_err(Self::errno),
+            Handle::Features(current) => {
+                *current = position as usize;
+                Ok(0)
+            }
+            _ => Err(EBADF),
+        }
+    }
+
+    fn length(&mut self, handle: u32) -> Result<u32, i32> {
+        match self.handle(handle)? {
+            Handle::File(file) => file.metadata().map(|metadata| metadata.len() as u32).map_err(Self::errno),
+            Handle::Features(_) => Ok(Self::features().len() as u32),
+            _ => Err(EBADF),
+        }
+    }
+
+    // the block holds the buffer and its size, the size is replaced by the length written
+    fn command_line<B: Bus>(&mut self, cpu: &mut CPU<B>, parameter: u32, buffer: u32, size: u32) -> Result<u32, i32> {
+        let mut data = self.command_line.clone().into_bytes();
+        if data.len() >= size as usize {
+            return Err(EINVAL);
+        }
+        let length = data.len() as u32;
+        data.push(0);
+
+        Self::write_bytes(cpu, buffer, &data).ok_or(EINVAL)?;
+        Self::write_bytes(cpu, parameter.wrapping_add(4), &length.to_le_bytes()).ok_or(EINVAL)?;
+        Ok(0)
+    }
+
+    fn output(&mut self, handle: &Handle, data: &[u8]) -> Result<(), i32> {
+        match handle {
+            Handle::Stderr => match &mut self.stderr {
+                Some(output) => output.extend_from_slice(data),
+                None => io::stderr().write_all(data).map_err(Self::errno)?,
+            },
+            _ => match &mut self.stdout {
+                Some(output) => output.extend_from_slice(data),
+                None => io::stdout().write_all(data).and_then(|_| io::stdout().flush()).map_err(Self::errno)?,
+            },
+        }
+
+        Ok(())
+    }
+
+    fn input(&mut self, data: &mut [u8]) -> Result<usize, i32> {
+        match &mut self.stdin {
+            Some(input) => {
+                let length = input.len().min(data.len());
+                input.drain(..length).zip(data.iter_mut()).for_each(|(byte, target)| *target = byte);
+                Ok(length)
+            }
+            None => io::stdin().read(data).map_err(Self::errno),
+        }
+    }
+
+    fn handle(&mut self, handle: u32) -> Result<&mut Handle, i32> {
+        self.files.get_mut(handle as usize).and_then(Option::as_mut).ok_or(EBADF)
+    }
+
+    // paths are relative to the root and may not leave it, also not through symlinks
+    fn host_path(&self, path: &str) -> Result<PathBuf, i32> {
+        linux::confine(self.root.as_ref().ok_or(EACCES)?, path)
+    }
+
+    fn features() -> [u8; 5] {
+        [b'S', b'H', b'F', b'B', FEATURE_EXIT_EXTENDED | FEATURE_STDOUT_STDERR]
+    }
+
+    fn read_word<B: Bus>(cpu: &mut CPU<B>, address: u32) -> Option<u32> {
+        Self::read_bytes(cpu, address, 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_bytes<B: Bus>(cpu: &mut CPU<B>, address: u32, length: u32) -> Option<Vec<u8>> {
+        (0..length).map(|offset| cpu.bus().read(address.wrapping_add(offset), AccessSize::Byte).ok().map(|byte| byte as u8)).collect()
+    }
+
+    // file names come with their length instead of a terminator
+    fn read_path<B: Bus>(cpu: &mut CPU<B>, address: u32, length: u32) -> Option<Vec<u8>> {
+        if length > PATH_MAX {
+            return None;
+        }
+        Self::read_bytes(cpu, address, length)
+    }
+
+    fn write_bytes<B: Bus>(cpu: &mut CPU<B>, address: u32, data: &[u8]) -> Option<()> {
+        for (offset, &byte) in data.iter().enumerate() {
+            cpu.bus().write(address.wrapping_add(offset as u32), AccessSize::Byte, byte as u32).ok()?;
+        }
+
+        Some(())
+    }
+
+    fn errno(error: io::Error) -> i32 {
+        match error.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            io::ErrorKind::PermissionDenied => EACCES,
+            _ => error.raw_os_error().unwrap_or(EIO),
+        }
+    }
+}
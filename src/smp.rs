@@ -0,0 +1,333 @@
+use std::cell::{Cell, RefCell, RefMut};
+use std::rc::Rc;
+
+use crate::CPU;
+use crate::bus::{AccessSize, Bus, BusError, SystemBus};
+use crate::csr::MHARTID;
+use crate::devices::virtio::rng::splitmix64;
+use crate::sbi::{self, HartState, SBI};
+use crate::snapshot::{self, Reader, SnapshotError, Writer};
+use crate::trap::Privilege;
+
+// one physical bus shared by all harts, clones refer to the same bus
+#[derive(Clone)]
+pub struct SharedBus {
+    bus: Rc<RefCell<SystemBus>>,
+    round: Rc<Cell<Round>>,
+}
+
+// devices tick at the start of a round in which every running hart could retire an instruction
+#[derive(Debug, Clone, Copy)]
+struct Round {
+    position: usize,
+    harts: usize,
+}
+
+impl Round {
+    // hart ticks before the next one that ticks the devices
+    fn until_start(self) -> u64 {
+        match self.position {
+            0 => 0,
+            position => self.harts.saturating_sub(position).max(1) as u64,
+        }
+    }
+}
+
+impl SharedBus {
+    pub fn new(bus: SystemBus) -> Self {
+        SharedBus { bus: Rc::new(RefCell::new(bus)), round: Rc::new(Cell::new(Round { position: 0, harts: 1 })) }
+    }
+
+    pub fn borrow_mut(&self) -> RefMut<'_, SystemBus> {
+        self.bus.borrow_mut()
+    }
+
+    fn set_harts(&self, harts: usize) {
+        self.round.set(Round { harts, ..self.round.get() });
+    }
+}
+
+impl Bus for SharedBus {
+    fn read(&mut self, address: u32, size: AccessSize) -> Result<u32, BusError> {
+        self.bus.borrow_mut().read(address, size)
+    }
+
+    fn write(&mut self, address: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        self.bus.borrow_mut().write(address, size, value)
+    }
+
+    fn tick(&mut self) {
+        let Round { position, harts } = self.round.get();
+        if position == 0 {
+            self.bus.borrow_mut().tick();
+        }
+        let position = if position + 1 >= harts { 0 } else { position + 1 };
+        self.round.set(Round { position, harts });
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        let round = self.round.get();
+        let idle = self.bus.borrow().idle_ticks().saturating_mul(round.harts.max(1) as u64);
+        round.until_start().saturating_add(idle)
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        let round = self.round.get();
+        let harts = round.harts.max(1) as u64;
+        let until = round.until_start();
+        if ticks <= until {
+            self.round.set(Round { position: if ticks == until { 0 } else { round.position + ticks as usize }, ..round });
+            return;
+        }
+
+        self.bus.borrow_mut().advance((ticks - until - 1) / harts + 1);
+        self.round.set(Round { position: ((ticks - until) % harts) as usize, ..round });
+    }
+
+    fn interrupts(&self, hart: u32) -> u32 {
+        self.bus.borrow().interrupts(hart)
+    }
+
+    fn time(&mut self) -> Option<u64> {
+        self.bus.borrow_mut().time()
+    }
+
+    fn page_generation(&self, address: u32) -> Option<u64> {
+        self.bus.borrow().page_generation(address)
+    }
+
+    #[cfg(feature = "jit")]
+    fn memory(&mut self) -> Option<crate::jit::Memory> {
+        self.bus.borrow_mut().memory()
+    }
+
+    fn reserve(&mut self, hart: u32, address: u32) {
+        self.bus.borrow_mut().reserve(hart, address);
+    }
+
+    fn release(&mut self, hart: u32, address: u32) -> bool {
+        self.bus.borrow_mut().release(hart, address)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    // every hart runs quantum instructions in turn
+    RoundRobin { quantum: u32 },
+    // a random hart runs between 1 and max_quantum instructions, reproducible for a seed
+    Random { seed: u64, max_quantum: u32 },
+}
+
+// harts sharing one bus, executed one instruction at a time in a deterministic order
+pub struct System {
+    harts: Vec<CPU<SharedBus>>,
+    running: Vec<bool>,
+    bus: SharedBus,
+    schedule: Schedule,
+    random: u64,
+    current: usize,
+    remaining: u32,
+    sbi: Option<SBI>,
+}
+
+impl System {
+    pub fn new(bus: SystemBus, harts: u32, schedule: Schedule) -> Self {
+        let bus = SharedBus::new(bus);
+        let harts: Vec<CPU<SharedBus>> = (0..harts)
+            .map(|hart| {
+                let mut cpu = CPU::with_bus(bus.clone());
+                cpu.csrs().write(MHARTID, hart);
+                cpu
+            })
+            .collect();
+        let running = vec![true; harts.len()];
+        bus.set_harts(harts.len());
+        let random = match schedule {
+            Schedule::Random { seed, .. } => seed,
+            Schedule::RoundRobin { .. } => 0,
+        };
+
+        // round robin moves on to hart 0 for the first slice
+        let current = harts.len().saturating_sub(1);
+
+        System { harts, running, bus, schedule, random, current, remaining: 0, sbi: None }
+    }
+
+    pub fn harts(&self) -> u32 {
+        self.harts.len() as u32
+    }
+
+    pub fn hart(&mut self, hart: u32) -> &mut CPU<SharedBus> {
+        &mut self.harts[hart as usize]
+    }
+
+    pub fn bus(&self) -> RefMut<'_, SystemBus> {
+        self.bus.borrow_mut()
+    }
+
+    // services traps into machine mode with the native sbi, hart 0 enters supervisor mode at its pc
+    // and the others wait until they are started through hsm
+    pub fn set_sbi(&mut self, mut sbi: SBI) {
+        let pc = *self.harts[0].pc();
+        sbi::enter_supervisor(&mut self.harts[0], pc);
+        for hart in 1..self.harts.len() as u32 {
+            sbi.set_hart_state(hart, HartState::Stopped);
+            self.set_running(hart, false);
+        }
+        self.sbi = Some(sbi);
+    }
+
+    pub fn sbi(&mut self) -> Option<&mut SBI> {
+        self.sbi.as_mut()
+    }
+
+    // stopped harts are skipped by the scheduler
+    pub fn set_running(&mut self, hart: u32, running: bool) {
+        self.running[hart as usize] = running;
+        if !running && self.current == hart as usize {
+            self.remaining = 0;
+        }
+        self.bus.set_harts(self.running.iter().filter(|&&running| running).count());
+    }
+
+    pub fn is_running(&self, hart: u32) -> bool {
+        self.running[hart as usize]
+    }
+
+    // executes one instruction and returns the hart that ran it
+    pub fn step(&mut self) -> Option<u32> {
+        if !self.running.contains(&true) {
+            return None;
+        }
+
+        if self.remaining == 0 {
+            self.next_slice();
+        }
+        self.remaining -= 1;
+
+        // the shared bus ticks the devices once per round
+        let current = self.current;
+        self.harts[current].tick();
+        if self.sbi.is_some() && self.harts[current].privilege() == Privilege::Machine {
+            self.service(current);
+        }
+
+        Some(current as u32)
+    }
+
+    // runs the native sbi for a hart in machine mode and applies hart state changes it made
+    fn service(&mut self, hart: usize) {
+        let Some(sbi) = &mut self.sbi else {
+            return;
+        };
+        sbi.trap(&mut self.harts[hart]);
+
+        let booted: Vec<u32> = (0..self.harts.len() as u32).filter(|&hart| sbi.boot(hart, &mut self.harts[hart as usize])).collect();
+        let stopped: Vec<u32> = (0..self.harts.len() as u32).filter(|&hart| sbi.hart_state(hart) == Some(HartState::Stopped)).collect();
+        for hart in booted {
+            self.set_running(hart, true);
+        }
+        for hart in stopped {
+            self.set_running(hart, false);
+        }
+    }
+
+    // returns the number of instructions executed before all harts stopped,
+    // the current hart runs its slice with its engine until it traps into machine mode for the native sbi
+    pub fn run(&mut self, steps: u64) -> u64 {
+        let sbi = self.sbi.is_some();
+        let mut step = 0;
+
+        while step < steps {
+            if !self.running.contains(&true) {
+                return step;
+            }
+            if self.remaining == 0 {
+                self.next_slice();
+            }
+
+            let current = self.current;
+            let slice = (self.remaining as u64).min(steps - step);
+            let ran = self.harts[current].run_until_privileged(slice, |cpu| sbi && cpu.privilege() == Privilege::Machine);
+            step += ran;
+            self.remaining -= ran as u32;
+            if sbi && self.harts[current].privilege() == Privilege::Machine {
+                self.service(current);
+            }
+        }
+
+        steps
+    }
+
+    // every hart, the shared bus, the scheduler position and the native sbi
+    pub fn save(&mut self) -> Vec<u8> {
+        let mut state = Writer::new();
+        snapshot::write_header(&mut state);
+        state.u32(self.harts.len() as u32);
+        for hart in self.harts.iter_mut() {
+            snapshot::save_hart(hart, &mut state);
+        }
+        self.bus.borrow_mut().save(&mut state);
+
+        state.bools(&self.running);
+        state.u64(self.random);
+        state.u32(self.current as u32);
+        state.u32(self.remaining);
+        state.u32(self.bus.round.get().position as u32);
+        state.bool(self.sbi.is_some());
+        if let Some(sbi) = &self.sbi {
+            sbi.save(&mut state);
+        }
+        state.into_inner()
+    }
+
+    // into a system built with the same harts, devices and schedule
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let mut state = Reader::new(data);
+        snapshot::read_header(&mut state)?;
+        state.expect(self.harts.len() as u32, "hart count")?;
+        for hart in self.harts.iter_mut() {
+            snapshot::restore_hart(hart, &mut state)?;
+        }
+        self.bus.borrow_mut().restore(&mut state)?;
+
+        state.bools_into(&mut self.running, "running harts")?;
+        self.random = state.u64()?;
+        self.current = state.u32()? as usize;
+        self.remaining = state.u32()?;
+        let position = state.u32()? as usize;
+        if self.current >= self.harts.len() {
+            return Err(SnapshotError::Mismatch(format!("current hart {}", self.current)));
+        }
+        let harts = self.running.iter().filter(|&&running| running).count();
+        self.bus.round.set(Round { position, harts });
+        match (state.bool()?, &mut self.sbi) {
+            (true, Some(sbi)) => sbi.restore(&mut state)?,
+            (false, None) => {}
+            _ => return Err(SnapshotError::Mismatch("firmware".to_string())),
+        }
+
+        if !state.is_empty() {
+            return Err(SnapshotError::Mismatch("trailing data".to_string()));
+        }
+        Ok(())
+    }
+
+    fn next_slice(&mut self) {
+        let harts = self.harts.len();
+
+        match self.schedule {
+            Schedule::RoundRobin { quantum } => {
+                let current = self.current;
+                self.current = (1..=harts).map(|offset| (current + offset) % harts).find(|&hart| self.running[hart]).unwrap();
+                self.remaining = quantum.max(1);
+            }
+            Schedule::Random { max_quantum, .. } => {
+                let running: Vec<usize> = (0..harts).filter(|&hart| self.running[hart]).collect();
+                let value = splitmix64(&mut self.random);
+                self.current = running[(value % running.len() as u64) as usize];
+                self.remaining = ((value >> 32) % max_quantum.max(1) as u64) as u32 + 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::CPU;
+use crate::bus::Bus;
+use crate::trap::Privilege;
+
+pub const MAGIC: [u8; 8] = *b"RV32SNAP";
+pub const VERSION: u32 = 4;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // not a snapshot file
+    Magic,
+    // written by a newer or older format
+    Version(u32),
+    Truncated,
+    // the machine is configured differently than the one that was saved
+    Mismatch(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "{}", error),
+            SnapshotError::Magic => write!(f, "not a snapshot"),
+            SnapshotError::Version(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Mismatch(what) => write!(f, "snapshot does not match the machine: {}", what),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+// little endian state, variable length data is prefixed with its length
+#[derive(Default)]
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.data.extend_from_slice(data);
+    }
+
+    pub fn bools(&mut self, values: &[bool]) {
+        self.u32(values.len() as u32);
+        self.data.extend(values.iter().map(|&value| value as u8));
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    pub fn bools(&mut self) -> Result<Vec<bool>, SnapshotError> {
+        Ok(self.bytes()?.iter().map(|&value| value != 0).collect())
+    }
+
+    // fails unless the saved value equals the one of the running machine
+    pub fn expect(&mut self, value: u32, what: &str) -> Result<(), SnapshotError> {
+        let saved = self.u32()?;
+        if saved != value {
+            return Err(SnapshotError::Mismatch(format!("{} is {} instead of {}", what, value, saved)));
+        }
+        Ok(())
+    }
+
+    // bools into an existing vector of the same length
+    pub fn bools_into(&mut self, values: &mut [bool], what: &str) -> Result<(), SnapshotError> {
+        let saved = self.bools()?;
+        if saved.len() != values.len() {
+            return Err(SnapshotError::Mismatch(format!("{} has {} entries instead of {}", what, values.len(), saved.len())));
+        }
+        values.copy_from_slice(&saved);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.data.len() < length {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+}
+
+pub fn write_header(state: &mut Writer) {
+    MAGIC.iter().for_each(|&byte| state.u8(byte));
+    state.u32(VERSION);
+}
+
+pub fn read_header(state: &mut Reader) -> Result<(), SnapshotError> {
+    let mut magic = [0; 8];
+    for byte in magic.iter_mut() {
+        *byte = state.u8().map_err(|_| SnapshotError::Magic)?;
+    }
+    if magic != MAGIC {
+        return Err(SnapshotError::Magic);
+    }
+
+    match state.u32()? {
+        VERSION => Ok(()),
+        version => Err(SnapshotError::Version(version)),
+    }
+}
+
+// registers, pc, privilege and csrs of one hart
+pub fn save_hart<B: Bus>(cpu: &mut CPU<B>, state: &mut Writer) {
+    cpu.registers().save(state);
+    state.u32(*cpu.pc());
+    state.u8(cpu.privilege() as u8);
+    cpu.csrs().save(state);
+}
+
+pub fn restore_hart<B: Bus>(cpu: &mut CPU<B>, state: &mut Reader) -> Result<(), SnapshotError> {
+    cpu.registers().restore(state)?;
+    *cpu.pc() = state.u32()?;
+    let privilege = match state.u8()? {
+        0 => Privilege::User,
+        1 => Privilege::Supervisor,
+        3 => Privilege::Machine,
+        privilege => return Err(SnapshotError::Mismatch(format!("privilege {}", privilege))),
+    };
+    cpu.set_privilege(privilege);
+    cpu.csrs().restore(state)
+}
+
+// the hart, ram and device state, backends and host resources are not saved
+pub fn save(cpu: &mut CPU) -> Vec<u8> {
+    let mut state = Writer::new();
+    write_header(&mut state);
+    save_hart(cpu, &mut state);
+    cpu.bus().save(&mut state);
+    state.into_inner()
+}
+
+// into a machine built with the same configuration, a failed restore leaves it partially restored
+pub fn restore(cpu: &mut CPU, data: &[u8]) -> Result<(), SnapshotError> {
+    let mut state = Reader::new(data);
+    read_header(&mut state)?;
+    restore_hart(cpu, &mut state)?;
+    cpu.bus().restore(&mut state)?;
+
+    if !state.is_empty() {
+        return Err(SnapshotError::Mismatch("trailing data".to_string()));
+    }
+    Ok(())
+}
+
+pub fn save_file<P: AsRef<Path>>(cpu: &mut CPU, path: P) -> Result<(), SnapshotError> {
+    Ok(fs::write(path, save(cpu))?)
+}
+
+pub fn restore_file<P: AsRef<Path>>(cpu: &mut CPU, path: P) -> Result<(), SnapshotError> {
+    restore(cpu, &fs::read(path)?)
+}
@@ -0,0 +1,152 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::bus::AccessSize;
+use crate::csr;
+use crate::instructions::InstructionGroup;
+use crate::trap::Privilege;
+
+struct Access {
+    address: u32,
+    size: AccessSize,
+    // stored value, None for loads
+    value: Option<u32>,
+}
+
+// one line per retired instruction in the format of spike --log-commits
+pub struct CommitLog {
+    output: Box<dyn Write>,
+    accesses: Vec<Access>,
+    trapped: bool,
+    error: Option<io::Error>,
+}
+
+impl CommitLog {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        CommitLog { output, accesses: Vec::new(), trapped: false, error: None }
+    }
+
+    // first write error, logging stops after it
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    pub(crate) fn begin(&mut self) {
+        self.accesses.clear();
+        self.trapped = false;
+    }
+
+    pub(crate) fn load(&mut self, address: u32, size: AccessSize) {
+        self.accesses.push(Access { address, size, value: None });
+    }
+
+    pub(crate) fn store(&mut self, address: u32, size: AccessSize, value: u32) {
+        let value = value & (u32::MAX >> (32 - size.bytes() * 8));
+        self.accesses.push(Access { address, size, value: Some(value) });
+    }
+
+    // instructions that trap are not retired and not logged
+    pub(crate) fn trap(&mut self) {
+        self.trapped = true;
+    }
+
+    pub(crate) fn trapped(&self) -> bool {
+        self.trapped
+    }
+
+    // writes the line for a retired instruction with its register and csr writebacks
+    pub(crate) fn commit(&mut self, hart: u32, privilege: Privilege, pc: u32, instruction: u32, register: Option<(u8, u32)>, csr: Option<(u16, u32)>) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let mut line = format!("core {:3}: {} 0x{:08x} (0x{:08x})", hart, privilege as u32, pc, instruction);
+        if let Some((register, value)) = register.filter(|&(register, _)| register != 0) {
+            line.push_str(&format!(" x{:<2} 0x{:08x}", register, value));
+        }
+        if let Some((number, value)) = csr {
+            line.push_str(&format!(" c{}_{} 0x{:08x}", number, csr::name(number).unwrap_or("unknown"), value));
+        }
+        for access in self.accesses.iter() {
+            line.push_str(&format!(" mem 0x{:08x}", access.address));
+            if let Some(value) = access.value {
+                let digits = access.size.bytes() as usize * 2;
+                line.push_str(&format!(" 0x{:0width$x}", value, width = digits));
+            }
+        }
+        line.push('\n');
+
+        if let Err(error) = self.output.write_all(line.as_bytes()) {
+            self.error = Some(error);
+        }
+    }
+}
+
+// in memory log output, clones share the same buffer
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    data: Rc<RefCell<Vec<u8>>>,
+}
+
+impl LogBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.data.borrow()).into_owned()
+    }
+
+    pub fn take(&self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut *self.data.borrow_mut())).into_owned()
+    }
+}
+
+impl Write for LogBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.data.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// destination register of instructions that write one
+pub fn destination(instruction: u32) -> Option<u8> {
+    let writes = [
+        InstructionGroup::LUI,
+        InstructionGroup::AUIPC,
+        InstructionGroup::JAL,
+        InstructionGroup::JALR,
+        InstructionGroup::LOAD,
+        InstructionGroup::MATHI,
+        InstructionGroup::MATH,
+    ]
+    .into_iter()
+    .any(|group| InstructionGroup::check(instruction, group));
+    let csr = InstructionGroup::check(instruction, InstructionGroup::CSR) && (instruction >> 12) & 0b111 != 0;
+
+    (writes || csr).then_some(((instruction >> 7) & 0x1F) as u8)
+}
+
+// csr written by a csr instruction, set and clear only write with a nonzero source
+pub fn csr_destination(instruction: u32) -> Option<u16> {
+    if !InstructionGroup::check(instruction, InstructionGroup::CSR) {
+        return None;
+    }
+
+    let funct3 = (instruction >> 12) & 0b111;
+    let source = (instruction >> 15) & 0x1F;
+    match funct3 {
+        0b001 | 0b101 => Some((instruction >> 20) as u16),
+        0b010 | 0b011 | 0b110 | 0b111 if source != 0 => Some((instruction >> 20) as u16),
+        _ => None,
+    }
+}
@@ -0,0 +1,101 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User       = 0,
+    Supervisor = 1,
+    Machine    = 3,
+}
+
+impl Privilege {
+    // from the two bit fields of mstatus, the reserved value is never stored
+    pub fn from_bits(bits: u32) -> Privilege {
+        match bits & 0b11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u32),
+    InstructionAccessFault(u32),
+    IllegalInstruction(u32),
+    Breakpoint(u32),
+    LoadAddressMisaligned(u32),
+    LoadAccessFault(u32),
+    StoreAddressMisaligned(u32),
+    StoreAccessFault(u32),
+    // privilege level the ecall was executed in
+    EnvironmentCall(Privilege),
+    InstructionPageFault(u32),
+    LoadPageFault(u32),
+    StorePageFault(u32),
+}
+
+impl Exception {
+    pub fn cause(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_)       => 1,
+            Exception::IllegalInstruction(_)           => 2,
+            Exception::Breakpoint(_)                   => 3,
+            Exception::LoadAddressMisaligned(_)        => 4,
+            Exception::LoadAccessFault(_)              => 5,
+            Exception::StoreAddressMisaligned(_)       => 6,
+            Exception::StoreAccessFault(_)             => 7,
+            Exception::EnvironmentCall(privilege)      => 8 + *privilege as u32,
+            Exception::InstructionPageFault(_)         => 12,
+            Exception::LoadPageFault(_)                => 13,
+            Exception::StorePageFault(_)               => 15,
+        }
+    }
+
+    // value written to mtval when the exception is taken
+    pub fn value(&self) -> u32 {
+        match *self {
+            Exception::InstructionAddressMisaligned(value)
+            | Exception::InstructionAccessFault(value)
+            | Exception::IllegalInstruction(value)
+            | Exception::Breakpoint(value)
+            | Exception::LoadAddressMisaligned(value)
+            | Exception::LoadAccessFault(value)
+            | Exception::StoreAddressMisaligned(value)
+            | Exception::StoreAccessFault(value)
+            | Exception::InstructionPageFault(value)
+            | Exception::LoadPageFault(value)
+            | Exception::StorePageFault(value) => value,
+            Exception::EnvironmentCall(_) => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware    = 3,
+    SupervisorTimer    = 5,
+    MachineTimer       = 7,
+    SupervisorExternal = 9,
+    MachineExternal    = 11,
+}
+
+impl Interrupt {
+    pub fn cause(&self) -> u32 {
+        0x80000000 | *self as u32
+    }
+
+    // highest priority interrupt among the given mip bits
+    pub fn from_pending(pending: u32) -> Option<Interrupt> {
+        [
+            Interrupt::MachineExternal,
+            Interrupt::MachineSoftware,
+            Interrupt::MachineTimer,
+            Interrupt::SupervisorExternal,
+            Interrupt::SupervisorSoftware,
+            Interrupt::SupervisorTimer,
+        ]
+            .into_iter()
+            .find(|interrupt| pending & (1 << *interrupt as u32) != 0)
+    }
+}
@@ -0,0 +1,90 @@
+use rust_risc_v::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::decode::*;
+
+const LR: u8 = 0b00010;
+const SC: u8 = 0b00011;
+
+#[test]
+fn decode_atomics() {
+    // encodings from the llvm assembler
+    assert_eq!(amo(LR, 10, 11, 0), 0x1005A52F);
+    assert_eq!(amo(SC, 10, 11, 12), 0x18C5A52F);
+    assert_eq!(decode(0x1005A52F), Operation::LoadReserved { rd: 10, rs1: 11 });
+    assert_eq!(decode(0x18C5A52F), Operation::StoreConditional { rd: 10, rs1: 11, rs2: 12 });
+    assert_eq!(decode(0x0063A2AF), Operation::Atomic { operation: AtomicOperation::Add, rd: 5, rs1: 7, rs2: 6 });
+    assert_eq!(decode(0xE063A2AF), Operation::Atomic { operation: AtomicOperation::MaxUnsigned, rd: 5, rs1: 7, rs2: 6 });
+
+    // only words, and lr has no source register
+    assert_eq!(decode(0x0063B2AF), Operation::Illegal);
+    assert_eq!(decode(amo(LR, 10, 11, 1)), Operation::Illegal);
+    assert_eq!(decode(amo(0b00101, 10, 11, 12)), Operation::Illegal);
+}
+
+#[test]
+fn read_modify_write() {
+    let cases = [
+        (0b00001, 0x8000_0005),
+        (0b00000, 0x8000_0004),
+        (0b00100, 0x7FFF_FFFA),
+        (0b01100, 0x8000_0005),
+        (0b01000, 0xFFFF_FFFF),
+        (0b10000, 0x8000_0005),
+        (0b10100, 0xFFFF_FFFF),
+        (0b11000, 0x8000_0005),
+        (0b11100, 0xFFFF_FFFF),
+    ];
+
+    for (funct5, expected) in cases {
+        let mut cpu = CPU::new(256);
+        cpu.ram().write_word(0, amo(funct5, 5, 6, 7));
+        cpu.ram().write_word(0x100, 0xFFFF_FFFF);
+        cpu.registers().write(6, 0x100);
+        cpu.registers().write(7, 0x8000_0005);
+
+        cpu.tick();
+        assert_eq!(*cpu.pc(), 4);
+        assert_eq!(cpu.registers().read(5), 0xFFFF_FFFF);
+        assert_eq!(cpu.ram().read_word(0x100), expected, "funct5 {:#b}", funct5);
+    }
+}
+
+#[test]
+fn load_reserved_store_conditional() {
+    let mut cpu = CPU::new(256);
+    cpu.ram().write_word(0, amo(LR, 5, 6, 0));
+    cpu.ram().write_word(4, amo(SC, 7, 6, 8));
+    cpu.ram().write_word(8, amo(SC, 9, 6, 8));
+    cpu.ram().write_word(0x100, 41);
+    cpu.registers().write(6, 0x100);
+    cpu.registers().write(8, 42);
+    cpu.registers().write(9, 5);
+
+    cpu.run_for(3);
+    assert_eq!(cpu.registers().read(5), 41);
+    assert_eq!(cpu.registers().read(7), 0);
+    assert_eq!(cpu.ram().read_word(0x100), 42);
+
+    // the first store conditional used up the reservation
+    assert_eq!(cpu.registers().read(9), 1);
+}
+
+#[test]
+fn misaligned_atomics() {
+    let mut cpu = CPU::new(256);
+    cpu.csrs().write(MTVEC, 0x80);
+    cpu.ram().write_word(0, amo(LR, 5, 6, 0));
+    cpu.ram().write_word(4, amo(0b00000, 5, 6, 7));
+    cpu.registers().write(6, 0x102);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 0x80);
+    assert_eq!(cpu.csrs().read(MCAUSE), 4);
+    assert_eq!(cpu.csrs().read(MTVAL), 0x102);
+
+    *cpu.pc() = 4;
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 6);
+    assert_eq!(cpu.registers().read(5), 0);
+}
@@ -13,7 +13,7 @@ fn auipc_test() {
     cpu.tick();
     // verify values
     assert_eq!(cpu.registers().read(0), 0);
-    assert_eq!(cpu.registers().read(1), 8);
+    assert_eq!(cpu.registers().read(1), 4);
     assert_eq!(cpu.registers().read(2), 4104);
-    assert_eq!(*cpu.pc(), 4104);
+    assert_eq!(*cpu.pc(), 12);
 }
@@ -0,0 +1,271 @@
+use rust_risc_v::*;
+use rust_risc_v::block::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::debug::{StopReason, WatchKind};
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::devices::uart::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::branch::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::machine::*;
+use rust_risc_v::sbi::*;
+
+const FENCE_I: u32 = 0x0000100F;
+
+fn program(cpu: &mut CPU, address: u32, code: &[u32]) {
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(address + index as u32 * 4, *instruction);
+    }
+}
+
+// the same machine once for the interpreter and once for blocks
+fn pair<F: Fn(&mut CPU)>(setup: F) -> (CPU, CPU) {
+    let mut interpreter = CPU::new(0x1000);
+    let mut blocks = CPU::new(0x1000);
+    setup(&mut interpreter);
+    setup(&mut blocks);
+    blocks.set_engine(Engine::Blocks);
+    (interpreter, blocks)
+}
+
+fn assert_same(interpreter: &mut CPU, blocks: &mut CPU) {
+    assert_eq!(*interpreter.pc(), *blocks.pc());
+    assert_eq!(interpreter.registers().inspect(), blocks.registers().inspect());
+    for csr in [MEPC, MCAUSE, MTVAL, MSTATUS] {
+        assert_eq!(interpreter.csrs().read(csr), blocks.csrs().read(csr));
+    }
+    assert_eq!(interpreter.ram().inspect(0, 0x4000), blocks.ram().inspect(0, 0x4000));
+}
+
+#[test]
+fn matches_interpreter() {
+    // walks a list in the data page and counts the walks in mscratch bits
+    let (mut interpreter, mut blocks) = pair(|cpu| {
+        program(cpu, 0, &[
+            load(LoadType::LW, 5, 0, 0x7FC),
+            csr(CSRType::CSRRSI, 7, 1, MSCRATCH),
+            load(LoadType::LBU, 8, 5, 4),
+            store(StoreType::SB, 5, 7, 5),
+            store(StoreType::SW, 0, 8, 0x7F0),
+            load(LoadType::LW, 5, 5, 0),
+            branch(BranchType::BNE, 5, 0, 0x1FF0),
+            csr(CSRType::CSRRCI, 0, 1, MSCRATCH),
+            jal(0, -32i32 as u32 & 0x1FFFFF),
+        ]);
+        cpu.ram().write_word(0x7FC, 0x2000);
+        for node in 0..6 {
+            let address = 0x2000 + node * 8;
+            cpu.ram().write_word(address, if node < 5 { address + 8 } else { 0 });
+            cpu.ram().write_word(address + 4, node * 7);
+        }
+    });
+
+    for instructions in [1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 1000] {
+        for _ in 0..instructions {
+            interpreter.tick();
+        }
+        assert_eq!(blocks.run_for(instructions), StopReason::InstructionLimit);
+        assert_same(&mut interpreter, &mut blocks);
+    }
+    assert_eq!(blocks.csrs().read(MCAUSE), 0);
+    assert!(!blocks.block_cache().is_empty());
+}
+
+#[test]
+fn precise_exceptions() {
+    // the third load of the block faults
+    let (mut interpreter, mut blocks) = pair(|cpu| {
+        program(cpu, 0, &[
+            load(LoadType::LW, 5, 0, 0x100),
+            load(LoadType::LW, 6, 0, 0x104),
+            load(LoadType::LW, 7, 1, 0),
+            load(LoadType::LW, 8, 0, 0x108),
+            jal(0, 0),
+        ]);
+        cpu.ram().write_word(0x100, 11);
+        cpu.ram().write_word(0x104, 22);
+        cpu.ram().write_word(0x108, 33);
+        cpu.registers().write(1, 0x4000_0000);
+        cpu.csrs().write(MTVEC, 0x200);
+        program(cpu, 0x200, &[jal(0, 0)]);
+    });
+
+    for _ in 0..4 {
+        interpreter.tick();
+    }
+    blocks.run_for(4);
+    assert_same(&mut interpreter, &mut blocks);
+    assert_eq!(blocks.csrs().read(MEPC), 8);
+    assert_eq!(blocks.csrs().read(MCAUSE), 5);
+    assert_eq!(blocks.csrs().read(MTVAL), 0x4000_0000);
+    assert_eq!(blocks.registers().read(6), 22);
+    assert_eq!(blocks.registers().read(8), 0);
+}
+
+#[test]
+fn interrupts_inside_blocks() {
+    // a long straight block interrupted by the timer after a fixed number of instructions
+    let (mut interpreter, mut blocks) = pair(|cpu| {
+        cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+        let code: Vec<u32> = (0..40).map(|index| store(StoreType::SW, 0, 0, 0x400 + index * 4)).collect();
+        program(cpu, 0, &code);
+        program(cpu, 160, &[jal(0, -160i32 as u32 & 0x1FFFFF)]);
+        program(cpu, 0x800, &[jal(0, 0)]);
+        cpu.bus().write(CLINT::BASE + CLINT::MTIMECMP, AccessSize::Word, 61).unwrap();
+        cpu.bus().write(CLINT::BASE + CLINT::MTIMECMP + 4, AccessSize::Word, 0).unwrap();
+        cpu.csrs().write(MTVEC, 0x800);
+        cpu.csrs().write(MIE, MIP_MTIP);
+        cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+    });
+
+    for _ in 0..80 {
+        interpreter.tick();
+    }
+    blocks.run_for(80);
+    assert_same(&mut interpreter, &mut blocks);
+    assert_eq!(blocks.csrs().read(MCAUSE), 0x80000007);
+    assert_eq!(blocks.csrs().read(MEPC), 76);
+}
+
+#[test]
+fn device_writes_inside_blocks() {
+    // the store to msip raises the software interrupt before the next instruction of the block
+    let (mut interpreter, mut blocks) = pair(|cpu| {
+        cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+        program(cpu, 0, &[
+            lui(5, CLINT::BASE),
+            mathi(0b000, 6, 0, 1),
+            store(StoreType::SW, 5, 6, CLINT::MSIP as u16),
+            mathi(0b000, 7, 0, 1),
+            mathi(0b000, 7, 7, 1),
+            jal(0, 0),
+        ]);
+        program(cpu, 0x800, &[store(StoreType::SW, 5, 0, CLINT::MSIP as u16), jal(0, 0)]);
+        cpu.csrs().write(MTVEC, 0x800);
+        cpu.csrs().write(MIE, MIP_MSIP);
+        cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+    });
+
+    for _ in 0..10 {
+        interpreter.tick();
+    }
+    blocks.run_for(10);
+    assert_same(&mut interpreter, &mut blocks);
+    assert_eq!(blocks.csrs().read(MCAUSE), 0x80000003);
+    assert_eq!(blocks.csrs().read(MEPC), 12);
+    assert_eq!(blocks.registers().read(7), 0);
+}
+
+#[test]
+fn time_read_inside_blocks() {
+    // the time csr sees the instructions before it in the block
+    let (mut interpreter, mut blocks) = pair(|cpu| {
+        cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+        program(cpu, 0, &[
+            mathi(0b000, 6, 6, 1),
+            mathi(0b000, 6, 6, 1),
+            csr(CSRType::CSRRS, 7, 0, TIME),
+            jal(0, -12i32 as u32 & 0x1FFFFF),
+        ]);
+    });
+
+    for _ in 0..30 {
+        interpreter.tick();
+    }
+    blocks.run_for(30);
+    assert_same(&mut interpreter, &mut blocks);
+    assert_eq!(blocks.registers().read(7), 27);
+}
+
+#[test]
+fn code_written_inside_a_block() {
+    // the store replaces the instruction after the next one in the same block
+    let (mut interpreter, mut blocks) = pair(|cpu| {
+        program(cpu, 0, &[
+            load(LoadType::LW, 5, 0, 0x100),
+            store(StoreType::SW, 0, 5, 12),
+            lui(6, 0x1000),
+            lui(6, 0x2000),
+            FENCE_I,
+            jal(0, -20i32 as u32 & 0x1FFFFF),
+        ]);
+        cpu.ram().write_word(0x100, lui(6, 0x3000));
+    });
+
+    blocks.run_for(4);
+    assert_eq!(blocks.registers().read(6), 0x3000);
+    blocks.run_for(1);
+    assert!(blocks.block_cache().is_empty());
+
+    for _ in 0..5 {
+        interpreter.tick();
+    }
+    assert_same(&mut interpreter, &mut blocks);
+
+    // host writes are seen the same way
+    blocks.run_for(3);
+    blocks.ram().write_word(8, lui(7, 0x4000));
+    blocks.run_for(12);
+    assert_eq!(blocks.registers().read(7), 0x4000);
+}
+
+#[test]
+fn debugger_inside_blocks() {
+    let (_, mut blocks) = pair(|cpu| {
+        let code: Vec<u32> = (0..8).map(|index| store(StoreType::SW, 0, 0, 0x400 + index * 4)).collect();
+        program(cpu, 0, &code);
+        program(cpu, 32, &[jal(0, -32i32 as u32 & 0x1FFFFF)]);
+    });
+
+    blocks.debugger().add_breakpoint(12);
+    assert_eq!(blocks.run(), StopReason::Breakpoint(12));
+    assert_eq!(*blocks.pc(), 12);
+    blocks.debugger().remove_breakpoint(12);
+
+    blocks.debugger().add_watchpoint(0x414..0x418, WatchKind::Write);
+    assert_eq!(blocks.run(), StopReason::Watchpoint { address: 0x414, kind: WatchKind::Write });
+    assert_eq!(*blocks.pc(), 24);
+}
+
+#[test]
+fn virt_runs_blocks() {
+    // prints through the legacy console extension and shuts down
+    let kernel: Vec<u8> = [
+        lui(5, RAM_BASE + KERNEL_OFFSET + 0x1000),
+        load(LoadType::LW, 17, 5, 8),
+        load(LoadType::LBU, 10, 5, 0),
+        ECALL,
+        load(LoadType::LBU, 10, 5, 1),
+        ECALL,
+        load(LoadType::LBU, 10, 5, 2),
+        ECALL,
+        load(LoadType::LW, 17, 5, 4),
+        load(LoadType::LW, 10, 5, 12),
+        ECALL,
+        jal(0, 0),
+    ]
+    .iter()
+    .flat_map(|instruction| instruction.to_le_bytes())
+    .chain(std::iter::repeat_n(0, 0x1000 - 48))
+    .chain(*b"hi!\0")
+    .chain(EXTENSION_SRST.to_le_bytes())
+    .chain(EXTENSION_CONSOLE_PUTCHAR.to_le_bytes())
+    .chain(0u32.to_le_bytes())
+    .collect();
+
+    let mut steps = Vec::new();
+    for engine in [Engine::Interpreter, Engine::Blocks] {
+        let console = BufferBackend::new();
+        let mut virt = Virt::new(VirtConfig::new(0x0080_0000, kernel.clone(), Box::new(console.clone()))).unwrap();
+        virt.cpu().set_engine(engine);
+
+        steps.push(virt.run(1000));
+        assert!(virt.halted());
+        assert_eq!(console.take_output(), b"hi!");
+    }
+    assert_eq!(steps[0], steps[1]);
+    assert_eq!(steps[0], 11);
+}
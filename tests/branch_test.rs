@@ -50,6 +50,7 @@ fn bne_test() {
 }
 
 #[test]
+#[allow(clippy::unnecessary_cast)]
 fn blt_test() {
     let mut cpu = CPU::new(16);
     // setup register with value
@@ -75,6 +76,7 @@ fn blt_test() {
 }
 
 #[test]
+#[allow(clippy::unnecessary_cast)]
 fn bge_test() {
     let mut cpu = CPU::new(16);
     // setup register with value
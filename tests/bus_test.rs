@@ -0,0 +1,131 @@
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::devices::plic::*;
+use rust_risc_v::devices::rom::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+
+struct Register {
+    value: u32,
+    ticks: u32,
+}
+
+impl Device for Register {
+    fn read(&mut self, _offset: u32, _size: AccessSize) -> Result<u32, BusError> {
+        Ok(self.value)
+    }
+
+    fn write(&mut self, _offset: u32, _size: AccessSize, value: u32) -> Result<(), BusError> {
+        self.value = value;
+        Ok(())
+    }
+
+    fn tick(&mut self, ticks: u64) {
+        self.ticks += ticks as u32;
+    }
+
+    fn interrupt(&self) -> bool {
+        self.value != 0
+    }
+}
+
+#[test]
+fn map_regions() {
+    let mut bus = SystemBus::new(0x8000_0000, RAM::new(64));
+
+    assert!(bus.map(0x1000_0000, 0x100, AccessSizes::ALL, Box::new(Register { value: 0, ticks: 0 })).is_ok());
+    assert_eq!(bus.map(0x1000_0080, 0x100, AccessSizes::ALL, Box::new(Register { value: 0, ticks: 0 })), Err(BusError::Overlap(0x1000_0080)));
+    assert_eq!(bus.map(0x8000_0000, 0x4, AccessSizes::ALL, Box::new(Register { value: 0, ticks: 0 })), Err(BusError::Overlap(0x8000_0000)));
+
+    bus.write(0x8000_0004, AccessSize::Word, 0x11223344).unwrap();
+    assert_eq!(bus.read(0x8000_0005, AccessSize::Byte), Ok(0x33));
+    assert_eq!(bus.ram().read_word(4), 0x11223344);
+
+    bus.write(0x1000_0010, AccessSize::Half, 7).unwrap();
+    assert_eq!(bus.read(0x1000_0000, AccessSize::Word), Ok(7));
+    assert_eq!(bus.device::<Register>(0x1000_0000).unwrap().value, 7);
+
+    assert_eq!(bus.read(0x2000_0000, AccessSize::Word), Err(BusError::AccessFault(0x2000_0000)));
+    assert_eq!(bus.read(0x8000_00FE, AccessSize::Word), Err(BusError::AccessFault(0x8000_00FE)));
+}
+
+#[test]
+fn access_sizes() {
+    let mut bus = SystemBus::new(0, RAM::new(64));
+    bus.map(0x1000_0000, 0x100, AccessSizes::WORD.union(AccessSizes::HALF), Box::new(Register { value: 0, ticks: 0 })).unwrap();
+
+    assert!(bus.write(0x1000_0000, AccessSize::Word, 1).is_ok());
+    assert!(bus.write(0x1000_0000, AccessSize::Half, 1).is_ok());
+    assert_eq!(bus.write(0x1000_0000, AccessSize::Byte, 1), Err(BusError::AccessFault(0x1000_0000)));
+    assert_eq!(bus.read(0x1000_00FE, AccessSize::Word), Err(BusError::AccessFault(0x1000_00FE)));
+}
+
+#[test]
+fn rom_region() {
+    let mut cpu = CPU::new(64);
+    let program = lui(1, 0x12345000).to_le_bytes().to_vec();
+    cpu.bus().map(0x1000, 0x100, AccessSizes::ALL, Box::new(ROM::new(program))).unwrap();
+    // setup ram with instructions
+    cpu.ram().write_word(0, jal(0, 0x1000));
+    cpu.ram().write_word(128, store(StoreType::SW, 0, 0, 0));
+    cpu.csrs().write(MTVEC, 128);
+
+    cpu.tick();
+    cpu.tick();
+    assert_eq!(cpu.registers().read(1), 0x12345000);
+
+    // the next word of the rom is out of range
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 128);
+    assert_eq!(cpu.csrs().read(MCAUSE), 1);
+    assert_eq!(cpu.csrs().read(MTVAL), 0x1004);
+
+    cpu.registers().write(1, 0x1000);
+    cpu.ram().write_word(128, store(StoreType::SW, 1, 0, 0));
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 7);
+    assert_eq!(cpu.csrs().read(MTVAL), 0x1000);
+}
+
+#[test]
+fn unmapped_load() {
+    let mut cpu = CPU::new(64);
+    // setup ram with instructions
+    cpu.ram().write_word(0, load(LoadType::LW, 2, 1, 0));
+    cpu.registers().write(1, 0x4000_0000);
+    cpu.csrs().write(MTVEC, 32);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 32);
+    assert_eq!(cpu.csrs().read(MCAUSE), 5);
+    assert_eq!(cpu.csrs().read(MTVAL), 0x4000_0000);
+    assert_eq!(cpu.csrs().read(MEPC), 0);
+}
+
+#[test]
+fn device_interrupt_through_plic() {
+    let mut cpu = CPU::new(64);
+    cpu.bus().map(PLIC::BASE, PLIC::SIZE, AccessSizes::WORD, Box::new(PLIC::new(8, 2))).unwrap();
+    cpu.bus().map(0x1000_0000, 0x100, AccessSizes::ALL, Box::new(Register { value: 0, ticks: 0 })).unwrap();
+    cpu.bus().connect_interrupt(0x1000_0000, 3).unwrap();
+    // setup ram with instructions
+    cpu.ram().write_word(0, store(StoreType::SW, 1, 2, 0));
+    cpu.registers().write(1, 0x1000_0000);
+    cpu.registers().write(2, 1);
+    // enable external interrupt
+    cpu.csrs().write(MTVEC, 32);
+    cpu.csrs().write(MIE, MIP_MEIP);
+    cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+    cpu.bus().device::<PLIC>(PLIC::BASE).unwrap().set_priority(3, 1);
+    cpu.bus().device::<PLIC>(PLIC::BASE).unwrap().set_enabled(0, 3, true);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 4);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 32);
+    assert_eq!(cpu.csrs().read(MCAUSE), 0x8000000B);
+    assert_eq!(cpu.bus().device::<Register>(0x1000_0000).unwrap().ticks, 2);
+}
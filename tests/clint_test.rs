@@ -0,0 +1,132 @@
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::load::*;
+
+#[test]
+fn mtime_instruction_time_base() {
+    let mut clint = CLINT::new(1, TimeBase::Instructions(2));
+
+    for _ in 0..10 {
+        clint.tick(1);
+    }
+    assert_eq!(clint.mtime(), 5);
+
+    clint.write_word(CLINT::MTIME + 4, 1);
+    assert_eq!(clint.read_word(CLINT::MTIME), 5);
+    assert_eq!(clint.read_word(CLINT::MTIME + 4), 1);
+    assert_eq!(clint.mtime(), 0x1_0000_0005);
+}
+
+#[test]
+fn mtime_wall_clock_time_base() {
+    let clint = CLINT::new(1, TimeBase::WallClock(1_000_000_000));
+    let first = clint.mtime();
+
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    assert!(clint.mtime() > first);
+}
+
+#[test]
+fn mtimecmp_and_msip_per_hart() {
+    let mut clint = CLINT::new(2, TimeBase::Instructions(1));
+
+    clint.write_word(CLINT::MSIP + 4, 1);
+    clint.write_word(CLINT::MTIMECMP, 3);
+    clint.write_word(CLINT::MTIMECMP + 4, 0);
+
+    assert_eq!(clint.interrupts(0), 0);
+    assert_eq!(clint.interrupts(1), MIP_MSIP);
+
+    for _ in 0..3 {
+        clint.tick(1);
+    }
+    assert_eq!(clint.interrupts(0), MIP_MTIP);
+    assert_eq!(clint.read_word(CLINT::MSIP + 4), 1);
+    assert_eq!(clint.read_word(CLINT::MTIMECMP), 3);
+}
+
+#[test]
+fn timer_interrupt() {
+    let mut cpu = CPU::new(256);
+    cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+    // setup ram with instructions
+    cpu.ram().write_word(0, lui(1, 0));
+    cpu.ram().write_word(4, lui(1, 0));
+    cpu.ram().write_word(8, lui(1, 0));
+    // enable timer interrupt
+    cpu.csrs().write(MTVEC, 512);
+    cpu.csrs().write(MIE, MIP_MTIP);
+    cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+    cpu.bus().device::<CLINT>(CLINT::BASE).unwrap().set_mtimecmp(0, 3);
+
+    cpu.tick();
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 8);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 512);
+    assert_eq!(cpu.csrs().read(MEPC), 8);
+    assert_eq!(cpu.csrs().read(MCAUSE), 0x80000007);
+    assert_eq!(cpu.csrs().read(MSTATUS) & MSTATUS_MIE, 0);
+    assert_eq!(cpu.csrs().read(MSTATUS) & MSTATUS_MPIE, MSTATUS_MPIE);
+}
+
+#[test]
+fn mtimecmp_set_between_ticks() {
+    let mut cpu = CPU::new(256);
+    cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+    // setup ram with instructions
+    cpu.ram().write_word(0, jal(0, 0));
+    cpu.ram().write_word(512, jal(0, 0));
+    // enable timer interrupt
+    cpu.csrs().write(MTVEC, 512);
+    cpu.csrs().write(MIE, MIP_MTIP);
+    cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+
+    for _ in 0..5 {
+        cpu.tick();
+    }
+    cpu.bus().device::<CLINT>(CLINT::BASE).unwrap().set_mtimecmp(0, 8);
+    for _ in 0..20 {
+        cpu.tick();
+    }
+    assert_eq!(cpu.csrs().read(MCAUSE), 0x80000007);
+}
+
+#[test]
+fn software_interrupt_vectored() {
+    let mut cpu = CPU::new(256);
+    cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+    // enable software interrupt with vectored mtvec
+    cpu.csrs().write(MTVEC, 512 | 1);
+    cpu.csrs().write(MIE, MIP_MSIP);
+    cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+    cpu.bus().device::<CLINT>(CLINT::BASE).unwrap().write_word(CLINT::MSIP, 1);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 524);
+    assert_eq!(cpu.csrs().read(MCAUSE), 0x80000003);
+    assert_eq!(cpu.csrs().read(MIP) & MIP_MSIP, MIP_MSIP);
+}
+
+#[test]
+fn load_mtime() {
+    let mut cpu = CPU::new(256);
+    cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+    // setup ram with instructions
+    cpu.ram().write_word(0, load(LoadType::LW, 2, 1, 0x7F8));
+    cpu.ram().write_word(4, load(LoadType::LB, 3, 1, 0x7F8));
+    cpu.registers().write(1, CLINT::BASE + CLINT::MTIME - 0x7F8);
+    cpu.csrs().write(MTVEC, 128);
+    cpu.bus().device::<CLINT>(CLINT::BASE).unwrap().set_mtime(41);
+
+    cpu.tick();
+    assert_eq!(cpu.registers().read(2), 42);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 128);
+    assert_eq!(cpu.csrs().read(MCAUSE), 5);
+}
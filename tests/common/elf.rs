@@ -0,0 +1,55 @@
+// minimal 32 bit risc-v executable with one segment per entry and a symbol table
+pub fn build(entry: u32, segments: &[(u32, &[u8])], symbols: &[(&str, u32)]) -> Vec<u8> {
+    let program_headers = 52;
+    let mut data_offset = program_headers + 32 * segments.len();
+    let mut headers = Vec::new();
+    let mut contents = Vec::new();
+
+    for (address, data) in segments {
+        for value in [1, data_offset as u32, *address, *address, data.len() as u32, data.len() as u32, 0b111, 4] {
+            headers.extend_from_slice(&value.to_le_bytes());
+        }
+        contents.extend_from_slice(data);
+        data_offset += data.len();
+    }
+
+    let mut strings = vec![0];
+    let mut table = vec![0; 16];
+    for (name, value) in symbols {
+        let entry = [strings.len() as u32, *value, 0, 0x10];
+        table.extend(entry.iter().flat_map(|field| field.to_le_bytes()));
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+    }
+
+    let table_offset = data_offset;
+    let strings_offset = table_offset + table.len();
+    let section_headers = strings_offset + strings.len();
+
+    let mut elf = vec![0x7F, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    elf.extend_from_slice(&2u16.to_le_bytes());
+    elf.extend_from_slice(&243u16.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&entry.to_le_bytes());
+    elf.extend_from_slice(&(program_headers as u32).to_le_bytes());
+    elf.extend_from_slice(&(section_headers as u32).to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    for value in [52u16, 32, segments.len() as u16, 40, 3, 0] {
+        elf.extend_from_slice(&value.to_le_bytes());
+    }
+    elf.extend_from_slice(&headers);
+    elf.extend_from_slice(&contents);
+    elf.extend_from_slice(&table);
+    elf.extend_from_slice(&strings);
+
+    // null section, symbol table linked to the string table
+    elf.extend_from_slice(&[0; 40]);
+    for value in [0, 2, 0, 0, table_offset as u32, table.len() as u32, 2, 1, 4, 16] {
+        elf.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [0, 3, 0, 0, strings_offset as u32, strings.len() as u32, 0, 0, 1, 0] {
+        elf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    elf
+}
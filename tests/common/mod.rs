@@ -0,0 +1,115 @@
+#![allow(dead_code)]
+
+pub mod elf;
+
+use rust_risc_v::bus::*;
+use rust_risc_v::devices::virtio::*;
+use rust_risc_v::devices::virtio::queue::*;
+
+pub const QUEUE_SIZE: u32 = 8;
+
+// driver side of a split virtqueue living in ram
+pub struct Driver {
+    pub base: u32,
+    pub queue: u32,
+    pub descriptors: u32,
+    pub available: u32,
+    pub used: u32,
+    next_descriptor: u16,
+    available_index: u16,
+    used_index: u16,
+}
+
+impl Driver {
+    // negotiates features and sets up the given queue with its rings placed at area
+    pub fn new(bus: &mut SystemBus, base: u32, queue: u32, area: u32) -> Self {
+        let driver = Driver {
+            base,
+            queue,
+            descriptors: area,
+            available: area + 0x400,
+            used: area + 0x800,
+            next_descriptor: 0,
+            available_index: 0,
+            used_index: 0,
+        };
+
+        if bus.read(base + STATUS, AccessSize::Word).unwrap() & STATUS_DRIVER_OK == 0 {
+            bus.write(base + STATUS, AccessSize::Word, STATUS_ACKNOWLEDGE | STATUS_DRIVER).unwrap();
+            bus.write(base + DEVICE_FEATURES_SEL, AccessSize::Word, 0).unwrap();
+            let low = bus.read(base + DEVICE_FEATURES, AccessSize::Word).unwrap();
+            bus.write(base + DEVICE_FEATURES_SEL, AccessSize::Word, 1).unwrap();
+            let high = bus.read(base + DEVICE_FEATURES, AccessSize::Word).unwrap();
+            bus.write(base + DRIVER_FEATURES_SEL, AccessSize::Word, 0).unwrap();
+            bus.write(base + DRIVER_FEATURES, AccessSize::Word, low).unwrap();
+            bus.write(base + DRIVER_FEATURES_SEL, AccessSize::Word, 1).unwrap();
+            bus.write(base + DRIVER_FEATURES, AccessSize::Word, high).unwrap();
+            bus.write(base + STATUS, AccessSize::Word, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK).unwrap();
+        }
+
+        bus.write(base + QUEUE_SEL, AccessSize::Word, queue).unwrap();
+        bus.write(base + QUEUE_NUM, AccessSize::Word, QUEUE_SIZE).unwrap();
+        bus.write(base + QUEUE_DESC_LOW, AccessSize::Word, driver.descriptors).unwrap();
+        bus.write(base + QUEUE_DRIVER_LOW, AccessSize::Word, driver.available).unwrap();
+        bus.write(base + QUEUE_DEVICE_LOW, AccessSize::Word, driver.used).unwrap();
+        bus.write(base + QUEUE_READY, AccessSize::Word, 1).unwrap();
+        bus.write(base + STATUS, AccessSize::Word, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK).unwrap();
+
+        driver
+    }
+
+    // places a chain of (address, length, writable) buffers and notifies the device
+    pub fn submit(&mut self, bus: &mut SystemBus, buffers: &[(u32, u32, bool)]) -> u16 {
+        let head = self.next_descriptor;
+
+        for (index, &(address, length, writable)) in buffers.iter().enumerate() {
+            let descriptor = (self.next_descriptor as u32 % QUEUE_SIZE) as u16;
+            let next = ((descriptor as u32 + 1) % QUEUE_SIZE) as u16;
+            let mut flags = if writable { DESC_F_WRITE } else { 0 };
+            if index + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+
+            let entry = self.descriptors + descriptor as u32 * 16;
+            bus.write(entry, AccessSize::Word, address).unwrap();
+            bus.write(entry + 4, AccessSize::Word, 0).unwrap();
+            bus.write(entry + 8, AccessSize::Word, length).unwrap();
+            bus.write(entry + 12, AccessSize::Half, flags as u32).unwrap();
+            bus.write(entry + 14, AccessSize::Half, next as u32).unwrap();
+            self.next_descriptor = next;
+        }
+
+        let slot = self.available_index as u32 % QUEUE_SIZE;
+        bus.write(self.available + 4 + slot * 2, AccessSize::Half, head as u32).unwrap();
+        self.available_index = self.available_index.wrapping_add(1);
+        bus.write(self.available + 2, AccessSize::Half, self.available_index as u32).unwrap();
+        bus.write(self.base + QUEUE_NOTIFY, AccessSize::Word, self.queue).unwrap();
+
+        head
+    }
+
+    // next used element as (head, length)
+    pub fn used(&mut self, bus: &mut SystemBus) -> Option<(u16, u32)> {
+        let index = bus.read(self.used + 2, AccessSize::Half).unwrap() as u16;
+        if index == self.used_index {
+            return None;
+        }
+
+        let slot = self.used_index as u32 % QUEUE_SIZE;
+        let head = bus.read(self.used + 4 + slot * 8, AccessSize::Word).unwrap() as u16;
+        let length = bus.read(self.used + 8 + slot * 8, AccessSize::Word).unwrap();
+        self.used_index = self.used_index.wrapping_add(1);
+
+        Some((head, length))
+    }
+}
+
+pub fn write_bytes(bus: &mut SystemBus, address: u32, data: &[u8]) {
+    for (offset, &byte) in data.iter().enumerate() {
+        bus.write(address + offset as u32, AccessSize::Byte, byte as u32).unwrap();
+    }
+}
+
+pub fn read_bytes(bus: &mut SystemBus, address: u32, length: u32) -> Vec<u8> {
+    (0..length).map(|offset| bus.read(address + offset, AccessSize::Byte).unwrap() as u8).collect()
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use std::path::Path;
+
+use rust_risc_v::compliance::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+
+const TOHOST: u32    = RAM_BASE + 0x1000;
+const SIGNATURE: u32 = RAM_BASE + 0x1200;
+
+// writes the tohost value from the data segment and spins, the signature holds two words
+fn test(code_before: &[u32], tohost: u32) -> Vec<u8> {
+    let mut code = code_before.to_vec();
+    code.extend([
+        lui(6, TOHOST),
+        load(LoadType::LW, 5, 6, 0x100),
+        store(StoreType::SW, 6, 5, 0),
+        store(StoreType::SW, 6, 0, 4),
+        jal(0, 0),
+    ]);
+    let code: Vec<u8> = code.iter().flat_map(|instruction| instruction.to_le_bytes()).collect();
+
+    let mut data = vec![0; 0x100];
+    data.extend(tohost.to_le_bytes());
+    data.resize(0x200, 0);
+    data.extend([0xDEAD_BEEFu32, 0x1234_5678].iter().flat_map(|word| word.to_le_bytes()));
+
+    let symbols = [("tohost", TOHOST), ("begin_signature", SIGNATURE), ("end_signature", SIGNATURE + 8)];
+    common::elf::build(RAM_BASE, &[(RAM_BASE, &code), (TOHOST, &data)], &symbols)
+}
+
+#[test]
+fn htif_results() {
+    assert_eq!(run("pass", &test(&[], 1), None, 100).outcome, Outcome::Pass);
+    assert_eq!(run("fail", &test(&[], 3 << 1 | 1), None, 100).outcome, Outcome::Fail(3));
+    assert_eq!(run("timeout", &test(&[jal(0, 0)], 1), None, 100).outcome, Outcome::Timeout);
+
+    // an all zero instruction is illegal and traps to mtvec at 0, outside of ram
+    let outcome = run("illegal", &test(&[0x0000_0000], 1), None, 100).outcome;
+    assert_eq!(outcome, Outcome::Timeout);
+    assert!(!outcome.passed());
+
+    assert!(matches!(run("invalid", &[0; 64], None, 100).outcome, Outcome::Invalid(_)));
+    let elf = common::elf::build(RAM_BASE, &[], &[]);
+    assert!(matches!(run("no tohost", &elf, None, 100).outcome, Outcome::Invalid(_)));
+}
+
+#[test]
+fn signatures() {
+    let report = run("signature", &test(&[], 1), Some(&[0xDEAD_BEEF, 0x1234_5678]), 100);
+    assert_eq!(report.outcome, Outcome::Pass);
+    assert_eq!(report.signature, Some(vec![0xDEAD_BEEF, 0x1234_5678]));
+
+    let report = run("signature", &test(&[], 1), Some(&[0xDEAD_BEEF, 0]), 100);
+    assert_eq!(report.outcome, Outcome::Mismatch { index: 1, expected: Some(0), actual: Some(0x1234_5678) });
+    let report = run("signature", &test(&[], 1), Some(&[0xDEAD_BEEF]), 100);
+    assert_eq!(report.outcome, Outcome::Mismatch { index: 1, expected: None, actual: Some(0x1234_5678) });
+
+    let text = format_signature(&[0xDEAD_BEEF, 0x1234_5678]);
+    assert_eq!(text, "deadbeef\n12345678\n");
+    assert_eq!(parse_signature(&text), Some(vec![0xDEAD_BEEF, 0x1234_5678]));
+    assert_eq!(parse_signature("xyz\n"), None);
+}
+
+#[test]
+fn test_discovery() {
+    let directory = std::env::temp_dir().join(format!("rust-risc-v-compliance-{}", std::process::id()));
+    std::fs::create_dir_all(directory.join("references")).unwrap();
+    std::fs::write(directory.join("rv32ui-p-add"), test(&[], 1)).unwrap();
+    std::fs::write(directory.join("rv32ui-p-add.dump"), "").unwrap();
+    std::fs::write(directory.join("add-01.elf"), test(&[], 1)).unwrap();
+    std::fs::write(directory.join("references/add-01.reference_output"), "deadbeef\n00000000\n").unwrap();
+    std::fs::write(directory.join("README"), "").unwrap();
+
+    let tests = find_tests(&directory).unwrap();
+    let names: Vec<_> = tests.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+    assert_eq!(names, ["add-01.elf", "rv32ui-p-add"]);
+
+    assert_eq!(find_reference(&tests[0]), Some(vec![0xDEAD_BEEF, 0]));
+    assert!(matches!(run_file(&tests[0], 100).outcome, Outcome::Mismatch { index: 1, .. }));
+    assert_eq!(run_file(&tests[1], 100).outcome, Outcome::Pass);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+// riscv-tests style images in tests/fixtures, assembled from the sources next to them
+fn fixture(image: &[u8]) -> Vec<u8> {
+    let tohost = RAM_BASE + 0x4000;
+    common::elf::build(RAM_BASE, &[(RAM_BASE, image)], &[("tohost", tohost), ("fromhost", tohost + 8)])
+}
+
+#[test]
+fn alu_tests() {
+    let report = run("rv32ui-p-alu", &fixture(include_bytes!("fixtures/rv32ui-p-alu.bin")), None, STEP_LIMIT);
+    assert_eq!(report.outcome, Outcome::Pass);
+    let report = run("rv32um-p-muldiv", &fixture(include_bytes!("fixtures/rv32um-p-muldiv.bin")), None, STEP_LIMIT);
+    assert_eq!(report.outcome, Outcome::Pass);
+
+    // a wrong result reports the number of the failing test
+    let mut image = include_bytes!("fixtures/rv32ui-p-alu.bin").to_vec();
+    image[0x58..0x5C].copy_from_slice(&math(0b0100000, 0b000, 14, 1, 2).to_le_bytes());
+    assert_eq!(run("rv32ui-p-alu", &fixture(&image), None, STEP_LIMIT).outcome, Outcome::Fail(2));
+}
+
+// RISCV_TESTS points to a directory of compiled riscv-tests or riscv-arch-test elfs
+#[test]
+fn riscv_tests() {
+    let Ok(directory) = std::env::var("RISCV_TESTS") else {
+        return;
+    };
+
+    let failed: Vec<String> = find_tests(Path::new(&directory)).unwrap().iter()
+        .map(|test| run_file(test, STEP_LIMIT))
+        .filter(|report| !report.outcome.passed())
+        .map(|report| format!("{}: {:?}", report.name, report.outcome))
+        .collect();
+    assert!(failed.is_empty(), "{}", failed.join("\n"));
+}
@@ -0,0 +1,172 @@
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::trap::Privilege;
+
+#[test]
+fn csrrw_test() {
+    let mut cpu = CPU::new(256);
+
+    cpu.registers().write(1, 0x1234);
+    cpu.csrs().write(MSCRATCH, 0x5678);
+    cpu.ram().write_word(0, csr(CSRType::CSRRW, 2, 1, MSCRATCH));
+    cpu.ram().write_word(4, csr(CSRType::CSRRWI, 3, 7, MSCRATCH));
+
+    cpu.tick();
+    assert_eq!(cpu.registers().read(2), 0x5678);
+    assert_eq!(cpu.csrs().read(MSCRATCH), 0x1234);
+
+    cpu.tick();
+    assert_eq!(cpu.registers().read(3), 0x1234);
+    assert_eq!(cpu.csrs().read(MSCRATCH), 7);
+    assert_eq!(*cpu.pc(), 8);
+}
+
+#[test]
+fn csrrs_csrrc_test() {
+    let mut cpu = CPU::new(256);
+
+    cpu.registers().write(1, 0b1100);
+    cpu.csrs().write(MSCRATCH, 0b1010);
+    cpu.ram().write_word(0, csr(CSRType::CSRRS, 2, 1, MSCRATCH));
+    cpu.ram().write_word(4, csr(CSRType::CSRRC, 3, 1, MSCRATCH));
+    cpu.ram().write_word(8, csr(CSRType::CSRRSI, 4, 1, MSCRATCH));
+    cpu.ram().write_word(12, csr(CSRType::CSRRCI, 5, 3, MSCRATCH));
+
+    cpu.tick();
+    assert_eq!(cpu.registers().read(2), 0b1010);
+    assert_eq!(cpu.csrs().read(MSCRATCH), 0b1110);
+
+    cpu.tick();
+    assert_eq!(cpu.registers().read(3), 0b1110);
+    assert_eq!(cpu.csrs().read(MSCRATCH), 0b0010);
+
+    cpu.tick();
+    assert_eq!(cpu.registers().read(4), 0b0010);
+    assert_eq!(cpu.csrs().read(MSCRATCH), 0b0011);
+
+    cpu.tick();
+    assert_eq!(cpu.registers().read(5), 0b0011);
+    assert_eq!(cpu.csrs().read(MSCRATCH), 0b0000);
+}
+
+#[test]
+fn read_only_csr_test() {
+    let mut cpu = CPU::new(256);
+
+    cpu.csrs().write(MTVEC, 128);
+    cpu.ram().write_word(0, csr(CSRType::CSRRS, 1, 0, MHARTID));
+    cpu.ram().write_word(4, csr(CSRType::CSRRW, 1, 0, MHARTID));
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 4);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 128);
+    assert_eq!(cpu.csrs().read(MCAUSE), 2);
+    assert_eq!(cpu.csrs().read(MEPC), 4);
+}
+
+#[test]
+fn unimplemented_csr_test() {
+    let mut cpu = CPU::new(256);
+
+    cpu.csrs().write(MTVEC, 128);
+    let instruction = csr(CSRType::CSRRS, 1, 0, 0x7C0);
+    cpu.ram().write_word(0, instruction);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 128);
+    assert_eq!(cpu.csrs().read(MCAUSE), 2);
+    assert_eq!(cpu.csrs().read(MTVAL), instruction);
+}
+
+#[test]
+fn warl_csr_test() {
+    let mut cpu = CPU::new(256);
+    let misa = cpu.csrs().read(MISA);
+
+    cpu.registers().write(5, u32::MAX);
+    for (index, number) in [MISA, MIP, MTVEC, MEPC, MSTATUS, MIE].into_iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, csr(CSRType::CSRRW, 0, 5, number));
+    }
+    for _ in 0..6 {
+        cpu.tick();
+    }
+
+    // writes are ignored or keep the legal part of the value, none of them trap
+    assert_eq!(*cpu.pc(), 24);
+    assert_eq!(cpu.csrs().read(MISA), misa);
+    assert_eq!(cpu.csrs().read(MIP), MIP_SSIP | MIP_STIP);
+    assert_eq!(cpu.csrs().read(MTVEC), 0xFFFF_FFFD);
+    assert_eq!(cpu.csrs().read(MEPC), 0xFFFF_FFFC);
+    assert_eq!(cpu.csrs().read(MSTATUS), MSTATUS_MASK);
+    assert_eq!(cpu.csrs().read(MIE), MIP_LINES | MIDELEG_MASK);
+}
+
+#[test]
+fn ecall_mret_test() {
+    let mut cpu = CPU::new(256);
+
+    cpu.csrs().write(MTVEC, 128);
+    cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+    cpu.ram().write_word(0, ECALL);
+    cpu.ram().write_word(128, csr(CSRType::CSRRS, 1, 0, MEPC));
+    cpu.ram().write_word(132, MRET);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 128);
+    assert_eq!(cpu.csrs().read(MCAUSE), 11);
+    assert_eq!(cpu.csrs().read(MSTATUS) & MSTATUS_MIE, 0);
+
+    cpu.tick();
+    assert_eq!(cpu.registers().read(1), 0);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 0);
+    assert_eq!(cpu.csrs().read(MSTATUS) & MSTATUS_MIE, MSTATUS_MIE);
+}
+
+#[test]
+fn time_csr_test() {
+    let mut cpu = CPU::new(256);
+    cpu.csrs().write(MTVEC, 128);
+    cpu.ram().write_word(0, csr(CSRType::CSRRS, 1, 0, TIME));
+    cpu.ram().write_word(4, csr(CSRType::CSRRS, 2, 0, TIMEH));
+
+    // without a timer there is no time to read
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 128);
+    assert_eq!(cpu.csrs().read(MCAUSE), 2);
+
+    cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+    cpu.bus().device::<CLINT>(CLINT::BASE).unwrap().set_mtime(0x1_0000_0010);
+    *cpu.pc() = 0;
+    cpu.tick();
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 8);
+    // mtime counts the tick of the reading instruction
+    assert_eq!(cpu.registers().read(1), 0x11);
+    assert_eq!(cpu.registers().read(2), 1);
+
+    // supervisor and user mode need the time bit of the counter enables above them
+    cpu.set_privilege(Privilege::Supervisor);
+    *cpu.pc() = 0;
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 2);
+
+    cpu.csrs().write(MCOUNTEREN, COUNTEREN_TM);
+    cpu.set_privilege(Privilege::User);
+    *cpu.pc() = 0;
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 2);
+
+    cpu.csrs().write(SCOUNTEREN, COUNTEREN_TM);
+    cpu.set_privilege(Privilege::User);
+    *cpu.pc() = 0;
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 4);
+    assert!(cpu.registers().read(1) > 0x10);
+}
@@ -0,0 +1,97 @@
+use rust_risc_v::*;
+use rust_risc_v::debug::*;
+use rust_risc_v::instructions::branch::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+
+// copies the word at 0x100 to 0x104 forever
+fn cpu() -> CPU {
+    let mut cpu = CPU::new(256);
+    let code = [
+        load(LoadType::LW, 5, 0, 0x100),
+        store(StoreType::SW, 0, 5, 0x104),
+        branch(BranchType::BEQ, 0, 0, 0b1111111111000),
+    ];
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+    cpu.ram().write_word(0x100, 42);
+    cpu
+}
+
+#[test]
+fn run_for() {
+    let mut cpu = cpu();
+    assert_eq!(cpu.run_for(0), StopReason::InstructionLimit);
+    assert_eq!(*cpu.pc(), 0);
+
+    assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+    assert_eq!(*cpu.pc(), 8);
+    assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+    assert_eq!(*cpu.pc(), 4);
+}
+
+#[test]
+fn run_until() {
+    let mut cpu = cpu();
+    assert_eq!(cpu.run_until(|cpu| cpu.ram().read_word(0x104) == 42), StopReason::Predicate);
+    assert_eq!(*cpu.pc(), 8);
+
+    let mut count = 0;
+    cpu.run_until(|cpu| {
+        count += 1;
+        *cpu.pc() == 8
+    });
+    assert_eq!(count, 3);
+}
+
+#[test]
+fn breakpoints() {
+    let mut cpu = cpu();
+    cpu.debugger().add_breakpoint(4);
+
+    assert_eq!(cpu.run(), StopReason::Breakpoint(4));
+    assert_eq!(*cpu.pc(), 4);
+    assert_eq!(cpu.registers().read(5), 42);
+    assert_eq!(cpu.ram().read_word(0x104), 0);
+
+    // resuming executes the instruction at the breakpoint
+    assert_eq!(cpu.run(), StopReason::Breakpoint(4));
+    assert_eq!(cpu.ram().read_word(0x104), 42);
+
+    assert!(cpu.debugger().remove_breakpoint(4));
+    assert!(!cpu.debugger().remove_breakpoint(4));
+    assert_eq!(cpu.run_for(10), StopReason::InstructionLimit);
+}
+
+#[test]
+fn watchpoints() {
+    let mut cpu = cpu();
+    cpu.debugger().add_watchpoint(0x106..0x108, WatchKind::Write);
+    cpu.debugger().add_watchpoint(0x100..0x101, WatchKind::Read);
+
+    // data watchpoints stop after the access
+    assert_eq!(cpu.run(), StopReason::Watchpoint { address: 0x100, kind: WatchKind::Read });
+    assert_eq!(*cpu.pc(), 4);
+    assert_eq!(cpu.run(), StopReason::Watchpoint { address: 0x104, kind: WatchKind::Write });
+    assert_eq!(*cpu.pc(), 8);
+    assert_eq!(cpu.ram().read_word(0x104), 42);
+
+    assert!(cpu.debugger().remove_watchpoint(0x100..0x101, WatchKind::Read));
+    assert!(!cpu.debugger().remove_watchpoint(0x100..0x101, WatchKind::Write));
+    cpu.debugger().add_watchpoint(0..8, WatchKind::Execute);
+    assert_eq!(cpu.run(), StopReason::Watchpoint { address: 0, kind: WatchKind::Execute });
+    assert_eq!(*cpu.pc(), 0);
+
+    cpu.debugger().clear();
+    assert_eq!(cpu.run_for(100), StopReason::InstructionLimit);
+}
+
+#[test]
+fn stale_accesses_are_ignored() {
+    let mut cpu = cpu();
+    cpu.debugger().add_watchpoint(0x100..0x104, WatchKind::Read);
+    cpu.tick();
+
+    assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+}
@@ -0,0 +1,137 @@
+use rust_risc_v::*;
+use rust_risc_v::bus::AccessSize;
+use rust_risc_v::csr::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::branch::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::decode::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+
+const FENCE_I: u32 = 0x0000100F;
+
+fn program(cpu: &mut CPU, address: u32, code: &[u32]) {
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(address + index as u32 * 4, *instruction);
+    }
+}
+
+#[test]
+fn decoded_fields() {
+    assert_eq!(decode(jal(1, -8i32 as u32 & 0x1FFFFF)), Operation::JAL { rd: 1, offset: -8i32 as u32 });
+    assert_eq!(decode(branch(BranchType::BLTU, 2, 3, 0x1FFC)), Operation::Branch { condition: Condition::LessThanUnsigned, rs1: 2, rs2: 3, offset: -4i32 as u32 });
+    assert_eq!(decode(load(LoadType::LH, 5, 6, 0x800)), Operation::Load { size: AccessSize::Half, signed: true, rd: 5, rs1: 6, offset: 0xFFFFF800 });
+    assert_eq!(decode(store(StoreType::SB, 7, 8, 0x7FF)), Operation::Store { size: AccessSize::Byte, rs1: 7, rs2: 8, offset: 0x7FF });
+    assert_eq!(decode(csr(CSRType::CSRRCI, 1, 4, MSCRATCH)), Operation::CSR { operation: CSROperation::ReadClear, immediate: true, rd: 1, rs1: 4, csr: MSCRATCH });
+    assert_eq!(decode(FENCE_I), Operation::FenceI);
+    assert_eq!(decode(MRET), Operation::MRET);
+    assert_eq!(decode(mathi(0b000, 1, 2, 0xFFF)), Operation::MathImmediate { operation: MathOperation::Add, rd: 1, rs1: 2, immediate: u32::MAX });
+    assert_eq!(decode(mathi(0b101, 1, 2, 0x41F)), Operation::MathImmediate { operation: MathOperation::ShiftRightArithmetic, rd: 1, rs1: 2, immediate: 31 });
+    assert_eq!(decode(math(0b0000001, 0b110, 3, 4, 5)), Operation::Math { operation: MathOperation::Remainder, rd: 3, rs1: 4, rs2: 5 });
+
+    // reserved encodings
+    assert_eq!(decode(mathi(0b001, 1, 1, 0x401)), Operation::Illegal);
+    assert_eq!(decode(math(0b0100000, 0b001, 1, 1, 1)), Operation::Illegal);
+    assert_eq!(decode(jalr(1, 2, 0) | 0b001 << 12), Operation::Illegal);
+    assert_eq!(decode(load(LoadType::LW, 1, 2, 0) | 0b111 << 12), Operation::Illegal);
+    assert_eq!(decode(ECALL | 0b100 << 12), Operation::Illegal);
+}
+
+#[test]
+fn self_modifying_code() {
+    let mut cpu = CPU::new(1024);
+    program(&mut cpu, 0, &[
+        jal(0, 12),
+        0,
+        0,
+        lui(6, 0x11111000),
+        load(LoadType::LW, 5, 0, 0x100),
+        store(StoreType::SW, 0, 5, 12),
+        jal(0, -12i32 as u32 & 0x1FFFFF),
+    ]);
+    cpu.ram().write_word(0x100, lui(6, 0x22222000));
+
+    cpu.tick();
+    cpu.tick();
+    assert_eq!(cpu.registers().read(6), 0x11111000);
+
+    // the store replaced the instruction at 12 after it was decoded
+    for _ in 0..4 {
+        cpu.tick();
+    }
+    assert_eq!(*cpu.pc(), 16);
+    assert_eq!(cpu.registers().read(6), 0x22222000);
+}
+
+#[test]
+fn host_writes_and_fence_i() {
+    let mut cpu = CPU::new(1024);
+    program(&mut cpu, 0, &[lui(6, 0x1000), FENCE_I, jal(0, -8i32 as u32 & 0x1FFFFF)]);
+
+    cpu.tick();
+    assert_eq!(cpu.decode_cache().pages(), 1);
+    cpu.tick();
+    assert_eq!(cpu.decode_cache().pages(), 0);
+    cpu.tick();
+
+    cpu.ram().write_word(0, lui(6, 0x2000));
+    cpu.tick();
+    assert_eq!(cpu.registers().read(6), 0x2000);
+}
+
+#[test]
+fn pages_sharing_a_slot() {
+    // 0 and 0x100000 map to the same slot and jump to each other
+    let mut cpu = CPU::new(0x40400);
+    program(&mut cpu, 0, &[lui(6, 0x1000), jalr(0, 1, 0)]);
+    program(&mut cpu, 0x100000, &[lui(6, 0x2000), jalr(0, 0, 0)]);
+    cpu.registers().write(1, 0x100000);
+
+    for round in 0..4 {
+        cpu.tick();
+        assert_eq!(cpu.registers().read(6), 0x1000, "round {}", round);
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.registers().read(6), 0x2000, "round {}", round);
+        cpu.tick();
+        assert_eq!(cpu.decode_cache().pages(), 1);
+    }
+}
+
+#[test]
+fn matches_uncached_execution() {
+    // copies a csr into memory and walks a four node list, with a store into the code page
+    let setup = |cpu: &mut CPU| {
+        program(cpu, 0, &[
+            csr(CSRType::CSRRSI, 7, 3, MSCRATCH),
+            load(LoadType::LW, 5, 0, 0x200),
+            load(LoadType::LBU, 8, 5, 4),
+            store(StoreType::SB, 5, 7, 5),
+            store(StoreType::SW, 0, 8, 0x40),
+            load(LoadType::LW, 5, 5, 0),
+            branch(BranchType::BNE, 5, 0, 0x1FF0),
+            jal(0, -28i32 as u32 & 0x1FFFFF),
+        ]);
+        for node in 0..4 {
+            let address = 0x200 + node * 8;
+            cpu.ram().write_word(address, if node < 3 { address + 8 } else { 0 });
+            cpu.ram().write_word(address + 4, node * 3);
+        }
+    };
+
+    let mut cached = CPU::new(1024);
+    let mut uncached = CPU::new(1024);
+    setup(&mut cached);
+    setup(&mut uncached);
+    uncached.decode_cache().set_enabled(false);
+
+    for _ in 0..1000 {
+        cached.tick();
+        uncached.tick();
+        assert_eq!(*cached.pc(), *uncached.pc());
+    }
+    assert_eq!(cached.registers().inspect(), uncached.registers().inspect());
+    assert_eq!(cached.ram().inspect(0, 0x1000), uncached.ram().inspect(0, 0x1000));
+    assert_eq!(cached.csrs().read(MCAUSE), 0);
+    assert_eq!(uncached.decode_cache().pages(), 0);
+}
@@ -0,0 +1,117 @@
+use rust_risc_v::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::differential::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+
+fn cpu() -> CPU {
+    let mut cpu = CPU::new(512);
+    let code = [
+        lui(5, 0x1234_5000),
+        store(StoreType::SW, 0, 5, 0x100),
+        ECALL,
+        load(LoadType::LW, 6, 0, 0x100),
+        jal(1, 8),
+    ];
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+    // the handler resumes after the ecall
+    cpu.ram().write_word(0x40, jal(0, 0u32.wrapping_sub(0x34)));
+    cpu.csrs().write(MTVEC, 0x40);
+    cpu
+}
+
+const SPIKE: &str = "\
+core   0: 0x0000000000000000 (0x123452b7) lui     t0, 0x12345
+core   0: 3 0x0000000000000000 (0x123452b7) x5  0x0000000012345000
+core   0: 3 0x0000000000000004 (0x10502023) mem 0x0000000000000100 0x12345000
+core   0: 3 0x0000000000000040 (0xfcdff06f)
+core   0: 3 0x000000000000000c (0x10002303) x6  0x0000000012345000 mem 0x0000000000000100
+core   0: 3 0x0000000000000010 (0x008000ef) x1  0x0000000000000014
+";
+
+#[test]
+fn matching_spike_trace() {
+    let reference = parse_trace(SPIKE);
+    assert_eq!(reference.len(), 5);
+    assert_eq!(reference[1].stores, vec![(0x100, 0x1234_5000)]);
+
+    let mut cpu = cpu();
+    assert_eq!(compare(&mut cpu, &reference, 4), Ok(5));
+    assert_eq!(*cpu.pc(), 0x18);
+}
+
+#[test]
+fn divergence_report() {
+    let mut reference = parse_trace(SPIKE);
+    reference[3].registers = vec![(6, 0x1234_5001)];
+    reference[3].line = "core   0: 3 0x0000000c (0x10002303) x6  0x12345001 mem 0x00000100".to_string();
+
+    let mut cpu = cpu();
+    let divergence = compare(&mut cpu, &reference, 2).unwrap_err();
+    assert_eq!(divergence.step, 3);
+    assert_eq!(divergence.reason, "register writes [(6, 12345000)] instead of [(6, 12345001)]");
+    assert_eq!(divergence.context, reference[1..3].to_vec());
+    assert_eq!(divergence.actual.as_ref().map(|actual| actual.pc), Some(0xC));
+
+    let report = divergence.to_string();
+    assert!(report.starts_with("divergence at step 3: register writes"));
+    assert!(report.contains("    core   0: 3 0x0000000000000040 (0xfcdff06f)\n"));
+    assert!(report.ends_with("actual:   core   0: 3 0x0000000c (0x10002303) x6  0x12345000 mem 0x00000100"));
+
+    // the cpu does not keep the comparison log
+    assert!(cpu.set_commit_log(None).is_none());
+}
+
+#[test]
+fn sail_trace() {
+    let reference = parse_trace("\
+[0] [M]: 0x0000000000000000 (0x123452B7) lui t0, 0x12345
+x5 <- 0x0000000012345000
+[1] [M]: 0x0000000000000004 (0x10502023) sw t0, 256(zero)
+mem[0x0000000000000100] <- 0x12345000
+[2] [M]: 0x0000000000000008 (0x00000073) ecall
+trapping from M to M to handle env-call-from-M
+CSR mepc <- 0x0000000000000008
+[3] [M]: 0x0000000000000040 (0xFCDFF06F) jal zero, -52
+x0 <- 0x0000000000000044
+");
+
+    assert_eq!(reference.len(), 3);
+    assert_eq!(reference[2].pc, 0x40);
+    assert_eq!(compare(&mut cpu(), &reference, 4), Ok(3));
+}
+
+#[test]
+fn wrong_pc() {
+    let mut reference = parse_trace(SPIKE);
+    reference.remove(2);
+
+    let divergence = compare(&mut cpu(), &reference, 4).unwrap_err();
+    assert_eq!(divergence.step, 2);
+    assert_eq!(divergence.reason, "pc 0x00000040 instead of 0x0000000c");
+    assert_eq!(divergence.context.len(), 2);
+}
+
+#[test]
+fn auipc_spike_trace() {
+    // spike writes pc plus the immediate to rd and moves on to the next instruction
+    let reference = parse_trace("\
+core   0: 3 0x0000000000000000 (0x00001317) x6  0x0000000000001000
+core   0: 3 0x0000000000000004 (0x00000397) x7  0x0000000000000004
+core   0: 3 0x0000000000000008 (0xfffff417) x8  0xfffffffffffff008
+core   0: 3 0x000000000000000c (0x0063a023) mem 0x0000000000000004 0x00001000
+");
+
+    let mut cpu = CPU::new(512);
+    let code = [auipc(6, 0x1000), auipc(7, 0), auipc(8, 0xFFFF_F000), store(StoreType::SW, 7, 6, 0)];
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+
+    assert_eq!(compare(&mut cpu, &reference, 4), Ok(4));
+    assert_eq!(*cpu.pc(), 0x10);
+}
@@ -0,0 +1,215 @@
+use rust_risc_v::fuzz::Generator;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::branch::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::decode::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+
+fn branch_type(funct3: u8) -> BranchType {
+    match funct3 {
+        0b000 => BranchType::BEQ,
+        0b001 => BranchType::BNE,
+        0b100 => BranchType::BLT,
+        0b101 => BranchType::BGE,
+        0b110 => BranchType::BLTU,
+        _ => BranchType::BGEU,
+    }
+}
+
+fn load_type(funct3: u8) -> LoadType {
+    match funct3 {
+        0b000 => LoadType::LB,
+        0b001 => LoadType::LH,
+        0b010 => LoadType::LW,
+        0b100 => LoadType::LBU,
+        _ => LoadType::LHU,
+    }
+}
+
+fn store_type(funct3: u8) -> StoreType {
+    match funct3 {
+        0b000 => StoreType::SB,
+        0b001 => StoreType::SH,
+        _ => StoreType::SW,
+    }
+}
+
+fn csr_type(funct3: u8) -> CSRType {
+    match funct3 {
+        0b001 => CSRType::CSRRW,
+        0b010 => CSRType::CSRRS,
+        0b011 => CSRType::CSRRC,
+        0b101 => CSRType::CSRRWI,
+        0b110 => CSRType::CSRRSI,
+        _ => CSRType::CSRRCI,
+    }
+}
+
+const BRANCHES: [u8; 6] = [0b000, 0b001, 0b100, 0b101, 0b110, 0b111];
+const LOADS: [u8; 5] = [0b000, 0b001, 0b010, 0b100, 0b101];
+const STORES: [u8; 3] = [0b000, 0b001, 0b010];
+const CSRS: [u8; 6] = [0b001, 0b010, 0b011, 0b101, 0b110, 0b111];
+
+// decodes the fields and encodes them again
+fn reencode(instruction: u32) -> u32 {
+    let rd = extract_rd_register(instruction);
+    let rs1 = extract_rs1_register(instruction);
+    let rs2 = extract_rs2_register(instruction);
+    let funct3 = extract_funct3(instruction);
+
+    match instruction & InstructionGroup::MASK as u32 {
+        0b0110111 => lui(rd, extract_immediate_31_12(instruction)),
+        0b0010111 => auipc(rd, extract_immediate_31_12(instruction)),
+        0b1101111 => jal(rd, extract_immediate_20_1(instruction)),
+        0b1100111 => jalr(rd, rs1, extract_immediate_11_0(instruction)),
+        0b1100011 => branch(branch_type(funct3), rs1, rs2, extract_immediate_12_1(instruction)),
+        0b0000011 => load(load_type(funct3), rd, rs1, extract_immediate_11_0(instruction)),
+        0b0100011 => store(store_type(funct3), rs1, rs2, extract_store_immediate_11_0(instruction)),
+        0b0010011 => mathi(funct3, rd, rs1, extract_immediate_11_0(instruction)),
+        0b0110011 => math(extract_funct7(instruction), funct3, rd, rs1, rs2),
+        0b1110011 => csr(csr_type(funct3), rd, rs1, extract_csr(instruction)),
+        _ => panic!("no encoder for 0x{:08x}", instruction),
+    }
+}
+
+#[test]
+fn u_type() {
+    for value in 0..1 << 20 {
+        let immediate = value << 12;
+        let rd = (value % 32) as u8;
+
+        for instruction in [lui(rd, immediate), auipc(rd, immediate)] {
+            assert_eq!(extract_rd_register(instruction), rd);
+            assert_eq!(extract_immediate_31_12(instruction), immediate);
+            assert_eq!(reencode(instruction), instruction);
+        }
+    }
+
+    // the low bits are not part of the immediate
+    assert_eq!(lui(1, 0xFFFF_FFFF), lui(1, 0xFFFF_F000));
+}
+
+#[test]
+fn j_type() {
+    for immediate in (0..1 << 21).step_by(2) {
+        let rd = (immediate >> 1 & 31) as u8;
+        let instruction = jal(rd, immediate);
+
+        assert_eq!(extract_rd_register(instruction), rd);
+        assert_eq!(extract_immediate_20_1(instruction), immediate);
+        assert_eq!(reencode(instruction), instruction);
+    }
+}
+
+#[test]
+fn i_type() {
+    for immediate in 0..1 << 12 {
+        let rd = (immediate % 32) as u8;
+        let rs1 = (immediate / 32 % 32) as u8;
+        let funct3 = (immediate % 8) as u8;
+
+        let mut instructions = vec![jalr(rd, rs1, immediate), mathi(funct3, rd, rs1, immediate)];
+        instructions.extend(LOADS.map(|funct3| load(load_type(funct3), rd, rs1, immediate)));
+        instructions.extend(CSRS.map(|funct3| csr(csr_type(funct3), rd, rs1, immediate)));
+
+        for instruction in instructions {
+            assert_eq!(extract_rd_register(instruction), rd);
+            assert_eq!(extract_rs1_register(instruction), rs1);
+            assert_eq!(extract_immediate_11_0(instruction), immediate);
+            assert_eq!(extract_csr(instruction), immediate);
+            assert_eq!(reencode(instruction), instruction);
+        }
+    }
+
+    for funct3 in LOADS {
+        assert_eq!(extract_funct3(load(load_type(funct3), 0, 0, 0)), funct3);
+    }
+    for funct3 in CSRS {
+        assert_eq!(extract_funct3(csr(csr_type(funct3), 0, 0, 0)), funct3);
+    }
+}
+
+#[test]
+fn s_type() {
+    for immediate in 0..1 << 12 {
+        let rs1 = (immediate % 32) as u8;
+        let rs2 = (immediate / 32 % 32) as u8;
+
+        for funct3 in STORES {
+            let instruction = store(store_type(funct3), rs1, rs2, immediate);
+            assert_eq!(extract_rs1_register(instruction), rs1);
+            assert_eq!(extract_rs2_register(instruction), rs2);
+            assert_eq!(extract_funct3(instruction), funct3);
+            assert_eq!(extract_store_immediate_11_0(instruction), immediate);
+            assert_eq!(reencode(instruction), instruction);
+        }
+    }
+}
+
+#[test]
+fn b_type() {
+    for immediate in (0..1 << 13).step_by(2) {
+        let rs1 = (immediate >> 1 & 31) as u8;
+        let rs2 = (immediate >> 6 & 31) as u8;
+
+        for funct3 in BRANCHES {
+            let instruction = branch(branch_type(funct3), rs1, rs2, immediate);
+            assert_eq!(extract_rs1_register(instruction), rs1);
+            assert_eq!(extract_rs2_register(instruction), rs2);
+            assert_eq!(extract_funct3(instruction), funct3);
+            assert_eq!(extract_immediate_12_1(instruction), immediate);
+            assert_eq!(reencode(instruction), instruction);
+        }
+    }
+}
+
+#[test]
+fn r_type() {
+    for funct7 in 0..1 << 7 {
+        for funct3 in 0..1 << 3 {
+            let instruction = math(funct7, funct3, funct7 % 32, funct3 * 4, 31 - funct7 % 32);
+            assert_eq!(extract_funct7(instruction), funct7);
+            assert_eq!(extract_funct3(instruction), funct3);
+            assert_eq!(reencode(instruction), instruction);
+        }
+    }
+
+    for rd in 0..32 {
+        for rs1 in 0..32 {
+            for rs2 in 0..32 {
+                let instruction = math(0b0100000, 0b101, rd, rs1, rs2);
+                assert_eq!(extract_rd_register(instruction), rd);
+                assert_eq!(extract_rs1_register(instruction), rs1);
+                assert_eq!(extract_rs2_register(instruction), rs2);
+                assert_eq!(reencode(instruction), instruction);
+            }
+        }
+    }
+}
+
+#[test]
+fn random_words() {
+    let mut generator = Generator::new(45, &[]);
+
+    for _ in 0..100_000 {
+        let bits = generator.next_u32() & !0b111000001111111;
+        let pick = |functs: &[u8]| (functs[(bits >> 7) as usize % functs.len()] as u32) << 12;
+
+        let words = [
+            bits | pick(&[0, 1, 2, 3, 4, 5, 6, 7]) | InstructionGroup::LUI as u32,
+            bits | pick(&[0, 1, 2, 3, 4, 5, 6, 7]) | InstructionGroup::AUIPC as u32,
+            bits | pick(&[0, 1, 2, 3, 4, 5, 6, 7]) | InstructionGroup::JAL as u32,
+            bits | InstructionGroup::JALR as u32,
+            bits | pick(&BRANCHES) | InstructionGroup::BRANCH as u32,
+            bits | pick(&LOADS) | InstructionGroup::LOAD as u32,
+            bits | pick(&STORES) | InstructionGroup::STORE as u32,
+            bits | pick(&[0, 1, 2, 3, 4, 5, 6, 7]) | InstructionGroup::MATHI as u32,
+            bits | pick(&[0, 1, 2, 3, 4, 5, 6, 7]) | InstructionGroup::MATH as u32,
+            bits | pick(&CSRS) | InstructionGroup::CSR as u32,
+        ];
+        for instruction in words {
+            assert_eq!(reencode(instruction), instruction, "0x{:08x}", instruction);
+        }
+    }
+}
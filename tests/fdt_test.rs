@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::devices::plic::*;
+use rust_risc_v::devices::uart::*;
+use rust_risc_v::devices::virtio;
+use rust_risc_v::devices::virtio::VirtioMMIO;
+use rust_risc_v::devices::virtio::rng::*;
+use rust_risc_v::fdt::*;
+
+fn be32(blob: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+}
+
+// properties of every node keyed by path and property name
+fn parse(blob: &[u8]) -> HashMap<String, Vec<u8>> {
+    let structure = be32(blob, 8) as usize;
+    let strings = be32(blob, 12) as usize;
+    let mut properties = HashMap::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut offset = structure;
+
+    loop {
+        let token = be32(blob, offset);
+        offset += 4;
+        match token {
+            1 => {
+                let end = offset + blob[offset..].iter().position(|&byte| byte == 0).unwrap();
+                path.push(String::from_utf8(blob[offset..end].to_vec()).unwrap());
+                offset = (end + 4) & !3;
+            }
+            2 => {
+                path.pop();
+            }
+            3 => {
+                let length = be32(blob, offset) as usize;
+                let name_offset = strings + be32(blob, offset + 4) as usize;
+                let name_end = name_offset + blob[name_offset..].iter().position(|&byte| byte == 0).unwrap();
+                let name = String::from_utf8(blob[name_offset..name_end].to_vec()).unwrap();
+                let value = blob[offset + 8..offset + 8 + length].to_vec();
+                properties.insert(format!("{}:{}", path.join("/"), name), value);
+                offset = (offset + 8 + length + 3) & !3;
+            }
+            9 => break,
+            _ => panic!("unexpected token {}", token),
+        }
+    }
+
+    properties
+}
+
+fn cells(value: &[u8]) -> Vec<u32> {
+    value.chunks(4).map(|cell| u32::from_be_bytes(cell.try_into().unwrap())).collect()
+}
+
+fn machine() -> CPU {
+    let mut bus = SystemBus::new(0x8000_0000, RAM::new(0x4000));
+    bus.map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(2, TimeBase::Instructions(1)))).unwrap();
+    bus.map(PLIC::BASE, PLIC::SIZE, AccessSizes::WORD, Box::new(PLIC::new(32, 4))).unwrap();
+    bus.map(UART::BASE, UART::SIZE, AccessSizes::BYTE, Box::new(UART::new(Box::new(BufferBackend::new())))).unwrap();
+    bus.connect_interrupt(UART::BASE, UART::IRQ).unwrap();
+    bus.map(virtio::BASE, virtio::SIZE, AccessSizes::ALL, Box::new(VirtioMMIO::new(VirtioRNG::seeded(1)))).unwrap();
+    bus.connect_interrupt(virtio::BASE, virtio::IRQ).unwrap();
+
+    CPU::with_bus(bus)
+}
+
+#[test]
+fn header() {
+    let mut cpu = machine();
+    let description = describe(&mut cpu, 2, 10_000_000);
+    let blob = generate(cpu.bus(), &description);
+
+    assert_eq!(be32(&blob, 0), MAGIC);
+    assert_eq!(be32(&blob, 4) as usize, blob.len());
+    assert_eq!(be32(&blob, 20), 17);
+    assert_eq!(be32(&blob, 24), 16);
+    assert_eq!(be32(&blob, 8) % 4, 0);
+}
+
+#[test]
+fn isa_from_misa() {
+    assert_eq!(isa_string(1 << 30 | 1 << 8), "rv32i");
+    assert_eq!(isa_string(1 << 30 | 1 << 0 | 1 << 8 | 1 << 12), "rv32aim");
+
+    let mut cpu = machine();
+    assert_eq!(describe(&mut cpu, 1, 1).isa, "rv32aim");
+}
+
+#[test]
+fn cpus_and_memory() {
+    let mut cpu = machine();
+    let description = describe(&mut cpu, 2, 10_000_000);
+    let properties = parse(&generate(cpu.bus(), &description));
+
+    assert_eq!(cells(&properties["/cpus:timebase-frequency"]), [10_000_000]);
+    assert_eq!(properties["/cpus/cpu@0:riscv,isa"], b"rv32aim\0");
+    assert_eq!(properties["/cpus/cpu@1:mmu-type"], b"riscv,sv32\0");
+    assert_eq!(cells(&properties["/cpus/cpu@1:reg"]), [1]);
+    assert_eq!(cells(&properties["/cpus/cpu@1/interrupt-controller:phandle"]), [2]);
+    assert!(!properties.contains_key("/cpus/cpu@2:reg"));
+
+    assert_eq!(properties["/memory@80000000:device_type"], b"memory\0");
+    assert_eq!(cells(&properties["/memory@80000000:reg"]), [0, 0x8000_0000, 0, 0x10000]);
+}
+
+#[test]
+fn devices() {
+    let mut cpu = machine();
+    let mut description = describe(&mut cpu, 2, 10_000_000);
+    description.bootargs = Some("console=ttyS0".to_string());
+    let properties = parse(&generate(cpu.bus(), &description));
+
+    assert_eq!(cells(&properties["/soc/clint@2000000:reg"]), [0, CLINT::BASE, 0, CLINT::SIZE]);
+    assert_eq!(cells(&properties["/soc/clint@2000000:interrupts-extended"]), [1, 3, 1, 7, 2, 3, 2, 7]);
+
+    assert_eq!(cells(&properties["/soc/plic@c000000:riscv,ndev"]), [32]);
+    assert_eq!(cells(&properties["/soc/plic@c000000:phandle"]), [3]);
+    assert_eq!(cells(&properties["/soc/plic@c000000:interrupts-extended"]), [1, 11, 1, 9, 2, 11, 2, 9]);
+
+    assert_eq!(properties["/soc/serial@10000000:compatible"], b"ns16550a\0");
+    assert_eq!(cells(&properties["/soc/serial@10000000:interrupts"]), [UART::IRQ]);
+    assert_eq!(cells(&properties["/soc/serial@10000000:interrupt-parent"]), [3]);
+
+    assert_eq!(properties["/soc/virtio_mmio@10001000:compatible"], b"virtio,mmio\0");
+    assert_eq!(cells(&properties["/soc/virtio_mmio@10001000:interrupts"]), [virtio::IRQ]);
+
+    assert_eq!(properties["/chosen:bootargs"], b"console=ttyS0\0");
+    assert_eq!(properties["/chosen:stdout-path"], b"/soc/serial@10000000\0");
+}
+
+#[test]
+fn place_in_memory() {
+    let mut cpu = machine();
+    let description = describe(&mut cpu, 1, 10_000_000);
+    let blob = generate(cpu.bus(), &description);
+
+    let address = place(&mut cpu, &blob).unwrap();
+    assert_eq!(address & 0xFFF, 0);
+    assert!(address >= 0x8000_0000 && address + blob.len() as u32 <= 0x8001_0000);
+    assert_eq!(cpu.registers().read(10), 0);
+    assert_eq!(cpu.registers().read(11), address);
+    assert_eq!(cpu.bus().ram().inspect(address - 0x8000_0000, blob.len() as u32), &blob[..]);
+
+    let mut small = CPU::with_bus(SystemBus::new(0x8000_0000, RAM::new(4)));
+    assert_eq!(place(&mut small, &blob), Err(BusError::AccessFault(0x8000_0000)));
+
+    // ram that ends at the top of the address space
+    let mut top = CPU::with_bus(SystemBus::new(0xFFFF_0000, RAM::new(0x4000)));
+    let address = place(&mut top, &blob).unwrap();
+    assert_eq!(address, (0u32.wrapping_sub(blob.len() as u32)) & !0xFFF);
+    assert_eq!(top.bus().ram().inspect(address - 0xFFFF_0000, blob.len() as u32), &blob[..]);
+}
@@ -0,0 +1,117 @@
+use rust_risc_v::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::fuzz::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::csr::*;
+
+#[test]
+fn generator() {
+    let program = Generator::new(7, &[Class::Jump, Class::System]).program(200);
+    assert_eq!(program, Generator::new(7, &[Class::Jump, Class::System]).program(200));
+    assert_ne!(program, Generator::new(8, &[Class::Jump, Class::System]).program(200));
+
+    assert!(program.iter().all(|&instruction| {
+        [ECALL, EBREAK, MRET, WFI].contains(&instruction)
+            || InstructionGroup::check(instruction, InstructionGroup::JAL)
+            || InstructionGroup::check(instruction, InstructionGroup::JALR)
+    }));
+
+    assert_eq!(Class::from_name("csr"), Some(Class::CSR));
+    assert_eq!(Class::from_name("vector"), None);
+}
+
+#[test]
+fn invariants() {
+    for seed in 0..200 {
+        let program = Generator::new(seed, &RV32I).program(32);
+        assert_eq!(execute(&program, 500), Ok(()), "seed {}", seed);
+    }
+
+    // every major opcode with all other bits clear and set
+    for opcode in 0..128 {
+        assert_eq!(execute(&[opcode, opcode | 0xFFFF_FF80], 2), Ok(()), "opcode {:07b}", opcode);
+    }
+}
+
+#[test]
+fn minimize_programs() {
+    let program = Generator::new(3, &RV32I).program(50);
+    let program: Vec<u32> = program.into_iter().filter(|&instruction| instruction != ECALL).chain([ECALL]).collect();
+
+    assert_eq!(minimize(&program, |candidate| candidate.contains(&ECALL)), vec![ECALL]);
+    assert_eq!(minimize(&program, |_| false), program);
+
+    let violation = Violation::Misaligned { step: 2, pc: 0x26 };
+    let test = reproducer("fuzz_example", &[0x02600cef], &violation);
+    assert!(test.starts_with("// pc 0x00000026 is misaligned after step 2\n#[test]\nfn fuzz_example() {\n"));
+    assert!(test.contains("        0x02600cef,\n"));
+    assert!(test.contains("    for _ in 0..3 {\n"));
+}
+
+#[test]
+fn misaligned_jumps_trap() {
+    let mut cpu = CPU::new(256);
+    cpu.ram().write_word(0, jal(1, 6));
+    cpu.csrs().write(MTVEC, 0x40);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 0x40);
+    assert_eq!(cpu.csrs().read(MCAUSE), 0);
+    assert_eq!(cpu.csrs().read(MEPC), 0);
+    assert_eq!(cpu.csrs().read(MTVAL), 6);
+    assert_eq!(cpu.registers().read(1), 0);
+}
+
+// panic at step 0: not yet implemented: math intermediate group not implemented
+#[test]
+fn fuzz_seed_1() {
+    let mut cpu = CPU::new(0x4000);
+    let program = [
+        0x012c1393,
+    ];
+    for (index, instruction) in program.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+
+    for _ in 0..1 {
+        cpu.tick();
+        assert_eq!(cpu.registers().read(0), 0);
+        assert_eq!(*cpu.pc() % 4, 0);
+    }
+}
+
+// pc 0x00000026 is misaligned after step 0
+#[test]
+fn fuzz_seed_0() {
+    let mut cpu = CPU::new(0x4000);
+    let program = [
+        0x02600cef,
+    ];
+    for (index, instruction) in program.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+
+    for _ in 0..1 {
+        cpu.tick();
+        assert_eq!(cpu.registers().read(0), 0);
+        assert_eq!(*cpu.pc() % 4, 0);
+    }
+}
+
+// panic at step 0: attempt to subtract with overflow
+#[test]
+fn fuzz_load_below_zero() {
+    let mut cpu = CPU::new(0x4000);
+    let program = [
+        0xffc02283,
+    ];
+    for (index, instruction) in program.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+
+    for _ in 0..1 {
+        cpu.tick();
+        assert_eq!(cpu.registers().read(0), 0);
+        assert_eq!(*cpu.pc() % 4, 0);
+    }
+}
@@ -0,0 +1,242 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::gdb::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::smp::*;
+
+// lw x5, 0x100(x0); sw x5, 0x104(x0); loop
+fn stub() -> GDBStub<CPU> {
+    let mut cpu = CPU::new(256);
+    for (index, instruction) in [load(LoadType::LW, 5, 0, 0x100), store(StoreType::SW, 0, 5, 0x104), jal(0, 0)].iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+    cpu.ram().write_word(0x100, 0x1234_5678);
+
+    GDBStub::new(cpu)
+}
+
+fn packet(stub: &mut GDBStub<CPU>, packet: &str) -> String {
+    stub.packet(packet).unwrap()
+}
+
+#[test]
+fn registers() {
+    let mut stub = stub();
+    stub.target().registers().write(1, 0x1122_3344);
+    *stub.target().pc() = 0x80;
+
+    let registers = packet(&mut stub, "g");
+    assert_eq!(registers.len(), 33 * 8);
+    assert_eq!(&registers[8..16], "44332211");
+    assert_eq!(&registers[256..264], "80000000");
+
+    assert_eq!(packet(&mut stub, "P5=efbeadde"), "OK");
+    assert_eq!(stub.target().registers().read(5), 0xDEAD_BEEF);
+    assert_eq!(packet(&mut stub, "p5"), "efbeadde");
+    assert_eq!(packet(&mut stub, "P20=00000000"), "OK");
+    assert_eq!(*stub.target().pc(), 0);
+
+    // csrs follow the numbering of the target description
+    assert_eq!(packet(&mut stub, &format!("P{:x}=08000000", CSR_REGISTER + MSTATUS as u32)), "OK");
+    assert_eq!(stub.target().csrs().read(MSTATUS), 8);
+    assert_eq!(packet(&mut stub, &format!("p{:x}", CSR_REGISTER + MSTATUS as u32)), "08000000");
+    assert_eq!(packet(&mut stub, "p2000"), "E01");
+
+    let mut all = "00000000".repeat(32);
+    all.push_str("10000000");
+    assert_eq!(packet(&mut stub, &format!("G{}", all)), "OK");
+    assert_eq!(stub.target().registers().read(5), 0);
+    assert_eq!(*stub.target().pc(), 0x10);
+
+    // a replacement character in the middle of a word is rejected instead of splitting it
+    let mut broken = "0000000\u{fffd}".to_string();
+    broken.push_str(&"00000000".repeat(33));
+    assert_eq!(packet(&mut stub, &format!("G{}", broken)), "E01");
+    assert_eq!(*stub.target().pc(), 0x10);
+}
+
+#[test]
+fn memory() {
+    let mut stub = stub();
+    assert_eq!(packet(&mut stub, "m100,4"), "78563412");
+    assert_eq!(packet(&mut stub, "M104,2:abcd"), "OK");
+    assert_eq!(stub.target().ram().read_half(0x104), 0xCDAB);
+
+    // reads stop at the end of memory, writes outside fail
+    assert_eq!(packet(&mut stub, "m3fe,4"), "0000");
+    assert_eq!(packet(&mut stub, "m1000,4"), "E14");
+    assert_eq!(packet(&mut stub, "M1000,1:00"), "E14");
+    assert_eq!(packet(&mut stub, "M100,2:00"), "E01");
+}
+
+#[test]
+fn queries() {
+    let mut stub = stub();
+    assert!(packet(&mut stub, "qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+    assert_eq!(packet(&mut stub, "?"), "T05thread:1;");
+    assert_eq!(packet(&mut stub, "vMustReplyEmpty"), "");
+
+    let mut description = String::new();
+    loop {
+        let chunk = packet(&mut stub, &format!("qXfer:features:read:target.xml:{:x},100", description.len()));
+        description.push_str(&chunk[1..]);
+        if chunk.starts_with('l') {
+            break;
+        }
+    }
+    assert!(description.contains("<architecture>riscv:rv32</architecture>"));
+    assert!(description.contains("<reg name=\"x31\" bitsize=\"32\" type=\"int\"/>"));
+    assert!(description.contains(&format!("<reg name=\"mepc\" bitsize=\"32\" regnum=\"{}\"/>", CSR_REGISTER + MEPC as u32)));
+}
+
+#[test]
+fn breakpoints() {
+    let mut stub = stub();
+    assert_eq!(packet(&mut stub, "Z0,8,4"), "OK");
+    assert_eq!(packet(&mut stub, "c"), "T05thread:1;swbreak:;");
+    assert_eq!(*stub.target().pc(), 8);
+    assert_eq!(stub.target().ram().read_word(0x104), 0x1234_5678);
+
+    // the breakpoint at the resume address does not trigger again
+    assert_eq!(packet(&mut stub, "Z1,8,4"), "OK");
+    assert_eq!(packet(&mut stub, "z0,8,4"), "OK");
+    assert_eq!(packet(&mut stub, "c"), "T05thread:1;hwbreak:;");
+    assert_eq!(packet(&mut stub, "z1,8,4"), "OK");
+
+    assert_eq!(packet(&mut stub, "s0"), "T05thread:1;");
+    assert_eq!(*stub.target().pc(), 4);
+    assert_eq!(packet(&mut stub, "Z9,0,4"), "");
+}
+
+#[test]
+fn watchpoints() {
+    let mut stub = stub();
+    assert_eq!(packet(&mut stub, "Z2,104,4"), "OK");
+    assert_eq!(packet(&mut stub, "Z3,102,4"), "OK");
+
+    // stops after the access
+    assert_eq!(packet(&mut stub, "c"), "T05thread:1;rwatch:102;");
+    assert_eq!(*stub.target().pc(), 4);
+    assert_eq!(packet(&mut stub, "c"), "T05thread:1;watch:104;");
+    assert_eq!(*stub.target().pc(), 8);
+
+    assert_eq!(packet(&mut stub, "z2,104,4"), "OK");
+    assert_eq!(packet(&mut stub, "z3,102,4"), "OK");
+    assert_eq!(packet(&mut stub, "Z4,104,1"), "OK");
+    assert_eq!(packet(&mut stub, "Z0,8,4"), "OK");
+    assert_eq!(packet(&mut stub, "c0"), "T05thread:1;awatch:104;");
+}
+
+#[test]
+fn atomic_watchpoints() {
+    let mut stub = stub();
+    // amoadd.w x5, x7, (x6) writes the word it reads
+    stub.target().ram().write_word(0, amo(0b00000, 5, 6, 7));
+    stub.target().registers().write(6, 0x104);
+
+    assert_eq!(packet(&mut stub, "Z2,104,4"), "OK");
+    assert_eq!(packet(&mut stub, "c"), "T05thread:1;watch:104;");
+    assert_eq!(*stub.target().pc(), 4);
+}
+
+#[test]
+fn threads() {
+    let mut bus = SystemBus::new(0, RAM::new(256));
+    for address in (0..0x100).step_by(4) {
+        bus.ram().write_word(address, WFI);
+    }
+    let mut stub = GDBStub::new(System::new(bus, 2, Schedule::RoundRobin { quantum: 1 }));
+
+    assert_eq!(stub.packet("qfThreadInfo").unwrap(), "m1,2");
+    assert_eq!(stub.packet("qsThreadInfo").unwrap(), "l");
+    assert_eq!(stub.packet("qThreadExtraInfo,2").unwrap(), "686172742031");
+    assert_eq!(stub.packet("T2").unwrap(), "OK");
+    assert_eq!(stub.packet("T3").unwrap(), "E01");
+
+    assert_eq!(stub.packet("Hg2").unwrap(), "OK");
+    assert_eq!(stub.packet("qC").unwrap(), "QC2");
+    assert_eq!(stub.packet(&format!("p{:x}", CSR_REGISTER + MHARTID as u32)).unwrap(), "01000000");
+
+    // stepping hart 1 lets hart 0 run first
+    assert_eq!(stub.packet("Hc2").unwrap(), "OK");
+    assert_eq!(stub.packet("s").unwrap(), "T05thread:2;");
+    assert_eq!(*stub.target().hart(0).pc(), 4);
+    assert_eq!(*stub.target().hart(1).pc(), 4);
+    assert_eq!(stub.packet("Hc-1").unwrap(), "OK");
+    assert_eq!(stub.packet("s").unwrap(), "T05thread:1;");
+
+    assert_eq!(stub.packet("Z0,c,4").unwrap(), "OK");
+    assert_eq!(stub.packet("c").unwrap(), "T05thread:1;swbreak:;");
+    assert_eq!(*stub.target().hart(1).pc(), 8);
+}
+
+fn frame(payload: &str) -> Vec<u8> {
+    let checksum = payload.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    format!("${}#{:02x}", payload, checksum).into_bytes()
+}
+
+fn receive(stream: &mut TcpStream) -> String {
+    let mut data = Vec::new();
+    let mut byte = [0];
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        data.push(byte[0]);
+        if data.len() > 3 && data[data.len() - 3] == b'#' {
+            break;
+        }
+    }
+    stream.write_all(b"+").unwrap();
+
+    let text = String::from_utf8(data).unwrap();
+    let start = text.find('$').unwrap();
+    text[start + 1..text.len() - 3].to_string()
+}
+
+#[test]
+fn tcp_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut replies = Vec::new();
+
+        // a corrupted packet is rejected
+        stream.write_all(b"$?#00").unwrap();
+        let mut ack = [0];
+        stream.read_exact(&mut ack).unwrap();
+        replies.push(String::from_utf8(ack.to_vec()).unwrap());
+
+        for payload in ["?", "m100,4", "QStartNoAckMode"] {
+            stream.write_all(&frame(payload)).unwrap();
+            stream.read_exact(&mut ack).unwrap();
+            replies.push(receive(&mut stream));
+        }
+
+        // interrupt the endless loop
+        stream.write_all(&frame("c")).unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        stream.write_all(&[0x03]).unwrap();
+        replies.push(receive(&mut stream));
+
+        stream.write_all(&frame("D")).unwrap();
+        replies.push(receive(&mut stream));
+        replies
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut stub = stub();
+    stub.serve(stream).unwrap();
+
+    let replies = client.join().unwrap();
+    assert_eq!(replies, ["-", "T05thread:1;", "78563412", "OK", "T02thread:1;", "OK"]);
+    assert_eq!(*stub.target().pc(), 8);
+}
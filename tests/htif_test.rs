@@ -0,0 +1,111 @@
+mod common;
+
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::elf::*;
+use rust_risc_v::htif::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::linux::SYS_WRITE;
+
+const BASE: u32     = 0x8000_0000;
+const TOHOST: u32   = 0x8000_1000;
+const FROMHOST: u32 = 0x8000_1008;
+const MAGIC: u32    = 0x8000_1040;
+const TABLE: u32    = 0x8000_1100;
+
+// stores the double word from table entries low and high into tohost, then exits with the last entry
+fn program(commands: &[(u32, u32)], exit: u32) -> (CPU, HTIF) {
+    let mut code = vec![lui(6, TOHOST)];
+    let mut table = Vec::new();
+    for &(low, high) in commands.iter().chain([(exit, 0)].iter()) {
+        let offset = 0x100 + table.len() as u16 * 4;
+        table.extend([low, high]);
+        code.extend([
+            load(LoadType::LW, 5, 6, offset),
+            load(LoadType::LW, 7, 6, offset + 4),
+            store(StoreType::SW, 6, 5, 0),
+            store(StoreType::SW, 6, 7, 4),
+        ]);
+    }
+    code.push(jal(0, 0));
+
+    let code: Vec<u8> = code.iter().flat_map(|instruction| instruction.to_le_bytes()).collect();
+    let table: Vec<u8> = table.iter().flat_map(|value| value.to_le_bytes()).collect();
+    let symbols = [("tohost", TOHOST), ("fromhost", FROMHOST)];
+    let elf = ELF::parse(&common::elf::build(BASE, &[(BASE, &code), (TABLE, &table)], &symbols)).unwrap();
+
+    let mut cpu = CPU::with_bus(SystemBus::new(BASE, RAM::new(0x1000)));
+    elf.load(cpu.bus()).unwrap();
+    *cpu.pc() = elf.entry;
+
+    let mut htif = HTIF::from_elf(&elf).unwrap();
+    htif.capture_output();
+    (cpu, htif)
+}
+
+#[test]
+fn encoding() {
+    let value = encode(DEVICE_CONSOLE, CONSOLE_PUTCHAR, b'a' as u64);
+    assert_eq!(value, 0x0101_0000_0000_0061);
+    assert_eq!(decode(value), (DEVICE_CONSOLE, CONSOLE_PUTCHAR, b'a' as u64));
+}
+
+#[test]
+fn symbols() {
+    let elf = ELF::parse(&common::elf::build(BASE, &[], &[("tohost", TOHOST)])).unwrap();
+    let htif = HTIF::from_elf(&elf).unwrap();
+    assert_eq!(htif.tohost(), TOHOST);
+    assert_eq!(htif.fromhost(), None);
+
+    let elf = ELF::parse(&common::elf::build(BASE, &[], &[])).unwrap();
+    assert!(HTIF::from_elf(&elf).is_none());
+}
+
+#[test]
+fn pass_and_fail() {
+    let (mut cpu, mut htif) = program(&[], 1);
+    assert_eq!(htif.run(&mut cpu, 100), Ok(Some(0)));
+    assert!(htif.passed());
+    assert_eq!(cpu.bus().read(TOHOST, AccessSize::Word), Ok(0));
+
+    // riscv-tests encode the failing test number
+    let (mut cpu, mut htif) = program(&[], 5 << 1 | 1);
+    assert_eq!(htif.run(&mut cpu, 100), Ok(Some(5)));
+    assert!(!htif.passed());
+    assert_eq!(htif.step(&mut cpu), Ok(Some(5)));
+}
+
+#[test]
+fn console() {
+    let putchar = (b'o' as u32, 0x0101_0000);
+    let (mut cpu, mut htif) = program(&[putchar, (b'k' as u32, 0x0101_0000)], 1);
+
+    assert_eq!(htif.run(&mut cpu, 100), Ok(Some(0)));
+    assert_eq!(htif.take_output(), b"ok");
+    assert_eq!(cpu.bus().read(FROMHOST + 4, AccessSize::Word), Ok(0x0101_0000));
+}
+
+#[test]
+fn syscall_proxy() {
+    let (mut cpu, mut htif) = program(&[(MAGIC, 0)], 1);
+    for (index, value) in [SYS_WRITE, 1, TABLE + 0x80, 3].iter().enumerate() {
+        cpu.bus().write(MAGIC + index as u32 * 8, AccessSize::Word, *value).unwrap();
+    }
+    for (offset, byte) in b"hey".iter().enumerate() {
+        cpu.bus().write(TABLE + 0x80 + offset as u32, AccessSize::Byte, *byte as u32).unwrap();
+    }
+
+    assert_eq!(htif.run(&mut cpu, 100), Ok(Some(0)));
+    assert_eq!(htif.take_output(), b"hey");
+    assert_eq!(cpu.bus().read(MAGIC, AccessSize::Word), Ok(3));
+    assert_eq!(cpu.bus().read(FROMHOST, AccessSize::Word), Ok(1));
+}
+
+#[test]
+fn unmapped_tohost() {
+    let mut cpu = CPU::with_bus(SystemBus::new(BASE, RAM::new(16)));
+    let mut htif = HTIF::new(0x1000, None);
+    assert_eq!(htif.step(&mut cpu), Err(BusError::AccessFault(0x1000)));
+}
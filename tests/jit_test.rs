@@ -0,0 +1,263 @@
+#![cfg(feature = "jit")]
+
+use rust_risc_v::*;
+use rust_risc_v::block::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::debug::StopReason;
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::branch::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::jit::HOT;
+
+fn program(cpu: &mut CPU, address: u32, code: &[u32]) {
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(address + index as u32 * 4, *instruction);
+    }
+}
+
+// the same machine once for the interpreter and once for native code
+fn pair<F: Fn(&mut CPU)>(setup: F) -> (CPU, CPU) {
+    let mut interpreter = CPU::new(0x1000);
+    let mut jit = CPU::new(0x1000);
+    setup(&mut interpreter);
+    setup(&mut jit);
+    jit.set_engine(Engine::Jit);
+    (interpreter, jit)
+}
+
+fn assert_same(interpreter: &mut CPU, jit: &mut CPU) {
+    assert_eq!(*interpreter.pc(), *jit.pc());
+    assert_eq!(interpreter.registers().inspect(), jit.registers().inspect());
+    for csr in [MEPC, MCAUSE, MTVAL, MSTATUS, MSCRATCH] {
+        assert_eq!(interpreter.csrs().read(csr), jit.csrs().read(csr));
+    }
+    assert_eq!(interpreter.ram().inspect(0, 0x4000), jit.ram().inspect(0, 0x4000));
+}
+
+#[test]
+fn matches_interpreter() {
+    // walks a list through a called function and returns with jalr
+    let (mut interpreter, mut jit) = pair(|cpu| {
+        program(cpu, 0, &[
+            lui(9, 0x3000),
+            auipc(10, 0),
+            load(LoadType::LW, 5, 0, 0x7FC),
+            jal(1, 24),
+            csr(CSRType::CSRRSI, 7, 1, MSCRATCH),
+            branch(BranchType::BGE, 9, 5, 8),
+            store(StoreType::SW, 0, 9, 0x7F8),
+            csr(CSRType::CSRRCI, 0, 1, MSCRATCH),
+            jal(0, -32i32 as u32 & 0x1FFFFF),
+            // walk
+            load(LoadType::LBU, 8, 5, 4),
+            store(StoreType::SB, 5, 7, 5),
+            store(StoreType::SW, 0, 8, 0x7F0),
+            load(LoadType::LW, 5, 5, 0),
+            branch(BranchType::BNE, 5, 0, 0x1FF0),
+            jalr(0, 1, 0),
+        ]);
+        cpu.ram().write_word(0x7FC, 0x2000);
+        for node in 0..6 {
+            let address = 0x2000 + node * 8;
+            cpu.ram().write_word(address, if node < 5 { address + 8 } else { 0 });
+            cpu.ram().write_word(address + 4, node * 7);
+        }
+    });
+
+    for instructions in [1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 1000, 10_000] {
+        for _ in 0..instructions {
+            interpreter.tick();
+        }
+        assert_eq!(jit.run_for(instructions), StopReason::InstructionLimit);
+        assert_same(&mut interpreter, &mut jit);
+    }
+    assert_eq!(jit.csrs().read(MCAUSE), 0);
+    assert!(jit.jit().compiled() > 0);
+}
+
+#[test]
+fn precise_exceptions() {
+    // the third load of a hot block faults once its base register is changed from outside
+    let (mut interpreter, mut jit) = pair(|cpu| {
+        program(cpu, 0, &[
+            load(LoadType::LW, 5, 0, 0x100),
+            load(LoadType::LW, 6, 0, 0x104),
+            load(LoadType::LW, 7, 1, 0),
+            load(LoadType::LW, 8, 0, 0x108),
+            jal(0, -16i32 as u32 & 0x1FFFFF),
+        ]);
+        cpu.ram().write_word(0x100, 11);
+        cpu.ram().write_word(0x104, 22);
+        cpu.ram().write_word(0x108, 33);
+        cpu.csrs().write(MTVEC, 0x200);
+        program(cpu, 0x200, &[jal(0, 0)]);
+    });
+
+    let warm = 5 * (HOT as u64 + 4);
+    for _ in 0..warm {
+        interpreter.tick();
+    }
+    jit.run_for(warm);
+    assert_eq!(jit.jit().compiled(), 1);
+
+    interpreter.registers().write(1, 0x4000_0000);
+    jit.registers().write(1, 0x4000_0000);
+    jit.registers().write(6, 0);
+    interpreter.registers().write(6, 0);
+    for _ in 0..5 {
+        interpreter.tick();
+    }
+    jit.run_for(5);
+    assert_same(&mut interpreter, &mut jit);
+    assert_eq!(jit.csrs().read(MEPC), 8);
+    assert_eq!(jit.csrs().read(MCAUSE), 5);
+    assert_eq!(jit.csrs().read(MTVAL), 0x4000_0000);
+    assert_eq!(jit.registers().read(6), 22);
+    assert_eq!(*jit.pc(), 0x200);
+}
+
+#[test]
+fn interrupts_inside_native_code() {
+    let (mut interpreter, mut jit) = pair(|cpu| {
+        cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+        let code: Vec<u32> = (0..40).map(|index| lui(5 + index as u8 % 8, index << 12)).collect();
+        program(cpu, 0, &code);
+        program(cpu, 160, &[jal(0, -160i32 as u32 & 0x1FFFFF)]);
+        program(cpu, 0x800, &[jal(0, 0)]);
+        cpu.bus().write(CLINT::BASE + CLINT::MTIMECMP, AccessSize::Word, 41 * HOT + 17).unwrap();
+        cpu.bus().write(CLINT::BASE + CLINT::MTIMECMP + 4, AccessSize::Word, 0).unwrap();
+        cpu.csrs().write(MTVEC, 0x800);
+        cpu.csrs().write(MIE, MIP_MTIP);
+        cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+    });
+
+    let instructions = 41 * HOT as u64 + 30;
+    for _ in 0..instructions {
+        interpreter.tick();
+    }
+    jit.run_for(instructions);
+    assert!(jit.jit().compiled() > 0);
+    assert_same(&mut interpreter, &mut jit);
+    assert_eq!(jit.csrs().read(MCAUSE), 0x80000007);
+}
+
+#[test]
+fn code_written_inside_native_code() {
+    // swaps two instructions in the next page and writes one of them ahead into the block
+    let (mut interpreter, mut jit) = pair(|cpu| {
+        program(cpu, 0, &[
+            load(LoadType::LW, 5, 9, 0x300),
+            load(LoadType::LW, 6, 9, 0x304),
+            store(StoreType::SW, 9, 5, 0x304),
+            store(StoreType::SW, 9, 6, 0x300),
+            store(StoreType::SW, 0, 5, 24),
+            lui(8, 0x2000),
+            lui(7, 0x1000),
+            jal(0, -28i32 as u32 & 0x1FFFFF),
+        ]);
+        program(cpu, 0x1300, &[lui(7, 0x3000), lui(7, 0x4000)]);
+        cpu.registers().write(9, 0x1000);
+    });
+
+    for instructions in [8, 80, 808, 8000] {
+        for _ in 0..instructions {
+            interpreter.tick();
+        }
+        jit.run_for(instructions);
+        assert_same(&mut interpreter, &mut jit);
+    }
+
+    // host writes are seen the same way
+    let instructions = 8 * HOT as u64 * 2;
+    jit.ram().write_word(20, lui(8, 0x5000));
+    interpreter.ram().write_word(20, lui(8, 0x5000));
+    for _ in 0..instructions {
+        interpreter.tick();
+    }
+    jit.run_for(instructions);
+    assert_same(&mut interpreter, &mut jit);
+    assert_eq!(jit.registers().read(8), 0x5000);
+}
+
+#[test]
+fn hot_blocks_only() {
+    let (_, mut jit) = pair(|cpu| {
+        program(cpu, 0, &[lui(5, 0x1000), jal(0, -4i32 as u32 & 0x1FFFFF)]);
+    });
+
+    jit.run_for(2 * (HOT as u64 - 1));
+    assert_eq!(jit.jit().compiled(), 0);
+    jit.run_for(2);
+    assert_eq!(jit.jit().compiled(), 1);
+    assert!(jit.jit().code_size() > 0);
+
+    // run_until and breakpoints go through the block engine
+    jit.debugger().add_breakpoint(4);
+    assert_eq!(jit.run_for(10), StopReason::Breakpoint(4));
+}
+
+#[test]
+fn arithmetic_and_ram_accesses() {
+    // mixes a word through every kind of load and store, crossing a page every round and leaving ram every fourth
+    let (mut interpreter, mut jit) = pair(|cpu| {
+        program(cpu, 0, &[
+            load(LoadType::LW, 5, 0, 0x400),
+            load(LoadType::LH, 6, 0, 0x402),
+            load(LoadType::LBU, 7, 0, 0x403),
+            load(LoadType::LB, 8, 0, 0x403),
+            math(1, 0, 10, 5, 6),
+            math(1, 1, 11, 5, 6),
+            math(1, 3, 12, 5, 6),
+            math(0, 2, 14, 6, 7),
+            math(0, 3, 15, 6, 7),
+            math(0x20, 5, 16, 5, 8),
+            mathi(5, 17, 5, 0x407),
+            mathi(2, 18, 6, 0xFFF),
+            math(0x20, 0, 5, 5, 10),
+            math(0, 0, 5, 5, 11),
+            math(0, 4, 5, 5, 16),
+            mathi(0, 5, 5, 0x135),
+            math(0, 4, 5, 5, 12),
+            math(0, 0, 5, 5, 17),
+            math(0, 4, 5, 5, 14),
+            math(0, 0, 5, 5, 15),
+            store(StoreType::SW, 0, 5, 0x400),
+            store(StoreType::SH, 9, 5, 0x7FD),
+            store(StoreType::SB, 9, 8, 0x7FF),
+            load(LoadType::LH, 19, 9, 0x7FD),
+            load(LoadType::LHU, 21, 9, 0x7FF),
+            math(0, 0, 5, 5, 8),
+            math(0, 4, 5, 5, 19),
+            math(0, 0, 5, 5, 21),
+            store(StoreType::SW, 0, 5, 0x400),
+            math(0, 0, 23, 9, 22),
+            store(StoreType::SH, 23, 5, 0x7FF),
+            mathi(0, 9, 9, 0x200),
+            mathi(7, 9, 9, 0x7FF),
+            math(1, 2, 13, 5, 7),
+            branch(BranchType::BNE, 9, 20, 8),
+            lui(9, 0x10000),
+            jal(0, -144i32 as u32 & 0x1FFFFF),
+        ]);
+        // the access outside of ram faults, the handler starts over
+        program(cpu, 0x200, &[mathi(0, 9, 0, 0), jal(0, -516i32 as u32 & 0x1FFFFF)]);
+        cpu.csrs().write(MTVEC, 0x200);
+        cpu.ram().write_word(0x400, 0x8123_4587);
+        cpu.registers().write(20, 0x600);
+        cpu.registers().write(22, 0x800);
+    });
+
+    for instructions in [37, 370, 3700, 37_000] {
+        for _ in 0..instructions {
+            interpreter.tick();
+        }
+        jit.run_for(instructions);
+        assert_same(&mut interpreter, &mut jit);
+    }
+    assert_eq!(jit.csrs().read(MCAUSE), 7);
+    assert!(jit.jit().compiled() > 0);
+}
@@ -0,0 +1,236 @@
+mod common;
+
+use rust_risc_v::elf::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::linux::*;
+
+const MEMORY: u32 = 0x0040_0000;
+const CODE: u32 = 0x0001_0000;
+const DATA: u32 = 0x0001_0400;
+
+fn process(args: &[&str], env: &[&str]) -> Process {
+    let code: Vec<u8> = (0..64).flat_map(|_| ECALL.to_le_bytes()).collect();
+    let elf = ELF::parse(&common::elf::build(CODE, &[(CODE, &code), (DATA, &[0; 0x100])], &[])).unwrap();
+
+    let mut process = Process::new(&elf, args, env, MEMORY).unwrap();
+    process.capture_output();
+    process
+}
+
+fn call(process: &mut Process, number: u32, arguments: &[u32]) -> u32 {
+    for (index, &argument) in arguments.iter().enumerate() {
+        process.cpu().registers().write(10 + index as u8, argument);
+    }
+    process.cpu().registers().write(17, number);
+    process.step();
+
+    process.cpu().registers().read(10)
+}
+
+fn write_string(process: &mut Process, address: u32, data: &[u8]) {
+    for (offset, &byte) in data.iter().chain([0].iter()).enumerate() {
+        process.cpu().ram().write_byte(address + offset as u32, byte);
+    }
+}
+
+fn errno(value: i32) -> u32 {
+    (-value) as u32
+}
+
+#[test]
+fn hello_world() {
+    // registers are loaded from a table since there are no immediate arithmetic instructions
+    let mut code = vec![lui(6, CODE)];
+    for (register, offset) in [(10, 0x400), (11, 0x404), (12, 0x408), (17, 0x40C)] {
+        code.push(load(LoadType::LW, register, 6, offset));
+    }
+    code.push(ECALL);
+    for (register, offset) in [(10, 0x410), (17, 0x414)] {
+        code.push(load(LoadType::LW, register, 6, offset));
+    }
+    code.push(ECALL);
+    let code: Vec<u8> = code.iter().flat_map(|instruction| instruction.to_le_bytes()).collect();
+
+    let mut data: Vec<u8> = [1, DATA + 0x20, 6, SYS_WRITE, 7, SYS_EXIT_GROUP].iter().flat_map(|value: &u32| value.to_le_bytes()).collect();
+    data.resize(0x20, 0);
+    data.extend_from_slice(b"hello\n");
+
+    let elf = ELF::parse(&common::elf::build(CODE, &[(CODE, &code), (DATA, &data)], &[])).unwrap();
+    let mut process = Process::new(&elf, &["hello"], &[], MEMORY).unwrap();
+    process.capture_output();
+
+    assert_eq!(process.run(100), Some(Exit::Exited(7)));
+    assert_eq!(process.take_stdout(), b"hello\n");
+    assert_eq!(process.step(), Some(Exit::Exited(7)));
+}
+
+#[test]
+fn initial_stack() {
+    let mut process = process(&["program", "-v"], &["HOME=/"]);
+    let sp = process.cpu().registers().read(2);
+    assert_eq!(sp & 0xF, 0);
+
+    let words = process.cpu().ram().inspect_word(sp, 28);
+    assert_eq!(words[0], 2);
+    assert_eq!(words[3], 0);
+    assert_eq!(words[5], 0);
+
+    let string = |process: &mut Process, address: u32| {
+        let bytes = process.cpu().ram().inspect(address, (MEMORY - address).min(16)).to_vec();
+        String::from_utf8(bytes.split(|&byte| byte == 0).next().unwrap().to_vec()).unwrap()
+    };
+    assert_eq!(string(&mut process, words[1]), "program");
+    assert_eq!(string(&mut process, words[2]), "-v");
+    assert_eq!(string(&mut process, words[4]), "HOME=/");
+
+    let auxv: Vec<(u32, u32)> = words[6..].chunks(2).map(|pair| (pair[0], pair[1])).collect();
+    assert!(auxv.contains(&(AT_PAGESZ, PAGE_SIZE)));
+    assert!(auxv.contains(&(AT_ENTRY, CODE)));
+    assert!(auxv.contains(&(AT_PHNUM, 2)));
+    assert!(auxv.iter().any(|&(key, value)| key == AT_RANDOM && value > sp && value < MEMORY));
+}
+
+#[test]
+fn memory_management() {
+    let mut process = process(&[], &[]);
+
+    let start = call(&mut process, SYS_BRK, &[0]);
+    assert_eq!(start, 0x0001_1000);
+    assert_eq!(call(&mut process, SYS_BRK, &[start + 0x2000]), start + 0x2000);
+    assert_eq!(call(&mut process, SYS_BRK, &[MEMORY]), start + 0x2000);
+    assert_eq!(process.brk(), start + 0x2000);
+
+    let first = call(&mut process, SYS_MMAP2, &[0, 0x1800, 3, MAP_ANONYMOUS, u32::MAX, 0]);
+    assert_eq!(first, MEMORY - STACK_SIZE - 0x2000);
+    let second = call(&mut process, SYS_MMAP2, &[0, 0x1000, 3, MAP_ANONYMOUS, u32::MAX, 0]);
+    assert_eq!(second, first - 0x1000);
+    assert_eq!(call(&mut process, SYS_MMAP2, &[0, MEMORY, 3, MAP_ANONYMOUS, u32::MAX, 0]), errno(ENOMEM));
+    assert_eq!(call(&mut process, SYS_MUNMAP, &[first, 0x1800]), 0);
+}
+
+#[test]
+fn sandboxed_files() {
+    let root = std::env::temp_dir().join(format!("rust-risc-v-linux-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let mut process = process(&[], &[]);
+
+    write_string(&mut process, DATA, b"out.txt");
+    write_string(&mut process, DATA + 0x20, b"data");
+    assert_eq!(call(&mut process, SYS_OPENAT, &[AT_FDCWD, DATA, 0]), errno(EACCES));
+
+    process.set_root(&root);
+    let flags = O_WRONLY | O_CREAT | O_TRUNC;
+    assert_eq!(call(&mut process, SYS_OPENAT, &[AT_FDCWD, DATA, flags, 0o644]), 3);
+    assert_eq!(call(&mut process, SYS_WRITE, &[3, DATA + 0x20, 4]), 4);
+    assert_eq!(call(&mut process, SYS_CLOSE, &[3]), 0);
+    assert_eq!(call(&mut process, SYS_CLOSE, &[3]), errno(EBADF));
+    assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"data");
+
+    assert_eq!(call(&mut process, SYS_OPENAT, &[AT_FDCWD, DATA, 0]), 3);
+    assert_eq!(call(&mut process, SYS_FSTAT, &[3, DATA + 0x40]), 0);
+    let stat = process.cpu().ram().inspect(DATA + 0x40, 104).to_vec();
+    assert_eq!(u32::from_le_bytes(stat[16..20].try_into().unwrap()) & 0o170000, 0o100000);
+    assert_eq!(u64::from_le_bytes(stat[48..56].try_into().unwrap()), 4);
+
+    assert_eq!(call(&mut process, SYS_LLSEEK, &[3, 0, 2, DATA + 0xB0, 0]), 0);
+    assert_eq!(process.cpu().ram().read_word(DATA + 0xB0), 2);
+    assert_eq!(call(&mut process, SYS_READ, &[3, DATA + 0xC0, 16]), 2);
+    assert_eq!(process.cpu().ram().inspect(DATA + 0xC0, 2), b"ta");
+
+    write_string(&mut process, DATA, b"../escape");
+    assert_eq!(call(&mut process, SYS_OPENAT, &[AT_FDCWD, DATA, 0]), errno(EACCES));
+    write_string(&mut process, DATA, b"missing");
+    assert_eq!(call(&mut process, SYS_OPENAT, &[AT_FDCWD, DATA, 0]), errno(ENOENT));
+
+    // symlinks are followed only while they stay inside the root
+    #[cfg(unix)]
+    {
+        let outside = std::env::temp_dir().join(format!("rust-risc-v-outside-{}", std::process::id()));
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink("out.txt", root.join("inside")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("directory")).unwrap();
+        std::os::unix::fs::symlink(outside.join("created"), root.join("dangling")).unwrap();
+
+        write_string(&mut process, DATA, b"inside");
+        assert_eq!(call(&mut process, SYS_OPENAT, &[AT_FDCWD, DATA, 0]), 4);
+        write_string(&mut process, DATA, b"directory/file");
+        assert_eq!(call(&mut process, SYS_OPENAT, &[AT_FDCWD, DATA, flags, 0o644]), errno(EACCES));
+        write_string(&mut process, DATA, b"dangling");
+        assert_eq!(call(&mut process, SYS_OPENAT, &[AT_FDCWD, DATA, flags, 0o644]), errno(EACCES));
+        assert!(std::fs::read_dir(&outside).unwrap().next().is_none());
+
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn standard_streams() {
+    let mut process = process(&[], &[]);
+    process.set_stdin(b"input");
+
+    assert_eq!(call(&mut process, SYS_READ, &[0, DATA, 3]), 3);
+    assert_eq!(process.cpu().ram().inspect(DATA, 3), b"inp");
+
+    // iovecs of base and length
+    write_string(&mut process, DATA + 0x20, b"ab");
+    for (index, value) in [DATA + 0x20, 1, DATA + 0x21, 1].iter().enumerate() {
+        process.cpu().ram().write_word(DATA + 0x40 + index as u32 * 4, *value);
+    }
+    assert_eq!(call(&mut process, SYS_WRITEV, &[2, DATA + 0x40, 2]), 2);
+    assert_eq!(process.take_stderr(), b"ab");
+    assert_eq!(call(&mut process, SYS_WRITEV, &[2, DATA + 0x40, IOV_MAX + 1]), errno(EINVAL));
+    assert_eq!(call(&mut process, SYS_WRITEV, &[2, u32::MAX - 4, 2]), errno(EFAULT));
+
+    assert_eq!(call(&mut process, SYS_FSTAT, &[1, DATA + 0x80]), 0);
+    assert_eq!(process.cpu().ram().read_word(DATA + 0x90) & 0o170000, 0o020000);
+    assert_eq!(call(&mut process, SYS_WRITE, &[1, MEMORY - 2, 8]), errno(EFAULT));
+}
+
+#[test]
+fn miscellaneous_calls() {
+    let mut process = process(&[], &[]);
+
+    assert_eq!(call(&mut process, SYS_CLOCK_GETTIME64, &[CLOCK_REALTIME, DATA]), 0);
+    assert!(process.cpu().ram().read_word(DATA) > 1_600_000_000);
+    assert_eq!(call(&mut process, SYS_CLOCK_GETTIME64, &[99, DATA]), errno(EINVAL));
+
+    assert_eq!(call(&mut process, SYS_UNAME, &[DATA]), 0);
+    assert_eq!(process.cpu().ram().inspect(DATA, 5), b"Linux");
+    assert_eq!(process.cpu().ram().inspect(DATA + 4 * 65, 7), b"riscv32");
+
+    assert_eq!(call(&mut process, SYS_GETRANDOM, &[DATA, 16, 0]), 16);
+    // lengths are checked against memory before anything is allocated
+    assert_eq!(call(&mut process, SYS_GETRANDOM, &[DATA, u32::MAX, 0]), errno(EFAULT));
+    assert_eq!(call(&mut process, SYS_READ, &[0, DATA, u32::MAX]), errno(EFAULT));
+    assert_eq!(call(&mut process, SYS_GETRANDOM, &[DATA, 0x0002_0010, 0]), 0x0002_0010);
+    assert_eq!(call(&mut process, SYS_IOCTL, &[1, 0x5413, DATA]), errno(ENOTTY));
+    assert_eq!(call(&mut process, 9999, &[]), errno(ENOSYS));
+
+    call(&mut process, SYS_EXIT, &[3]);
+    assert_eq!(process.exit(), Some(Exit::Exited(3)));
+}
+
+#[test]
+fn faults_end_the_process() {
+    let code: Vec<u8> = [lui(6, 0xF000_0000), load(LoadType::LW, 5, 6, 0)].iter().flat_map(|instruction| instruction.to_le_bytes()).collect();
+    let elf = ELF::parse(&common::elf::build(CODE, &[(CODE, &code)], &[])).unwrap();
+    let mut process = Process::new(&elf, &[], &[], MEMORY).unwrap();
+
+    // nothing is mapped above the process memory
+    assert_eq!(process.run(10), Some(Exit::Fault { cause: 5, pc: CODE + 4, value: 0xF000_0000 }));
+}
+
+#[test]
+fn parse_errors() {
+    assert!(matches!(ELF::parse(&[0; 10]), Err(ELFError::Truncated)));
+    assert!(matches!(ELF::parse(&[0; 64]), Err(ELFError::NotELF)));
+
+    let mut elf = common::elf::build(CODE, &[], &[("tohost", 0x1000)]);
+    assert_eq!(ELF::parse(&elf).unwrap().symbol("tohost"), Some(0x1000));
+    elf[4] = 2;
+    assert!(matches!(ELF::parse(&elf), Err(ELFError::Unsupported)));
+}
@@ -0,0 +1,230 @@
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::devices::uart::*;
+use rust_risc_v::devices::virtio::block::*;
+use rust_risc_v::fdt;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::machine::*;
+use rust_risc_v::sbi::*;
+use rust_risc_v::trap::Privilege;
+
+const RAM_SIZE: u32 = 0x0080_0000;
+
+fn kernel() -> Vec<u8> {
+    (0..16).flat_map(|_| ECALL.to_le_bytes()).collect()
+}
+
+fn native() -> (Virt, BufferBackend) {
+    let console = BufferBackend::new();
+    let virt = Virt::new(VirtConfig::new(RAM_SIZE, kernel(), Box::new(console.clone()))).unwrap();
+    (virt, console)
+}
+
+fn call(virt: &mut Virt, extension: u32, function: u32, arguments: &[u32]) -> (i32, u32) {
+    for (index, &argument) in arguments.iter().enumerate() {
+        virt.cpu().registers().write(10 + index as u8, argument);
+    }
+    virt.cpu().registers().write(16, function);
+    virt.cpu().registers().write(17, extension);
+
+    let pc = *virt.cpu().pc();
+    virt.step();
+    assert_eq!(*virt.cpu().pc(), pc + 4);
+
+    (virt.cpu().registers().read(10) as i32, virt.cpu().registers().read(11))
+}
+
+#[test]
+fn boot_state() {
+    let console = BufferBackend::new();
+    let mut config = VirtConfig::new(RAM_SIZE, kernel(), Box::new(console));
+    config.initrd = Some(vec![0xAB; 0x1800]);
+    config.disk = Some(Disk::memory(vec![0; 4096], DiskMode::ReadOnly));
+    config.bootargs = Some("console=hvc0".to_string());
+    let mut virt = Virt::new(config).unwrap();
+
+    let dtb = virt.dtb();
+    assert_eq!(*virt.cpu().pc(), RAM_BASE + KERNEL_OFFSET);
+    assert_eq!(virt.cpu().registers().read(10), 0);
+    assert_eq!(virt.cpu().registers().read(11), dtb);
+    assert!(dtb >= RAM_BASE + RAM_SIZE - DTB_RESERVED);
+
+    assert_eq!(virt.cpu().bus().read(dtb, AccessSize::Word), Ok(fdt::MAGIC.swap_bytes()));
+    assert_eq!(virt.cpu().bus().read(RAM_BASE + KERNEL_OFFSET, AccessSize::Word), Ok(ECALL));
+
+    let initrd = (RAM_BASE + RAM_SIZE - DTB_RESERVED - 0x1800) & !0xFFF;
+    assert_eq!(virt.cpu().bus().read(initrd, AccessSize::Byte), Ok(0xAB));
+    assert_eq!(virt.cpu().bus().read(initrd + 0x17FF, AccessSize::Byte), Ok(0xAB));
+
+    // the block device answers at the first virtio transport
+    assert_eq!(virt.cpu().bus().read(0x1000_1000, AccessSize::Word), Ok(0x74726976));
+    assert_eq!(virt.cpu().bus().read(0x1000_1008, AccessSize::Word), Ok(2));
+}
+
+#[test]
+fn opensbi_firmware() {
+    let mut config = VirtConfig::new(RAM_SIZE, kernel(), Box::new(BufferBackend::new()));
+    config.firmware = Firmware::OpenSBI(vec![0x13, 0, 0, 0]);
+    let mut virt = Virt::new(config).unwrap();
+
+    assert!(virt.sbi().is_none());
+    assert_eq!(*virt.cpu().pc(), RAM_BASE);
+    assert_eq!(virt.cpu().bus().read(RAM_BASE, AccessSize::Word), Ok(0x13));
+    assert_eq!(virt.cpu().bus().read(RAM_BASE + KERNEL_OFFSET, AccessSize::Word), Ok(ECALL));
+
+    let mut config = VirtConfig::new(RAM_SIZE, kernel(), Box::new(BufferBackend::new()));
+    config.firmware = Firmware::OpenSBI(vec![0; KERNEL_OFFSET as usize + 1]);
+    assert!(matches!(Virt::new(config), Err(BusError::Overlap(_))));
+}
+
+#[test]
+fn images_must_fit() {
+    let mut config = VirtConfig::new(RAM_SIZE, kernel(), Box::new(BufferBackend::new()));
+    config.initrd = Some(vec![0; RAM_SIZE as usize]);
+    assert!(matches!(Virt::new(config), Err(BusError::Overlap(_))));
+
+    let config = VirtConfig::new(RAM_SIZE, vec![0; RAM_SIZE as usize], Box::new(BufferBackend::new()));
+    assert!(matches!(Virt::new(config), Err(BusError::AccessFault(_))));
+}
+
+#[test]
+fn base_extension() {
+    let (mut virt, _) = native();
+
+    assert_eq!(call(&mut virt, EXTENSION_BASE, 0, &[]), (SUCCESS, SPEC_VERSION));
+    assert_eq!(call(&mut virt, EXTENSION_BASE, 1, &[]), (SUCCESS, IMPLEMENTATION_ID));
+    assert_eq!(call(&mut virt, EXTENSION_BASE, 3, &[EXTENSION_HSM]), (SUCCESS, 1));
+    assert_eq!(call(&mut virt, EXTENSION_BASE, 3, &[0x0A000000]), (SUCCESS, 0));
+    assert_eq!(call(&mut virt, 0x0A000000, 0, &[]).0, ERR_NOT_SUPPORTED);
+}
+
+#[test]
+fn console() {
+    let (mut virt, console) = native();
+
+    assert_eq!(call(&mut virt, EXTENSION_DBCN, 2, &[b'h' as u32]), (SUCCESS, 0));
+    call(&mut virt, EXTENSION_CONSOLE_PUTCHAR, 0, &[b'i' as u32]);
+    assert_eq!(console.take_output(), b"hi");
+
+    let buffer = RAM_BASE + 0x1000;
+    for (offset, &byte) in b"hello".iter().enumerate() {
+        virt.cpu().bus().write(buffer + offset as u32, AccessSize::Byte, byte as u32).unwrap();
+    }
+    assert_eq!(call(&mut virt, EXTENSION_DBCN, 0, &[5, buffer, 0]), (SUCCESS, 5));
+    assert_eq!(console.take_output(), b"hello");
+    assert_eq!(call(&mut virt, EXTENSION_DBCN, 0, &[5, 0x4000_0000, 0]).0, ERR_INVALID_PARAM);
+
+    // input arrives through the uart
+    console.push_input(b"ok");
+    virt.cpu().bus().tick();
+    assert_eq!(call(&mut virt, EXTENSION_DBCN, 1, &[4, buffer, 0]), (SUCCESS, 1));
+    assert_eq!(virt.cpu().bus().read(buffer, AccessSize::Byte), Ok(b'o' as u32));
+    virt.cpu().bus().tick();
+    call(&mut virt, EXTENSION_CONSOLE_GETCHAR, 0, &[]);
+    assert_eq!(virt.cpu().registers().read(10), b'k' as u32);
+    call(&mut virt, EXTENSION_CONSOLE_GETCHAR, 0, &[]);
+    assert_eq!(virt.cpu().registers().read(10) as i32, -1);
+}
+
+#[test]
+fn timer_and_ipi() {
+    let (mut virt, _) = native();
+
+    assert_eq!(call(&mut virt, EXTENSION_TIME, 0, &[0x1234, 0x5]), (SUCCESS, 0));
+    let clint = virt.cpu().bus().device::<CLINT>(CLINT::BASE).unwrap();
+    assert_eq!(clint.mtimecmp(0), 0x5_0000_1234);
+
+    assert_eq!(call(&mut virt, EXTENSION_IPI, 0, &[0b10, 0]).0, ERR_INVALID_PARAM);
+    assert_eq!(call(&mut virt, EXTENSION_IPI, 0, &[0b1, 0]), (SUCCESS, 0));
+    assert!(virt.cpu().bus().device::<CLINT>(CLINT::BASE).unwrap().msip(0));
+
+    // the software interrupt reaches the kernel as a supervisor one
+    let pc = *virt.cpu().pc();
+    virt.step();
+    assert_eq!(*virt.cpu().pc(), pc);
+    assert_eq!(virt.cpu().privilege(), Privilege::Supervisor);
+    assert!(!virt.cpu().bus().device::<CLINT>(CLINT::BASE).unwrap().msip(0));
+    assert_eq!(virt.cpu().csrs().read(SIP), MIP_SSIP);
+
+    // so does the timer, until the next one is set
+    assert_eq!(call(&mut virt, EXTENSION_TIME, 0, &[0, 0]), (SUCCESS, 0));
+    virt.step();
+    assert_eq!(*virt.cpu().pc(), pc + 4);
+    assert_eq!(virt.cpu().csrs().read(SIP), MIP_SSIP | MIP_STIP);
+    assert_eq!(call(&mut virt, EXTENSION_TIME, 0, &[u32::MAX, u32::MAX]), (SUCCESS, 0));
+    assert_eq!(virt.cpu().csrs().read(SIP), MIP_SSIP);
+
+    assert_eq!(call(&mut virt, EXTENSION_RFENCE, 1, &[0, 0, 0, 0]), (SUCCESS, 0));
+}
+
+#[test]
+fn supervisor_ecalls_only() {
+    let (mut virt, _) = native();
+    assert_eq!(virt.cpu().privilege(), Privilege::Supervisor);
+    assert_eq!(virt.cpu().csrs().read(SATP), 0);
+
+    // an ecall from machine mode is the machine's own trap
+    virt.cpu().set_privilege(Privilege::Machine);
+    virt.cpu().csrs().write(MTVEC, RAM_BASE + 0x100);
+    virt.cpu().registers().write(17, EXTENSION_BASE);
+    let pc = *virt.cpu().pc();
+    virt.step();
+    assert_eq!(*virt.cpu().pc(), RAM_BASE + 0x100);
+    assert_eq!(virt.cpu().privilege(), Privilege::Machine);
+    assert_eq!(virt.cpu().csrs().read(MCAUSE), 11);
+    assert_eq!(virt.cpu().csrs().read(MEPC), pc);
+}
+
+#[test]
+fn hart_state_management() {
+    let (mut virt, _) = native();
+
+    assert_eq!(call(&mut virt, EXTENSION_HSM, 2, &[0]), (SUCCESS, 0));
+    assert_eq!(call(&mut virt, EXTENSION_HSM, 2, &[1]).0, ERR_INVALID_PARAM);
+    assert_eq!(call(&mut virt, EXTENSION_HSM, 0, &[0, RAM_BASE, 0]).0, ERR_ALREADY_STARTED);
+
+    call(&mut virt, EXTENSION_HSM, 1, &[]);
+    assert!(virt.halted());
+    assert_eq!(virt.sbi().unwrap().hart_state(0), Some(HartState::Stopped));
+}
+
+#[test]
+fn system_reset() {
+    let (mut virt, _) = native();
+
+    assert_eq!(virt.run(3), 3);
+    assert!(!virt.halted());
+
+    call(&mut virt, EXTENSION_SRST, 0, &[0, 0]);
+    assert_eq!(virt.reset(), Some(SystemReset { reset_type: 0, reason: 0 }));
+
+    let pc = *virt.cpu().pc();
+    assert_eq!(virt.run(10), 0);
+    assert_eq!(*virt.cpu().pc(), pc);
+}
+
+#[test]
+fn snapshot_restore() {
+    let (mut virt, _) = native();
+    virt.run(3);
+    let data = virt.save();
+    let pc = *virt.cpu().pc();
+
+    call(&mut virt, EXTENSION_SRST, 0, &[0, 0]);
+    assert!(virt.halted());
+
+    virt.restore(&data).unwrap();
+    assert!(!virt.halted());
+    assert_eq!(*virt.cpu().pc(), pc);
+
+    // every test case can start from the same booted state
+    let (mut other, _) = native();
+    other.restore(&data).unwrap();
+    assert_eq!(*other.cpu().pc(), pc);
+    assert_eq!(other.cpu().ram().read_word(0), virt.cpu().ram().read_word(0));
+
+    let config = VirtConfig { firmware: Firmware::OpenSBI(vec![0; 16]), ..VirtConfig::new(RAM_SIZE, kernel(), Box::new(BufferBackend::new())) };
+    let mut firmware = Virt::new(config).unwrap();
+    assert!(firmware.restore(&data).is_err());
+}
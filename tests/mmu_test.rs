@@ -0,0 +1,201 @@
+use rust_risc_v::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::mmu::*;
+use rust_risc_v::trap::Privilege;
+
+const ROOT: u32 = 0x1000;
+const TABLE: u32 = 0x2000;
+const CODE: u32 = 0x4000_0000;
+const DATA: u32 = 0x4000_1000;
+
+fn pte(physical: u32, flags: u32) -> u32 {
+    (physical >> 12) << 10 | flags
+}
+
+// code at 0x3000 and data at 0x4000 are mapped right above 1 GiB, the code runs in supervisor mode
+fn paged(program: &[u32]) -> CPU {
+    let mut cpu = CPU::new(0x4000);
+    cpu.ram().write_word(ROOT + (CODE >> 22) * 4, pte(TABLE, PTE_V));
+    cpu.ram().write_word(TABLE, pte(0x3000, PTE_V | PTE_R | PTE_X));
+    cpu.ram().write_word(TABLE + 4, pte(0x4000, PTE_V | PTE_R | PTE_W));
+    for (index, &instruction) in program.iter().enumerate() {
+        cpu.ram().write_word(0x3000 + index as u32 * 4, instruction);
+    }
+
+    cpu.csrs().write(SATP, SATP_MODE | ROOT >> 12);
+    cpu.csrs().write(MTVEC, 0x100);
+    cpu.set_privilege(Privilege::Supervisor);
+    *cpu.pc() = CODE;
+    cpu.registers().write(6, DATA);
+    cpu
+}
+
+#[test]
+fn translated_accesses() {
+    let mut cpu = paged(&[
+        load(LoadType::LW, 5, 6, 0),
+        store(StoreType::SW, 6, 5, 4),
+    ]);
+    cpu.ram().write_word(0x4000, 0xCAFE);
+
+    cpu.tick();
+    cpu.tick();
+    assert_eq!(*cpu.pc(), CODE + 8);
+    assert_eq!(cpu.registers().read(5), 0xCAFE);
+    assert_eq!(cpu.ram().read_word(0x4004), 0xCAFE);
+
+    // the walk sets accessed and dirty instead of faulting
+    assert_eq!(cpu.ram().read_word(TABLE) & (PTE_A | PTE_D), PTE_A);
+    assert_eq!(cpu.ram().read_word(TABLE + 4) & (PTE_A | PTE_D), PTE_A | PTE_D);
+
+    // machine mode ignores satp
+    cpu.set_privilege(Privilege::Machine);
+    cpu.registers().write(6, 0x4000);
+    *cpu.pc() = 0x3000;
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 0x3004);
+}
+
+#[test]
+fn page_faults() {
+    // the code page is not writable and nothing is mapped behind the data page
+    let mut cpu = paged(&[
+        store(StoreType::SW, 7, 0, 0),
+        load(LoadType::LW, 5, 6, 0x7FC),
+    ]);
+    cpu.registers().write(6, DATA + 0x1000);
+    cpu.registers().write(7, CODE);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 0x100);
+    assert_eq!(cpu.privilege(), Privilege::Machine);
+    assert_eq!(cpu.csrs().read(MCAUSE), 15);
+    assert_eq!(cpu.csrs().read(MTVAL), CODE);
+    assert_eq!(cpu.csrs().read(MSTATUS) & MSTATUS_MPP, 1 << 11);
+
+    cpu.set_privilege(Privilege::Supervisor);
+    *cpu.pc() = CODE + 4;
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 13);
+    assert_eq!(cpu.csrs().read(MTVAL), DATA + 0x17FC);
+
+    cpu.set_privilege(Privilege::Supervisor);
+    *cpu.pc() = CODE + 0x1000;
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 12);
+    assert_eq!(cpu.csrs().read(MTVAL), CODE + 0x1000);
+}
+
+#[test]
+fn user_pages() {
+    let mut cpu = paged(&[load(LoadType::LW, 5, 6, 0), load(LoadType::LW, 5, 6, 0)]);
+    cpu.ram().write_word(TABLE + 4, pte(0x4000, PTE_V | PTE_R | PTE_U));
+
+    // supervisor mode only reads user pages with sum set
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 13);
+
+    cpu.set_privilege(Privilege::Supervisor);
+    cpu.csrs().write(MSTATUS, MSTATUS_SUM);
+    *cpu.pc() = CODE;
+    cpu.tick();
+    assert_eq!(*cpu.pc(), CODE + 4);
+
+    // and user mode can not execute supervisor pages
+    cpu.set_privilege(Privilege::User);
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 12);
+}
+
+#[test]
+fn megapages() {
+    let mut cpu = paged(&[]);
+    cpu.ram().write_word(ROOT + 0x101 * 4, pte(0, PTE_V | PTE_R | PTE_X));
+    cpu.ram().write_word(ROOT + 0x102 * 4, pte(0x1000, PTE_V | PTE_R | PTE_X));
+    cpu.ram().write_word(0x3008, csr(CSRType::CSRRS, 5, 0, SSCRATCH));
+
+    // the low bits of the virtual address select the byte in the 4 MiB page
+    *cpu.pc() = 0x4040_3008;
+    cpu.csrs().write(SSCRATCH, 9);
+    cpu.tick();
+    assert_eq!(cpu.registers().read(5), 9);
+    assert_eq!(*cpu.pc(), 0x4040_300C);
+
+    // a megapage that is not aligned to 4 MiB faults
+    *cpu.pc() = 0x4080_3008;
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 12);
+}
+
+#[test]
+fn delegated_traps() {
+    let mut cpu = paged(&[load(LoadType::LW, 5, 7, 0), SRET]);
+    cpu.csrs().write(MEDELEG, (1 << 13) | (1 << 5));
+    cpu.csrs().write(STVEC, CODE + 4);
+    cpu.csrs().write(MSTATUS, MSTATUS_SIE);
+
+    // the fault goes to the supervisor handler, which returns to the load
+    cpu.tick();
+    assert_eq!(*cpu.pc(), CODE + 4);
+    assert_eq!(cpu.privilege(), Privilege::Supervisor);
+    assert_eq!(cpu.csrs().read(SCAUSE), 13);
+    assert_eq!(cpu.csrs().read(STVAL), 0);
+    assert_eq!(cpu.csrs().read(SEPC), CODE);
+    assert_eq!(cpu.csrs().read(SSTATUS), MSTATUS_SPIE | MSTATUS_SPP);
+    assert_eq!(cpu.csrs().read(MCAUSE), 0);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), CODE);
+    assert_eq!(cpu.csrs().read(SSTATUS), MSTATUS_SIE | MSTATUS_SPIE);
+
+    // machine mode keeps its own traps
+    cpu.set_privilege(Privilege::Machine);
+    cpu.registers().write(7, DATA);
+    *cpu.pc() = 0x3000;
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 0x100);
+    assert_eq!(cpu.csrs().read(MCAUSE), 5);
+}
+
+#[test]
+fn privileged_instructions() {
+    let mut cpu = CPU::new(256);
+    cpu.csrs().write(MTVEC, 0x80);
+    cpu.ram().write_word(0, MRET);
+    cpu.ram().write_word(4, csr(CSRType::CSRRS, 5, 0, MSTATUS));
+    cpu.ram().write_word(8, SRET);
+
+    // machine instructions and csrs trap below machine mode
+    for pc in [0, 4] {
+        cpu.set_privilege(Privilege::Supervisor);
+        *cpu.pc() = pc;
+        cpu.tick();
+        assert_eq!(*cpu.pc(), 0x80);
+        assert_eq!(cpu.csrs().read(MCAUSE), 2);
+    }
+
+    // sret is refused to user mode and to supervisor mode under tsr
+    cpu.set_privilege(Privilege::User);
+    *cpu.pc() = 8;
+    cpu.tick();
+    assert_eq!(cpu.csrs().read(MCAUSE), 2);
+
+    cpu.set_privilege(Privilege::Supervisor);
+    cpu.csrs().write(MSTATUS, MSTATUS_TSR);
+    *cpu.pc() = 8;
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 0x80);
+    assert_eq!(cpu.csrs().read(MTVAL), SRET);
+
+    // mret drops to the privilege in mpp
+    cpu.csrs().write(MSTATUS, 1 << 11);
+    cpu.csrs().write(MEPC, 0x40);
+    *cpu.pc() = 0;
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 0x40);
+    assert_eq!(cpu.privilege(), Privilege::Supervisor);
+    assert_eq!(cpu.csrs().read(MSTATUS) & MSTATUS_MPP, 0);
+}
@@ -0,0 +1,88 @@
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::devices::plic::*;
+use rust_risc_v::instructions::load::*;
+
+#[test]
+fn claim_highest_priority() {
+    let mut plic = PLIC::new(8, 2);
+
+    plic.write_word(PLIC::PRIORITY + 4 * 3, 2);
+    plic.write_word(PLIC::PRIORITY + 4 * 5, 6);
+    plic.write_word(PLIC::ENABLE, (1 << 3) | (1 << 5));
+    plic.set_level(3, true);
+    plic.set_level(5, true);
+
+    assert_eq!(plic.read_word(PLIC::PENDING), (1 << 3) | (1 << 5));
+    assert_eq!(plic.interrupts(0), MIP_MEIP);
+
+    assert_eq!(plic.read_word(PLIC::CLAIM), 5);
+    assert_eq!(plic.read_word(PLIC::CLAIM), 3);
+    assert_eq!(plic.read_word(PLIC::CLAIM), 0);
+    assert_eq!(plic.interrupts(0), 0);
+}
+
+#[test]
+fn threshold_masks_sources() {
+    let mut plic = PLIC::new(8, 2);
+
+    plic.set_priority(1, 3);
+    plic.set_enabled(1, 1, true);
+    plic.set_level(1, true);
+    assert_eq!(plic.interrupts(0), MIP_SEIP);
+
+    plic.write_word(PLIC::THRESHOLD + PLIC::CONTEXT_STRIDE, 3);
+    assert_eq!(plic.read_word(PLIC::THRESHOLD + PLIC::CONTEXT_STRIDE), 3);
+    assert_eq!(plic.interrupts(0), 0);
+    assert_eq!(plic.read_word(PLIC::CLAIM + PLIC::CONTEXT_STRIDE), 0);
+}
+
+#[test]
+fn complete_rearms_level_source() {
+    let mut plic = PLIC::new(8, 2);
+
+    plic.set_priority(2, 1);
+    plic.set_enabled(0, 2, true);
+    plic.set_level(2, true);
+
+    assert_eq!(plic.claim(0), 2);
+    assert!(!plic.is_pending(2));
+
+    // still asserted while claimed
+    plic.set_level(2, true);
+    assert!(!plic.is_pending(2));
+
+    plic.write_word(PLIC::CLAIM, 2);
+    assert!(plic.is_pending(2));
+
+    assert_eq!(plic.claim(0), 2);
+    plic.set_level(2, false);
+    plic.complete(0, 2);
+    assert!(!plic.is_pending(2));
+}
+
+#[test]
+fn external_interrupt() {
+    let mut cpu = CPU::new(256);
+    cpu.bus().map(PLIC::BASE, PLIC::SIZE, AccessSizes::WORD, Box::new(PLIC::new(8, 2))).unwrap();
+    // setup ram with instructions
+    cpu.ram().write_word(128, load(LoadType::LW, 2, 1, 4));
+    cpu.registers().write(1, PLIC::BASE + PLIC::THRESHOLD);
+    // enable external interrupt
+    cpu.csrs().write(MTVEC, 128);
+    cpu.csrs().write(MIE, MIP_MEIP);
+    cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+    cpu.bus().device::<PLIC>(PLIC::BASE).unwrap().set_priority(4, 1);
+    cpu.bus().device::<PLIC>(PLIC::BASE).unwrap().set_enabled(0, 4, true);
+    cpu.bus().device::<PLIC>(PLIC::BASE).unwrap().set_level(4, true);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 128);
+    assert_eq!(cpu.csrs().read(MCAUSE), 0x8000000B);
+
+    // claim the source from the handler
+    cpu.tick();
+    assert_eq!(cpu.registers().read(2), 4);
+    assert_eq!(cpu.bus().device::<PLIC>(PLIC::BASE).unwrap().interrupts(0), 0);
+}
@@ -0,0 +1,314 @@
+mod common;
+
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::debug::{StopReason, WatchKind};
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::devices::uart::*;
+use rust_risc_v::devices::virtio::{self, VirtioMMIO};
+use rust_risc_v::devices::virtio::net::*;
+use rust_risc_v::devices::virtio::rng::VirtioRNG;
+use rust_risc_v::gdb::GDBStub;
+use rust_risc_v::machine::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::reverse::*;
+use rust_risc_v::smp::*;
+use common::*;
+
+// copies the last received byte to 0x100 forever
+fn recorder(backend: &BufferBackend) -> Recorder {
+    let inputs = InputLog::new();
+    let mut cpu = CPU::new(256);
+    let uart = UART::new(Box::new(ReplayBackend::new(Box::new(backend.clone()), inputs.clone())));
+    cpu.bus().map(UART::BASE, UART::SIZE, AccessSizes::BYTE, Box::new(uart)).unwrap();
+    let code = [
+        load(LoadType::LBU, 5, 1, UART::RBR as u16),
+        store(StoreType::SB, 0, 5, 0x100),
+        jal(0, -8i32 as u32 & 0x1FFFFF),
+    ];
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+    cpu.registers().write(1, UART::BASE);
+
+    Recorder::new(cpu, inputs)
+}
+
+// pc, x5 and the byte at 0x100 before every step
+fn state(recorder: &mut Recorder) -> (u32, u32, u8) {
+    let cpu = recorder.cpu();
+    (*cpu.pc(), cpu.registers().read(5), cpu.ram().read_byte(0x100))
+}
+
+#[test]
+fn replay_history() {
+    let backend = BufferBackend::new();
+    let mut recorder = recorder(&backend);
+
+    let mut states = Vec::new();
+    for position in 0..10_000 {
+        if [5, 5000, 9000].contains(&position) {
+            backend.push_input(&[b'a' + position as u8 % 26]);
+        }
+        states.push(state(&mut recorder));
+        recorder.step();
+    }
+    assert_eq!(recorder.position(), 10_000);
+    assert_eq!(recorder.end(), 10_000);
+    let last = state(&mut recorder);
+
+    assert!(recorder.reverse_step());
+    assert_eq!(recorder.position(), 9_999);
+    assert_eq!(state(&mut recorder), states[9_999]);
+
+    // host input is replayed from the log, not read again
+    backend.push_input(b"z");
+    for position in [0, 4, 6, 4_999, 5_001, 8_191, 9_002, 3] {
+        recorder.seek(position);
+        assert_eq!(state(&mut recorder), states[position as usize], "position {}", position);
+    }
+    recorder.seek(10_000);
+    assert_eq!(state(&mut recorder), last);
+    assert!(!recorder.inputs().replaying());
+
+    recorder.seek(0);
+    assert!(!recorder.reverse_step());
+    assert_eq!(recorder.position(), 0);
+}
+
+#[test]
+fn reverse_continue() {
+    let backend = BufferBackend::new();
+    let mut recorder = recorder(&backend);
+    backend.push_input(b"x");
+    recorder.run_for(20);
+
+    assert_eq!(recorder.reverse_continue(), StopReason::HistoryStart);
+    assert_eq!(recorder.position(), 0);
+
+    recorder.run_for(20);
+    recorder.cpu().debugger().add_breakpoint(4);
+    assert_eq!(recorder.reverse_continue(), StopReason::Breakpoint(4));
+    assert_eq!(recorder.position(), 19);
+    assert_eq!(recorder.reverse_continue(), StopReason::Breakpoint(4));
+    assert_eq!(recorder.position(), 16);
+
+    // forward again to the breakpoint that was reversed over
+    assert_eq!(recorder.run(), StopReason::Breakpoint(4));
+    assert_eq!(recorder.position(), 19);
+
+    recorder.cpu().debugger().remove_breakpoint(4);
+    recorder.cpu().debugger().add_watchpoint(0x100..0x101, WatchKind::Write);
+    assert_eq!(recorder.reverse_continue(), StopReason::Watchpoint { address: 0x100, kind: WatchKind::Write });
+    assert_eq!(recorder.position(), 16);
+    assert_eq!(*recorder.cpu().pc(), 4);
+}
+
+#[test]
+fn last_writer() {
+    let backend = BufferBackend::new();
+    let mut recorder = recorder(&backend);
+    backend.push_input(b"q");
+    recorder.run_for(3);
+    backend.push_input(b"r");
+    recorder.run_for(8);
+
+    // the receiver is empty after the second byte was read
+    assert_eq!(recorder.last_register_write(5), Some(Change { position: 9, pc: 0, value: 0 }));
+    assert_eq!(recorder.last_memory_write(0x100), Some(Change { position: 10, pc: 4, value: 0 }));
+
+    // queries do not move through the history
+    assert_eq!(recorder.position(), 11);
+    assert_eq!(recorder.last_memory_write(0x101), None);
+    assert_eq!(recorder.last_register_write(6), None);
+
+    recorder.seek(6);
+    assert_eq!(recorder.last_register_write(5), Some(Change { position: 3, pc: 0, value: b'r' as u32 }));
+    assert_eq!(recorder.last_memory_write(0x100), Some(Change { position: 4, pc: 4, value: b'r' as u32 }));
+    recorder.seek(3);
+    assert_eq!(recorder.last_memory_write(0x100), Some(Change { position: 1, pc: 4, value: b'q' as u32 }));
+}
+
+#[test]
+fn recorded_clock() {
+    let inputs = InputLog::new();
+    let mut cpu = CPU::new(256);
+    cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Recorded(1_000_000_000, inputs.clone())))).unwrap();
+    cpu.ram().write_word(0, load(LoadType::LW, 5, 1, 0));
+    cpu.ram().write_word(4, jal(0, -4i32 as u32 & 0x1FFFFF));
+    cpu.registers().write(1, CLINT::BASE + CLINT::MTIME);
+    let mut recorder = Recorder::new(cpu, inputs);
+
+    let mut times = Vec::new();
+    for _ in 0..50 {
+        recorder.run_for(2);
+        times.push(recorder.cpu().registers().read(5));
+    }
+
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    recorder.seek(0);
+    for &time in times.iter() {
+        recorder.run_for(2);
+        assert_eq!(recorder.cpu().registers().read(5), time);
+    }
+}
+
+#[test]
+fn edits_discard_the_future() {
+    let backend = BufferBackend::new();
+    let mut recorder = recorder(&backend);
+    backend.push_input(b"e");
+    recorder.run_for(30);
+
+    recorder.seek(12);
+    recorder.edit().ram().write_byte(0x100, b'!');
+    assert_eq!(recorder.end(), 12);
+
+    recorder.run_for(1);
+    assert_eq!(recorder.end(), 13);
+    assert_eq!(recorder.cpu().ram().read_byte(0x100), b'!');
+
+    // replaying into the edited position restores the edit
+    recorder.seek(2);
+    assert_eq!(recorder.cpu().ram().read_byte(0x100), b'e');
+    recorder.seek(13);
+    assert_eq!(recorder.cpu().ram().read_byte(0x100), b'!');
+}
+
+#[test]
+fn gdb_reverse_packets() {
+    let backend = BufferBackend::new();
+    let mut stub = GDBStub::new(recorder(&backend));
+    backend.push_input(b"g");
+
+    assert!(stub.packet("qSupported").unwrap().contains("ReverseStep+;ReverseContinue+"));
+    assert_eq!(stub.packet("bs").unwrap(), "T05thread:1;replaylog:begin;");
+
+    assert_eq!(stub.packet("s").unwrap(), "T05thread:1;");
+    assert_eq!(stub.packet("s").unwrap(), "T05thread:1;");
+    assert_eq!(stub.packet("bs").unwrap(), "T05thread:1;");
+    assert_eq!(stub.target().position(), 1);
+    assert_eq!(*stub.target().cpu().pc(), 4);
+
+    assert_eq!(stub.packet("Z0,4,4").unwrap(), "OK");
+    assert_eq!(stub.packet("c").unwrap(), "T05thread:1;swbreak:;");
+    assert_eq!(stub.target().position(), 4);
+    assert_eq!(stub.packet("bc").unwrap(), "T05thread:1;swbreak:;");
+    assert_eq!(stub.target().position(), 1);
+    assert_eq!(stub.packet("bc").unwrap(), "T05thread:1;replaylog:begin;");
+
+    assert_eq!(stub.packet("z0,4,4").unwrap(), "OK");
+    assert_eq!(stub.packet("Z2,100,1").unwrap(), "OK");
+    stub.target().run_for(10);
+    assert_eq!(stub.packet("bc").unwrap(), "T05thread:1;watch:100;");
+    assert_eq!(stub.target().position(), 7);
+
+    // a plain cpu has no history
+    let mut stub = GDBStub::new(CPU::new(64));
+    assert!(!stub.packet("qSupported").unwrap().contains("Reverse"));
+    assert_eq!(stub.packet("bc").unwrap(), "E01");
+}
+
+#[test]
+fn recorded_virtio_inputs() {
+    let inputs = InputLog::new();
+    let (backend, mut peer) = LoopbackBackend::pair();
+    let net = VirtioNet::new(Box::new(ReplayPacketBackend::new(Box::new(backend), inputs.clone())), [2, 0, 0, 0, 0, 1]);
+    let mut cpu = CPU::new(0x1000);
+    cpu.ram().write_word(0, jal(0, 0));
+    cpu.bus().map(virtio::BASE, virtio::SIZE, AccessSizes::ALL, Box::new(VirtioMMIO::new(VirtioRNG::recorded(inputs.clone())))).unwrap();
+    cpu.bus().map(virtio::BASE + virtio::SIZE, virtio::SIZE, AccessSizes::ALL, Box::new(VirtioMMIO::new(net))).unwrap();
+
+    let mut rng = Driver::new(cpu.bus(), virtio::BASE, 0, 0x1000);
+    let mut receive = Driver::new(cpu.bus(), virtio::BASE + virtio::SIZE, RECEIVE as u32, 0x2000);
+    rng.submit(cpu.bus(), &[(0x3000, 64, true)]);
+    receive.submit(cpu.bus(), &[(0x3400, 1526, true)]);
+    peer.send(&[0xAB; 60]);
+
+    let mut recorder = Recorder::new(cpu, inputs);
+    recorder.run_for(4);
+    let entropy = read_bytes(recorder.cpu().bus(), 0x3000, 64);
+    let frame = read_bytes(recorder.cpu().bus(), 0x3400 + HEADER_SIZE as u32, 60);
+    assert!(entropy.iter().any(|&byte| byte != 0));
+    assert_eq!(frame, [0xAB; 60]);
+
+    // the replay takes the logged entropy and frame instead of new host input
+    peer.send(&[0xCD; 60]);
+    recorder.seek(0);
+    assert_eq!(read_bytes(recorder.cpu().bus(), 0x3000, 64), [0; 64]);
+    recorder.seek(4);
+    assert_eq!(read_bytes(recorder.cpu().bus(), 0x3000, 64), entropy);
+    assert_eq!(read_bytes(recorder.cpu().bus(), 0x3400 + HEADER_SIZE as u32, 60), frame);
+}
+
+// two harts counting in x5 in turns
+fn system_recorder() -> Recorder<System> {
+    let mut bus = SystemBus::new(0, RAM::new(256));
+    bus.ram().write_word(0, mathi(0b000, 5, 5, 1));
+    bus.ram().write_word(4, jal(0, -4i32 as u32 & 0x1FFFFF));
+
+    Recorder::new(System::new(bus, 2, Schedule::RoundRobin { quantum: 1 }), InputLog::new())
+}
+
+#[test]
+fn recorded_system() {
+    let mut recorder = system_recorder();
+    recorder.run_for(20);
+    assert_eq!(recorder.machine().hart(0).registers().read(5), 5);
+
+    // any hart about to execute the instruction stops reverse execution
+    recorder.machine().hart(1).debugger().add_breakpoint(4);
+    assert_eq!(recorder.reverse_continue(), StopReason::Breakpoint(4));
+    assert_eq!(recorder.position(), 19);
+    assert_eq!(*recorder.machine().hart(1).pc(), 4);
+    assert_eq!(recorder.last_register_write(5), Some(Change { position: 17, pc: 0, value: 5 }));
+
+    recorder.seek(20);
+    assert_eq!(recorder.machine().hart(1).registers().read(5), 5);
+}
+
+#[test]
+fn gdb_reverse_on_systems() {
+    let mut stub = GDBStub::new(system_recorder());
+    stub.target().run_for(20);
+
+    assert_eq!(stub.packet("Z0,4,4").unwrap(), "OK");
+    assert_eq!(stub.packet("bc").unwrap(), "T05thread:2;swbreak:;");
+    assert_eq!(stub.target().position(), 19);
+    assert_eq!(stub.packet("bc").unwrap(), "T05thread:1;swbreak:;");
+    assert_eq!(stub.target().position(), 18);
+
+    // breakpoints of the stub are not left behind in the harts
+    assert_eq!(stub.packet("z0,4,4").unwrap(), "OK");
+    assert_eq!(stub.packet("bc").unwrap(), "T05thread:1;replaylog:begin;");
+    assert_eq!(stub.target().run_for(5), StopReason::InstructionLimit);
+}
+
+#[test]
+fn recorded_virt() {
+    let inputs = InputLog::new();
+    let kernel: Vec<u8> = [load(LoadType::LW, 5, 6, 0), jal(0, -4i32 as u32 & 0x1FFFFF)].iter().flat_map(|instruction| instruction.to_le_bytes()).collect();
+    let console = ReplayBackend::new(Box::new(BufferBackend::new()), inputs.clone());
+    let config = VirtConfig { inputs: Some(inputs.clone()), ..VirtConfig::new(0x0080_0000, kernel, Box::new(console)) };
+    let mut virt = Virt::new(config).unwrap();
+    virt.cpu().registers().write(6, CLINT::BASE + CLINT::MTIME);
+    let mut recorder = Recorder::new(virt, inputs);
+
+    let mut times = Vec::new();
+    for _ in 0..20 {
+        recorder.run_for(2);
+        times.push(recorder.machine().cpu().registers().read(5));
+    }
+
+    assert!(times[19] > times[0]);
+
+    // the clock of the board is replayed from the log
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    recorder.seek(0);
+    for &time in times.iter() {
+        recorder.run_for(2);
+        assert_eq!(recorder.machine().cpu().registers().read(5), time);
+    }
+}
@@ -0,0 +1,234 @@
+use rust_risc_v::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::semihosting::*;
+
+const BLOCK: u32  = 0x200;
+const BUFFER: u32 = 0x300;
+
+fn cpu() -> CPU {
+    cpu_with_memory(256)
+}
+
+fn cpu_with_memory(words: u32) -> CPU {
+    let mut cpu = CPU::new(words);
+    for (index, instruction) in [ENTRY_NOP, EBREAK, EXIT_NOP].iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+    cpu
+}
+
+fn call(cpu: &mut CPU, semihosting: &mut Semihosting, operation: u32, block: &[u32]) -> u32 {
+    for (index, value) in block.iter().enumerate() {
+        cpu.ram().write_word(BLOCK + index as u32 * 4, *value);
+    }
+    cpu.registers().write(10, operation);
+    cpu.registers().write(11, BLOCK);
+    *cpu.pc() = 0;
+    semihosting.step(cpu);
+
+    assert_eq!(*cpu.pc(), 12);
+    cpu.registers().read(10)
+}
+
+fn write_string(cpu: &mut CPU, address: u32, data: &[u8]) -> u32 {
+    for (offset, &byte) in data.iter().enumerate() {
+        cpu.ram().write_byte(address + offset as u32, byte);
+    }
+    data.len() as u32
+}
+
+#[test]
+fn call_sequence() {
+    let mut cpu = cpu();
+    assert_eq!(Semihosting::call_at(&mut cpu), Some(12));
+    *cpu.pc() = 4;
+    assert_eq!(Semihosting::call_at(&mut cpu), Some(8));
+    *cpu.pc() = 8;
+    assert_eq!(Semihosting::call_at(&mut cpu), None);
+
+    // an ebreak on its own is still a breakpoint
+    cpu.ram().write_word(0x10, EBREAK);
+    cpu.csrs().write(MTVEC, 0x100);
+    *cpu.pc() = 0x10;
+    let mut semihosting = Semihosting::new();
+    semihosting.step(&mut cpu);
+    assert_eq!(*cpu.pc(), 0x100);
+    assert_eq!(cpu.csrs().read(MCAUSE), 3);
+}
+
+#[test]
+fn console() {
+    let mut cpu = cpu();
+    let mut semihosting = Semihosting::new();
+    semihosting.capture_output();
+    semihosting.set_stdin(b"xy");
+
+    write_string(&mut cpu, BLOCK, b"hi\0");
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE0, &[]), 0);
+    assert_eq!(semihosting.take_stdout(), b"hi");
+
+    let name = write_string(&mut cpu, BUFFER, CONSOLE.as_bytes());
+    let stdin = call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 0, name]);
+    let stdout = call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 4, name]);
+    let stderr = call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 8, name]);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_ISTTY, &[stdout]), 1);
+
+    let length = write_string(&mut cpu, BUFFER + 0x20, b"out");
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE, &[stdout, BUFFER + 0x20, length]), 0);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE, &[stderr, BUFFER + 0x20, length]), 0);
+    assert_eq!(semihosting.take_stdout(), b"out");
+    assert_eq!(semihosting.take_stderr(), b"out");
+
+    // the result is the number of bytes not read
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_READ, &[stdin, BUFFER + 0x40, 1]), 0);
+    assert_eq!(cpu.ram().read_byte(BUFFER + 0x40), b'x');
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_READC, &[]), b'y' as u32);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_READ, &[stdin, BUFFER + 0x40, 4]), 4);
+}
+
+#[test]
+fn long_strings_and_buffers() {
+    let mut cpu = cpu_with_memory(0x0005_0000 / 4);
+    let mut semihosting = Semihosting::new();
+    semihosting.capture_output();
+
+    // longer than a staging chunk
+    let text = vec![b'a'; 0x0002_0001];
+    write_string(&mut cpu, BLOCK, &text);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE0, &[]), 0);
+    assert_eq!(semihosting.take_stdout(), text);
+
+    let name = write_string(&mut cpu, 0x0004_8000, CONSOLE.as_bytes());
+    let stdout = call(&mut cpu, &mut semihosting, SYS_OPEN, &[0x0004_8000, 4, name]);
+    let length = write_string(&mut cpu, 0x0002_4000, &text);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE, &[stdout, 0x0002_4000, length]), 0);
+    assert_eq!(semihosting.take_stdout(), text);
+
+    // buffers and strings running past memory and overlong names fail without being staged
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE, &[stdout, 0x0002_4000, u32::MAX]), u32::MAX);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 22);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_OPEN, &[0x0002_4000, 4, 4097]), u32::MAX);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 22);
+    write_string(&mut cpu, 0x0005_0000 - 2, b"ab");
+    cpu.registers().write(10, SYS_WRITE0);
+    cpu.registers().write(11, 0x0005_0000 - 2);
+    *cpu.pc() = 0;
+    semihosting.step(&mut cpu);
+    assert_eq!(cpu.registers().read(10), u32::MAX);
+}
+
+#[test]
+fn sandboxed_files() {
+    let root = std::env::temp_dir().join(format!("rust-risc-v-semihosting-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let mut cpu = cpu();
+    let mut semihosting = Semihosting::new();
+
+    let name = write_string(&mut cpu, BUFFER, b"file.txt");
+    let data = write_string(&mut cpu, BUFFER + 0x20, b"semihosting");
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 4, name]), u32::MAX);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 13);
+
+    semihosting.set_root(&root);
+    let handle = call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 5, name]);
+    assert_ne!(handle, u32::MAX);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_ISTTY, &[handle]), 0);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE, &[handle, BUFFER + 0x20, data]), 0);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_CLOSE, &[handle]), 0);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_CLOSE, &[handle]), u32::MAX);
+    assert_eq!(std::fs::read(root.join("file.txt")).unwrap(), b"semihosting");
+
+    let handle = call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 1, name]);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_FLEN, &[handle]), 11);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_SEEK, &[handle, 4]), 0);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_READ, &[handle, BUFFER + 0x40, 16]), 9);
+    assert_eq!(cpu.ram().inspect(BUFFER + 0x40, 7), b"hosting");
+
+    // a huge length is not staged in host memory at once
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_SEEK, &[handle, 4]), 0);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_READ, &[handle, BUFFER + 0x40, 0xFFFF_0000]), 0xFFFF_0000 - 7);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_CLOSE, &[handle]), 0);
+
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_REMOVE, &[BUFFER, name]), 0);
+    assert!(!root.join("file.txt").exists());
+
+    let name = write_string(&mut cpu, BUFFER, b"../escape");
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 4, name]), u32::MAX);
+    let name = write_string(&mut cpu, BUFFER, b"missing");
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 0, name]), u32::MAX);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 2);
+
+    // symlinks may not point out of the root
+    #[cfg(unix)]
+    {
+        let outside = std::env::temp_dir().join(format!("rust-risc-v-semihosting-outside-{}", std::process::id()));
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("directory")).unwrap();
+        std::os::unix::fs::symlink(outside.join("created"), root.join("dangling")).unwrap();
+
+        let name = write_string(&mut cpu, BUFFER, b"directory/file");
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 4, name]), u32::MAX);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 13);
+        let name = write_string(&mut cpu, BUFFER, b"dangling");
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 4, name]), u32::MAX);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 13);
+        assert!(std::fs::read_dir(&outside).unwrap().next().is_none());
+
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn environment() {
+    let mut cpu = cpu();
+    let mut semihosting = Semihosting::new();
+    semihosting.set_command_line(&["program", "-v"]);
+
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_GET_CMDLINE, &[BUFFER, 64]), 0);
+    assert_eq!(cpu.ram().inspect(BUFFER, 11), b"program -v\0");
+    assert_eq!(cpu.ram().read_word(BLOCK + 4), 10);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_GET_CMDLINE, &[BUFFER, 4]), u32::MAX);
+
+    assert!(call(&mut cpu, &mut semihosting, SYS_CLOCK, &[]) < 100);
+    assert!(call(&mut cpu, &mut semihosting, SYS_TIME, &[]) > 1_600_000_000);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_TICKFREQ, &[]), TICK_FREQUENCY);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_ISERROR, &[u32::MAX]), 1);
+    assert_eq!(call(&mut cpu, &mut semihosting, 0xFF, &[]), u32::MAX);
+
+    let name = write_string(&mut cpu, BUFFER, FEATURES.as_bytes());
+    let handle = call(&mut cpu, &mut semihosting, SYS_OPEN, &[BUFFER, 0, name]);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_FLEN, &[handle]), 5);
+    assert_eq!(call(&mut cpu, &mut semihosting, SYS_READ, &[handle, BUFFER, 5]), 0);
+    assert_eq!(cpu.ram().inspect(BUFFER, 5), [b'S', b'H', b'F', b'B', FEATURE_EXIT_EXTENDED | FEATURE_STDOUT_STDERR]);
+}
+
+#[test]
+fn exit_codes() {
+    let mut cpu = cpu();
+    let mut semihosting = Semihosting::new();
+    call(&mut cpu, &mut semihosting, SYS_EXIT_EXTENDED, &[ADP_STOPPED_APPLICATION_EXIT, 42]);
+    assert_eq!(semihosting.exit(), Some(42));
+
+    let mut semihosting = Semihosting::new();
+    call(&mut cpu, &mut semihosting, SYS_EXIT_EXTENDED, &[0x20023, 42]);
+    assert_eq!(semihosting.exit(), Some(1));
+
+    // a program loading the reason from a table, 32 bit SYS_EXIT passes it in a1
+    let mut cpu = CPU::new(256);
+    let code = [load(LoadType::LW, 10, 0, 0x100), load(LoadType::LW, 11, 0, 0x104), ENTRY_NOP, EBREAK, EXIT_NOP, jal(0, 0)];
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+    cpu.ram().write_word(0x100, SYS_EXIT);
+    cpu.ram().write_word(0x104, ADP_STOPPED_APPLICATION_EXIT);
+
+    let mut semihosting = Semihosting::new();
+    assert_eq!(semihosting.run(&mut cpu, 100), Some(0));
+    assert_eq!(*cpu.pc(), 20);
+    assert_eq!(semihosting.step(&mut cpu), Some(0));
+}
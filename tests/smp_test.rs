@@ -0,0 +1,223 @@
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::branch::*;
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::sbi::*;
+use rust_risc_v::smp::*;
+use rust_risc_v::trap::Privilege;
+
+fn system(harts: u32, schedule: Schedule) -> System {
+    let mut bus = SystemBus::new(0, RAM::new(256));
+    for address in (0..0x100).step_by(4) {
+        bus.ram().write_word(address, WFI);
+    }
+
+    System::new(bus, harts, schedule)
+}
+
+fn trace(system: &mut System, steps: usize) -> Vec<u32> {
+    (0..steps).map(|_| system.step().unwrap()).collect()
+}
+
+#[test]
+fn hart_ids() {
+    let mut system = system(4, Schedule::RoundRobin { quantum: 1 });
+
+    assert_eq!(system.harts(), 4);
+    for hart in 0..4 {
+        assert_eq!(system.hart(hart).csrs().read(MHARTID), hart);
+    }
+}
+
+#[test]
+fn round_robin() {
+    let mut system = system(3, Schedule::RoundRobin { quantum: 2 });
+
+    assert_eq!(trace(&mut system, 8), [0, 0, 1, 1, 2, 2, 0, 0]);
+    assert_eq!(*system.hart(0).pc(), 16);
+    assert_eq!(*system.hart(1).pc(), 8);
+    assert_eq!(*system.hart(2).pc(), 8);
+
+    system.set_running(1, false);
+    assert_eq!(trace(&mut system, 6), [2, 2, 0, 0, 2, 2]);
+
+    system.set_running(0, false);
+    system.set_running(2, false);
+    assert_eq!(system.step(), None);
+    assert_eq!(system.run(10), 0);
+}
+
+#[test]
+fn seeded_random() {
+    let schedule = Schedule::Random { seed: 42, max_quantum: 4 };
+    let first = trace(&mut system(4, schedule), 200);
+    let second = trace(&mut system(4, schedule), 200);
+    assert_eq!(first, second);
+
+    let other = trace(&mut system(4, Schedule::Random { seed: 43, max_quantum: 4 }), 200);
+    assert_ne!(first, other);
+
+    for hart in 0..4 {
+        assert!(first.contains(&hart));
+    }
+
+    let mut stopped = system(4, schedule);
+    stopped.set_running(2, false);
+    assert!(!trace(&mut stopped, 200).contains(&2));
+}
+
+#[test]
+fn shared_memory() {
+    let mut system = system(2, Schedule::RoundRobin { quantum: 1 });
+    system.bus().ram().write_word(0, csr(CSRType::CSRRS, 5, 0, MHARTID));
+    system.bus().ram().write_word(4, store(StoreType::SW, 0, 5, 0x200));
+
+    // both harts read their id before either stores it
+    system.run(3);
+    assert_eq!(system.bus().ram().read_word(0x200), 0);
+    system.run(1);
+    assert_eq!(system.bus().ram().read_word(0x200), 1);
+
+    assert_eq!(system.hart(0).registers().read(5), 0);
+    assert_eq!(system.hart(1).registers().read(5), 1);
+}
+
+#[test]
+fn per_hart_interrupts() {
+    let mut system = system(2, Schedule::RoundRobin { quantum: 1 });
+    system.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(2, TimeBase::Instructions(1)))).unwrap();
+    system.bus().write(CLINT::BASE + CLINT::MSIP + 4, AccessSize::Word, 1).unwrap();
+
+    system.run(2);
+    assert_eq!(system.hart(0).csrs().read(MIP) & MIP_MSIP, 0);
+    assert_eq!(system.hart(1).csrs().read(MIP) & MIP_MSIP, MIP_MSIP);
+}
+
+#[test]
+fn hart_start_through_sbi() {
+    let mut system = system(2, Schedule::RoundRobin { quantum: 1 });
+    system.bus().ram().write_word(0, ECALL);
+    system.bus().ram().write_word(0x40, ECALL);
+    system.set_sbi(SBI::new(2));
+    assert!(!system.is_running(1));
+
+    for (register, value) in [(10, 1), (11, 0x40), (12, 0x1234), (16, 0), (17, EXTENSION_HSM)] {
+        system.hart(0).registers().write(register, value);
+    }
+    assert_eq!(system.step(), Some(0));
+    assert_eq!(system.hart(0).registers().read(10), SUCCESS as u32);
+    assert_eq!(system.sbi().unwrap().hart_state(1), Some(HartState::Started));
+    assert!(system.is_running(1));
+
+    let hart = system.hart(1);
+    assert_eq!(*hart.pc(), 0x40);
+    assert_eq!(hart.privilege(), Privilege::Supervisor);
+    assert_eq!(hart.registers().read(10), 1);
+    assert_eq!(hart.registers().read(11), 0x1234);
+
+    // the started hart stops itself again
+    system.hart(1).registers().write(16, 1);
+    system.hart(1).registers().write(17, EXTENSION_HSM);
+    assert_eq!(system.step(), Some(1));
+    assert_eq!(system.sbi().unwrap().hart_state(1), Some(HartState::Stopped));
+    assert!(!system.is_running(1));
+}
+
+#[test]
+fn devices_tick_once_per_round() {
+    let mut system = system(2, Schedule::RoundRobin { quantum: 3 });
+    system.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(2, TimeBase::Instructions(1)))).unwrap();
+
+    system.run(10);
+    assert_eq!(system.bus().device::<CLINT>(CLINT::BASE).unwrap().mtime(), 5);
+
+    // a stopped hart does not slow down time
+    system.set_running(1, false);
+    system.run(4);
+    assert_eq!(system.bus().device::<CLINT>(CLINT::BASE).unwrap().mtime(), 9);
+}
+
+#[test]
+fn harts_read_the_shared_time() {
+    let mut system = system(2, Schedule::RoundRobin { quantum: 1 });
+    system.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(2, TimeBase::Instructions(1)))).unwrap();
+    system.bus().ram().write_word(0, csr(CSRType::CSRRS, 5, 0, TIME));
+
+    assert_eq!(trace(&mut system, 2), [0, 1]);
+    for hart in 0..2 {
+        assert_eq!(*system.hart(hart).pc(), 4);
+        assert_eq!(system.hart(hart).registers().read(5), 1);
+    }
+}
+
+#[test]
+fn stores_break_reservations() {
+    let mut system = system(2, Schedule::RoundRobin { quantum: 1 });
+    system.bus().ram().write_word(0, amo(0b00010, 5, 6, 0));
+    system.bus().ram().write_word(4, amo(0b00011, 7, 6, 8));
+    for hart in 0..2 {
+        system.hart(hart).registers().write(6, 0x200);
+        system.hart(hart).registers().write(8, hart + 1);
+    }
+
+    // both harts reserve the word, the store of hart 0 breaks the reservation of hart 1
+    system.run(4);
+    assert_eq!(system.hart(0).registers().read(7), 0);
+    assert_eq!(system.hart(1).registers().read(7), 1);
+    assert_eq!(system.bus().ram().read_word(0x200), 1);
+}
+
+fn counter() -> System {
+    let mut system = system(3, Schedule::Random { seed: 7, max_quantum: 3 });
+    let program = [
+        amo(0b00010, 5, 6, 0),
+        mathi(0b000, 5, 5, 1),
+        amo(0b00011, 7, 6, 5),
+        branch(BranchType::BNE, 7, 0, (-12i16) as u16),
+        mathi(0b000, 8, 8, 1),
+        branch(BranchType::BLT, 8, 9, (-20i16) as u16),
+        jal(0, 0),
+    ];
+    for (index, &instruction) in program.iter().enumerate() {
+        system.bus().ram().write_word(index as u32 * 4, instruction);
+    }
+    for hart in 0..3 {
+        system.hart(hart).registers().write(6, 0x200);
+        system.hart(hart).registers().write(9, 100);
+    }
+    system
+}
+
+#[test]
+fn lock_free_counter() {
+    let mut system = counter();
+    system.run(10_000);
+    assert_eq!(system.bus().ram().read_word(0x200), 300);
+    for hart in 0..3 {
+        assert_eq!(system.hart(hart).registers().read(8), 100);
+    }
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let mut saved = counter();
+    saved.run(500);
+    let data = saved.save();
+    let expected = trace(&mut saved, 1000);
+
+    // harts, reservations and the scheduler continue where they were saved
+    let mut other = counter();
+    other.restore(&data).unwrap();
+    assert_eq!(trace(&mut other, 1000), expected);
+    assert_eq!(other.save(), saved.save());
+
+    let mut smaller = system(2, Schedule::Random { seed: 7, max_quantum: 3 });
+    assert!(smaller.restore(&data).is_err());
+    let mut firmware = counter();
+    firmware.set_sbi(SBI::new(3));
+    assert!(firmware.restore(&data).is_err());
+}
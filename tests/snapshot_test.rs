@@ -0,0 +1,142 @@
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::devices::clint::*;
+use rust_risc_v::devices::plic::*;
+use rust_risc_v::devices::uart::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::snapshot::*;
+
+// links every other instruction into x1 and stores it to 0x300
+fn counter() -> CPU {
+    let mut cpu = CPU::new(256);
+    for address in (0..0x200).step_by(8) {
+        cpu.ram().write_word(address, jal(1, 4));
+        cpu.ram().write_word(address + 4, store(StoreType::SW, 0, 1, 0x300));
+    }
+    cpu
+}
+
+#[test]
+fn cpu_round_trip() {
+    let mut cpu = counter();
+    for _ in 0..10 {
+        cpu.tick();
+    }
+    cpu.csrs().write(MSCRATCH, 0x1234);
+    let data = save(&mut cpu);
+
+    for _ in 0..20 {
+        cpu.tick();
+    }
+    cpu.csrs().write(MSCRATCH, 0);
+    assert_eq!(cpu.registers().read(1), 116);
+
+    restore(&mut cpu, &data).unwrap();
+    assert_eq!(cpu.registers().read(1), 36);
+    assert_eq!(*cpu.pc(), 40);
+    assert_eq!(cpu.ram().read_word(0x300), 36);
+    assert_eq!(cpu.csrs().read(MSCRATCH), 0x1234);
+
+    // a fresh machine continues exactly like the restored one
+    let mut other = counter();
+    restore(&mut other, &data).unwrap();
+    for _ in 0..20 {
+        cpu.tick();
+        other.tick();
+    }
+    assert_eq!(cpu.registers().read(1), other.registers().read(1));
+    assert_eq!(save(&mut cpu), save(&mut other));
+}
+
+#[test]
+fn invalid_snapshots() {
+    let mut cpu = counter();
+    let data = save(&mut cpu);
+
+    assert!(matches!(restore(&mut cpu, b"RV32"), Err(SnapshotError::Magic)));
+    assert!(matches!(restore(&mut cpu, b"ELF\0\0\0\0\0\x01\0\0\0"), Err(SnapshotError::Magic)));
+
+    let mut newer = data.clone();
+    newer[8] = VERSION as u8 + 1;
+    assert!(matches!(restore(&mut cpu, &newer), Err(SnapshotError::Version(version)) if version == VERSION + 1));
+
+    assert!(matches!(restore(&mut cpu, &data[..data.len() - 1]), Err(SnapshotError::Truncated)));
+
+    let mut longer = data.clone();
+    longer.push(0);
+    assert!(matches!(restore(&mut cpu, &longer), Err(SnapshotError::Mismatch(_))));
+
+    let mut larger = CPU::new(512);
+    assert!(matches!(restore(&mut larger, &data), Err(SnapshotError::Mismatch(_))));
+
+    let mut mapped = counter();
+    mapped.bus().map(UART::BASE, UART::SIZE, AccessSizes::BYTE, Box::new(UART::new(Box::new(BufferBackend::new())))).unwrap();
+    assert!(matches!(restore(&mut mapped, &data), Err(SnapshotError::Mismatch(_))));
+}
+
+fn machine(backend: &BufferBackend) -> CPU {
+    let mut cpu = CPU::new(256);
+    cpu.bus().map(CLINT::BASE, CLINT::SIZE, AccessSizes::WORD, Box::new(CLINT::new(1, TimeBase::Instructions(1)))).unwrap();
+    cpu.bus().map(PLIC::BASE, PLIC::SIZE, AccessSizes::WORD, Box::new(PLIC::new(32, 1))).unwrap();
+    cpu.bus().map(UART::BASE, UART::SIZE, AccessSizes::BYTE, Box::new(UART::new(Box::new(backend.clone())))).unwrap();
+    cpu
+}
+
+#[test]
+fn device_round_trip() {
+    let backend = BufferBackend::new();
+    let mut cpu = machine(&backend);
+    cpu.bus().write(UART::BASE + UART::FCR, AccessSize::Byte, UART::FCR_ENABLE as u32).unwrap();
+    backend.push_input(b"ab");
+    for _ in 0..5 {
+        cpu.tick();
+    }
+
+    let bus = cpu.bus();
+    bus.write(CLINT::BASE + CLINT::MTIMECMP, AccessSize::Word, 100).unwrap();
+    bus.write(CLINT::BASE + CLINT::MSIP, AccessSize::Word, 1).unwrap();
+    bus.write(PLIC::BASE + PLIC::PRIORITY + 4 * 3, AccessSize::Word, 5).unwrap();
+    bus.write(PLIC::BASE + PLIC::ENABLE, AccessSize::Word, 1 << 3).unwrap();
+    bus.write(PLIC::BASE + PLIC::THRESHOLD, AccessSize::Word, 2).unwrap();
+    bus.write(UART::BASE + UART::SCR, AccessSize::Byte, 0x5A).unwrap();
+    let mtime = bus.read(CLINT::BASE + CLINT::MTIME, AccessSize::Word).unwrap();
+    let data = save(&mut cpu);
+
+    // a new machine with the same devices and a fresh backend
+    let other_backend = BufferBackend::new();
+    let mut other = machine(&other_backend);
+    restore(&mut other, &data).unwrap();
+
+    let bus = other.bus();
+    assert_eq!(bus.read(CLINT::BASE + CLINT::MTIME, AccessSize::Word).unwrap(), mtime);
+    assert_eq!(bus.read(CLINT::BASE + CLINT::MTIMECMP, AccessSize::Word).unwrap(), 100);
+    assert_eq!(bus.read(CLINT::BASE + CLINT::MSIP, AccessSize::Word).unwrap(), 1);
+    assert_eq!(bus.read(PLIC::BASE + PLIC::PRIORITY + 4 * 3, AccessSize::Word).unwrap(), 5);
+    assert_eq!(bus.read(PLIC::BASE + PLIC::ENABLE, AccessSize::Word).unwrap(), 1 << 3);
+    assert_eq!(bus.read(PLIC::BASE + PLIC::THRESHOLD, AccessSize::Word).unwrap(), 2);
+    assert_eq!(bus.read(UART::BASE + UART::SCR, AccessSize::Byte).unwrap(), 0x5A);
+
+    // received bytes are part of the uart state, not of the backend
+    assert_eq!(bus.read(UART::BASE + UART::RBR, AccessSize::Byte).unwrap(), b'a' as u32);
+    assert_eq!(bus.read(UART::BASE + UART::RBR, AccessSize::Byte).unwrap(), b'b' as u32);
+    assert_eq!(bus.read(UART::BASE + UART::LSR, AccessSize::Byte).unwrap() as u8 & UART::LSR_DR, 0);
+}
+
+#[test]
+fn file_round_trip() {
+    let path = std::env::temp_dir().join(format!("rv32-snapshot-{}.bin", std::process::id()));
+    let mut cpu = counter();
+    for _ in 0..7 {
+        cpu.tick();
+    }
+    save_file(&mut cpu, &path).unwrap();
+
+    let mut other = counter();
+    restore_file(&mut other, &path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(save(&mut cpu), save(&mut other));
+
+    assert!(matches!(restore_file(&mut other, &path), Err(SnapshotError::Io(_))));
+}
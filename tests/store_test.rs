@@ -0,0 +1,56 @@
+use rust_risc_v::*;
+use rust_risc_v::instructions::store::*;
+
+#[test]
+fn store_byte() {
+    let mut cpu = CPU::new(256);
+
+    cpu.ram().write_word(0, store(StoreType::SB, 1, 2, 0));
+    cpu.ram().write_word(4, store(StoreType::SB, 1, 3, 1));
+    cpu.registers().write(1, 64);
+    cpu.registers().write(2, 0x12345678);
+    cpu.registers().write(3, 0xFFFFFF81);
+
+    cpu.tick();
+    cpu.tick();
+
+    assert_eq!(cpu.ram().read_byte(64), 0x78);
+    assert_eq!(cpu.ram().read_byte(65), 0x81);
+    assert_eq!(cpu.ram().read_byte(66), 0);
+    assert_eq!(*cpu.pc(), 8);
+}
+
+#[test]
+fn store_half() {
+    let mut cpu = CPU::new(256);
+
+    cpu.ram().write_word(0, store(StoreType::SH, 1, 2, 0));
+    cpu.ram().write_word(4, store(StoreType::SH, 1, 2, 0b111111111110));
+    cpu.registers().write(1, 64);
+    cpu.registers().write(2, 0x12345678);
+
+    cpu.tick();
+    cpu.tick();
+
+    assert_eq!(cpu.ram().read_half(64), 0x5678);
+    assert_eq!(cpu.ram().read_half(62), 0x5678);
+    assert_eq!(cpu.ram().read_word(62), 0x56785678);
+}
+
+#[test]
+fn store_word() {
+    let mut cpu = CPU::new(256);
+
+    cpu.ram().write_word(0, store(StoreType::SW, 1, 2, 4));
+    cpu.ram().write_word(4, store(StoreType::SW, 1, 0, 8));
+    cpu.ram().write_word(72, 0xFFFFFFFF);
+    cpu.registers().write(1, 64);
+    cpu.registers().write(2, 0x12345678);
+
+    cpu.tick();
+    cpu.tick();
+
+    assert_eq!(cpu.ram().read_word(68), 0x12345678);
+    assert_eq!(cpu.ram().read_byte(68), 0x78);
+    assert_eq!(cpu.ram().read_word(72), 0);
+}
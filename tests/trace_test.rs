@@ -0,0 +1,77 @@
+use rust_risc_v::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::instructions::*;
+use rust_risc_v::instructions::csr::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+use rust_risc_v::trace::*;
+
+fn cpu(code: &[u32]) -> (CPU, LogBuffer) {
+    let mut cpu = CPU::new(256);
+    for (index, instruction) in code.iter().enumerate() {
+        cpu.ram().write_word(index as u32 * 4, *instruction);
+    }
+
+    let buffer = LogBuffer::new();
+    cpu.set_commit_log(Some(CommitLog::new(Box::new(buffer.clone()))));
+    (cpu, buffer)
+}
+
+#[test]
+fn register_writes() {
+    let (mut cpu, buffer) = cpu(&[lui(5, 0x1234_5000), lui(0, 0x1000), jal(1, 8)]);
+    cpu.run_for(3);
+
+    assert_eq!(buffer.take(), [
+        "core   0: 3 0x00000000 (0x123452b7) x5  0x12345000\n",
+        "core   0: 3 0x00000004 (0x00001037)\n",
+        "core   0: 3 0x00000008 (0x008000ef) x1  0x0000000c\n",
+    ].concat());
+}
+
+#[test]
+fn memory_accesses() {
+    let (mut cpu, buffer) = cpu(&[
+        load(LoadType::LW, 10, 0, 0x100),
+        store(StoreType::SB, 0, 10, 0x104),
+        store(StoreType::SH, 0, 10, 0x108),
+    ]);
+    cpu.ram().write_word(0x100, 0xDEAD_BEEF);
+    cpu.run_for(3);
+
+    let log = buffer.take();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines[0], "core   0: 3 0x00000000 (0x10002503) x10 0xdeadbeef mem 0x00000100");
+    assert_eq!(lines[1], "core   0: 3 0x00000004 (0x10a00223) mem 0x00000104 0xef");
+    assert_eq!(lines[2], "core   0: 3 0x00000008 (0x10a01423) mem 0x00000108 0xbeef");
+}
+
+#[test]
+fn csr_writes() {
+    let (mut cpu, buffer) = cpu(&[csr(CSRType::CSRRW, 6, 5, MSCRATCH), csr(CSRType::CSRRS, 7, 0, MISA)]);
+    cpu.registers().write(5, 0x55);
+    cpu.csrs().write(MHARTID, 2);
+    cpu.run_for(2);
+
+    assert_eq!(buffer.take(), [
+        "core   2: 3 0x00000000 (0x34029373) x6  0x00000000 c832_mscratch 0x00000055\n",
+        "core   2: 3 0x00000004 (0x301023f3) x7  0x40141101\n",
+    ].concat());
+}
+
+#[test]
+fn traps_are_not_logged() {
+    let (mut cpu, buffer) = cpu(&[ECALL, load(LoadType::LW, 5, 0, 0x7FF)]);
+    cpu.csrs().write(MTVEC, 4);
+    cpu.run_for(2);
+
+    // the misaligned load at the handler faults too
+    assert_eq!(buffer.contents(), "");
+    assert_eq!(*cpu.pc(), 4);
+
+    let log = cpu.set_commit_log(None);
+    assert!(log.is_some_and(|log| log.error().is_none()));
+    cpu.ram().write_word(4, lui(5, 0));
+    cpu.run_for(1);
+    assert_eq!(buffer.contents(), "");
+}
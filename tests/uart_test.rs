@@ -0,0 +1,186 @@
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::csr::*;
+use rust_risc_v::devices::plic::*;
+use rust_risc_v::devices::uart::*;
+use rust_risc_v::instructions::load::*;
+use rust_risc_v::instructions::store::*;
+
+fn setup(backend: &BufferBackend) -> CPU {
+    let mut cpu = CPU::new(256);
+    cpu.bus().map(UART::BASE, UART::SIZE, AccessSizes::BYTE, Box::new(UART::new(Box::new(backend.clone())))).unwrap();
+    cpu.registers().write(1, UART::BASE);
+    cpu
+}
+
+#[test]
+fn transmit() {
+    let backend = BufferBackend::new();
+    let mut cpu = setup(&backend);
+    // setup ram with instructions
+    cpu.ram().write_word(0, store(StoreType::SB, 1, 2, UART::THR as u16));
+    cpu.ram().write_word(4, store(StoreType::SB, 1, 3, UART::THR as u16));
+    cpu.ram().write_word(8, load(LoadType::LBU, 4, 1, UART::LSR as u16));
+    cpu.registers().write(2, b'h' as u32);
+    cpu.registers().write(3, b'i' as u32);
+
+    cpu.tick();
+    cpu.tick();
+    cpu.tick();
+
+    assert_eq!(backend.output(), b"hi");
+    assert_eq!(cpu.registers().read(4) as u8, UART::LSR_THRE | UART::LSR_TEMT);
+}
+
+#[test]
+fn receive() {
+    let backend = BufferBackend::new();
+    let mut cpu = setup(&backend);
+    // setup ram with instructions
+    cpu.ram().write_word(0, load(LoadType::LBU, 2, 1, UART::LSR as u16));
+    cpu.ram().write_word(4, load(LoadType::LBU, 3, 1, UART::RBR as u16));
+    cpu.ram().write_word(8, load(LoadType::LBU, 4, 1, UART::LSR as u16));
+    backend.push_input(b"x");
+
+    cpu.tick();
+    cpu.tick();
+    cpu.tick();
+
+    assert_eq!(cpu.registers().read(2) as u8 & UART::LSR_DR, UART::LSR_DR);
+    assert_eq!(cpu.registers().read(3), b'x' as u32);
+    assert_eq!(cpu.registers().read(4) as u8 & UART::LSR_DR, 0);
+}
+
+#[test]
+fn fifo_and_overrun() {
+    let backend = BufferBackend::new();
+    let mut uart = UART::new(Box::new(backend.clone()));
+    backend.push_input(&[7; 20]);
+
+    // without the fifo only one byte is held
+    uart.tick(1);
+    uart.tick(1);
+    assert_eq!(uart.read_register(UART::LSR), UART::LSR_DR | UART::LSR_THRE | UART::LSR_TEMT);
+
+    uart.write_register(UART::FCR, UART::FCR_ENABLE | UART::FCR_CLEAR_RX | (0b10 << 6));
+    uart.write_register(UART::IER, UART::IER_RDA);
+    for _ in 0..7 {
+        uart.tick(1);
+    }
+    assert_eq!(uart.interrupt_identification(), UART::IIR_NONE);
+
+    uart.tick(1);
+    assert_eq!(uart.interrupt_identification(), UART::IIR_RDA);
+    assert_eq!(uart.read_register(UART::IIR), UART::IIR_RDA | 0b11000000);
+
+    for _ in 0..20 {
+        uart.tick(1);
+    }
+    let mut received = 0;
+    while uart.read_register(UART::LSR) & UART::LSR_DR != 0 {
+        assert_eq!(uart.read_register(UART::RBR), 7);
+        received += 1;
+    }
+    assert_eq!(received, UART::FIFO_SIZE);
+}
+
+#[test]
+fn receive_timeout() {
+    let backend = BufferBackend::new();
+    let mut uart = UART::new(Box::new(backend.clone()));
+    uart.write_register(UART::FCR, UART::FCR_ENABLE | (0b11 << 6));
+    uart.write_register(UART::IER, UART::IER_RDA);
+    backend.push_input(b"a");
+
+    uart.tick(1);
+    assert_eq!(uart.interrupt_identification(), UART::IIR_NONE);
+
+    for _ in 0..UART::TIMEOUT_TICKS {
+        uart.tick(1);
+    }
+    assert_eq!(uart.interrupt_identification(), UART::IIR_TIMEOUT);
+    assert_eq!(uart.read_register(UART::RBR), b'a');
+    assert_eq!(uart.interrupt_identification(), UART::IIR_NONE);
+}
+
+#[test]
+fn input_burst_polled_every_tick() {
+    let backend = BufferBackend::new();
+    let mut uart = UART::new(Box::new(backend.clone()));
+    uart.write_register(UART::FCR, UART::FCR_ENABLE);
+    assert_eq!(uart.deadline(), POLL_TICKS);
+
+    backend.push_input(b"abcd");
+    uart.tick(POLL_TICKS);
+    // the rest of the burst is taken without waiting for the next poll
+    for _ in 0..3 {
+        assert_eq!(uart.deadline(), 1);
+        uart.tick(1);
+    }
+    assert_eq!(uart.deadline(), 1);
+    uart.tick(1);
+    assert!(uart.deadline() > 1);
+
+    for &byte in b"abcd" {
+        assert_eq!(uart.read_register(UART::RBR), byte);
+    }
+    assert_eq!(uart.read_register(UART::LSR) & UART::LSR_DR, 0);
+}
+
+#[test]
+fn transmit_empty_interrupt() {
+    let backend = BufferBackend::new();
+    let mut uart = UART::new(Box::new(backend.clone()));
+
+    uart.write_register(UART::IER, UART::IER_THRE);
+    assert_eq!(uart.interrupt_identification(), UART::IIR_THRE);
+    assert_eq!(uart.read_register(UART::IIR), UART::IIR_THRE);
+    assert_eq!(uart.interrupt_identification(), UART::IIR_NONE);
+
+    uart.write_register(UART::THR, b'z');
+    assert_eq!(uart.interrupt_identification(), UART::IIR_THRE);
+    assert_eq!(backend.take_output(), b"z");
+}
+
+#[test]
+fn divisor_latch_and_loopback() {
+    let backend = BufferBackend::new();
+    let mut uart = UART::new(Box::new(backend.clone()));
+
+    uart.write_register(UART::LCR, UART::LCR_DLAB | 0b11);
+    uart.write_register(UART::THR, 0x34);
+    uart.write_register(UART::IER, 0x12);
+    assert_eq!(uart.read_register(UART::RBR), 0x34);
+    assert_eq!(uart.read_register(UART::IER), 0x12);
+    uart.write_register(UART::LCR, 0b11);
+    assert_eq!(uart.read_register(UART::IER), 0);
+
+    uart.write_register(UART::MCR, UART::MCR_LOOP);
+    uart.write_register(UART::THR, b'l');
+    assert_eq!(uart.read_register(UART::RBR), b'l');
+    assert!(backend.output().is_empty());
+}
+
+#[test]
+fn interrupt_through_plic() {
+    let backend = BufferBackend::new();
+    let mut cpu = setup(&backend);
+    cpu.bus().map(PLIC::BASE, PLIC::SIZE, AccessSizes::WORD, Box::new(PLIC::new(32, 2))).unwrap();
+    cpu.bus().connect_interrupt(UART::BASE, UART::IRQ).unwrap();
+    cpu.bus().device::<PLIC>(PLIC::BASE).unwrap().set_priority(UART::IRQ, 1);
+    cpu.bus().device::<PLIC>(PLIC::BASE).unwrap().set_enabled(0, UART::IRQ, true);
+    // setup ram with instructions
+    cpu.ram().write_word(0, store(StoreType::SB, 1, 2, UART::IER as u16));
+    cpu.registers().write(2, UART::IER_RDA as u32);
+    cpu.csrs().write(MTVEC, 128);
+    cpu.csrs().write(MIE, MIP_MEIP);
+    cpu.csrs().write(MSTATUS, MSTATUS_MIE);
+
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 4);
+
+    backend.push_input(b"!");
+    cpu.tick();
+    assert_eq!(*cpu.pc(), 128);
+    assert_eq!(cpu.csrs().read(MCAUSE), 0x8000000B);
+}
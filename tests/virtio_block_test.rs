@@ -0,0 +1,180 @@
+mod common;
+
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::devices::virtio::*;
+use rust_risc_v::devices::virtio::block::*;
+use common::*;
+
+const HEADER: u32 = 0x4000;
+const DATA: u32 = 0x5000;
+const STATUS_BYTE: u32 = 0x6000;
+
+fn setup(disk: Disk) -> (SystemBus, Driver) {
+    let mut bus = SystemBus::new(0, RAM::new(0x4000));
+    bus.map(BASE, SIZE, AccessSizes::ALL, Box::new(VirtioMMIO::new(VirtioBlock::new(disk)))).unwrap();
+    let driver = Driver::new(&mut bus, BASE, 0, 0x1000);
+    (bus, driver)
+}
+
+fn image() -> Vec<u8> {
+    (0..4 * SECTOR_SIZE).map(|index| (index / SECTOR_SIZE) as u8 + 1).collect()
+}
+
+fn request(bus: &mut SystemBus, driver: &mut Driver, request_type: u32, sector: u64, length: u32, writable: bool) -> (u8, u32) {
+    write_bytes(bus, HEADER, &request_type.to_le_bytes());
+    write_bytes(bus, HEADER + 4, &0u32.to_le_bytes());
+    write_bytes(bus, HEADER + 8, &sector.to_le_bytes());
+
+    let mut buffers = vec![(HEADER, 16, false)];
+    if length != 0 {
+        buffers.push((DATA, length, writable));
+    }
+    buffers.push((STATUS_BYTE, 1, true));
+    let head = driver.submit(bus, &buffers);
+
+    bus.tick();
+    let (used_head, used_length) = driver.used(bus).unwrap();
+    assert_eq!(used_head, head);
+    (read_bytes(bus, STATUS_BYTE, 1)[0], used_length)
+}
+
+#[test]
+fn identification() {
+    let (mut bus, _) = setup(Disk::memory(image(), DiskMode::ReadWrite));
+
+    assert_eq!(bus.read(BASE + MAGIC_VALUE, AccessSize::Word), Ok(MAGIC));
+    assert_eq!(bus.read(BASE + VERSION, AccessSize::Word), Ok(2));
+    assert_eq!(bus.read(BASE + DEVICE_ID, AccessSize::Word), Ok(DEVICE_ID_BLOCK));
+    assert_eq!(bus.read(BASE + CONFIG, AccessSize::Word), Ok(4));
+    assert_eq!(bus.read(BASE + CONFIG + 4, AccessSize::Word), Ok(0));
+    assert_eq!(bus.read(BASE + STATUS, AccessSize::Word).unwrap() & STATUS_FEATURES_OK, STATUS_FEATURES_OK);
+}
+
+#[test]
+fn read_sectors() {
+    let (mut bus, mut driver) = setup(Disk::memory(image(), DiskMode::ReadWrite));
+
+    let (status, length) = request(&mut bus, &mut driver, VIRTIO_BLK_T_IN, 1, 2 * SECTOR_SIZE as u32, true);
+    assert_eq!(status, VIRTIO_BLK_S_OK);
+    assert_eq!(length, 2 * SECTOR_SIZE as u32 + 1);
+    assert_eq!(read_bytes(&mut bus, DATA, 1)[0], 2);
+    assert_eq!(read_bytes(&mut bus, DATA + SECTOR_SIZE as u32, 1)[0], 3);
+
+    // reading past the end fails
+    let (status, _) = request(&mut bus, &mut driver, VIRTIO_BLK_T_IN, 3, 2 * SECTOR_SIZE as u32, true);
+    assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+    // the used buffer raised an interrupt
+    assert_eq!(bus.read(BASE + INTERRUPT_STATUS, AccessSize::Word), Ok(INTERRUPT_USED));
+    bus.write(BASE + INTERRUPT_ACK, AccessSize::Word, INTERRUPT_USED).unwrap();
+    assert_eq!(bus.read(BASE + INTERRUPT_STATUS, AccessSize::Word), Ok(0));
+}
+
+#[test]
+fn write_and_flush() {
+    let (mut bus, mut driver) = setup(Disk::memory(image(), DiskMode::ReadWrite));
+
+    write_bytes(&mut bus, DATA, &[0xAA; SECTOR_SIZE]);
+    let (status, length) = request(&mut bus, &mut driver, VIRTIO_BLK_T_OUT, 2, SECTOR_SIZE as u32, false);
+    assert_eq!((status, length), (VIRTIO_BLK_S_OK, 1));
+
+    let (status, _) = request(&mut bus, &mut driver, VIRTIO_BLK_T_FLUSH, 0, 0, false);
+    assert_eq!(status, VIRTIO_BLK_S_OK);
+
+    write_bytes(&mut bus, DATA, &[0; SECTOR_SIZE]);
+    request(&mut bus, &mut driver, VIRTIO_BLK_T_IN, 2, SECTOR_SIZE as u32, true);
+    assert_eq!(read_bytes(&mut bus, DATA, 4), [0xAA; 4]);
+}
+
+#[test]
+fn get_id_and_unsupported() {
+    let (mut bus, mut driver) = setup(Disk::memory(image(), DiskMode::ReadWrite));
+
+    let (status, _) = request(&mut bus, &mut driver, VIRTIO_BLK_T_GET_ID, 0, 20, true);
+    assert_eq!(status, VIRTIO_BLK_S_OK);
+    assert_eq!(&read_bytes(&mut bus, DATA, 15), b"rust-risc-v-blk");
+
+    let (status, _) = request(&mut bus, &mut driver, 11, 0, 0, false);
+    assert_eq!(status, VIRTIO_BLK_S_UNSUPP);
+}
+
+#[test]
+fn read_only() {
+    let (mut bus, mut driver) = setup(Disk::memory(image(), DiskMode::ReadOnly));
+
+    bus.write(BASE + DEVICE_FEATURES_SEL, AccessSize::Word, 0).unwrap();
+    assert_eq!(bus.read(BASE + DEVICE_FEATURES, AccessSize::Word).unwrap() as u64 & VIRTIO_BLK_F_RO, VIRTIO_BLK_F_RO);
+
+    let (status, _) = request(&mut bus, &mut driver, VIRTIO_BLK_T_OUT, 0, SECTOR_SIZE as u32, false);
+    assert_eq!(status, VIRTIO_BLK_S_IOERR);
+}
+
+#[test]
+fn copy_on_write_file() {
+    let path = std::env::temp_dir().join(format!("rust-risc-v-cow-{}.img", std::process::id()));
+    std::fs::write(&path, image()).unwrap();
+
+    let (mut bus, mut driver) = setup(Disk::open(&path, DiskMode::CopyOnWrite).unwrap());
+
+    write_bytes(&mut bus, DATA, &[0x55; SECTOR_SIZE]);
+    let (status, _) = request(&mut bus, &mut driver, VIRTIO_BLK_T_OUT, 0, SECTOR_SIZE as u32, false);
+    assert_eq!(status, VIRTIO_BLK_S_OK);
+
+    write_bytes(&mut bus, DATA, &[0; 2 * SECTOR_SIZE]);
+    request(&mut bus, &mut driver, VIRTIO_BLK_T_IN, 0, 2 * SECTOR_SIZE as u32, true);
+    assert_eq!(read_bytes(&mut bus, DATA, 1)[0], 0x55);
+    assert_eq!(read_bytes(&mut bus, DATA + SECTOR_SIZE as u32, 1)[0], 2);
+
+    let device = bus.device::<VirtioMMIO<VirtioBlock>>(BASE).unwrap();
+    assert_eq!(device.device().disk().dirty_sectors(), 1);
+
+    // the image on disk is untouched
+    assert_eq!(std::fs::read(&path).unwrap(), image());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn malformed_queue_needs_reset() {
+    let (mut bus, mut driver) = setup(Disk::memory(image(), DiskMode::ReadWrite));
+
+    driver.submit(&mut bus, &[(0x8000_0000, 16, false), (STATUS_BYTE, 1, true)]);
+    bus.tick();
+    assert_eq!(bus.read(BASE + STATUS, AccessSize::Word).unwrap() & STATUS_NEEDS_RESET, STATUS_NEEDS_RESET);
+    assert_eq!(bus.read(BASE + INTERRUPT_STATUS, AccessSize::Word), Ok(INTERRUPT_CONFIG));
+
+    bus.write(BASE + STATUS, AccessSize::Word, 0).unwrap();
+    assert_eq!(bus.read(BASE + STATUS, AccessSize::Word), Ok(0));
+}
+
+fn needs_reset(bus: &mut SystemBus) -> bool {
+    bus.read(BASE + STATUS, AccessSize::Word).unwrap() & STATUS_NEEDS_RESET != 0
+}
+
+#[test]
+fn hostile_requests() {
+    // sectors past the end of the 64 bit sector space fail instead of wrapping
+    let (mut bus, mut driver) = setup(Disk::memory(image(), DiskMode::ReadWrite));
+    let (status, length) = request(&mut bus, &mut driver, VIRTIO_BLK_T_IN, u64::MAX, 2 * SECTOR_SIZE as u32, true);
+    assert_eq!((status, length), (VIRTIO_BLK_S_IOERR, 1));
+    let (status, _) = request(&mut bus, &mut driver, VIRTIO_BLK_T_OUT, u64::MAX, 2 * SECTOR_SIZE as u32, false);
+    assert_eq!(status, VIRTIO_BLK_S_IOERR);
+    assert!(!needs_reset(&mut bus));
+
+    // lengths that overflow when summed or describe gigabytes are refused before anything is allocated
+    driver.submit(&mut bus, &[(HEADER, 16, false), (DATA, u32::MAX, false), (DATA, u32::MAX, false), (STATUS_BYTE, 1, true)]);
+    bus.tick();
+    assert!(needs_reset(&mut bus));
+
+    let (mut bus, mut driver) = setup(Disk::memory(image(), DiskMode::ReadWrite));
+    driver.submit(&mut bus, &[(HEADER, 16, false), (DATA, 0x8000_0000, true)]);
+    bus.tick();
+    assert!(needs_reset(&mut bus));
+
+    // a buffer at the top of the address space
+    let (mut bus, mut driver) = setup(Disk::memory(image(), DiskMode::ReadWrite));
+    write_bytes(&mut bus, HEADER, &VIRTIO_BLK_T_IN.to_le_bytes());
+    driver.submit(&mut bus, &[(HEADER, 16, false), (u32::MAX, 2, true)]);
+    bus.tick();
+    assert!(needs_reset(&mut bus));
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::devices::uart::*;
+use rust_risc_v::devices::virtio::*;
+use rust_risc_v::devices::virtio::console::*;
+use common::*;
+
+const BUFFER: u32 = 0x8000;
+
+fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut message = id.to_le_bytes().to_vec();
+    message.extend_from_slice(&event.to_le_bytes());
+    message.extend_from_slice(&value.to_le_bytes());
+    message
+}
+
+#[test]
+fn console_port() {
+    let backend = BufferBackend::new();
+    let mut bus = SystemBus::new(0, RAM::new(0x4000));
+    bus.map(BASE, SIZE, AccessSizes::ALL, Box::new(VirtioMMIO::new(VirtioConsole::new(Box::new(backend.clone()))))).unwrap();
+    let mut receive = Driver::new(&mut bus, BASE, 0, 0x1000);
+    let mut transmit = Driver::new(&mut bus, BASE, 1, 0x2000);
+
+    assert_eq!(bus.read(BASE + DEVICE_ID, AccessSize::Word), Ok(DEVICE_ID_CONSOLE));
+    assert_eq!(bus.read(BASE + CONFIG, AccessSize::Half), Ok(80));
+    assert_eq!(bus.read(BASE + CONFIG + 4, AccessSize::Word), Ok(1));
+
+    // output
+    write_bytes(&mut bus, BUFFER, b"hvc0");
+    let head = transmit.submit(&mut bus, &[(BUFFER, 4, false)]);
+    bus.tick();
+    assert_eq!(transmit.used(&mut bus), Some((head, 0)));
+    assert_eq!(backend.take_output(), b"hvc0");
+
+    // input waits for a receive buffer
+    backend.push_input(b"abc");
+    bus.tick();
+    assert_eq!(receive.used(&mut bus), None);
+
+    let head = receive.submit(&mut bus, &[(BUFFER + 0x100, 2, true)]);
+    bus.tick();
+    assert_eq!(receive.used(&mut bus), Some((head, 2)));
+    assert_eq!(read_bytes(&mut bus, BUFFER + 0x100, 2), b"ab");
+
+    let head = receive.submit(&mut bus, &[(BUFFER + 0x100, 16, true)]);
+    bus.tick();
+    assert_eq!(receive.used(&mut bus), Some((head, 1)));
+    assert_eq!(read_bytes(&mut bus, BUFFER + 0x100, 1), b"c");
+
+    // emergency write
+    bus.write(BASE + CONFIG + 8, AccessSize::Word, b'!' as u32).unwrap();
+    assert_eq!(backend.take_output(), b"!");
+}
+
+#[test]
+fn multiport() {
+    let console = BufferBackend::new();
+    let data = BufferBackend::new();
+    let mut device = VirtioConsole::new(Box::new(console.clone()));
+    assert_eq!(device.add_port("data", Box::new(data.clone())), 1);
+
+    let mut bus = SystemBus::new(0, RAM::new(0x4000));
+    bus.map(BASE, SIZE, AccessSizes::ALL, Box::new(VirtioMMIO::new(device))).unwrap();
+    let mut control_receive = Driver::new(&mut bus, BASE, CONTROL_RECEIVE as u32, 0x1000);
+    let mut control_transmit = Driver::new(&mut bus, BASE, CONTROL_TRANSMIT as u32, 0x2000);
+    let (receive_queue, transmit_queue) = VirtioConsole::port_queues(1);
+    let mut port_receive = Driver::new(&mut bus, BASE, receive_queue as u32, 0x3000);
+    let mut port_transmit = Driver::new(&mut bus, BASE, transmit_queue as u32, 0x4000);
+
+    assert_eq!(bus.read(BASE + CONFIG + 4, AccessSize::Word), Ok(2));
+
+    // device ready announces both ports
+    write_bytes(&mut bus, BUFFER, &control(0, VIRTIO_CONSOLE_DEVICE_READY, 1));
+    control_transmit.submit(&mut bus, &[(BUFFER, 8, false)]);
+    for index in 0..4 {
+        control_receive.submit(&mut bus, &[(BUFFER + 0x100 + index * 0x20, 0x20, true)]);
+    }
+    bus.tick();
+    assert!(control_transmit.used(&mut bus).is_some());
+    assert_eq!(control_receive.used(&mut bus).unwrap().1, 8);
+    assert_eq!(read_bytes(&mut bus, BUFFER + 0x100, 8), control(0, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+    assert_eq!(read_bytes(&mut bus, BUFFER + 0x120, 8), control(1, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+
+    // port ready reports the name and opens the port
+    write_bytes(&mut bus, BUFFER, &control(1, VIRTIO_CONSOLE_PORT_READY, 1));
+    control_transmit.submit(&mut bus, &[(BUFFER, 8, false)]);
+    bus.tick();
+    assert_eq!(control_receive.used(&mut bus).unwrap().1, 8);
+    let mut name = control(1, VIRTIO_CONSOLE_PORT_NAME, 1);
+    name.extend_from_slice(b"data");
+    assert_eq!(control_receive.used(&mut bus).unwrap().1, 12);
+    assert_eq!(read_bytes(&mut bus, BUFFER + 0x140, 12), name);
+    assert_eq!(control_receive.used(&mut bus).unwrap().1, 8);
+    assert_eq!(read_bytes(&mut bus, BUFFER + 0x160, 8), control(1, VIRTIO_CONSOLE_PORT_OPEN, 1));
+
+    // port i/o is separate from the console
+    write_bytes(&mut bus, BUFFER, b"port");
+    port_transmit.submit(&mut bus, &[(BUFFER, 4, false)]);
+    data.push_input(b"in");
+    port_receive.submit(&mut bus, &[(BUFFER + 0x400, 8, true)]);
+    bus.tick();
+    assert_eq!(data.take_output(), b"port");
+    assert!(console.output().is_empty());
+    assert_eq!(port_receive.used(&mut bus).unwrap().1, 2);
+    assert_eq!(read_bytes(&mut bus, BUFFER + 0x400, 2), b"in");
+}
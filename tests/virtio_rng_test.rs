@@ -0,0 +1,50 @@
+mod common;
+
+use rust_risc_v::*;
+use rust_risc_v::bus::*;
+use rust_risc_v::devices::virtio::*;
+use rust_risc_v::devices::virtio::rng::*;
+use common::*;
+
+const BUFFER: u32 = 0x8000;
+
+fn entropy(rng: VirtioRNG, length: u32) -> Vec<u8> {
+    let mut bus = SystemBus::new(0, RAM::new(0x4000));
+    bus.map(BASE, SIZE, AccessSizes::ALL, Box::new(VirtioMMIO::new(rng))).unwrap();
+    let mut driver = Driver::new(&mut bus, BASE, 0, 0x1000);
+
+    assert_eq!(bus.read(BASE + DEVICE_ID, AccessSize::Word), Ok(DEVICE_ID_RNG));
+
+    let head = driver.submit(&mut bus, &[(BUFFER, length / 2, true), (BUFFER + length / 2, length - length / 2, true)]);
+    bus.tick();
+    assert_eq!(driver.used(&mut bus), Some((head, length)));
+
+    read_bytes(&mut bus, BUFFER, length)
+}
+
+#[test]
+fn seeded_is_reproducible() {
+    let first = entropy(VirtioRNG::seeded(42), 37);
+    let second = entropy(VirtioRNG::seeded(42), 37);
+    let other = entropy(VirtioRNG::seeded(43), 37);
+
+    assert_eq!(first, second);
+    assert_ne!(first, other);
+    assert!(first.iter().any(|&byte| byte != 0));
+}
+
+#[test]
+fn host_entropy() {
+    let first = entropy(VirtioRNG::host(), 64);
+    let second = entropy(VirtioRNG::host(), 64);
+
+    assert_ne!(first, second);
+}
+
+#[test]
+fn large_requests_in_chunks() {
+    // the stream is the same as one fill of the whole buffer
+    let mut expected = vec![0; 10_000];
+    VirtioRNG::seeded(42).fill(&mut expected);
+    assert_eq!(entropy(VirtioRNG::seeded(42), 10_000), expected);
+}